@@ -1,6 +1,11 @@
-use notaro_core::{DatabaseConnection, Note, UserSettings};
+use notaro_core::sync::presence_device_id;
+use notaro_core::{DatabaseConnection, Note, NotePresence, SyncMessage, UserSettings};
+use serde::Serialize;
 use std::sync::Mutex;
-use tauri::{Manager, State};
+use tauri::{AppHandle, Emitter, Manager, State};
+
+/// Event the UI listens to for the other devices that have a note open
+pub const PRESENCE_EVENT: &str = "note-presence";
 
 // 1. Define AppState to hold the database connection safely
 struct AppState {
@@ -62,6 +67,35 @@ fn save_settings(state: State<AppState>, settings: UserSettings) -> Result<(), S
     db.update_settings(&settings).map_err(|e| e.to_string())
 }
 
+/// Payload of `PRESENCE_EVENT`
+#[derive(Clone, Serialize)]
+struct PresencePayload<'a> {
+    note_id: &'a str,
+    others: Vec<&'a NotePresence>,
+}
+
+/// Forwards a `PresenceUpdate` from the sync server to the UI, leaving out this device, which
+/// is logged in as session `session_id` of account `user_id`. Other messages are ignored.
+pub fn emit_presence(
+    app: &AppHandle,
+    db: &DatabaseConnection,
+    session_id: &str,
+    user_id: &str,
+    message: &SyncMessage,
+) -> tauri::Result<()> {
+    if let SyncMessage::PresenceUpdate { note_id, present } = message {
+        // The server lists devices by an id salted per note scope, so ours differs per note
+        let own_device = db
+            .get_note_by_id(note_id)
+            .ok()
+            .map(|note| presence_device_id(session_id, user_id, &note));
+        let others =
+            present.iter().filter(|p| own_device.as_deref() != Some(p.device.as_str())).collect();
+        app.emit(PRESENCE_EVENT, PresencePayload { note_id, others })?;
+    }
+    Ok(())
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
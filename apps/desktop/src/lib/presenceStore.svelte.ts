@@ -0,0 +1,37 @@
+import { listen } from '@tauri-apps/api/event';
+
+export interface NotePresence {
+  device: string;
+  device_name: string;
+  username: string;
+  typing: boolean;
+}
+
+interface PresencePayload {
+  note_id: string;
+  others: NotePresence[];
+}
+
+class PresenceStore {
+  // Other devices with each note open, by note id
+  byNote = $state<Record<string, NotePresence[]>>({});
+
+  constructor() {}
+
+  async init() {
+    try {
+      await listen<PresencePayload>('note-presence', (event) => {
+        const { note_id, others } = event.payload;
+        this.byNote = { ...this.byNote, [note_id]: others };
+      });
+    } catch (e) {
+      console.error('Failed to listen for presence:', e);
+    }
+  }
+
+  others(noteId: string): NotePresence[] {
+    return this.byNote[noteId] ?? [];
+  }
+}
+
+export const presenceStore = new PresenceStore();
@@ -0,0 +1,1600 @@
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use chrono::{DateTime, Utc};
+use notaro_core::SyncFilter;
+use rand_core::{OsRng, RngCore};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Type, ValueRef};
+use rusqlite::{Connection, OptionalExtension, ToSql, params};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::LazyLock;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Shortest password accepted when creating an account
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Checked in place of an account's hash when the username is unknown, so that such logins are
+/// refused as slowly as wrong passwords and do not reveal which names exist
+static UNKNOWN_USER_HASH: LazyLock<String> = LazyLock::new(|| {
+    hash_password("no account has this password").expect("hashing a fixed password failed")
+});
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+    #[error("username is already taken")]
+    UsernameTaken,
+
+    #[error("username must not be empty")]
+    InvalidUsername,
+
+    #[error("password must be at least {MIN_PASSWORD_LEN} characters")]
+    WeakPassword,
+
+    #[error("invalid username or password")]
+    InvalidCredentials,
+
+    #[error("missing or unknown token")]
+    InvalidToken,
+
+    /// The token belonged to a device session that has since been revoked
+    #[error("this device was signed out")]
+    SignedOut,
+
+    #[error("registration is closed on this server")]
+    RegistrationClosed,
+
+    #[error("this account is disabled")]
+    AccountDisabled,
+
+    #[error("no such device session")]
+    SessionNotFound,
+
+    #[error("no such user")]
+    UnknownUser,
+
+    #[error("collection name must not be empty")]
+    InvalidCollectionName,
+
+    /// Also returned to users who are not members, so collection ids cannot be probed
+    #[error("no such collection")]
+    CollectionNotFound,
+
+    #[error("only the collection owner can manage its members")]
+    NotCollectionOwner,
+
+    #[error("a collection keeps exactly one owner")]
+    OwnerRole,
+
+    #[error("user is not a member of this collection")]
+    NotAMember,
+
+    #[error("no such note")]
+    NoteNotFound,
+
+    /// Also returned for links that were revoked or belong to someone else
+    #[error("no such share link")]
+    ShareNotFound,
+
+    #[error("a share link must expire in the future")]
+    InvalidExpiry,
+
+    /// Also returned for webhooks of other users
+    #[error("no such webhook")]
+    WebhookNotFound,
+
+    #[error("webhook URL must be an absolute http or https URL")]
+    InvalidWebhookUrl,
+
+    /// The URL leads into the server's own network, e.g. to a loopback or private address
+    #[error("webhook URL must point to a public address")]
+    PrivateWebhookUrl,
+
+    #[error("Database error: {0}")]
+    Db(#[from] rusqlite::Error),
+
+    #[error("Password hashing error: {0}")]
+    Hash(argon2::password_hash::Error),
+
+    /// Reading or writing the notes of a shared collection failed
+    #[error("Note storage error: {0}")]
+    Store(#[from] notaro_core::NotaroError),
+}
+
+impl From<argon2::password_hash::Error> for AuthError {
+    fn from(e: argon2::password_hash::Error) -> Self {
+        Self::Hash(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, AuthError>;
+
+/// Server-wide account database: users with Argon2 password hashes, the device sessions
+/// logged in as them, who belongs to which shared collection, the public share links of their
+/// notes and their webhooks with the queue of deliveries to them. Notes live elsewhere, in one
+/// store per user and per collection.
+///
+/// Each login creates a device session identified by a bearer token. Only a SHA-256 of the token
+/// is stored, so a leaked accounts database cannot be replayed. Share link tokens are kept the
+/// same way.
+pub struct AccountStore {
+    conn: Connection,
+}
+
+impl AccountStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let store = Self { conn: Connection::open(path)? };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    pub fn in_memory() -> Result<Self> {
+        Self::open(":memory:")
+    }
+
+    fn migrate(&self) -> Result<()> {
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS users (
+                id TEXT PRIMARY KEY,
+                username TEXT NOT NULL UNIQUE,
+                password_hash TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS tokens (
+                token_hash TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL REFERENCES users(id),
+                created_at TEXT NOT NULL,
+                revoked_at TEXT
+            );",
+        )?;
+
+        // Tokens became device sessions: an id to address them by, a name and where they were
+        // last seen
+        self.add_column_if_missing("tokens", "id", "TEXT")?;
+        self.add_column_if_missing("tokens", "device_name", "TEXT NOT NULL DEFAULT ''")?;
+        self.add_column_if_missing("tokens", "last_seen_at", "TEXT")?;
+        self.add_column_if_missing("tokens", "last_ip", "TEXT")?;
+        self.add_column_if_missing("users", "disabled_at", "TEXT")?;
+        self.conn.execute_batch(
+            "UPDATE tokens SET id = lower(hex(randomblob(16))) WHERE id IS NULL;
+             CREATE UNIQUE INDEX IF NOT EXISTS tokens_id ON tokens(id);",
+        )?;
+
+        // Shared collections and each member's role
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS collections (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS collection_members (
+                collection_id TEXT NOT NULL REFERENCES collections(id),
+                user_id TEXT NOT NULL REFERENCES users(id),
+                role TEXT NOT NULL,
+                added_at TEXT NOT NULL,
+                PRIMARY KEY (collection_id, user_id)
+            );
+            CREATE INDEX IF NOT EXISTS collection_members_user ON collection_members(user_id);",
+        )?;
+
+        // Read-only links to single notes for people without an account
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS share_links (
+                id TEXT PRIMARY KEY,
+                token_hash TEXT NOT NULL UNIQUE,
+                user_id TEXT NOT NULL REFERENCES users(id),
+                note_id TEXT NOT NULL,
+                password_hash TEXT,
+                created_at TEXT NOT NULL,
+                expires_at TEXT,
+                revoked_at TEXT
+            );
+            CREATE INDEX IF NOT EXISTS share_links_user ON share_links(user_id);",
+        )?;
+
+        // Webhooks, and the change notifications queued for them. A delivery stays due while
+        // `next_attempt_at` is set and is kept afterwards as the delivery log.
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS webhooks (
+                id TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL REFERENCES users(id),
+                url TEXT NOT NULL,
+                secret TEXT NOT NULL,
+                filter TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS webhooks_user ON webhooks(user_id);
+            CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                id TEXT PRIMARY KEY,
+                webhook_id TEXT NOT NULL REFERENCES webhooks(id),
+                event TEXT NOT NULL,
+                note_id TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at TEXT,
+                delivered_at TEXT,
+                last_status INTEGER,
+                last_error TEXT
+            );
+            CREATE INDEX IF NOT EXISTS webhook_deliveries_due
+                ON webhook_deliveries(next_attempt_at);
+            CREATE INDEX IF NOT EXISTS webhook_deliveries_webhook
+                ON webhook_deliveries(webhook_id, created_at);",
+        )?;
+
+        // Security-relevant and destructive events. Rows are only ever inserted; the triggers
+        // refuse changes even from other tools opening the file.
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                at TEXT NOT NULL,
+                event TEXT NOT NULL,
+                user_id TEXT,
+                session_id TEXT,
+                device_name TEXT,
+                ip TEXT,
+                target TEXT,
+                details TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS audit_log_user ON audit_log(user_id, id);
+            CREATE INDEX IF NOT EXISTS audit_log_at ON audit_log(at);
+            CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+            BEGIN SELECT RAISE(ABORT, 'the audit log is append-only'); END;
+            CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+            BEGIN SELECT RAISE(ABORT, 'the audit log is append-only'); END;",
+        )?;
+        Ok(())
+    }
+
+    fn add_column_if_missing(&self, table: &str, column: &str, definition: &str) -> Result<()> {
+        let mut stmt = self.conn.prepare(&format!("PRAGMA table_info({table})"))?;
+        let exists = stmt
+            .query_map([], |row| row.get::<_, String>(1))?
+            .collect::<rusqlite::Result<Vec<_>>>()?
+            .iter()
+            .any(|name| name == column);
+        if !exists {
+            self.conn
+                .execute(&format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"), [])?;
+        }
+        Ok(())
+    }
+
+    /// Creates an account and returns its user id
+    pub fn create_user(&self, username: &str, password: &str) -> Result<String> {
+        self.insert_user(username, &hash_password(password)?)
+    }
+
+    /// Creates an account with a hash made by `hash_password` and returns its user id. Lets
+    /// callers hash without holding the store.
+    pub fn insert_user(&self, username: &str, password_hash: &str) -> Result<String> {
+        let username = username.trim();
+        if username.is_empty() {
+            return Err(AuthError::InvalidUsername);
+        }
+
+        let id = Uuid::new_v4().to_string();
+        // The UNIQUE constraint decides, so two registrations racing for a name cannot both pass
+        let inserted = self.conn.execute(
+            "INSERT INTO users (id, username, password_hash, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![id, username, password_hash, Utc::now()],
+        );
+        match inserted {
+            Ok(_) => Ok(id),
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Err(AuthError::UsernameTaken)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Id of the account called `username`, if there is one
+    pub fn user_id_of(&self, username: &str) -> Result<Option<String>> {
+        Ok(self.find_user(username.trim())?.map(|user| user.id))
+    }
+
+    /// Checks a password and starts a device session for the account
+    pub fn login(&self, username: &str, password: &str, device_name: &str) -> Result<Login> {
+        let user = self.credentials(username)?;
+        let user_id = verify_login(user.as_ref(), password)?;
+        self.start_session(&user_id, device_name)
+    }
+
+    /// The stored password of the account called `username`, for `verify_login`
+    pub fn credentials(&self, username: &str) -> Result<Option<StoredUser>> {
+        self.find_user(username.trim())
+    }
+
+    /// Starts a device session for an account whose password was checked
+    pub fn start_session(&self, user_id: &str, device_name: &str) -> Result<Login> {
+        let token = generate_token();
+        let session_id = Uuid::new_v4().to_string();
+        self.conn.execute(
+            "INSERT INTO tokens (token_hash, id, user_id, device_name, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![hash_token(&token), session_id, user_id, device_name.trim(), Utc::now()],
+        )?;
+        Ok(Login { user_id: user_id.to_string(), session_id, token })
+    }
+
+    /// Resolves a bearer token to its device session and records it as seen from `ip`.
+    /// A revoked token fails with `SignedOut` so clients can tell it apart from a bad one.
+    pub fn authenticate(&self, token: &str, ip: Option<&str>) -> Result<Authenticated> {
+        let token_hash = hash_token(token);
+        let (user_id, session_id, revoked_at): (String, String, Option<DateTime<Utc>>) = self
+            .conn
+            .query_row(
+                "SELECT user_id, id, revoked_at FROM tokens WHERE token_hash = ?1",
+                params![token_hash],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?
+            .ok_or(AuthError::InvalidToken)?;
+        if revoked_at.is_some() {
+            return Err(AuthError::SignedOut);
+        }
+
+        self.conn.execute(
+            "UPDATE tokens SET last_seen_at = ?1, last_ip = COALESCE(?2, last_ip)
+             WHERE token_hash = ?3",
+            params![Utc::now(), ip, token_hash],
+        )?;
+        Ok(Authenticated { user_id, session_id })
+    }
+
+    /// False once a device session has been revoked
+    pub fn is_session_active(&self, session_id: &str) -> Result<bool> {
+        let active: Option<bool> = self
+            .conn
+            .query_row(
+                "SELECT revoked_at IS NULL FROM tokens WHERE id = ?1",
+                params![session_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(active.unwrap_or(false))
+    }
+
+    /// Who a device session belongs to and what the device is called, for showing it to others
+    pub fn device_label(&self, session_id: &str) -> Result<Option<DeviceLabel>> {
+        self.conn
+            .query_row(
+                "SELECT u.username, t.device_name FROM tokens t JOIN users u ON u.id = t.user_id
+                 WHERE t.id = ?1",
+                params![session_id],
+                |row| Ok(DeviceLabel { username: row.get(0)?, device_name: row.get(1)? }),
+            )
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// Active device sessions of a user, most recently seen first
+    pub fn list_sessions(&self, user_id: &str) -> Result<Vec<DeviceSession>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, device_name, created_at, last_seen_at, last_ip FROM tokens
+             WHERE user_id = ?1 AND revoked_at IS NULL
+             ORDER BY COALESCE(last_seen_at, created_at) DESC",
+        )?;
+        let sessions = stmt
+            .query_map(params![user_id], |row| {
+                Ok(DeviceSession {
+                    id: row.get(0)?,
+                    device_name: row.get(1)?,
+                    created_at: row.get(2)?,
+                    last_seen_at: row.get(3)?,
+                    last_ip: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(sessions)
+    }
+
+    /// Revokes a device session and returns the user it belonged to.
+    /// With `owner` set, only that user's sessions can be revoked; admins pass `None`.
+    pub fn revoke_session(&self, session_id: &str, owner: Option<&str>) -> Result<String> {
+        self.conn
+            .query_row(
+                "UPDATE tokens SET revoked_at = ?1
+                 WHERE id = ?2 AND revoked_at IS NULL AND (?3 IS NULL OR user_id = ?3)
+                 RETURNING user_id",
+                params![Utc::now(), session_id, owner],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or(AuthError::SessionNotFound)
+    }
+
+    /// Revokes every active session of a user and returns how many there were
+    pub fn revoke_all_sessions(&self, user_id: &str) -> Result<usize> {
+        Ok(self.conn.execute(
+            "UPDATE tokens SET revoked_at = ?1 WHERE user_id = ?2 AND revoked_at IS NULL",
+            params![Utc::now(), user_id],
+        )?)
+    }
+
+    pub fn user_id_by_name(&self, username: &str) -> Result<String> {
+        self.find_user(username.trim())?.map(|user| user.id).ok_or(AuthError::UnknownUser)
+    }
+
+    /// Every account, oldest first
+    pub fn list_users(&self) -> Result<Vec<UserSummary>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, username, created_at, disabled_at IS NOT NULL,
+                (SELECT COUNT(*) FROM tokens WHERE user_id = users.id AND revoked_at IS NULL)
+             FROM users ORDER BY created_at",
+        )?;
+        let users = stmt
+            .query_map([], |row| {
+                Ok(UserSummary {
+                    id: row.get(0)?,
+                    username: row.get(1)?,
+                    created_at: row.get(2)?,
+                    disabled: row.get(3)?,
+                    active_sessions: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(users)
+    }
+
+    /// Disables or re-enables an account and returns its user id.
+    /// Disabling signs out every device; the account's notes are kept.
+    pub fn set_disabled(&self, username: &str, disabled: bool) -> Result<String> {
+        let user_id = self.user_id_by_name(username)?;
+        let disabled_at = disabled.then(Utc::now);
+        self.conn.execute(
+            "UPDATE users SET disabled_at = ?1 WHERE id = ?2",
+            params![disabled_at, user_id],
+        )?;
+        if disabled {
+            self.revoke_all_sessions(&user_id)?;
+        }
+        Ok(user_id)
+    }
+
+    /// Replaces a password and signs out every device logged in with the old one
+    pub fn reset_password(&self, username: &str, password: &str) -> Result<String> {
+        let user_id = self.user_id_by_name(username)?;
+        let hash = hash_password(password)?;
+        self.conn
+            .execute("UPDATE users SET password_hash = ?1 WHERE id = ?2", params![hash, user_id])?;
+        self.revoke_all_sessions(&user_id)?;
+        Ok(user_id)
+    }
+
+    /// Fails if the database cannot be queried
+    pub fn ping(&self) -> Result<()> {
+        Ok(self.conn.query_row("SELECT 1", [], |_| Ok(()))?)
+    }
+
+    /// Runs SQLite's integrity check; returns the problems found
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare("PRAGMA integrity_check")?;
+        let problems = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter(|line| !matches!(line.as_deref(), Ok("ok")))
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(problems)
+    }
+
+    /// Creates a shared collection owned by `owner_id`
+    pub fn create_collection(&self, owner_id: &str, name: &str) -> Result<Collection> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(AuthError::InvalidCollectionName);
+        }
+
+        let collection = Collection {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            role: Role::Owner,
+            created_at: Utc::now(),
+        };
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO collections (id, name, created_at) VALUES (?1, ?2, ?3)",
+            params![collection.id, collection.name, collection.created_at],
+        )?;
+        tx.execute(
+            "INSERT INTO collection_members (collection_id, user_id, role, added_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![collection.id, owner_id, Role::Owner, collection.created_at],
+        )?;
+        tx.commit()?;
+        Ok(collection)
+    }
+
+    /// Collections `user_id` is a member of, by name
+    pub fn list_collections(&self, user_id: &str) -> Result<Vec<Collection>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT c.id, c.name, m.role, c.created_at
+             FROM collections c JOIN collection_members m ON m.collection_id = c.id
+             WHERE m.user_id = ?1 ORDER BY c.name, c.id",
+        )?;
+        let collections = stmt
+            .query_map(params![user_id], collection_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(collections)
+    }
+
+    /// The collection as seen by `user_id`, or `None` unless they are a member
+    pub fn membership(&self, collection_id: &str, user_id: &str) -> Result<Option<Collection>> {
+        self.conn
+            .query_row(
+                "SELECT c.id, c.name, m.role, c.created_at
+                 FROM collections c JOIN collection_members m ON m.collection_id = c.id
+                 WHERE c.id = ?1 AND m.user_id = ?2",
+                params![collection_id, user_id],
+                collection_from_row,
+            )
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// Members of a collection, owner first
+    pub fn collection_members(&self, collection_id: &str) -> Result<Vec<CollectionMember>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT m.user_id, u.username, m.role, m.added_at
+             FROM collection_members m JOIN users u ON u.id = m.user_id
+             WHERE m.collection_id = ?1 ORDER BY m.role = 'owner' DESC, u.username",
+        )?;
+        let members = stmt
+            .query_map(params![collection_id], |row| {
+                Ok(CollectionMember {
+                    user_id: row.get(0)?,
+                    username: row.get(1)?,
+                    role: row.get(2)?,
+                    added_at: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(members)
+    }
+
+    /// Adds `user_id` to a collection as an editor or viewer, or changes their role.
+    /// Returns true if they were not a member before.
+    pub fn set_collection_member(
+        &self,
+        collection_id: &str,
+        user_id: &str,
+        role: Role,
+    ) -> Result<bool> {
+        let current = self.membership(collection_id, user_id)?.map(|c| c.role);
+        if role == Role::Owner || current == Some(Role::Owner) {
+            return Err(AuthError::OwnerRole);
+        }
+        self.conn.execute(
+            "INSERT INTO collection_members (collection_id, user_id, role, added_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(collection_id, user_id) DO UPDATE SET role = excluded.role",
+            params![collection_id, user_id, role, Utc::now()],
+        )?;
+        Ok(current.is_none())
+    }
+
+    /// Removes a member other than the owner from a collection
+    pub fn remove_collection_member(&self, collection_id: &str, user_id: &str) -> Result<()> {
+        match self.membership(collection_id, user_id)?.map(|c| c.role) {
+            None => Err(AuthError::NotAMember),
+            Some(Role::Owner) => Err(AuthError::OwnerRole),
+            Some(_) => {
+                self.conn.execute(
+                    "DELETE FROM collection_members WHERE collection_id = ?1 AND user_id = ?2",
+                    params![collection_id, user_id],
+                )?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Ids of every collection on the server
+    pub fn collection_ids(&self) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare("SELECT id FROM collections ORDER BY created_at")?;
+        let ids = stmt.query_map([], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+        Ok(ids)
+    }
+
+    /// Creates a share link to `user_id`'s note. The link stops working at `expires_at`, and
+    /// with a `password` visitors have to enter it first.
+    pub fn create_share_link(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        expires_at: Option<DateTime<Utc>>,
+        password: Option<&str>,
+    ) -> Result<NewShareLink> {
+        let created_at = Utc::now();
+        if expires_at.is_some_and(|expires_at| expires_at <= created_at) {
+            return Err(AuthError::InvalidExpiry);
+        }
+        let password_hash = password.map(hash_password).transpose()?;
+
+        let token = generate_token();
+        let link = ShareLink {
+            id: Uuid::new_v4().to_string(),
+            note_id: note_id.to_string(),
+            created_at,
+            expires_at,
+            has_password: password_hash.is_some(),
+        };
+        self.conn.execute(
+            "INSERT INTO share_links
+                 (id, token_hash, user_id, note_id, password_hash, created_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                link.id,
+                hash_token(&token),
+                user_id,
+                link.note_id,
+                password_hash,
+                link.created_at,
+                link.expires_at
+            ],
+        )?;
+        Ok(NewShareLink { path: format!("/s/{token}"), link, token })
+    }
+
+    /// `user_id`'s share links that were not revoked, expired ones included, newest first
+    pub fn list_share_links(&self, user_id: &str) -> Result<Vec<ShareLink>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, note_id, created_at, expires_at, password_hash IS NOT NULL
+             FROM share_links WHERE user_id = ?1 AND revoked_at IS NULL
+             ORDER BY created_at DESC, id",
+        )?;
+        let links = stmt
+            .query_map(params![user_id], share_link_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(links)
+    }
+
+    /// Revokes one of `owner`'s share links
+    pub fn revoke_share_link(&self, link_id: &str, owner: &str) -> Result<()> {
+        let revoked = self.conn.execute(
+            "UPDATE share_links SET revoked_at = ?1
+             WHERE id = ?2 AND user_id = ?3 AND revoked_at IS NULL",
+            params![Utc::now(), link_id, owner],
+        )?;
+        if revoked == 0 {
+            return Err(AuthError::ShareNotFound);
+        }
+        Ok(())
+    }
+
+    /// Resolves a share token to its link. Revoked links and links of disabled accounts are not
+    /// found; expiry is left to the caller so it can tell visitors the link expired.
+    pub fn resolve_share_link(&self, token: &str) -> Result<SharedNote> {
+        self.conn
+            .query_row(
+                "SELECT s.id, s.note_id, s.created_at, s.expires_at, s.password_hash IS NOT NULL,
+                        s.user_id, s.token_hash, s.password_hash
+                 FROM share_links s JOIN users u ON u.id = s.user_id
+                 WHERE s.token_hash = ?1 AND s.revoked_at IS NULL AND u.disabled_at IS NULL",
+                params![hash_token(token)],
+                |row| {
+                    Ok(SharedNote {
+                        link: share_link_from_row(row)?,
+                        user_id: row.get(5)?,
+                        token_hash: row.get(6)?,
+                        password_hash: row.get(7)?,
+                    })
+                },
+            )
+            .optional()?
+            .ok_or(AuthError::ShareNotFound)
+    }
+
+    /// Subscribes `user_id` to changes of the notes `filter` lets through. The secret that signs
+    /// deliveries is generated here and kept in the clear, since signing needs it.
+    pub fn create_webhook(
+        &self,
+        user_id: &str,
+        url: &str,
+        filter: &SyncFilter,
+    ) -> Result<NewWebhook> {
+        let webhook = Webhook {
+            id: Uuid::new_v4().to_string(),
+            url: url.to_string(),
+            filter: filter.clone(),
+            created_at: Utc::now(),
+        };
+        let secret = generate_token();
+        self.conn.execute(
+            "INSERT INTO webhooks (id, user_id, url, secret, filter, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                webhook.id,
+                user_id,
+                webhook.url,
+                secret,
+                filter_json(&webhook.filter),
+                webhook.created_at
+            ],
+        )?;
+        Ok(NewWebhook { webhook, secret })
+    }
+
+    /// `user_id`'s webhooks, oldest first
+    pub fn list_webhooks(&self, user_id: &str) -> Result<Vec<Webhook>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, url, filter, created_at FROM webhooks WHERE user_id = ?1
+             ORDER BY created_at, id",
+        )?;
+        let webhooks =
+            stmt.query_map(params![user_id], webhook_from_row)?.collect::<rusqlite::Result<_>>()?;
+        Ok(webhooks)
+    }
+
+    /// Deletes one of `owner`'s webhooks together with its deliveries, pending ones included
+    pub fn delete_webhook(&self, webhook_id: &str, owner: &str) -> Result<()> {
+        self.find_webhook(webhook_id, owner)?;
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM webhook_deliveries WHERE webhook_id = ?1", params![webhook_id])?;
+        tx.execute("DELETE FROM webhooks WHERE id = ?1", params![webhook_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn find_webhook(&self, webhook_id: &str, owner: &str) -> Result<Webhook> {
+        self.conn
+            .query_row(
+                "SELECT id, url, filter, created_at FROM webhooks WHERE id = ?1 AND user_id = ?2",
+                params![webhook_id, owner],
+                webhook_from_row,
+            )
+            .optional()?
+            .ok_or(AuthError::WebhookNotFound)
+    }
+
+    /// Queues `event` about `note_id` for a webhook, due right away
+    pub fn enqueue_delivery(
+        &self,
+        webhook_id: &str,
+        event: &str,
+        note_id: &str,
+        payload: &str,
+    ) -> Result<String> {
+        let id = Uuid::new_v4().to_string();
+        let now = Utc::now();
+        self.conn.execute(
+            "INSERT INTO webhook_deliveries
+                 (id, webhook_id, event, note_id, payload, created_at, next_attempt_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+            params![id, webhook_id, event, note_id, payload, now],
+        )?;
+        Ok(id)
+    }
+
+    /// Up to `limit` deliveries due at `now`, oldest first
+    pub fn due_deliveries(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<DueDelivery>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT d.id, d.event, d.payload, d.attempts, w.url, w.secret
+             FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id
+             WHERE d.next_attempt_at IS NOT NULL AND d.next_attempt_at <= ?1
+             ORDER BY d.next_attempt_at, d.created_at, d.id LIMIT ?2",
+        )?;
+        let due = stmt
+            .query_map(params![now, limit as i64], |row| {
+                Ok(DueDelivery {
+                    id: row.get(0)?,
+                    event: row.get(1)?,
+                    payload: row.get(2)?,
+                    attempts: row.get(3)?,
+                    url: row.get(4)?,
+                    secret: row.get(5)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(due)
+    }
+
+    /// When the next queued delivery falls due, if any is queued
+    pub fn next_delivery_at(&self) -> Result<Option<DateTime<Utc>>> {
+        let next = self.conn.query_row(
+            "SELECT MIN(next_attempt_at) FROM webhook_deliveries",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(next)
+    }
+
+    /// Records one attempt at a delivery. `retry_at` schedules the next one; without it the
+    /// delivery is finished, delivered if `outcome` is a success.
+    pub fn record_attempt(
+        &self,
+        delivery_id: &str,
+        outcome: &AttemptOutcome,
+        retry_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let (status, error, delivered_at) = match outcome {
+            AttemptOutcome::Delivered(status) => (Some(*status), None, Some(Utc::now())),
+            AttemptOutcome::Refused(status) => (Some(*status), None, None),
+            AttemptOutcome::Failed(error) => (None, Some(error.as_str()), None),
+        };
+        self.conn.execute(
+            "UPDATE webhook_deliveries
+             SET attempts = attempts + 1, next_attempt_at = ?1, delivered_at = ?2,
+                 last_status = ?3, last_error = ?4
+             WHERE id = ?5",
+            params![retry_at, delivered_at, status, error, delivery_id],
+        )?;
+        Ok(())
+    }
+
+    /// The `limit` most recent deliveries to one of `owner`'s webhooks, newest first
+    pub fn list_deliveries(
+        &self,
+        webhook_id: &str,
+        owner: &str,
+        limit: usize,
+    ) -> Result<Vec<Delivery>> {
+        self.find_webhook(webhook_id, owner)?;
+        let mut stmt = self.conn.prepare(
+            "SELECT id, event, note_id, created_at, attempts, next_attempt_at, delivered_at,
+                    last_status, last_error
+             FROM webhook_deliveries WHERE webhook_id = ?1
+             ORDER BY created_at DESC, id LIMIT ?2",
+        )?;
+        let deliveries = stmt
+            .query_map(params![webhook_id, limit as i64], |row| {
+                let next_attempt_at: Option<DateTime<Utc>> = row.get(5)?;
+                let delivered_at: Option<DateTime<Utc>> = row.get(6)?;
+                let status = match (delivered_at, next_attempt_at) {
+                    (Some(_), _) => DeliveryStatus::Delivered,
+                    (None, Some(_)) => DeliveryStatus::Pending,
+                    (None, None) => DeliveryStatus::Failed,
+                };
+                Ok(Delivery {
+                    id: row.get(0)?,
+                    event: row.get(1)?,
+                    note_id: row.get(2)?,
+                    created_at: row.get(3)?,
+                    attempts: row.get(4)?,
+                    status,
+                    next_attempt_at,
+                    delivered_at,
+                    last_status: row.get(7)?,
+                    last_error: row.get(8)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(deliveries)
+    }
+
+    /// Appends an event to the audit log. The device's name and last address are copied from
+    /// its session, so the entry still says where it came from after the session is gone.
+    pub fn record_audit(&self, entry: &AuditEntry) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO audit_log (at, event, user_id, session_id, device_name, ip, target, details)
+             SELECT ?1, ?2, ?3, ?4, t.device_name, COALESCE(?5, t.last_ip), ?6, ?7
+             FROM (SELECT 1) LEFT JOIN tokens t ON t.id = ?4",
+            params![
+                Utc::now(),
+                entry.event,
+                entry.user_id,
+                entry.session_id,
+                entry.ip,
+                entry.target,
+                entry.details.to_string()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Audit log entries matching `query`, oldest first. With a limit, the most recent ones.
+    pub fn audit_log(&self, query: &AuditQuery) -> Result<Vec<AuditRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT * FROM (
+                SELECT a.id, a.at, a.event, a.user_id, u.username, a.session_id, a.device_name,
+                       a.ip, a.target, a.details
+                FROM audit_log a LEFT JOIN users u ON u.id = a.user_id
+                WHERE (?1 IS NULL OR a.user_id = ?1) AND (?2 IS NULL OR a.event = ?2)
+                    AND (?3 IS NULL OR a.at >= ?3)
+                ORDER BY a.id DESC LIMIT ?4
+             ) ORDER BY id",
+        )?;
+        let limit = query.limit.map_or(-1, |limit| limit as i64);
+        let records = stmt
+            .query_map(params![query.user_id, query.event, query.since, limit], |row| {
+                let details: String = row.get(9)?;
+                Ok(AuditRecord {
+                    id: row.get(0)?,
+                    at: row.get(1)?,
+                    event: row.get(2)?,
+                    user_id: row.get(3)?,
+                    username: row.get(4)?,
+                    session_id: row.get(5)?,
+                    device_name: row.get(6)?,
+                    ip: row.get(7)?,
+                    target: row.get(8)?,
+                    details: serde_json::from_str(&details).map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(9, Type::Text, Box::new(e))
+                    })?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(records)
+    }
+
+    fn find_user(&self, username: &str) -> Result<Option<StoredUser>> {
+        self.conn
+            .query_row(
+                "SELECT id, password_hash, disabled_at IS NOT NULL FROM users WHERE username = ?1",
+                params![username],
+                |row| {
+                    Ok(StoredUser {
+                        id: row.get(0)?,
+                        password_hash: row.get(1)?,
+                        disabled: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(Into::into)
+    }
+}
+
+/// An account's password hash, as read to check a login
+pub struct StoredUser {
+    id: String,
+    password_hash: String,
+    disabled: bool,
+}
+
+/// An account as listed to operators
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UserSummary {
+    pub id: String,
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+    pub disabled: bool,
+    pub active_sessions: usize,
+}
+
+/// A freshly started device session; the plain token is only ever available here
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Login {
+    pub user_id: String,
+    pub session_id: String,
+    pub token: String,
+}
+
+/// Who a valid token belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authenticated {
+    pub user_id: String,
+    pub session_id: String,
+}
+
+/// One logged-in device, as shown when listing sessions
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceSession {
+    pub id: String,
+    pub device_name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub last_ip: Option<String>,
+}
+
+/// Names shown for a device session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceLabel {
+    pub username: String,
+    pub device_name: String,
+}
+
+/// What a member may do in a shared collection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Reads the collection's notes
+    Viewer,
+    /// Also creates, edits and deletes them
+    Editor,
+    /// Also manages the members; every collection has exactly one
+    Owner,
+}
+
+impl Role {
+    pub fn can_edit(self) -> bool {
+        self >= Role::Editor
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        }
+    }
+}
+
+impl ToSql for Role {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for Role {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "viewer" => Ok(Role::Viewer),
+            "editor" => Ok(Role::Editor),
+            "owner" => Ok(Role::Owner),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// A shared collection as seen by one of its members
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Collection {
+    pub id: String,
+    pub name: String,
+    /// The member's role
+    pub role: Role,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Maps a row selected as `id, name, role, created_at` into a `Collection`
+fn collection_from_row(row: &rusqlite::Row) -> rusqlite::Result<Collection> {
+    Ok(Collection {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        role: row.get(2)?,
+        created_at: row.get(3)?,
+    })
+}
+
+/// One member of a shared collection
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CollectionMember {
+    pub user_id: String,
+    pub username: String,
+    pub role: Role,
+    pub added_at: DateTime<Utc>,
+}
+
+/// A public, read-only link to a single note, as listed to its owner
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ShareLink {
+    pub id: String,
+    pub note_id: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub has_password: bool,
+}
+
+impl ShareLink {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+}
+
+/// Maps a row selected as `id, note_id, created_at, expires_at, has_password` into a `ShareLink`
+fn share_link_from_row(row: &rusqlite::Row) -> rusqlite::Result<ShareLink> {
+    Ok(ShareLink {
+        id: row.get(0)?,
+        note_id: row.get(1)?,
+        created_at: row.get(2)?,
+        expires_at: row.get(3)?,
+        has_password: row.get(4)?,
+    })
+}
+
+/// A subscription to changes of some of a user's notes, as listed to its owner
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    /// Which notes trigger it, by folder and tag
+    pub filter: SyncFilter,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Maps a row selected as `id, url, filter, created_at` into a `Webhook`
+fn webhook_from_row(row: &rusqlite::Row) -> rusqlite::Result<Webhook> {
+    let filter: String = row.get(2)?;
+    Ok(Webhook {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        filter: serde_json::from_str(&filter)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, Type::Text, Box::new(e)))?,
+        created_at: row.get(3)?,
+    })
+}
+
+fn filter_json(filter: &SyncFilter) -> String {
+    serde_json::to_string(filter).expect("filters serialize")
+}
+
+/// A freshly created webhook; the signing secret is only ever shown here
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NewWebhook {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    pub secret: String,
+}
+
+/// A queued delivery that is due, with what is needed to send it
+#[derive(Debug, Clone, PartialEq)]
+pub struct DueDelivery {
+    pub id: String,
+    pub event: String,
+    pub payload: String,
+    /// Attempts made so far
+    pub attempts: u32,
+    pub url: String,
+    pub secret: String,
+}
+
+/// How one delivery attempt went
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttemptOutcome {
+    /// The receiver answered with a 2xx status
+    Delivered(u16),
+    /// The receiver answered with another status
+    Refused(u16),
+    /// No answer, e.g. the connection failed or timed out
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// Waiting for its first attempt or a retry
+    Pending,
+    Delivered,
+    /// Given up after the last attempt failed
+    Failed,
+}
+
+/// An entry of a webhook's delivery log
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Delivery {
+    pub id: String,
+    pub event: String,
+    pub note_id: String,
+    pub created_at: DateTime<Utc>,
+    pub attempts: u32,
+    pub status: DeliveryStatus,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    /// HTTP status of the last answer
+    pub last_status: Option<u16>,
+    /// Why the last attempt got no answer
+    pub last_error: Option<String>,
+}
+
+/// Kinds of events kept in the audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// A device logged in
+    Login,
+    /// A login was refused for a wrong password, an unknown username or a disabled account
+    LoginFailed,
+    /// A device session was revoked, by its owner, by logging out or by an admin
+    SessionRevoked,
+    /// A note was deleted by a push or over HTTP
+    NoteDeleted,
+    /// Deleted notes were permanently removed by `purge-tombstones`
+    TombstonesPurged,
+    ShareLinkCreated,
+    ShareLinkRevoked,
+    /// A user was added to a collection or given another role in it
+    MemberSet,
+    MemberRemoved,
+    UserDisabled,
+    UserEnabled,
+    PasswordReset,
+}
+
+impl AuditEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditEvent::Login => "session.login",
+            AuditEvent::LoginFailed => "session.login_failed",
+            AuditEvent::SessionRevoked => "session.revoked",
+            AuditEvent::NoteDeleted => "note.deleted",
+            AuditEvent::TombstonesPurged => "notes.purged",
+            AuditEvent::ShareLinkCreated => "share_link.created",
+            AuditEvent::ShareLinkRevoked => "share_link.revoked",
+            AuditEvent::MemberSet => "collection.member_set",
+            AuditEvent::MemberRemoved => "collection.member_removed",
+            AuditEvent::UserDisabled => "user.disabled",
+            AuditEvent::UserEnabled => "user.enabled",
+            AuditEvent::PasswordReset => "user.password_reset",
+        }
+    }
+}
+
+impl ToSql for AuditEvent {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+/// An event to append to the audit log. Entries without a user were made through the admin
+/// commands or are failed logins to unknown usernames.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub event: AuditEvent,
+    /// Who did it, or for a failed login the account it was tried on
+    pub user_id: Option<String>,
+    /// The device session they did it from
+    pub session_id: Option<String>,
+    /// Where the request came from; the session's last known address when not given
+    pub ip: Option<String>,
+    /// What it was done to, e.g. a note, session or collection id
+    pub target: Option<String>,
+    pub details: serde_json::Value,
+}
+
+impl AuditEntry {
+    pub fn new(event: AuditEvent) -> Self {
+        Self {
+            event,
+            user_id: None,
+            session_id: None,
+            ip: None,
+            target: None,
+            details: serde_json::Value::Object(Default::default()),
+        }
+    }
+
+    /// Done by `user_id` from the device session `session_id`
+    pub fn by(mut self, user_id: &str, session_id: &str) -> Self {
+        self.user_id = Some(user_id.to_string());
+        self.session_id = Some(session_id.to_string());
+        self
+    }
+
+    /// Concerning the account `user_id`, for events no session made
+    pub fn about(mut self, user_id: Option<String>) -> Self {
+        self.user_id = user_id;
+        self
+    }
+
+    pub fn from_ip(mut self, ip: Option<String>) -> Self {
+        self.ip = ip;
+        self
+    }
+
+    pub fn target(mut self, target: &str) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    pub fn details(mut self, details: serde_json::Value) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+/// Which audit log entries to read
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuditQuery {
+    /// Only events done by this user
+    pub user_id: Option<String>,
+    /// Only events of this kind, e.g. `note.deleted`
+    pub event: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    /// At most this many, the most recent
+    pub limit: Option<usize>,
+}
+
+/// An entry of the audit log as read back
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditRecord {
+    pub id: i64,
+    pub at: DateTime<Utc>,
+    pub event: String,
+    pub user_id: Option<String>,
+    pub username: Option<String>,
+    pub session_id: Option<String>,
+    /// Name of the device session when the event was recorded
+    pub device_name: Option<String>,
+    /// Address the device was last seen from when the event was recorded
+    pub ip: Option<String>,
+    pub target: Option<String>,
+    pub details: serde_json::Value,
+}
+
+/// A freshly created share link; the plain token is only ever available here
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NewShareLink {
+    #[serde(flatten)]
+    pub link: ShareLink,
+    pub token: String,
+    /// Where the note is rendered, relative to the server's address
+    pub path: String,
+}
+
+/// What a share token opens
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedNote {
+    pub link: ShareLink,
+    /// Owner of the note
+    pub user_id: String,
+    token_hash: String,
+    password_hash: Option<String>,
+}
+
+impl SharedNote {
+    /// Checks a visitor's password against the link's
+    pub fn check_password(&self, password: &str) -> bool {
+        let Some(hash) = &self.password_hash else { return true };
+        PasswordHash::new(hash).is_ok_and(|parsed| {
+            Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok()
+        })
+    }
+
+    /// Proof that a visitor entered the password, kept in a cookie so they are not asked again.
+    /// It is derived from the salted password hash and cannot be computed without it.
+    pub fn unlock_key(&self) -> Option<String> {
+        let hash = self.password_hash.as_ref()?;
+        Some(hash_token(&format!("{}:{hash}", self.token_hash)))
+    }
+}
+
+/// Checks `password` against an account read by `AccountStore::credentials` and returns its user
+/// id. Unknown usernames are checked against a stand-in hash so they take as long to refuse.
+pub fn verify_login(user: Option<&StoredUser>, password: &str) -> Result<String> {
+    let hash = user.map_or(UNKNOWN_USER_HASH.as_str(), |user| user.password_hash.as_str());
+    let parsed = PasswordHash::new(hash)?;
+    let matches = Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok();
+    let user = user.filter(|_| matches).ok_or(AuthError::InvalidCredentials)?;
+    if user.disabled {
+        return Err(AuthError::AccountDisabled);
+    }
+    Ok(user.id.clone())
+}
+
+/// Checks the length policy and returns a salted Argon2 hash
+pub fn hash_password(password: &str) -> Result<String> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(AuthError::WeakPassword);
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+/// 256 random bits, hex encoded
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_issues_revocable_token() {
+        let store = AccountStore::in_memory().unwrap();
+        let alice = store.create_user("alice", "correct horse").unwrap();
+
+        let login = store.login("alice", "correct horse", "Laptop").unwrap();
+        assert_eq!(login.user_id, alice);
+        let session = store.authenticate(&login.token, None).unwrap();
+        assert_eq!(
+            (session.user_id.as_str(), session.session_id.as_str()),
+            (alice.as_str(), login.session_id.as_str())
+        );
+
+        assert_eq!(store.revoke_session(&login.session_id, Some(&alice)).unwrap(), alice);
+        assert!(matches!(store.authenticate(&login.token, None), Err(AuthError::SignedOut)));
+        assert!(matches!(
+            store.revoke_session(&login.session_id, None),
+            Err(AuthError::SessionNotFound)
+        ));
+        assert!(!store.is_session_active(&login.session_id).unwrap());
+    }
+
+    #[test]
+    fn test_lists_device_sessions_per_user() {
+        let store = AccountStore::in_memory().unwrap();
+        let alice = store.create_user("alice", "correct horse").unwrap();
+        let bob = store.create_user("bob", "battery staple").unwrap();
+        let laptop = store.login("alice", "correct horse", "Laptop").unwrap();
+        let phone = store.login("alice", "correct horse", " Phone ").unwrap();
+        store.login("bob", "battery staple", "Desktop").unwrap();
+
+        store.authenticate(&phone.token, Some("203.0.113.7")).unwrap();
+        let sessions = store.list_sessions(&alice).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].device_name, "Phone");
+        assert_eq!(sessions[0].last_ip.as_deref(), Some("203.0.113.7"));
+        assert!(sessions[1].last_seen_at.is_none());
+
+        // Users cannot revoke each other's devices
+        assert!(matches!(
+            store.revoke_session(&laptop.session_id, Some(&bob)),
+            Err(AuthError::SessionNotFound)
+        ));
+        assert_eq!(store.user_id_by_name("bob").unwrap(), bob);
+    }
+
+    #[test]
+    fn test_rejects_bad_credentials_and_duplicates() {
+        let store = AccountStore::in_memory().unwrap();
+        store.create_user("alice", "correct horse").unwrap();
+
+        assert!(matches!(
+            store.login("alice", "wrong horse", ""),
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            store.login("bob", "correct horse", ""),
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(matches!(store.create_user("alice", "another one"), Err(AuthError::UsernameTaken)));
+        assert!(matches!(store.create_user("bob", "short"), Err(AuthError::WeakPassword)));
+        assert!(matches!(store.authenticate("not-a-token", None), Err(AuthError::InvalidToken)));
+    }
+
+    #[test]
+    fn test_disable_and_reset_password_sign_devices_out() {
+        let store = AccountStore::in_memory().unwrap();
+        store.create_user("alice", "correct horse").unwrap();
+        let laptop = store.login("alice", "correct horse", "Laptop").unwrap();
+
+        store.set_disabled("alice", true).unwrap();
+        assert!(matches!(store.authenticate(&laptop.token, None), Err(AuthError::SignedOut)));
+        assert!(matches!(
+            store.login("alice", "correct horse", "Laptop"),
+            Err(AuthError::AccountDisabled)
+        ));
+        assert!(store.list_users().unwrap()[0].disabled);
+
+        store.set_disabled("alice", false).unwrap();
+        let phone = store.login("alice", "correct horse", "Phone").unwrap();
+        assert!(matches!(store.reset_password("alice", "short"), Err(AuthError::WeakPassword)));
+        store.reset_password("alice", "battery staple").unwrap();
+        assert!(matches!(store.authenticate(&phone.token, None), Err(AuthError::SignedOut)));
+        store.login("alice", "battery staple", "Phone").unwrap();
+        assert_eq!(store.list_users().unwrap()[0].active_sessions, 1);
+        assert!(matches!(store.set_disabled("bob", true), Err(AuthError::UnknownUser)));
+    }
+
+    #[test]
+    fn test_collection_membership_and_roles() {
+        let store = AccountStore::in_memory().unwrap();
+        let alice = store.create_user("alice", "correct horse").unwrap();
+        let bob = store.create_user("bob", "battery staple").unwrap();
+        let runbooks = store.create_collection(&alice, " Runbooks ").unwrap();
+        assert_eq!((runbooks.name.as_str(), runbooks.role), ("Runbooks", Role::Owner));
+        assert!(matches!(
+            store.create_collection(&alice, ""),
+            Err(AuthError::InvalidCollectionName)
+        ));
+
+        assert!(store.membership(&runbooks.id, &bob).unwrap().is_none());
+        assert!(store.set_collection_member(&runbooks.id, &bob, Role::Viewer).unwrap());
+        assert!(!store.set_collection_member(&runbooks.id, &bob, Role::Editor).unwrap());
+        assert_eq!(store.list_collections(&bob).unwrap()[0].role, Role::Editor);
+        let members = store.collection_members(&runbooks.id).unwrap();
+        let names: Vec<_> = members.iter().map(|m| (m.username.as_str(), m.role)).collect();
+        assert_eq!(names, [("alice", Role::Owner), ("bob", Role::Editor)]);
+
+        // The owner stays the owner
+        assert!(matches!(
+            store.set_collection_member(&runbooks.id, &bob, Role::Owner),
+            Err(AuthError::OwnerRole)
+        ));
+        assert!(matches!(
+            store.remove_collection_member(&runbooks.id, &alice),
+            Err(AuthError::OwnerRole)
+        ));
+
+        store.remove_collection_member(&runbooks.id, &bob).unwrap();
+        assert!(store.list_collections(&bob).unwrap().is_empty());
+        assert!(matches!(
+            store.remove_collection_member(&runbooks.id, &bob),
+            Err(AuthError::NotAMember)
+        ));
+        assert_eq!(store.collection_ids().unwrap(), [runbooks.id]);
+    }
+
+    #[test]
+    fn test_share_links_resolve_until_revoked() {
+        let store = AccountStore::in_memory().unwrap();
+        let alice = store.create_user("alice", "correct horse").unwrap();
+        let bob = store.create_user("bob", "battery staple").unwrap();
+        let open = store.create_share_link(&alice, "note-1", None, None).unwrap();
+        let locked = store.create_share_link(&alice, "note-2", None, Some("open sesame")).unwrap();
+        assert_eq!(open.path, format!("/s/{}", open.token));
+
+        let shared = store.resolve_share_link(&open.token).unwrap();
+        assert_eq!((&shared.link, shared.user_id.as_str()), (&open.link, alice.as_str()));
+        assert!(shared.unlock_key().is_none());
+        let shared = store.resolve_share_link(&locked.token).unwrap();
+        assert!(shared.link.has_password);
+        assert!(shared.check_password("open sesame") && !shared.check_password("open barley"));
+        assert!(shared.unlock_key().is_some());
+        assert_eq!(store.list_share_links(&alice).unwrap().len(), 2);
+
+        let yesterday = Utc::now() - chrono::Duration::days(1);
+        assert!(matches!(
+            store.create_share_link(&alice, "note-1", Some(yesterday), None),
+            Err(AuthError::InvalidExpiry)
+        ));
+
+        // Only the owner revokes, and a revoked token opens nothing
+        assert!(matches!(
+            store.revoke_share_link(&open.link.id, &bob),
+            Err(AuthError::ShareNotFound)
+        ));
+        store.revoke_share_link(&open.link.id, &alice).unwrap();
+        assert!(matches!(store.resolve_share_link(&open.token), Err(AuthError::ShareNotFound)));
+        assert_eq!(store.list_share_links(&alice).unwrap(), [locked.link]);
+    }
+
+    #[test]
+    fn test_audit_log_is_append_only_and_keeps_the_device() {
+        let store = AccountStore::in_memory().unwrap();
+        let alice = store.create_user("alice", "correct horse").unwrap();
+        let login = store.login("alice", "correct horse", "Laptop").unwrap();
+        let entry = AuditEntry::new(AuditEvent::Login)
+            .by(&alice, &login.session_id)
+            .from_ip(Some("192.0.2.7".into()));
+        store.record_audit(&entry).unwrap();
+        store.revoke_session(&login.session_id, None).unwrap();
+        store
+            .record_audit(
+                &AuditEntry::new(AuditEvent::NoteDeleted)
+                    .by(&alice, &login.session_id)
+                    .target("n1"),
+            )
+            .unwrap();
+        store.record_audit(&AuditEntry::new(AuditEvent::UserDisabled).target(&alice)).unwrap();
+
+        let log = store.audit_log(&AuditQuery::default()).unwrap();
+        let events: Vec<&str> = log.iter().map(|record| record.event.as_str()).collect();
+        assert_eq!(events, ["session.login", "note.deleted", "user.disabled"]);
+        assert_eq!(log[1].username.as_deref(), Some("alice"));
+        assert_eq!(log[1].device_name.as_deref(), Some("Laptop"));
+        assert_eq!(log[0].ip.as_deref(), Some("192.0.2.7"));
+        assert_eq!((log[2].user_id.as_deref(), log[2].target.as_ref()), (None, Some(&alice)));
+
+        let query =
+            AuditQuery { user_id: Some(alice.clone()), limit: Some(1), ..Default::default() };
+        assert_eq!(store.audit_log(&query).unwrap(), [log[1].clone()]);
+        let query = AuditQuery { event: Some("session.login".into()), ..Default::default() };
+        assert_eq!(store.audit_log(&query).unwrap(), [log[0].clone()]);
+
+        for statement in ["UPDATE audit_log SET event = 'x'", "DELETE FROM audit_log"] {
+            let err = store.conn.execute(statement, []).unwrap_err();
+            assert!(err.to_string().contains("append-only"), "{err}");
+        }
+        assert_eq!(store.audit_log(&AuditQuery::default()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_stores_only_hashes() {
+        let store = AccountStore::in_memory().unwrap();
+        store.create_user("alice", "correct horse").unwrap();
+        let login = store.login("alice", "correct horse", "Laptop").unwrap();
+
+        let (password_hash, token_hash): (String, String) = store
+            .conn
+            .query_row("SELECT password_hash, token_hash FROM users, tokens", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert!(password_hash.starts_with("$argon2"));
+        assert_ne!(token_hash, login.token);
+    }
+}
@@ -0,0 +1,210 @@
+use crate::accounts::{AccountStore, AuditEntry, AuditEvent, AuthError};
+use crate::config::{Backend, Config};
+use crate::store::{UserStores, collection_key, dir_size};
+use chrono::{DateTime, Utc};
+use notaro_core::postgres::PostgresDatabase;
+use notaro_core::tls::ServerTrust;
+use notaro_core::{NotaroError, Note, SettingEntry};
+use serde::Serialize;
+use serde_json::json;
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AdminError {
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+
+    #[error(transparent)]
+    Store(#[from] NotaroError),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, AdminError>;
+
+/// Opens the account database under `data_dir` and the note stores of the configured backend,
+/// the layout both the server and the admin commands use.
+/// Connecting to PostgreSQL blocks, so call this outside the async runtime.
+pub fn open_storage(config: &Config) -> Result<(AccountStore, UserStores)> {
+    std::fs::create_dir_all(&config.data_dir)?;
+    let accounts = AccountStore::open(config.data_dir.join("accounts.db"))?;
+    let storage = &config.storage;
+    let stores = match (storage.backend, &storage.postgres_url) {
+        (Backend::Postgres, Some(url)) => {
+            let trust = match &storage.postgres_ca {
+                Some(path) => ServerTrust::CustomCa(std::fs::read_to_string(path)?),
+                None => ServerTrust::PublicRoots,
+            };
+            UserStores::postgres(PostgresDatabase::connect(
+                url,
+                storage.postgres_pool_size,
+                &trust,
+            )?)
+        }
+        _ => UserStores::at(config.data_dir.join("users")),
+    };
+    Ok((accounts, stores))
+}
+
+/// Maintenance operations behind the `notaro_server` subcommands. They work on the same files as
+/// a running server; SQLite's locking keeps the two from corrupting each other.
+pub struct Admin {
+    data_dir: PathBuf,
+    pub accounts: AccountStore,
+    pub stores: UserStores,
+}
+
+/// Instance-wide totals reported by `stats`
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct Stats {
+    pub users: usize,
+    pub disabled_users: usize,
+    pub active_sessions: usize,
+    pub notes: usize,
+    pub tombstones: usize,
+    /// Size of every file in the data directory, plus the note tables when they are in PostgreSQL
+    pub storage_bytes: u64,
+}
+
+/// Everything stored for one user, as written by `export-user`
+#[derive(Debug, Clone, Serialize)]
+pub struct UserExport {
+    pub user_id: String,
+    pub username: String,
+    pub exported_at: DateTime<Utc>,
+    /// Deleted notes are included, flagged with `is_deleted`
+    pub notes: Vec<Note>,
+    pub settings: Vec<SettingEntry>,
+}
+
+impl Admin {
+    pub fn open(config: &Config) -> Result<Self> {
+        let (accounts, stores) = open_storage(config)?;
+        Ok(Self { data_dir: config.data_dir.clone(), accounts, stores })
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        let mut stats = Stats { storage_bytes: dir_size(&self.data_dir)?, ..Default::default() };
+        if self.stores.is_postgres() {
+            stats.storage_bytes += self.stores.disk_usage()?;
+        }
+        for user in self.accounts.list_users()? {
+            stats.users += 1;
+            stats.disabled_users += usize::from(user.disabled);
+            stats.active_sessions += user.active_sessions;
+            if let Some(db) = self.stores.get_existing(&user.id)? {
+                let counts = db.lock().expect("user db lock poisoned").note_counts()?;
+                stats.notes += counts.live;
+                stats.tombstones += counts.tombstones;
+            }
+        }
+        Ok(stats)
+    }
+
+    pub fn export_user(&self, username: &str) -> Result<UserExport> {
+        let user_id = self.accounts.user_id_by_name(username)?;
+        let (notes, settings) = match self.stores.get_existing(&user_id)? {
+            Some(db) => {
+                let db = db.lock().expect("user db lock poisoned");
+                (db.get_all_notes()?, db.get_synced_settings()?)
+            }
+            None => Default::default(),
+        };
+        Ok(UserExport {
+            user_id,
+            username: username.trim().to_string(),
+            exported_at: Utc::now(),
+            notes,
+            settings,
+        })
+    }
+
+    /// Purges tombstones older than `before` from every user's and collection's notes and
+    /// returns how many. The purge is recorded in the audit log with the count per store.
+    pub fn purge_tombstones(&self, before: DateTime<Utc>) -> Result<usize> {
+        let mut per_store = serde_json::Map::new();
+        for key in self.store_keys()? {
+            if let Some(db) = self.stores.get_existing(&key)? {
+                let purged = db.lock().expect("user db lock poisoned").purge_tombstones(before)?;
+                if purged > 0 {
+                    per_store.insert(key, purged.into());
+                }
+            }
+        }
+        let purged = per_store.values().filter_map(|count| count.as_u64()).sum::<u64>() as usize;
+        self.accounts.record_audit(
+            &AuditEntry::new(AuditEvent::TombstonesPurged)
+                .details(json!({ "before": before, "purged": purged, "stores": per_store })),
+        )?;
+        Ok(purged)
+    }
+
+    /// Checks every database and returns the problems found, each prefixed with where it is
+    pub fn check_db(&self) -> Result<Vec<String>> {
+        let mut problems: Vec<String> = self
+            .accounts
+            .integrity_check()?
+            .into_iter()
+            .map(|problem| format!("accounts.db: {problem}"))
+            .collect();
+
+        for key in self.store_keys()? {
+            let Some(db) = self.stores.get_existing(&key)? else { continue };
+            let found = db.lock().expect("user db lock poisoned").integrity_check()?;
+            let location = self.stores.location(&key);
+            problems.extend(found.into_iter().map(|problem| format!("{location}: {problem}")));
+        }
+        Ok(problems)
+    }
+
+    /// Every note store that may exist: one per user and one per shared collection
+    fn store_keys(&self) -> Result<Vec<String>> {
+        let users = self.accounts.list_users()?.into_iter().map(|user| user.id);
+        let collections = self.accounts.collection_ids()?.into_iter().map(|id| collection_key(&id));
+        Ok(users.chain(collections).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn temp_admin() -> Admin {
+        let data_dir = std::env::temp_dir().join(format!("notaro-admin-{}", Uuid::new_v4()));
+        Admin::open(&Config { data_dir, ..Default::default() }).unwrap()
+    }
+
+    #[test]
+    fn test_stats_and_export_cover_only_synced_users() {
+        let admin = temp_admin();
+        let alice = admin.accounts.create_user("alice", "correct horse").unwrap();
+        admin.accounts.create_user("bob", "battery staple").unwrap();
+        admin.accounts.set_disabled("bob", true).unwrap();
+
+        let db = admin.stores.get(&alice).unwrap();
+        let note = db.lock().unwrap().create_note("Hello".into(), "".into(), None).unwrap();
+        db.lock().unwrap().create_note("Bye".into(), "".into(), None).unwrap();
+        db.lock().unwrap().delete_note(&note.id).unwrap();
+
+        let stats = admin.stats().unwrap();
+        assert_eq!((stats.users, stats.disabled_users), (2, 1));
+        assert_eq!((stats.notes, stats.tombstones), (1, 1));
+        assert!(stats.storage_bytes > 0);
+
+        assert_eq!(admin.export_user("alice").unwrap().notes.len(), 2);
+        assert!(admin.export_user("bob").unwrap().notes.is_empty());
+        assert!(matches!(
+            admin.export_user("carol"),
+            Err(AdminError::Auth(AuthError::UnknownUser))
+        ));
+
+        assert_eq!(admin.purge_tombstones(Utc::now() + chrono::Duration::days(1)).unwrap(), 1);
+        let log = admin.accounts.audit_log(&Default::default()).unwrap();
+        assert_eq!(log[0].event, "notes.purged");
+        assert_eq!(log[0].details["stores"], json!({ alice.as_str(): 1 }));
+        assert!(admin.check_db().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+use crate::PeerAddr;
+use crate::accounts::{
+    AuditEntry, AuditEvent, AuthError, DeviceSession, Login, hash_password, verify_login,
+};
+use crate::config::Registration;
+use crate::state::AppState;
+use axum::Json;
+use axum::extract::{ConnectInfo, FromRequestParts, Path, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::convert::Infallible;
+
+/// Device name recorded when a login does not give one
+const UNNAMED_DEVICE: &str = "Unnamed device";
+
+/// Body of the account creation request
+#[derive(Debug, Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Body of the login request
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+    /// Shown when listing sessions, e.g. "Work laptop"
+    #[serde(default)]
+    pub device_name: Option<String>,
+}
+
+/// The user and device session a request is authenticated as, from its
+/// `Authorization: Bearer` header. Handlers that take this extractor reject anonymous callers
+/// with 401; a revoked device gets a 401 saying it was signed out.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub user_id: String,
+    pub session_id: String,
+}
+
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AuthError> {
+        let ClientIp(ip) = ClientIp::of(parts);
+        let token = bearer_token(&parts.headers).ok_or(AuthError::InvalidToken)?;
+        let session = state
+            .accounts
+            .lock()
+            .expect("accounts lock poisoned")
+            .authenticate(token, ip.as_deref())?;
+        Ok(Self { user_id: session.user_id, session_id: session.session_id })
+    }
+}
+
+/// Address of the connection a request came over, when the server records it
+#[derive(Debug, Clone)]
+pub struct ClientIp(pub Option<String>);
+
+impl ClientIp {
+    fn of(parts: &Parts) -> Self {
+        Self(parts.extensions.get::<ConnectInfo<PeerAddr>>().map(|c| c.0.0.ip().to_string()))
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Infallible> {
+        Ok(Self::of(parts))
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ").map(str::trim)
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            AuthError::UsernameTaken | AuthError::OwnerRole => StatusCode::CONFLICT,
+            AuthError::InvalidUsername
+            | AuthError::WeakPassword
+            | AuthError::InvalidCollectionName
+            | AuthError::InvalidExpiry
+            | AuthError::InvalidWebhookUrl
+            | AuthError::PrivateWebhookUrl => StatusCode::BAD_REQUEST,
+            AuthError::InvalidCredentials | AuthError::InvalidToken | AuthError::SignedOut => {
+                StatusCode::UNAUTHORIZED
+            }
+            AuthError::RegistrationClosed
+            | AuthError::AccountDisabled
+            | AuthError::NotCollectionOwner => StatusCode::FORBIDDEN,
+            AuthError::SessionNotFound
+            | AuthError::UnknownUser
+            | AuthError::CollectionNotFound
+            | AuthError::NotAMember
+            | AuthError::NoteNotFound
+            | AuthError::ShareNotFound
+            | AuthError::WebhookNotFound => StatusCode::NOT_FOUND,
+            AuthError::Db(_) | AuthError::Hash(_) | AuthError::Store(_) => {
+                tracing::error!("account store failure: {self}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        (status, Json(json!({ "error": self.to_string() }))).into_response()
+    }
+}
+
+/// `POST /accounts`: creates an account, unless registration is closed
+pub async fn register(
+    State(state): State<AppState>,
+    Json(credentials): Json<Credentials>,
+) -> Result<impl IntoResponse, AuthError> {
+    if state.config.registration == Registration::Closed {
+        return Err(AuthError::RegistrationClosed);
+    }
+
+    // Argon2 is deliberately slow; keep it off the async workers and out of the accounts lock
+    let user_id = tokio::task::spawn_blocking(move || {
+        let hash = hash_password(&credentials.password)?;
+        let accounts = state.accounts.lock().expect("accounts lock poisoned");
+        accounts.insert_user(&credentials.username, &hash)
+    })
+    .await
+    .expect("account task panicked")?;
+
+    tracing::info!(user_id, "account created");
+    Ok((StatusCode::CREATED, Json(json!({ "user_id": user_id }))))
+}
+
+/// `POST /sessions`: logs a device in and returns its bearer token
+pub async fn login(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Json(request): Json<LoginRequest>,
+) -> Result<Json<Login>, AuthError> {
+    let login = tokio::task::spawn_blocking(move || {
+        let device_name = request.device_name.as_deref().unwrap_or(UNNAMED_DEVICE);
+        // The password is checked between the two locks, so other requests are not held up
+        let user = state
+            .accounts
+            .lock()
+            .expect("accounts lock poisoned")
+            .credentials(&request.username)?;
+        let verified = verify_login(user.as_ref(), &request.password);
+        let accounts = state.accounts.lock().expect("accounts lock poisoned");
+        let login = match verified {
+            Ok(user_id) => accounts.start_session(&user_id, device_name)?,
+            Err(e @ (AuthError::InvalidCredentials | AuthError::AccountDisabled)) => {
+                let entry = AuditEntry::new(AuditEvent::LoginFailed)
+                    .about(accounts.user_id_of(&request.username)?)
+                    .from_ip(ip)
+                    .target(request.username.trim())
+                    .details(json!({ "reason": e.to_string() }));
+                accounts.record_audit(&entry)?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        accounts.record_audit(
+            &AuditEntry::new(AuditEvent::Login).by(&login.user_id, &login.session_id).from_ip(ip),
+        )?;
+        Ok::<Login, AuthError>(login)
+    })
+    .await
+    .expect("account task panicked")?;
+
+    Ok(Json(login))
+}
+
+/// A device session as listed to its owner
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    #[serde(flatten)]
+    pub session: DeviceSession,
+    /// True for the session making the request
+    pub current: bool,
+}
+
+/// `GET /sessions`: the caller's logged-in devices
+pub async fn list_sessions(
+    State(state): State<AppState>,
+    user: AuthUser,
+) -> Result<Json<Vec<SessionInfo>>, AuthError> {
+    let sessions =
+        state.accounts.lock().expect("accounts lock poisoned").list_sessions(&user.user_id)?;
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(|session| SessionInfo { current: session.id == user.session_id, session })
+            .collect(),
+    ))
+}
+
+/// `DELETE /sessions/{id}`: signs one of the caller's devices out, closing its live sockets
+pub async fn revoke_session(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(session_id): Path<String>,
+) -> Result<StatusCode, AuthError> {
+    sign_out(&state, &session_id, Some(&user))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `DELETE /sessions/current`: signs the calling device out
+pub async fn logout(
+    State(state): State<AppState>,
+    user: AuthUser,
+) -> Result<StatusCode, AuthError> {
+    sign_out(&state, &user.session_id, Some(&user))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Revokes a device session of `owner`, or of anyone when `None`, and disconnects its sockets
+pub fn sign_out(
+    state: &AppState,
+    session_id: &str,
+    owner: Option<&AuthUser>,
+) -> Result<(), AuthError> {
+    let user_id = {
+        let accounts = state.accounts.lock().expect("accounts lock poisoned");
+        let user_id =
+            accounts.revoke_session(session_id, owner.map(|owner| owner.user_id.as_str()))?;
+        let entry = AuditEntry::new(AuditEvent::SessionRevoked).target(session_id);
+        let entry = match owner {
+            Some(owner) => entry.by(&owner.user_id, &owner.session_id),
+            None => entry.details(json!({ "user_id": user_id })),
+        };
+        accounts.record_audit(&entry)?;
+        user_id
+    };
+    let sockets = state.hub.sign_out_device(&user_id, session_id);
+    tracing::info!(user_id, session_id, sockets, "device signed out");
+    Ok(())
+}
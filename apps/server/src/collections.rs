@@ -0,0 +1,347 @@
+use crate::accounts::{
+    AccountStore, AuditEntry, AuditEvent, AuthError, Collection, CollectionMember, Role,
+};
+use crate::auth::AuthUser;
+use crate::hub::SessionId;
+use crate::limits::{self, Quota};
+use crate::session::{Merged, PushBatch, merge_push};
+use crate::state::AppState;
+use crate::webhooks;
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use notaro_core::error::Result;
+use notaro_core::{NotaroError, Note, NoteStore, RejectedNote, SyncMessage};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+
+/// Body of `POST /collections`
+#[derive(Debug, Deserialize)]
+pub struct NewCollection {
+    pub name: String,
+}
+
+/// Body of `POST /collections/{id}/members`
+#[derive(Debug, Deserialize)]
+pub struct Invitation {
+    pub username: String,
+    /// `editor` or `viewer`
+    pub role: Role,
+}
+
+/// `POST /collections`: creates a shared collection owned by the caller
+pub async fn create(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Json(request): Json<NewCollection>,
+) -> std::result::Result<impl IntoResponse, AuthError> {
+    let accounts = state.accounts.lock().expect("accounts lock poisoned");
+    let collection = accounts.create_collection(&user.user_id, &request.name)?;
+    tracing::info!(user_id = user.user_id, collection_id = collection.id, "collection created");
+    Ok((StatusCode::CREATED, Json(collection)))
+}
+
+/// `GET /collections`: the collections the caller belongs to, with their role in each
+pub async fn list(
+    State(state): State<AppState>,
+    user: AuthUser,
+) -> std::result::Result<Json<Vec<Collection>>, AuthError> {
+    let accounts = state.accounts.lock().expect("accounts lock poisoned");
+    Ok(Json(accounts.list_collections(&user.user_id)?))
+}
+
+/// `GET /collections/{id}/members`: every member and their role; members only
+pub async fn members(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(collection_id): Path<String>,
+) -> std::result::Result<Json<Vec<CollectionMember>>, AuthError> {
+    let accounts = state.accounts.lock().expect("accounts lock poisoned");
+    accounts.membership(&collection_id, &user.user_id)?.ok_or(AuthError::CollectionNotFound)?;
+    Ok(Json(accounts.collection_members(&collection_id)?))
+}
+
+/// `POST /collections/{id}/members`: invites a user as editor or viewer, or changes their role;
+/// owner only. A new member's devices receive the collection's notes right away.
+pub async fn add_member(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(collection_id): Path<String>,
+    Json(invitation): Json<Invitation>,
+) -> std::result::Result<StatusCode, AuthError> {
+    tokio::task::spawn_blocking(move || {
+        let (member_id, joined) = {
+            let accounts = state.accounts.lock().expect("accounts lock poisoned");
+            require_owner(&accounts, &collection_id, &user.user_id)?;
+            let member_id = accounts.user_id_by_name(&invitation.username)?;
+            let joined =
+                accounts.set_collection_member(&collection_id, &member_id, invitation.role)?;
+            accounts.record_audit(
+                &AuditEntry::new(AuditEvent::MemberSet)
+                    .by(&user.user_id, &user.session_id)
+                    .target(&collection_id)
+                    .details(json!({
+                        "member_id": member_id,
+                        "role": invitation.role,
+                        "joined": joined,
+                    })),
+            )?;
+            (member_id, joined)
+        };
+
+        if joined {
+            share_with(&state, &collection_id, &member_id)?;
+        }
+        let role = invitation.role.as_str();
+        tracing::info!(collection_id, member_id, role, joined, "collection member set");
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+    .expect("collection task panicked")
+}
+
+/// `DELETE /collections/{id}/members/{user_id}`: removes a member, which the owner may do for
+/// anyone but themselves and every other member for themselves. The collection's notes are
+/// evicted from the removed member's devices.
+pub async fn remove_member(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path((collection_id, member_id)): Path<(String, String)>,
+) -> std::result::Result<StatusCode, AuthError> {
+    tokio::task::spawn_blocking(move || {
+        {
+            let accounts = state.accounts.lock().expect("accounts lock poisoned");
+            if member_id == user.user_id {
+                accounts
+                    .membership(&collection_id, &user.user_id)?
+                    .ok_or(AuthError::CollectionNotFound)?;
+            } else {
+                require_owner(&accounts, &collection_id, &user.user_id)?;
+            }
+            accounts.remove_collection_member(&collection_id, &member_id)?;
+            accounts.record_audit(
+                &AuditEntry::new(AuditEvent::MemberRemoved)
+                    .by(&user.user_id, &user.session_id)
+                    .target(&collection_id)
+                    .details(json!({ "member_id": member_id })),
+            )?;
+        }
+
+        unshare_with(&state, &collection_id, &member_id)?;
+        tracing::info!(collection_id, member_id, "collection member removed");
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+    .expect("collection task panicked")
+}
+
+fn require_owner(
+    accounts: &AccountStore,
+    collection_id: &str,
+    user_id: &str,
+) -> std::result::Result<(), AuthError> {
+    match accounts.membership(collection_id, user_id)? {
+        None => Err(AuthError::CollectionNotFound),
+        Some(collection) if collection.role != Role::Owner => Err(AuthError::NotCollectionOwner),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Copies a collection's notes into a new member's store and sends them to their devices
+fn share_with(state: &AppState, collection_id: &str, member_id: &str) -> Result<()> {
+    let notes = state
+        .stores
+        .get_collection(collection_id)?
+        .lock()
+        .expect("database lock poisoned")
+        .get_all_notes()?;
+    if !notes.is_empty() {
+        mirror(state, member_id, None, notes)?;
+    }
+    Ok(())
+}
+
+/// Drops a collection's notes from a former member's store and evicts them from their devices
+fn unshare_with(state: &AppState, collection_id: &str, member_id: &str) -> Result<()> {
+    let ids: Vec<String> = state
+        .stores
+        .get_collection(collection_id)?
+        .lock()
+        .expect("database lock poisoned")
+        .get_all_notes()?
+        .into_iter()
+        .map(|note| note.id)
+        .collect();
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    let current_version = {
+        let db = state.user_db(member_id)?;
+        let db = db.lock().expect("database lock poisoned");
+        db.evict_notes(&ids)?;
+        db.current_version()?
+    };
+    let update = SyncMessage::PullResponse {
+        changes: Vec::new(),
+        current_version,
+        has_more: false,
+        next_cursor: None,
+        evicted: ids,
+    };
+    state.hub.broadcast(member_id, &update);
+    Ok(())
+}
+
+/// Merges a collection's notes into one member's store and forwards that member's copies to
+/// their sessions, except `origin`
+fn mirror(
+    state: &AppState,
+    member_id: &str,
+    origin: Option<SessionId>,
+    notes: Vec<Note>,
+) -> Result<()> {
+    let ids: Vec<String> = notes.iter().map(|note| note.id.clone()).collect();
+    let update = {
+        let db = state.user_db(member_id)?;
+        let mut db = db.lock().expect("database lock poisoned");
+        db.merge_changes(notes)?;
+        SyncMessage::PullResponse {
+            changes: ids.iter().map(|id| db.get_note_by_id(id)).collect::<Result<_>>()?,
+            current_version: db.current_version()?,
+            has_more: false,
+            next_cursor: None,
+            evicted: Vec::new(),
+        }
+    };
+    state.hub.broadcast_from(member_id, origin, &update);
+    Ok(())
+}
+
+/// A push sorted by where each of its notes goes
+#[derive(Debug, Default)]
+pub struct RoutedPush {
+    /// Private notes, merged into the pusher's own store
+    pub private: PushBatch,
+    /// Notes of collections the pusher may edit, by collection id
+    pub shared: BTreeMap<String, PushBatch>,
+    pub rejected: Vec<RejectedNote>,
+}
+
+/// Where a pushed note may be stored
+enum Route {
+    Private,
+    Shared(String),
+    Rejected(String),
+}
+
+/// Sorts a push into private notes, notes of collections `user_id` may edit, and refused notes.
+/// A note's collection is taken from the pushed copy; a note that already belongs to a
+/// collection can never be pushed into another one or made private.
+pub fn route_push(
+    state: &AppState,
+    user_id: &str,
+    own: &dyn NoteStore,
+    batch: PushBatch,
+) -> Result<RoutedPush> {
+    let PushBatch { changes, deltas } = batch;
+    let stored = |id: &str| -> Result<Option<String>> {
+        Ok(own.find_note(id)?.and_then(|note| note.collection))
+    };
+    let mut routes = HashMap::new();
+    for note in changes.iter().chain(deltas.iter().map(|delta| &delta.note)) {
+        routes.insert(note.id.clone(), (stored(&note.id)?, note.collection.clone()));
+    }
+
+    let accounts = state.accounts.lock().expect("accounts lock poisoned");
+    let mut memberships: HashMap<String, Option<Collection>> = HashMap::new();
+    let mut route = |id: &str| -> Result<Route> {
+        let (stored, pushed) = &routes[id];
+        if stored.is_some() && stored != pushed {
+            return Ok(Route::Rejected(
+                "notes cannot leave the shared collection they belong to".to_string(),
+            ));
+        }
+        let Some(collection_id) = pushed else { return Ok(Route::Private) };
+        if !memberships.contains_key(collection_id) {
+            let membership =
+                accounts.membership(collection_id, user_id).map_err(account_failure)?;
+            memberships.insert(collection_id.clone(), membership);
+        }
+        Ok(match &memberships[collection_id] {
+            None => Route::Rejected("you are not a member of this note's collection".to_string()),
+            Some(collection) if !collection.role.can_edit() => Route::Rejected(format!(
+                "you can only view \"{}\"; ask its owner for editor access",
+                collection.name
+            )),
+            Some(_) => Route::Shared(collection_id.clone()),
+        })
+    };
+
+    let mut routed = RoutedPush::default();
+    for note in changes {
+        match route(&note.id)? {
+            Route::Private => routed.private.changes.push(note),
+            Route::Shared(collection) => {
+                routed.shared.entry(collection).or_default().changes.push(note)
+            }
+            Route::Rejected(reason) => {
+                routed.rejected.push(RejectedNote { id: note.id, reason, limit: None })
+            }
+        }
+    }
+    for delta in deltas {
+        match route(&delta.note.id)? {
+            Route::Private => routed.private.deltas.push(delta),
+            Route::Shared(collection) => {
+                routed.shared.entry(collection).or_default().deltas.push(delta)
+            }
+            Route::Rejected(reason) => {
+                routed.rejected.push(RejectedNote { id: delta.note.id, reason, limit: None })
+            }
+        }
+    }
+    Ok(routed)
+}
+
+/// Applies a push to a collection's canonical store, within the collection's quota, then mirrors
+/// the merged notes into every member's store and to their sessions other than `origin`.
+/// Returns the canonical merge and the notes refused.
+pub fn apply_shared_push(
+    state: &AppState,
+    collection_id: &str,
+    origin: Option<SessionId>,
+    batch: PushBatch,
+) -> Result<(Merged, Vec<RejectedNote>)> {
+    let (merged, rejected) = {
+        let db = state.stores.get_collection(collection_id)?;
+        let mut db = db.lock().expect("database lock poisoned");
+        let (batch, rejected) =
+            limits::enforce_quota(&*db, &state.config.limits, Quota::Collection, batch)?;
+        if batch.is_empty() {
+            return Ok((Merged::default(), rejected));
+        }
+        (merge_push(&mut *db, batch)?, rejected)
+    };
+
+    if !merged.notes.is_empty() {
+        let members = state
+            .accounts
+            .lock()
+            .expect("accounts lock poisoned")
+            .collection_members(collection_id)
+            .map_err(account_failure)?;
+        for member in members {
+            mirror(state, &member.user_id, origin, merged.notes.clone())?;
+            webhooks::notify(state, &member.user_id, merged.changed_notes());
+        }
+    }
+    Ok((merged, rejected))
+}
+
+/// Account lookups made while syncing fail the session like storage errors do
+pub(crate) fn account_failure(e: AuthError) -> NotaroError {
+    NotaroError::Io(std::io::Error::other(e))
+}
@@ -0,0 +1,507 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// File read when neither `--config` nor `NOTARO_CONFIG` names one, if it exists
+pub const DEFAULT_CONFIG_FILE: &str = "notaro.toml";
+
+/// Smallest accepted `max_message_size`; anything lower could not carry a single note
+pub const MIN_MESSAGE_SIZE: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("cannot read {path}: {source}")]
+    Io { path: PathBuf, source: std::io::Error },
+
+    /// Syntax errors and wrong types, reported by `toml` with the key and line
+    #[error("{path}: {source}")]
+    Parse { path: PathBuf, source: toml::de::Error },
+
+    #[error("{var} (overrides `{key}`): {message}")]
+    Env { var: &'static str, key: &'static str, message: String },
+
+    #[error("invalid `{key}`: {message}")]
+    Invalid { key: &'static str, message: String },
+}
+
+pub type Result<T> = std::result::Result<T, ConfigError>;
+
+/// Server settings, read from a TOML file and then overridden by `NOTARO_*` environment
+/// variables. Every key is optional; see `notaro.example.toml` for the defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the server listens on (`NOTARO_BIND`)
+    pub bind: SocketAddr,
+    /// Holds `accounts.db` and the per-user note databases (`NOTARO_DATA_DIR`)
+    pub data_dir: PathBuf,
+    /// Largest WebSocket message a client may send, in bytes (`NOTARO_MAX_MESSAGE_SIZE`)
+    pub max_message_size: usize,
+    /// Whether anyone may create an account over HTTP (`NOTARO_REGISTRATION`)
+    pub registration: Registration,
+    pub retention: Retention,
+    pub storage: Storage,
+    pub limits: Limits,
+    pub webhooks: Webhooks,
+    pub shutdown: Shutdown,
+    /// `tracing` filter such as `info` or `notaro_server=debug` (`NOTARO_LOG_LEVEL`)
+    pub log_level: String,
+    /// Serve HTTPS/WSS with this certificate and key instead of plain HTTP
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Registration {
+    Open,
+    /// Only operators can add accounts, with `notaro_server user add`
+    Closed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    /// Deleted notes older than this are removed by `purge-tombstones`
+    /// (`NOTARO_TOMBSTONE_RETENTION_DAYS`)
+    pub tombstone_days: i64,
+}
+
+/// What a client may store and send. Storage quotas apply to every account and, separately, to
+/// every shared collection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Most live notes; unlimited when unset (`NOTARO_MAX_NOTES`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_notes: Option<usize>,
+    /// Most bytes of note titles and bodies; unlimited when unset (`NOTARO_MAX_STORAGE_BYTES`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_storage_bytes: Option<u64>,
+    /// Largest title and body of a single note, in bytes (`NOTARO_MAX_NOTE_SIZE`)
+    pub max_note_size: usize,
+    /// Messages one connection may send per second, on average; 0 turns rate limiting off
+    /// (`NOTARO_RATE_LIMIT`)
+    pub messages_per_second: u32,
+    /// Messages a connection may send at once before the rate applies
+    pub burst: u32,
+}
+
+/// How change notifications are delivered to webhook receivers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Webhooks {
+    /// Attempts per delivery before it is given up (`NOTARO_WEBHOOK_MAX_ATTEMPTS`)
+    pub max_attempts: u32,
+    /// Wait before the first retry, in milliseconds; each further retry waits twice as long, up
+    /// to an hour
+    pub retry_backoff_ms: u64,
+    /// How long a receiver may take to answer, in seconds
+    pub timeout_secs: u64,
+    /// Hosts that may be webhook receivers even though they resolve to a loopback, private or
+    /// link-local address, e.g. a receiver running next to the server
+    pub allowed_hosts: Vec<String>,
+    /// Lets webhooks reach any address, including the server's own network
+    pub allow_private: bool,
+}
+
+/// How the server stops on SIGTERM or Ctrl-C
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Shutdown {
+    /// Longest wait for open connections to finish, in seconds; the server exits after it even
+    /// if some have not (`NOTARO_SHUTDOWN_DEADLINE`)
+    pub deadline_secs: u64,
+    /// How long clients are told to wait before reconnecting, in milliseconds
+    pub retry_after_ms: u64,
+}
+
+/// Where notes live. Accounts always stay in `data_dir/accounts.db`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Storage {
+    /// `NOTARO_STORAGE_BACKEND`
+    pub backend: Backend,
+    /// `postgres://` URL of the database holding every user's notes (`NOTARO_POSTGRES_URL`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub postgres_url: Option<String>,
+    /// Most connections the server keeps open to PostgreSQL
+    pub postgres_pool_size: u32,
+    /// PEM CA that signed the PostgreSQL server's certificate; public CAs are trusted otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub postgres_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// One SQLite file per user under `data_dir/users`
+    Sqlite,
+    /// One PostgreSQL database shared by every user, at `postgres_url`
+    Postgres,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain (`NOTARO_TLS_CERT`)
+    pub cert_path: PathBuf,
+    /// PEM private key (`NOTARO_TLS_KEY`)
+    pub key_path: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            data_dir: PathBuf::from("notaro_data"),
+            max_message_size: 16 * 1024 * 1024,
+            registration: Registration::Open,
+            retention: Retention::default(),
+            storage: Storage::default(),
+            limits: Limits::default(),
+            webhooks: Webhooks::default(),
+            shutdown: Shutdown::default(),
+            log_level: "info".to_string(),
+            tls: None,
+        }
+    }
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self { tombstone_days: 90 }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_notes: None,
+            max_storage_bytes: None,
+            max_note_size: 1024 * 1024,
+            messages_per_second: 50,
+            burst: 200,
+        }
+    }
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            retry_backoff_ms: 30_000,
+            timeout_secs: 10,
+            allowed_hosts: Vec::new(),
+            allow_private: false,
+        }
+    }
+}
+
+impl Webhooks {
+    /// Whether receivers on `host` may be sent webhooks whatever it resolves to
+    pub fn allows_any_address(&self, host: &str) -> bool {
+        self.allow_private
+            || self.allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host))
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self { deadline_secs: 30, retry_after_ms: 5_000 }
+    }
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            backend: Backend::Sqlite,
+            postgres_url: None,
+            postgres_pool_size: 16,
+            postgres_ca: None,
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "sqlite" => Ok(Self::Sqlite),
+            "postgres" => Ok(Self::Postgres),
+            _ => Err("expected `sqlite` or `postgres`".to_string()),
+        }
+    }
+}
+
+impl FromStr for Registration {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "open" => Ok(Self::Open),
+            "closed" => Ok(Self::Closed),
+            _ => Err("expected `open` or `closed`".to_string()),
+        }
+    }
+}
+
+impl Config {
+    /// Loads `path`, or `notaro.toml` when it exists and no path is given, then applies the
+    /// environment and validates the result
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let default_file = Path::new(DEFAULT_CONFIG_FILE);
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None if default_file.exists() => Self::from_file(default_file)?,
+            None => Self::default(),
+        };
+        config.apply_env(|var| std::env::var(var).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|source| ConfigError::Io { path: path.to_path_buf(), source })?;
+        toml::from_str(&text)
+            .map_err(|source| ConfigError::Parse { path: path.to_path_buf(), source })
+    }
+
+    /// Overrides keys from `NOTARO_*` variables, looked up through `var`
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        if let Some(bind) = env_value(&var, "NOTARO_BIND", "bind")? {
+            self.bind = bind;
+        }
+        if let Some(dir) = var("NOTARO_DATA_DIR") {
+            self.data_dir = dir.into();
+        }
+        if let Some(size) = env_value(&var, "NOTARO_MAX_MESSAGE_SIZE", "max_message_size")? {
+            self.max_message_size = size;
+        }
+        if let Some(registration) = env_value(&var, "NOTARO_REGISTRATION", "registration")? {
+            self.registration = registration;
+        }
+        if let Some(days) =
+            env_value(&var, "NOTARO_TOMBSTONE_RETENTION_DAYS", "retention.tombstone_days")?
+        {
+            self.retention.tombstone_days = days;
+        }
+        if let Some(backend) = env_value(&var, "NOTARO_STORAGE_BACKEND", "storage.backend")? {
+            self.storage.backend = backend;
+        }
+        if let Some(url) = var("NOTARO_POSTGRES_URL") {
+            self.storage.postgres_url = Some(url);
+        }
+        if let Some(max) = env_value(&var, "NOTARO_MAX_NOTES", "limits.max_notes")? {
+            self.limits.max_notes = Some(max);
+        }
+        if let Some(max) = env_value(&var, "NOTARO_MAX_STORAGE_BYTES", "limits.max_storage_bytes")?
+        {
+            self.limits.max_storage_bytes = Some(max);
+        }
+        if let Some(size) = env_value(&var, "NOTARO_MAX_NOTE_SIZE", "limits.max_note_size")? {
+            self.limits.max_note_size = size;
+        }
+        if let Some(rate) = env_value(&var, "NOTARO_RATE_LIMIT", "limits.messages_per_second")? {
+            self.limits.messages_per_second = rate;
+        }
+        if let Some(attempts) =
+            env_value(&var, "NOTARO_WEBHOOK_MAX_ATTEMPTS", "webhooks.max_attempts")?
+        {
+            self.webhooks.max_attempts = attempts;
+        }
+        if let Some(deadline) =
+            env_value(&var, "NOTARO_SHUTDOWN_DEADLINE", "shutdown.deadline_secs")?
+        {
+            self.shutdown.deadline_secs = deadline;
+        }
+        if let Some(level) = var("NOTARO_LOG_LEVEL") {
+            self.log_level = level;
+        }
+
+        match (var("NOTARO_TLS_CERT"), var("NOTARO_TLS_KEY"), &mut self.tls) {
+            (None, None, _) => {}
+            (cert, key, Some(tls)) => {
+                tls.cert_path = cert.map_or_else(|| tls.cert_path.clone(), Into::into);
+                tls.key_path = key.map_or_else(|| tls.key_path.clone(), Into::into);
+            }
+            (Some(cert), Some(key), None) => {
+                self.tls = Some(TlsConfig { cert_path: cert.into(), key_path: key.into() });
+            }
+            (cert, _, None) => {
+                let (var, key) = if cert.is_some() {
+                    ("NOTARO_TLS_CERT", "tls.cert_path")
+                } else {
+                    ("NOTARO_TLS_KEY", "tls.key_path")
+                };
+                return Err(ConfigError::Env {
+                    var,
+                    key,
+                    message: "NOTARO_TLS_CERT and NOTARO_TLS_KEY must be set together".into(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks values that parse but cannot work
+    pub fn validate(&self) -> Result<()> {
+        if self.data_dir.as_os_str().is_empty() {
+            return Err(invalid("data_dir", "must not be empty"));
+        }
+        if self.max_message_size < MIN_MESSAGE_SIZE {
+            return Err(invalid(
+                "max_message_size",
+                format!("must be at least {MIN_MESSAGE_SIZE} bytes"),
+            ));
+        }
+        if self.limits.max_note_size > self.max_message_size {
+            return Err(invalid("limits.max_note_size", "must not exceed max_message_size"));
+        }
+        if self.limits.messages_per_second > 0 && self.limits.burst < 1 {
+            return Err(invalid("limits.burst", "must be at least 1 while rate limiting is on"));
+        }
+        if self.webhooks.max_attempts < 1 {
+            return Err(invalid("webhooks.max_attempts", "must be at least 1"));
+        }
+        if self.webhooks.timeout_secs < 1 {
+            return Err(invalid("webhooks.timeout_secs", "must be at least 1"));
+        }
+        if self.shutdown.deadline_secs < 1 {
+            return Err(invalid("shutdown.deadline_secs", "must be at least 1"));
+        }
+        if self.retention.tombstone_days < 1 {
+            return Err(invalid("retention.tombstone_days", "must be at least 1"));
+        }
+        if self.storage.backend == Backend::Postgres && self.storage.postgres_url.is_none() {
+            return Err(invalid("storage.postgres_url", "is required by the postgres backend"));
+        }
+        if self.storage.postgres_pool_size < 1 {
+            return Err(invalid("storage.postgres_pool_size", "must be at least 1"));
+        }
+        if let Some(ca) = &self.storage.postgres_ca
+            && !ca.is_file()
+        {
+            return Err(invalid("storage.postgres_ca", format!("{} does not exist", ca.display())));
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log_level) {
+            return Err(invalid("log_level", e.to_string()));
+        }
+        if let Some(tls) = &self.tls {
+            for (key, path) in [("tls.cert_path", &tls.cert_path), ("tls.key_path", &tls.key_path)]
+            {
+                if !path.is_file() {
+                    return Err(invalid(key, format!("{} does not exist", path.display())));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn env_value<T>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &'static str,
+    key: &'static str,
+) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    var(name)
+        .map(|raw| {
+            raw.trim().parse().map_err(|e: T::Err| ConfigError::Env {
+                var: name,
+                key,
+                message: format!("{e} (got `{raw}`)"),
+            })
+        })
+        .transpose()
+}
+
+fn invalid(key: &'static str, message: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid { key, message: message.into() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let map: HashMap<String, String> =
+            pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |var| map.get(var).cloned()
+    }
+
+    #[test]
+    fn test_file_values_and_env_overrides() {
+        let mut config: Config = toml::from_str(
+            r#"
+            bind = "0.0.0.0:9000"
+            registration = "closed"
+
+            [retention]
+            tombstone_days = 30
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.max_message_size, Config::default().max_message_size);
+
+        config
+            .apply_env(env(&[
+                ("NOTARO_BIND", "127.0.0.1:7000"),
+                ("NOTARO_LOG_LEVEL", "debug"),
+                ("NOTARO_STORAGE_BACKEND", "postgres"),
+                ("NOTARO_POSTGRES_URL", "postgres://notaro@db/notaro"),
+                ("NOTARO_MAX_NOTES", "10000"),
+            ]))
+            .unwrap();
+        assert_eq!(config.bind, "127.0.0.1:7000".parse().unwrap());
+        assert_eq!(config.registration, Registration::Closed);
+        assert_eq!(config.retention.tombstone_days, 30);
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(config.storage.backend, Backend::Postgres);
+        assert_eq!(config.storage.postgres_pool_size, 16);
+        assert_eq!(config.limits.max_notes, Some(10_000));
+        assert_eq!(config.limits.max_storage_bytes, None);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_errors_name_the_bad_key() {
+        let err = toml::from_str::<Config>("[retention]\ntombstone_dayz = 3").unwrap_err();
+        assert!(err.to_string().contains("tombstone_dayz"), "{err}");
+        let err = toml::from_str::<Config>("max_message_size = \"big\"").unwrap_err();
+        assert!(err.to_string().contains("max_message_size"), "{err}");
+
+        let err = Config::default()
+            .apply_env(env(&[("NOTARO_REGISTRATION", "maybe")]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("NOTARO_REGISTRATION (overrides `registration`)"), "{err}");
+        let err = Config::default().apply_env(env(&[("NOTARO_TLS_KEY", "key.pem")])).unwrap_err();
+        assert!(err.to_string().contains("tls.key_path"), "{err}");
+
+        let config = Config { max_message_size: 10, ..Default::default() };
+        assert!(
+            config.validate().unwrap_err().to_string().starts_with("invalid `max_message_size`")
+        );
+        let limits = Limits { max_note_size: usize::MAX, ..Default::default() };
+        let config = Config { limits, ..Default::default() };
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.starts_with("invalid `limits.max_note_size`"), "{err}");
+        let storage = Storage { backend: Backend::Postgres, ..Default::default() };
+        let config = Config { storage, ..Default::default() };
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.starts_with("invalid `storage.postgres_url`"), "{err}");
+        let tls = Some(TlsConfig { cert_path: "missing.pem".into(), key_path: "k.pem".into() });
+        let config = Config { tls, ..Default::default() };
+        assert!(config.validate().unwrap_err().to_string().starts_with("invalid `tls.cert_path`"));
+    }
+}
@@ -0,0 +1,35 @@
+use crate::state::AppState;
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use serde_json::{Value, json};
+
+/// `GET /healthz`: the process is up and serving requests
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// `GET /readyz`: the account database answers and the note database directory is usable.
+/// Answers 503 naming the failed check otherwise.
+pub async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+    let checks = tokio::task::spawn_blocking(move || {
+        let accounts = match state.accounts.lock() {
+            Ok(accounts) => accounts.ping().map_err(|e| e.to_string()),
+            Err(_) => Err("accounts lock poisoned".to_string()),
+        };
+        [("accounts", accounts), ("notes", state.stores.check().map_err(|e| e.to_string()))]
+    })
+    .await
+    .expect("readiness check panicked");
+
+    match checks.into_iter().find_map(|(name, result)| result.err().map(|e| (name, e))) {
+        None => (StatusCode::OK, Json(json!({ "status": "ready" }))),
+        Some((check, error)) => {
+            tracing::warn!(check, "not ready: {error}");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({ "status": "unavailable", "check": check, "error": error })),
+            )
+        }
+    }
+}
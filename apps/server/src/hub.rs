@@ -0,0 +1,155 @@
+use notaro_core::SyncMessage;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+pub type SessionId = u64;
+
+/// Registry of connected sessions, grouped by user.
+/// Each session owns the receiving half of an outbox; anything sent through the hub is written
+/// to that session's socket by its own task.
+#[derive(Clone, Default)]
+pub struct Hub {
+    inner: Arc<Mutex<HubInner>>,
+}
+
+#[derive(Default)]
+struct HubInner {
+    next_id: SessionId,
+    users: HashMap<String, HashMap<SessionId, Connection>>,
+}
+
+struct Connection {
+    /// Device session (login) the socket authenticated with
+    device: String,
+    tx: UnboundedSender<SyncMessage>,
+}
+
+impl Hub {
+    /// Adds a session for `user`, opened with the device session `device`, and returns its id
+    /// together with its outbox.
+    pub fn register(
+        &self,
+        user: &str,
+        device: &str,
+    ) -> (SessionId, UnboundedReceiver<SyncMessage>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut inner = self.inner.lock().expect("hub lock poisoned");
+        inner.next_id += 1;
+        let id = inner.next_id;
+        let connection = Connection { device: device.to_string(), tx };
+        inner.users.entry(user.to_string()).or_default().insert(id, connection);
+        (id, rx)
+    }
+
+    pub fn unregister(&self, user: &str, session: SessionId) {
+        let mut inner = self.inner.lock().expect("hub lock poisoned");
+        if let Some(sessions) = inner.users.get_mut(user) {
+            sessions.remove(&session);
+            if sessions.is_empty() {
+                inner.users.remove(user);
+            }
+        }
+    }
+
+    /// Queues `message` for every session of `user` except `origin`.
+    /// Sessions whose socket task has already exited are dropped from the registry.
+    pub fn broadcast_except(&self, user: &str, origin: SessionId, message: &SyncMessage) {
+        let mut inner = self.inner.lock().expect("hub lock poisoned");
+        if let Some(sessions) = inner.users.get_mut(user) {
+            sessions.retain(|id, conn| *id == origin || conn.tx.send(message.clone()).is_ok());
+        }
+    }
+
+    /// Queues `message` for every session of `user`
+    pub fn broadcast(&self, user: &str, message: &SyncMessage) {
+        let mut inner = self.inner.lock().expect("hub lock poisoned");
+        if let Some(sessions) = inner.users.get_mut(user) {
+            sessions.retain(|_, conn| conn.tx.send(message.clone()).is_ok());
+        }
+    }
+
+    /// `broadcast_except` for a change made by session `origin`, or `broadcast` for one that came
+    /// in outside any session
+    pub fn broadcast_from(&self, user: &str, origin: Option<SessionId>, message: &SyncMessage) {
+        match origin {
+            Some(origin) => self.broadcast_except(user, origin, message),
+            None => self.broadcast(user, message),
+        }
+    }
+
+    /// Tells every live socket of a revoked device session that it was signed out and drops it
+    /// from the registry; each socket closes after delivering the message. Returns how many
+    /// sockets were signed out.
+    pub fn sign_out_device(&self, user: &str, device: &str) -> usize {
+        let mut inner = self.inner.lock().expect("hub lock poisoned");
+        let Some(sessions) = inner.users.get_mut(user) else { return 0 };
+
+        let before = sessions.len();
+        sessions.retain(|_, conn| {
+            if conn.device != device {
+                return true;
+            }
+            let _ = conn.tx.send(SyncMessage::SessionRevoked);
+            false
+        });
+        let signed_out = before - sessions.len();
+        if sessions.is_empty() {
+            inner.users.remove(user);
+        }
+        signed_out
+    }
+
+    /// Number of live sessions for `user`
+    pub fn session_count(&self, user: &str) -> usize {
+        let inner = self.inner.lock().expect("hub lock poisoned");
+        inner.users.get(user).map_or(0, HashMap::len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_broadcast_skips_origin_and_other_users() {
+        let hub = Hub::default();
+        let (a, mut rx_a) = hub.register("alice", "laptop");
+        let (_b, mut rx_b) = hub.register("alice", "laptop");
+        let (_c, mut rx_c) = hub.register("bob", "desktop");
+
+        hub.broadcast_except("alice", a, &SyncMessage::Ack);
+
+        assert_eq!(rx_b.try_recv().unwrap(), SyncMessage::Ack);
+        assert!(rx_a.try_recv().is_err());
+        assert!(rx_c.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_sign_out_device_only_hits_that_device() {
+        let hub = Hub::default();
+        let (_a, mut rx_a) = hub.register("alice", "laptop");
+        let (_b, mut rx_b) = hub.register("alice", "laptop");
+        let (_c, mut rx_c) = hub.register("alice", "phone");
+
+        assert_eq!(hub.sign_out_device("alice", "laptop"), 2);
+        assert_eq!(rx_a.try_recv().unwrap(), SyncMessage::SessionRevoked);
+        assert_eq!(rx_b.try_recv().unwrap(), SyncMessage::SessionRevoked);
+        assert!(rx_c.try_recv().is_err());
+        assert_eq!(hub.session_count("alice"), 1);
+    }
+
+    #[test]
+    fn test_closed_sessions_are_pruned() {
+        let hub = Hub::default();
+        let (a, _rx_a) = hub.register("alice", "laptop");
+        let (_b, rx_b) = hub.register("alice", "laptop");
+        drop(rx_b);
+
+        hub.broadcast_except("alice", a, &SyncMessage::Ack);
+        assert_eq!(hub.session_count("alice"), 1);
+
+        hub.unregister("alice", a);
+        assert_eq!(hub.session_count("alice"), 0);
+    }
+}
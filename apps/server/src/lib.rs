@@ -0,0 +1,141 @@
+pub mod accounts;
+pub mod admin;
+pub mod auth;
+pub mod collections;
+pub mod config;
+pub mod health;
+pub mod hub;
+pub mod limits;
+pub mod metrics;
+pub mod presence;
+pub mod rest;
+pub mod session;
+pub mod shares;
+pub mod shutdown;
+pub mod state;
+pub mod store;
+pub mod tls;
+pub mod webhooks;
+
+use axum::Router;
+use axum::extract::DefaultBodyLimit;
+use axum::extract::connect_info::Connected;
+use axum::routing::{delete, get, post};
+use axum::serve::IncomingStream;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+pub use admin::Admin;
+pub use config::Config;
+pub use hub::{Hub, SessionId};
+pub use state::AppState;
+pub use store::UserStores;
+pub use tls::TlsListener;
+
+/// Builds the HTTP router: the sync WebSocket lives at `/sync`, next to the account endpoints
+/// that issue the bearer tokens it requires, webhook management, the public share link pages
+/// under `/s` and the health and metrics endpoints. The HTTP sync API lives under `/v1`,
+/// described by the OpenAPI document at `/v1/openapi.json`.
+pub fn router(state: AppState) -> Router {
+    let (api, _) = rest::api().split_for_parts();
+    let api = api
+        .route("/v1/openapi.json", get(rest::document))
+        .layer(DefaultBodyLimit::max(state.config.max_message_size));
+    Router::new()
+        .route("/sync", get(session::ws_handler))
+        .route("/accounts", post(auth::register))
+        .route("/sessions", post(auth::login).get(auth::list_sessions))
+        .route("/sessions/current", delete(auth::logout))
+        .route("/sessions/{id}", delete(auth::revoke_session))
+        .route("/collections", post(collections::create).get(collections::list))
+        .route("/collections/{id}/members", post(collections::add_member).get(collections::members))
+        .route("/collections/{id}/members/{user_id}", delete(collections::remove_member))
+        .route("/notes/{id}/shares", post(shares::create))
+        .route("/shares", get(shares::list))
+        .route("/shares/{id}", delete(shares::revoke))
+        .route("/s/{token}", get(shares::page).post(shares::unlock))
+        .route("/s/{token}/events", get(shares::events))
+        .route("/webhooks", post(webhooks::create).get(webhooks::list))
+        .route("/webhooks/{id}", delete(webhooks::delete))
+        .route("/webhooks/{id}/deliveries", get(webhooks::deliveries))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/metrics", get(metrics::metrics))
+        .merge(api)
+        .with_state(state)
+}
+
+/// Serves the sync API on an already bound listener until `state.shutdown` begins and the
+/// connections have drained, delivering webhooks and expiring presence in the background. Peer
+/// addresses are recorded so device sessions can show where they were last seen.
+pub async fn serve(listener: TcpListener, state: AppState) -> std::io::Result<()> {
+    tokio::spawn(webhooks::deliver(state.clone()));
+    tokio::spawn(presence::sweep(state.clone()));
+    let app = router(state.clone()).into_make_service_with_connect_info::<PeerAddr>();
+    let mut shutdown = state.shutdown.watch();
+    let server =
+        axum::serve(listener, app).with_graceful_shutdown(async move { shutdown.begun().await });
+    drain(&state, server.into_future()).await
+}
+
+/// Like `serve`, over TLS
+pub async fn serve_tls(listener: TlsListener, state: AppState) -> std::io::Result<()> {
+    tokio::spawn(webhooks::deliver(state.clone()));
+    tokio::spawn(presence::sweep(state.clone()));
+    let app = router(state.clone()).into_make_service_with_connect_info::<PeerAddr>();
+    let mut shutdown = state.shutdown.watch();
+    let server =
+        axum::serve(listener, app).with_graceful_shutdown(async move { shutdown.begun().await });
+    drain(&state, server.into_future()).await
+}
+
+/// Runs `server` until the shutdown begins. From then on no connection is accepted; HTTP
+/// requests run to completion and sockets finish the message they are handling, tell their
+/// client and close. Returns once all of that is done, or when `shutdown.deadline_secs` is up.
+async fn drain(
+    state: &AppState,
+    server: impl Future<Output = std::io::Result<()>>,
+) -> std::io::Result<()> {
+    tokio::pin!(server);
+    let mut shutdown = state.shutdown.watch();
+    tokio::select! {
+        result = &mut server => return result,
+        () = shutdown.begun() => {}
+    }
+    drop(shutdown);
+
+    let deadline = Duration::from_secs(state.config.shutdown.deadline_secs);
+    tracing::info!(open = state.shutdown.open(), ?deadline, "shutting down");
+    let drained = tokio::time::timeout(deadline, async {
+        let result = server.await;
+        state.shutdown.drained().await;
+        result
+    });
+    match drained.await {
+        Ok(result) => {
+            tracing::info!("all connections closed");
+            result
+        }
+        Err(_) => {
+            tracing::warn!(open = state.shutdown.open(), "shutdown deadline passed; exiting");
+            Ok(())
+        }
+    }
+}
+
+/// Remote address of a connection, plain or TLS, available to handlers as `ConnectInfo<PeerAddr>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerAddr(pub SocketAddr);
+
+impl Connected<IncomingStream<'_, TcpListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self(*stream.remote_addr())
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        Self(*stream.remote_addr())
+    }
+}
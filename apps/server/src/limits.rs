@@ -0,0 +1,218 @@
+use crate::config::Limits;
+use crate::session::PushBatch;
+use notaro_core::error::Result;
+use notaro_core::{Limit, Note, NoteStore, RejectedNote};
+use std::time::{Duration, Instant};
+
+/// Token bucket limiting how many messages one connection may send
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    /// `None` when rate limiting is off
+    pub fn new(limits: &Limits) -> Option<Self> {
+        (limits.messages_per_second > 0)
+            .then(|| Self::with_rate(f64::from(limits.messages_per_second), limits.burst))
+    }
+
+    /// Allows `burst` at once, then `rate` a second
+    pub fn with_rate(rate: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Self { rate, burst, tokens: burst, refilled: Instant::now() }
+    }
+
+    /// Whether the bucket will have refilled by `now`, so forgetting it changes nothing
+    pub fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.burst
+    }
+
+    /// Takes a token for a message received at `now`, or returns how long until one is free
+    pub fn check(&mut self, now: Instant) -> std::result::Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+/// Whose quota a push is checked against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    /// A user's own notes. The copies of shared notes mirrored into their store count towards
+    /// the collections' quotas instead.
+    Account,
+    /// A shared collection's notes, in its canonical store
+    Collection,
+}
+
+/// Splits a push into the notes `db` may take under `limits` and the ones refused. Deletions and
+/// edits that do not grow a store are always let through, so a store over its quota can shrink.
+pub fn enforce_quota(
+    db: &dyn NoteStore,
+    limits: &Limits,
+    scope: Quota,
+    batch: PushBatch,
+) -> Result<(PushBatch, Vec<RejectedNote>)> {
+    let quota = limits.max_notes.is_some() || limits.max_storage_bytes.is_some();
+    let counts = if quota { Some(db.note_counts()?) } else { None };
+    let (mut notes, mut bytes) = match (scope, counts) {
+        (_, None) => (0, 0),
+        (Quota::Account, Some(c)) => {
+            ((c.live - c.shared) as i64, (c.bytes - c.shared_bytes) as i64)
+        }
+        (Quota::Collection, Some(c)) => (c.live as i64, c.bytes as i64),
+    };
+
+    let mut check = |note: &Note| -> Result<Option<RejectedNote>> {
+        let refuse = |limit, reason: String| {
+            Ok(Some(RejectedNote { id: note.id.clone(), reason, limit: Some(limit) }))
+        };
+        if note.size() > limits.max_note_size {
+            let reason = format!("notes may be at most {} bytes", limits.max_note_size);
+            return refuse(Limit::NoteSize, reason);
+        }
+        if !quota {
+            return Ok(None);
+        }
+
+        let counted = |note: &Note| scope == Quota::Collection || note.collection.is_none();
+        let footprint = |note: Option<&Note>| match note {
+            Some(note) if !note.is_deleted && counted(note) => (1, note.size() as i64),
+            _ => (0, 0),
+        };
+        let (old_notes, old_bytes) = footprint(db.find_note(&note.id)?.as_ref());
+        let (new_notes, new_bytes) = footprint(Some(note));
+        let (grown_notes, grown_bytes) = (new_notes - old_notes, new_bytes - old_bytes);
+        if let Some(max) = limits.max_notes
+            && grown_notes > 0
+            && notes + grown_notes > max as i64
+        {
+            return refuse(Limit::NoteCount, format!("the quota of {max} notes is used up"));
+        }
+        if let Some(max) = limits.max_storage_bytes
+            && grown_bytes > 0
+            && bytes + grown_bytes > max as i64
+        {
+            return refuse(
+                Limit::StorageBytes,
+                format!("the storage quota of {max} bytes is used up"),
+            );
+        }
+        notes += grown_notes;
+        bytes += grown_bytes;
+        Ok(None)
+    };
+
+    let mut allowed = PushBatch::default();
+    let mut rejected = Vec::new();
+    for note in batch.changes {
+        match check(&note)? {
+            Some(refused) => rejected.push(refused),
+            None => allowed.changes.push(note),
+        }
+    }
+    for delta in batch.deltas {
+        // A delta's size is only known once rebuilt; one whose base is gone is left for the merge
+        // to send back as `NeedFullCopy`
+        let rebuilt = db
+            .find_note(&delta.note.id)?
+            .and_then(|stored| delta.apply(&stored.content))
+            .unwrap_or_else(|| delta.note.clone());
+        match check(&rebuilt)? {
+            Some(refused) => rejected.push(refused),
+            None => allowed.deltas.push(delta),
+        }
+    }
+    Ok((allowed, rejected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notaro_core::DatabaseConnection;
+
+    #[test]
+    fn test_rate_limiter_allows_bursts_then_refills() {
+        let limits = Limits { messages_per_second: 10, burst: 3, ..Default::default() };
+        let mut limiter = RateLimiter::new(&limits).unwrap();
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.check(start).unwrap();
+        }
+        let wait = limiter.check(start).unwrap_err();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100));
+        limiter.check(start + Duration::from_millis(100)).unwrap();
+
+        let off = Limits { messages_per_second: 0, ..Default::default() };
+        assert!(RateLimiter::new(&off).is_none());
+    }
+
+    #[test]
+    fn test_quota_refuses_growth_but_not_shrinking() {
+        let db = DatabaseConnection::new(":memory:").unwrap();
+        let kept = db.create_note("Kept".into(), "x".repeat(10), None).unwrap();
+        let limits =
+            Limits { max_notes: Some(2), max_storage_bytes: Some(40), ..Default::default() };
+
+        let second = Note::new("Two".into(), "".into(), None);
+        let third = Note::new("Three".into(), "".into(), None);
+        let huge = Note::new("Huge".into(), "x".repeat(2 * 1024 * 1024), None);
+        let batch = PushBatch {
+            changes: vec![second.clone(), third.clone(), huge.clone()],
+            deltas: vec![],
+        };
+        let (allowed, rejected) = enforce_quota(&db, &limits, Quota::Account, batch).unwrap();
+        assert_eq!(allowed.changes, vec![second]);
+        let limits_hit: Vec<_> = rejected.iter().map(|r| (r.id.as_str(), r.limit)).collect();
+        assert_eq!(
+            limits_hit,
+            [
+                (third.id.as_str(), Some(Limit::NoteCount)),
+                (huge.id.as_str(), Some(Limit::NoteSize))
+            ]
+        );
+
+        let mut grown = kept.clone();
+        grown.content = "x".repeat(50);
+        let mut deleted = kept.clone();
+        deleted.is_deleted = true;
+        let batch = PushBatch { changes: vec![grown], deltas: vec![] };
+        let (_, rejected) = enforce_quota(&db, &limits, Quota::Account, batch).unwrap();
+        assert_eq!(rejected[0].limit, Some(Limit::StorageBytes));
+        let batch = PushBatch { changes: vec![deleted.clone()], deltas: vec![] };
+        assert_eq!(
+            enforce_quota(&db, &limits, Quota::Account, batch).unwrap().0.changes,
+            vec![deleted]
+        );
+    }
+
+    #[test]
+    fn test_shared_notes_do_not_count_towards_the_account_quota() {
+        let db = DatabaseConnection::new(":memory:").unwrap();
+        let shared = db.create_note("Runbook".into(), "x".repeat(30), None).unwrap();
+        db.move_to_collection(&shared.id, "team").unwrap();
+        let limits =
+            Limits { max_notes: Some(1), max_storage_bytes: Some(20), ..Default::default() };
+
+        let private = Note::new("Mine".into(), "".into(), None);
+        let batch = PushBatch { changes: vec![private.clone()], deltas: vec![] };
+        let (allowed, rejected) = enforce_quota(&db, &limits, Quota::Account, batch).unwrap();
+        assert_eq!(allowed.changes, vec![private.clone()]);
+        assert!(rejected.is_empty());
+
+        let batch = PushBatch { changes: vec![private], deltas: vec![] };
+        let (_, rejected) = enforce_quota(&db, &limits, Quota::Collection, batch).unwrap();
+        assert_eq!(rejected[0].limit, Some(Limit::NoteCount));
+    }
+}
@@ -1,3 +1,360 @@
-fn main() {
-    println!("Hello, world!");
+use chrono::{DateTime, Duration, Utc};
+use clap::{Parser, Subcommand};
+use notaro_server::accounts::{AccountStore, AuditEntry, AuditEvent, AuditQuery};
+use notaro_server::admin::open_storage;
+use notaro_server::config::Config;
+use notaro_server::shutdown::{Shutdown, signal};
+use notaro_server::tls::Certificates;
+#[cfg(unix)]
+use notaro_server::tls::reload_on_sighup;
+use notaro_server::{Admin, AppState, TlsListener, UserStores};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tokio::net::TcpListener;
+use tracing_subscriber::EnvFilter;
+
+/// Notaro sync server
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// TOML configuration file; `notaro.toml` is used when it exists
+    #[arg(short, long, global = true, env = "NOTARO_CONFIG")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the sync server (the default)
+    Serve,
+    /// Manage accounts
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage logged-in devices
+    #[command(subcommand)]
+    Sessions(SessionsCommand),
+    /// Show user, note and storage totals
+    Stats,
+    /// Write a user's notes and settings as JSON
+    ExportUser {
+        username: String,
+        /// File to write instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Permanently remove deleted notes. Devices offline for longer than the cutoff may bring
+    /// purged notes back.
+    PurgeTombstones {
+        /// Only purge notes deleted at least this many days ago. Defaults to
+        /// `retention.tombstone_days`.
+        #[arg(long)]
+        older_than_days: Option<i64>,
+    },
+    /// Check every database for corruption
+    CheckDb,
+    /// Show logins, revocations, deletions, share links, permission changes and purges, oldest
+    /// first
+    Audit {
+        /// Only events done by this user
+        #[arg(long)]
+        user: Option<String>,
+        /// Only events of this kind, e.g. `note.deleted`
+        #[arg(long)]
+        event: Option<String>,
+        /// Only events at or after this RFC 3339 time
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+        /// Only the most recent events
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Print JSON lines instead of a table
+        #[arg(long)]
+        json: bool,
+        /// File to write JSON lines to instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Validate the file and environment, then print the effective configuration
+    Check,
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    /// Create an account. The password is read from standard input.
+    Add { username: String },
+    /// List accounts
+    List,
+    /// Block logins and sign out every device. Notes are kept.
+    Disable { username: String },
+    /// Allow a disabled account to log in again
+    Enable { username: String },
+    /// Set a new password, read from standard input, and sign out every device
+    ResetPassword { username: String },
+}
+
+#[derive(Subcommand)]
+enum SessionsCommand {
+    /// List a user's active device sessions
+    List { username: String },
+    /// Sign a device out. Its live sockets close within 2 seconds.
+    Revoke { session_id: String },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match Config::load(cli.config.as_deref()) {
+        Ok(config) => {
+            // RUST_LOG still wins, for one-off debugging
+            let filter = EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new(&config.log_level));
+            tracing_subscriber::fmt().with_env_filter(filter).init();
+            run(cli.command.unwrap_or(Command::Serve), config)
+        }
+        Err(e) => Err(e.into()),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Admin commands run without an async runtime; only `serve` starts one
+fn run(command: Command, config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let admin = || Admin::open(&config);
+    match command {
+        Command::Serve => serve(config)?,
+        Command::User(command) => user(&admin()?, command)?,
+        Command::Sessions(command) => sessions(&admin()?, command)?,
+        Command::Stats => {
+            let stats = admin()?.stats()?;
+            println!("users\t{} ({} disabled)", stats.users, stats.disabled_users);
+            println!("sessions\t{}", stats.active_sessions);
+            println!("notes\t{}", stats.notes);
+            println!("tombstones\t{}", stats.tombstones);
+            println!("storage\t{} bytes", stats.storage_bytes);
+        }
+        Command::ExportUser { username, output } => {
+            let export = admin()?.export_user(&username)?;
+            match output {
+                Some(path) => {
+                    serde_json::to_writer_pretty(std::fs::File::create(&path)?, &export)?;
+                    eprintln!("Exported {} notes to {}", export.notes.len(), path.display());
+                }
+                None => {
+                    let mut stdout = std::io::stdout().lock();
+                    serde_json::to_writer_pretty(&mut stdout, &export)?;
+                    writeln!(stdout)?;
+                }
+            }
+        }
+        Command::PurgeTombstones { older_than_days } => {
+            let older_than_days = older_than_days.unwrap_or(config.retention.tombstone_days);
+            let purged = admin()?.purge_tombstones(Utc::now() - Duration::days(older_than_days))?;
+            println!("Purged {purged} deleted notes");
+        }
+        Command::CheckDb => {
+            let problems = admin()?.check_db()?;
+            for problem in &problems {
+                println!("{problem}");
+            }
+            if !problems.is_empty() {
+                return Err(format!("{} problems found", problems.len()).into());
+            }
+            println!("All databases are healthy");
+        }
+        Command::Audit { user, event, since, limit, json, output } => {
+            let admin = admin()?;
+            let user_id = user.map(|name| admin.accounts.user_id_by_name(&name)).transpose()?;
+            let query = AuditQuery { user_id, event, since, limit };
+            let records = admin.accounts.audit_log(&query)?;
+            match output {
+                Some(path) => {
+                    let mut file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+                    for record in &records {
+                        serde_json::to_writer(&mut file, record)?;
+                        writeln!(file)?;
+                    }
+                    file.flush()?;
+                    eprintln!("Exported {} events to {}", records.len(), path.display());
+                }
+                None => {
+                    let mut stdout = std::io::stdout().lock();
+                    for record in &records {
+                        if json {
+                            serde_json::to_writer(&mut stdout, record)?;
+                            writeln!(stdout)?;
+                            continue;
+                        }
+                        let actor = record.username.as_deref().unwrap_or("admin");
+                        let device = match (&record.device_name, &record.session_id) {
+                            (Some(name), _) => name.as_str(),
+                            (None, Some(id)) => id.as_str(),
+                            (None, None) => "-",
+                        };
+                        writeln!(
+                            stdout,
+                            "{}\t{}\t{}\t{}\tfrom {}\t{}\t{}",
+                            record.at.to_rfc3339(),
+                            record.event,
+                            actor,
+                            device,
+                            record.ip.as_deref().unwrap_or("-"),
+                            record.target.as_deref().unwrap_or("-"),
+                            record.details,
+                        )?;
+                    }
+                }
+            }
+        }
+        Command::Config(ConfigCommand::Check) => {
+            print!("{}", toml::to_string(&config)?);
+            eprintln!("Configuration is valid");
+        }
+    }
+    Ok(())
+}
+
+fn serve(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    // Opened before the runtime starts, since connecting to PostgreSQL blocks
+    let (accounts, stores) = open_storage(&config)?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(listen(config, accounts, stores));
+    // Whatever is still running once the deadline passed is abandoned. Merges are transactions,
+    // so one cut short leaves nothing half-applied behind.
+    runtime.shutdown_background();
+    result
+}
+
+async fn listen(
+    config: Config,
+    accounts: AccountStore,
+    stores: UserStores,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(config.bind).await?;
+    let addr = listener.local_addr()?;
+    let tls = config.tls.clone();
+    let state = AppState::new(accounts, stores).with_config(config);
+    tokio::spawn(stop_on_signal(state.shutdown.clone()));
+
+    match tls {
+        Some(paths) => {
+            let certificates = Certificates::load(paths)?;
+            #[cfg(unix)]
+            tokio::spawn(reload_on_sighup(certificates.clone()));
+            tracing::info!("Notaro sync server listening on {addr} (TLS)");
+            notaro_server::serve_tls(TlsListener::new(listener, certificates)?, state).await?;
+        }
+        None => {
+            tracing::info!("Notaro sync server listening on {addr}");
+            notaro_server::serve(listener, state).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Begins a graceful shutdown on the first SIGTERM or Ctrl-C and exits at once on the second
+async fn stop_on_signal(shutdown: Shutdown) {
+    if let Err(e) = signal().await {
+        tracing::error!("cannot listen for shutdown signals: {e}");
+        return;
+    }
+    tracing::info!("shutdown requested; send the signal again to exit right away");
+    shutdown.begin();
+    if signal().await.is_ok() {
+        std::process::exit(1);
+    }
+}
+
+fn user(admin: &Admin, command: UserCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let accounts = &admin.accounts;
+    match command {
+        UserCommand::Add { username } => {
+            let user_id = accounts.create_user(&username, &read_password()?)?;
+            println!("Created {username} ({user_id})");
+        }
+        UserCommand::List => {
+            for user in accounts.list_users()? {
+                println!(
+                    "{}\t{}\tcreated {}\t{} sessions{}",
+                    user.id,
+                    user.username,
+                    user.created_at.to_rfc3339(),
+                    user.active_sessions,
+                    if user.disabled { "\tdisabled" } else { "" },
+                );
+            }
+        }
+        UserCommand::Disable { username } => {
+            let user_id = accounts.set_disabled(&username, true)?;
+            accounts.record_audit(&AuditEntry::new(AuditEvent::UserDisabled).target(&user_id))?;
+            println!("Disabled {username}");
+        }
+        UserCommand::Enable { username } => {
+            let user_id = accounts.set_disabled(&username, false)?;
+            accounts.record_audit(&AuditEntry::new(AuditEvent::UserEnabled).target(&user_id))?;
+            println!("Enabled {username}");
+        }
+        UserCommand::ResetPassword { username } => {
+            let user_id = accounts.reset_password(&username, &read_password()?)?;
+            accounts.record_audit(&AuditEntry::new(AuditEvent::PasswordReset).target(&user_id))?;
+            println!("Reset the password of {username}");
+        }
+    }
+    Ok(())
+}
+
+/// Reads one line from standard input, prompting when it is a terminal.
+/// Keeping passwords out of the arguments keeps them out of shell history.
+fn read_password() -> std::io::Result<String> {
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        eprint!("Password: ");
+        std::io::stderr().flush()?;
+    }
+    let mut line = String::new();
+    stdin.lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn sessions(admin: &Admin, command: SessionsCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let accounts = &admin.accounts;
+    match command {
+        SessionsCommand::List { username } => {
+            let user_id = accounts.user_id_by_name(&username)?;
+            for session in accounts.list_sessions(&user_id)? {
+                let last_seen =
+                    session.last_seen_at.map_or_else(|| "never".to_string(), |t| t.to_rfc3339());
+                println!(
+                    "{}\t{}\tlast seen {}\tfrom {}",
+                    session.id,
+                    session.device_name,
+                    last_seen,
+                    session.last_ip.as_deref().unwrap_or("-"),
+                );
+            }
+        }
+        SessionsCommand::Revoke { session_id } => {
+            let user_id = accounts.revoke_session(&session_id, None)?;
+            accounts.record_audit(
+                &AuditEntry::new(AuditEvent::SessionRevoked)
+                    .target(&session_id)
+                    .details(serde_json::json!({ "user_id": user_id })),
+            )?;
+            println!("Revoked {session_id}");
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,186 @@
+use crate::state::AppState;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{IntoResponse, Response};
+use notaro_core::{Limit, MergeReport, SyncMessage};
+use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue, LabelValueEncoder};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{Histogram, exponential_buckets};
+use prometheus_client::registry::Registry;
+use std::time::Duration;
+
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+impl EncodeLabelValue for Direction {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        let value = match self {
+            Self::Received => "received",
+            Self::Sent => "sent",
+        };
+        EncodeLabelValue::encode(&value, encoder)
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct MessageLabels {
+    message_type: &'static str,
+    direction: Direction,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct MergeLabels {
+    outcome: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct LimitLabels {
+    limit: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct WebhookLabels {
+    outcome: &'static str,
+}
+
+/// Prometheus metrics for the whole server, exposed at `/metrics`
+pub struct Metrics {
+    registry: Registry,
+    sessions: Gauge,
+    messages: Family<MessageLabels, Counter>,
+    merges: Family<MergeLabels, Counter>,
+    limit_rejections: Family<LimitLabels, Counter>,
+    webhook_attempts: Family<WebhookLabels, Counter>,
+    push_duration: Histogram,
+    pull_duration: Histogram,
+    database_bytes: Gauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        // 1 ms up to ~8 s
+        let latency = || Histogram::new(exponential_buckets(0.001, 2.0, 14));
+        let mut metrics = Self {
+            registry: Registry::with_prefix("notaro"),
+            sessions: Gauge::default(),
+            messages: Family::default(),
+            merges: Family::default(),
+            limit_rejections: Family::default(),
+            webhook_attempts: Family::default(),
+            push_duration: latency(),
+            pull_duration: latency(),
+            database_bytes: Gauge::default(),
+        };
+
+        let registry = &mut metrics.registry;
+        registry.register("sessions", "Connected sync sessions", metrics.sessions.clone());
+        registry.register("messages", "Sync messages by type", metrics.messages.clone());
+        registry.register(
+            "merge_outcomes",
+            "Pushed notes and deltas by how they merged",
+            metrics.merges.clone(),
+        );
+        registry.register(
+            "limit_rejections",
+            "Messages and pushed notes refused for breaking a server limit",
+            metrics.limit_rejections.clone(),
+        );
+        registry.register(
+            "webhook_attempts",
+            "Webhook delivery attempts by whether they were delivered, will be retried or failed",
+            metrics.webhook_attempts.clone(),
+        );
+        registry.register(
+            "push_duration_seconds",
+            "Time to apply a push",
+            metrics.push_duration.clone(),
+        );
+        registry.register(
+            "pull_duration_seconds",
+            "Time to answer a pull",
+            metrics.pull_duration.clone(),
+        );
+        registry.register(
+            "database_bytes",
+            "Size of the note databases on disk",
+            metrics.database_bytes.clone(),
+        );
+        metrics
+    }
+}
+
+impl Metrics {
+    pub fn session_opened(&self) {
+        self.sessions.inc();
+    }
+
+    pub fn session_closed(&self) {
+        self.sessions.dec();
+    }
+
+    pub fn message(&self, direction: Direction, message: &SyncMessage) {
+        self.messages
+            .get_or_create(&MessageLabels { message_type: message.kind(), direction })
+            .inc();
+    }
+
+    /// Records a push: its merged notes, and its deltas split into applied ones and ones the
+    /// client has to resend in full
+    pub fn push(&self, report: &MergeReport, deltas: usize, full_copies: usize, took: Duration) {
+        let outcomes = [
+            ("inserted", report.inserted),
+            ("updated", report.updated),
+            ("ignored", report.ignored),
+            ("concurrent", report.concurrent.len()),
+            ("delta_applied", deltas - full_copies),
+            ("full_copy_requested", full_copies),
+        ];
+        for (outcome, count) in outcomes {
+            self.merges.get_or_create(&MergeLabels { outcome }).inc_by(count as u64);
+        }
+        self.push_duration.observe(took.as_secs_f64());
+    }
+
+    /// Records a message or pushed note refused for breaking `limit`
+    pub fn limit_exceeded(&self, limit: Limit) {
+        self.limit_rejections.get_or_create(&LimitLabels { limit: limit.as_str() }).inc();
+    }
+
+    /// One attempt at a webhook delivery: `delivered`, `retrying` or `failed`
+    pub fn webhook_attempt(&self, outcome: &'static str) {
+        self.webhook_attempts.get_or_create(&WebhookLabels { outcome }).inc();
+    }
+
+    pub fn pull(&self, took: Duration) {
+        self.pull_duration.observe(took.as_secs_f64());
+    }
+
+    /// OpenMetrics text for a scrape
+    pub fn encode(&self, database_bytes: u64) -> String {
+        self.database_bytes.set(database_bytes.try_into().unwrap_or(i64::MAX));
+        let mut text = String::new();
+        prometheus_client::encoding::text::encode(&mut text, &self.registry)
+            .expect("writing to a String cannot fail");
+        text
+    }
+}
+
+/// `GET /metrics`
+pub async fn metrics(State(state): State<AppState>) -> Response {
+    let stores = state.stores.clone();
+    let usage = tokio::task::spawn_blocking(move || stores.disk_usage()).await;
+    let database_bytes = usage.expect("measuring storage panicked").unwrap_or_else(|e| {
+        tracing::warn!("cannot measure the note databases: {e}");
+        0
+    });
+    let text = state.metrics.encode(database_bytes);
+    (StatusCode::OK, [(CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE)], text).into_response()
+}
@@ -0,0 +1,291 @@
+use crate::accounts::AuthError;
+use crate::auth::AuthUser;
+use crate::collections::account_failure;
+use crate::hub::SessionId;
+use crate::state::AppState;
+use notaro_core::error::Result;
+use notaro_core::sync::{PRESENCE_TIMEOUT, TYPING_TIMEOUT, presence_device_id};
+use notaro_core::{Note, NoteActivity, NotePresence, SyncMessage};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often lapsed presence is looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sessions that see a note's presence
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Audience {
+    /// A private note's owner
+    User(String),
+    /// Every member of a shared note's collection
+    Collection(String),
+}
+
+/// A note as its audience knows it. Clients pick note ids, so the same id in two users' private
+/// stores is two different notes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NoteKey {
+    pub audience: Audience,
+    pub note_id: String,
+}
+
+impl NoteKey {
+    /// The key of `note` as stored for `user`
+    pub fn of(user: &str, note: &Note) -> Self {
+        let audience = match &note.collection {
+            Some(collection) => Audience::Collection(collection.clone()),
+            None => Audience::User(user.to_string()),
+        };
+        Self { audience, note_id: note.id.clone() }
+    }
+}
+
+/// One socket's presence on a note
+struct Entry {
+    presence: NotePresence,
+    expires_at: Instant,
+    typing_until: Option<Instant>,
+}
+
+/// Notes open on connected sockets, each until its presence lapses
+#[derive(Clone, Default)]
+pub struct Presence {
+    notes: Arc<Mutex<HashMap<NoteKey, BTreeMap<SessionId, Entry>>>>,
+}
+
+/// A note whose devices changed, with the devices now present
+pub type Change = (NoteKey, Vec<NotePresence>);
+
+impl Presence {
+    /// Marks the note open on socket `session`, and typing if `typing`, calling `label` for the
+    /// device's names the first time. Returns the note's devices and whether that list changed.
+    pub fn open(
+        &self,
+        key: &NoteKey,
+        session: SessionId,
+        typing: bool,
+        label: impl FnOnce() -> Result<NotePresence>,
+        now: Instant,
+    ) -> Result<(Vec<NotePresence>, bool)> {
+        let mut notes = self.notes.lock().expect("presence lock poisoned");
+        let known = notes.get(key).is_some_and(|entries| entries.contains_key(&session));
+        let presence = if known { None } else { Some(label()?) };
+        let entries = notes.entry(key.clone()).or_default();
+        let before = listing(entries);
+        let entry = entries.entry(session).or_insert_with(|| Entry {
+            presence: presence.expect("new sockets are labelled above"),
+            expires_at: now,
+            typing_until: None,
+        });
+        entry.expires_at = now + PRESENCE_TIMEOUT;
+        if typing {
+            entry.typing_until = Some(now + TYPING_TIMEOUT);
+            entry.presence.typing = true;
+        }
+        let after = listing(entries);
+        let changed = after != before;
+        Ok((after, changed))
+    }
+
+    /// Drops socket `session` from every note with id `note_id`
+    pub fn close(&self, session: SessionId, note_id: &str) -> Vec<Change> {
+        self.remove(session, |key| key.note_id == note_id)
+    }
+
+    /// Drops socket `session` from every note, e.g. once it disconnected
+    pub fn leave(&self, session: SessionId) -> Vec<Change> {
+        self.remove(session, |_| true)
+    }
+
+    fn remove(&self, session: SessionId, matches: impl Fn(&NoteKey) -> bool) -> Vec<Change> {
+        let mut notes = self.notes.lock().expect("presence lock poisoned");
+        let mut changes = Vec::new();
+        notes.retain(|key, entries| {
+            if matches(key) {
+                let before = listing(entries);
+                if entries.remove(&session).is_some() {
+                    let after = listing(entries);
+                    if after != before {
+                        changes.push((key.clone(), after));
+                    }
+                }
+            }
+            !entries.is_empty()
+        });
+        changes
+    }
+
+    /// Forgets presence that was not renewed by `now` and ends typing that lapsed
+    pub fn expire(&self, now: Instant) -> Vec<Change> {
+        let mut notes = self.notes.lock().expect("presence lock poisoned");
+        let mut changes = Vec::new();
+        notes.retain(|key, entries| {
+            let before = listing(entries);
+            entries.retain(|_, entry| entry.expires_at > now);
+            for entry in entries.values_mut() {
+                if entry.typing_until.is_some_and(|until| until <= now) {
+                    entry.typing_until = None;
+                    entry.presence.typing = false;
+                }
+            }
+            let after = listing(entries);
+            if after != before {
+                changes.push((key.clone(), after));
+            }
+            !entries.is_empty()
+        });
+        changes
+    }
+}
+
+/// Devices present on a note, in the order they opened it. A device with several sockets on
+/// the note is listed once, as typing if any of them is.
+fn listing(entries: &BTreeMap<SessionId, Entry>) -> Vec<NotePresence> {
+    let mut present: Vec<NotePresence> = Vec::new();
+    for entry in entries.values() {
+        match present.iter_mut().find(|listed| listed.device == entry.presence.device) {
+            Some(listed) => listed.typing |= entry.presence.typing,
+            None => present.push(entry.presence.clone()),
+        }
+    }
+    present
+}
+
+/// Applies a `SetPresence` from socket `session` and tells the note's other sessions if that
+/// changed who is present. Notes the user has no live copy of are ignored. `Opened` is answered
+/// with the note's devices.
+pub fn announce(
+    state: &AppState,
+    auth: &AuthUser,
+    session: SessionId,
+    note_id: String,
+    activity: NoteActivity,
+) -> Result<Option<SyncMessage>> {
+    if activity == NoteActivity::Closed {
+        for (key, present) in state.presence.close(session, &note_id) {
+            publish(state, &key, present, Some(session))?;
+        }
+        return Ok(None);
+    }
+
+    let note = state
+        .user_db(&auth.user_id)?
+        .lock()
+        .expect("database lock poisoned")
+        .find_note(&note_id)?;
+    let Some(note) = note.filter(|note| !note.is_deleted) else { return Ok(None) };
+    let key = NoteKey::of(&auth.user_id, &note);
+    let label = || {
+        let accounts = state.accounts.lock().expect("accounts lock poisoned");
+        let label = accounts
+            .device_label(&auth.session_id)
+            .and_then(|label| label.ok_or(AuthError::InvalidToken))
+            .map_err(account_failure)?;
+        Ok(NotePresence {
+            device: presence_device_id(&auth.session_id, &auth.user_id, &note),
+            device_name: label.device_name,
+            username: label.username,
+            typing: false,
+        })
+    };
+    let typing = activity == NoteActivity::Typing;
+    let (present, changed) = state.presence.open(&key, session, typing, label, Instant::now())?;
+    if changed {
+        publish(state, &key, present.clone(), Some(session))?;
+    }
+    Ok((activity == NoteActivity::Opened)
+        .then_some(SyncMessage::PresenceUpdate { note_id, present }))
+}
+
+/// Drops a disconnected socket from every note it had open, telling the sessions concerned
+pub fn leave(state: &AppState, session: SessionId) {
+    for (key, present) in state.presence.leave(session) {
+        if let Err(e) = publish(state, &key, present, None) {
+            tracing::error!(note_id = key.note_id, "failed to publish presence: {e}");
+        }
+    }
+}
+
+/// Expires presence that was not renewed, telling the sessions concerned. Runs until the
+/// server stops.
+pub async fn sweep(state: AppState) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        for (key, present) in state.presence.expire(Instant::now()) {
+            if let Err(e) = publish(&state, &key, present, None) {
+                tracing::error!(note_id = key.note_id, "failed to publish presence: {e}");
+            }
+        }
+    }
+}
+
+/// Sends a note's devices to every session of its audience except `origin`
+fn publish(
+    state: &AppState,
+    key: &NoteKey,
+    present: Vec<NotePresence>,
+    origin: Option<SessionId>,
+) -> Result<()> {
+    let update = SyncMessage::PresenceUpdate { note_id: key.note_id.clone(), present };
+    match &key.audience {
+        Audience::User(user) => state.hub.broadcast_from(user, origin, &update),
+        Audience::Collection(collection_id) => {
+            let members = state
+                .accounts
+                .lock()
+                .expect("accounts lock poisoned")
+                .collection_members(collection_id)
+                .map_err(account_failure)?;
+            for member in members {
+                state.hub.broadcast_from(&member.user_id, origin, &update);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(name: &str) -> NotePresence {
+        NotePresence {
+            device: name.to_string(),
+            device_name: name.to_string(),
+            username: "alice".to_string(),
+            typing: false,
+        }
+    }
+
+    #[test]
+    fn test_presence_lapses_and_typing_ends() {
+        let presence = Presence::default();
+        let key = NoteKey { audience: Audience::User("alice".into()), note_id: "n".into() };
+        let start = Instant::now();
+
+        let (present, changed) =
+            presence.open(&key, 1, false, || Ok(device("laptop")), start).unwrap();
+        assert!(changed);
+        assert_eq!(present, vec![device("laptop")]);
+        // A second socket of the same device changes nothing others see
+        let (_, changed) = presence.open(&key, 2, false, || Ok(device("laptop")), start).unwrap();
+        assert!(!changed);
+        let (present, changed) =
+            presence.open(&key, 3, true, || Ok(device("phone")), start).unwrap();
+        assert!(changed);
+        assert!(present[1].typing);
+
+        let typing_over = start + TYPING_TIMEOUT;
+        let changes = presence.expire(typing_over);
+        assert_eq!(changes, vec![(key.clone(), vec![device("laptop"), device("phone")])]);
+
+        // Renewing keeps a socket present past the timeout
+        presence.open(&key, 1, false, || unreachable!(), typing_over).unwrap();
+        let changes = presence.expire(start + PRESENCE_TIMEOUT);
+        assert_eq!(changes, vec![(key.clone(), vec![device("laptop")])]);
+        assert_eq!(presence.close(1, "n"), vec![(key, vec![])]);
+        assert!(presence.leave(1).is_empty());
+    }
+}
@@ -0,0 +1,401 @@
+use crate::auth::AuthUser;
+use crate::session::{self, PushBatch, PushOutcome};
+use crate::state::AppState;
+use axum::Json;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use notaro_core::delta::{DeltaOp, NoteDelta, TextDelta};
+use notaro_core::hlc::HybridClock;
+use notaro_core::sync::answer_pull;
+use notaro_core::{ChangeCursor, Limit, NotaroError, Note, RejectedNote, SyncFilter, SyncMessage};
+use serde::{Deserialize, Serialize};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Notaro sync API",
+        description = "Syncs notes over plain HTTP for clients that cannot hold a WebSocket open. \
+            Pushes go through the same merge, permission checks and limits as `/sync`."
+    ),
+    components(schemas(DeltaOp, TextDelta, Limit)),
+    modifiers(&BearerToken),
+    security(("bearer" = []))
+)]
+struct ApiDoc;
+
+/// Declares the bearer tokens issued by `POST /sessions`
+struct BearerToken;
+
+impl Modify for BearerToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let scheme = HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build();
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme("bearer", SecurityScheme::Http(scheme));
+    }
+}
+
+/// The `/v1` routes together with their OpenAPI description
+pub fn api() -> OpenApiRouter<AppState> {
+    OpenApiRouter::with_openapi(ApiDoc::openapi()).routes(routes!(changes, push)).routes(routes!(
+        get_note,
+        put_note,
+        delete_note
+    ))
+}
+
+/// OpenAPI document of the `/v1` routes, generated from the handlers
+pub fn openapi() -> utoipa::openapi::OpenApi {
+    api().split_for_parts().1
+}
+
+/// `GET /v1/openapi.json`
+pub async fn document() -> Json<utoipa::openapi::OpenApi> {
+    Json(openapi())
+}
+
+/// Error answered by the `/v1` endpoints
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("no such note")]
+    NoteNotFound,
+    #[error("cursor must be a next_cursor returned by GET /v1/changes")]
+    InvalidCursor,
+    #[error("{}", .0.reason)]
+    Rejected(RejectedNote),
+    #[error(transparent)]
+    Store(#[from] NotaroError),
+}
+
+/// Body of every error response
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: String,
+    /// Set when a server limit was hit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<Limit>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, limit) = match &self {
+            ApiError::NoteNotFound => (StatusCode::NOT_FOUND, None),
+            ApiError::InvalidCursor => (StatusCode::BAD_REQUEST, None),
+            ApiError::Rejected(RejectedNote { limit: None, .. }) => (StatusCode::FORBIDDEN, None),
+            ApiError::Rejected(RejectedNote { limit: Some(limit), .. }) => {
+                let status = match limit {
+                    Limit::NoteCount | Limit::StorageBytes => StatusCode::INSUFFICIENT_STORAGE,
+                    _ => StatusCode::PAYLOAD_TOO_LARGE,
+                };
+                (status, Some(*limit))
+            }
+            ApiError::Store(e) => {
+                tracing::error!("note store failure: {e}");
+                (StatusCode::INTERNAL_SERVER_ERROR, None)
+            }
+        };
+        (status, Json(ErrorBody { error: self.to_string(), limit })).into_response()
+    }
+}
+
+/// Query of `GET /v1/changes`
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ChangesQuery {
+    /// Change feed version the client last saw; 0 for everything
+    #[serde(default)]
+    pub since: i32,
+    /// `next_cursor` of the previous page, to continue a pull that had `has_more`
+    pub cursor: Option<String>,
+    /// Most notes to return
+    pub limit: Option<u32>,
+}
+
+/// One page of the change feed
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ChangesPage {
+    /// Notes changed since the requested version, deletions included
+    pub changes: Vec<Note>,
+    /// Feed version to pass as `since` once every page was read
+    pub current_version: i32,
+    pub has_more: bool,
+    /// Passed back as `cursor` to fetch the next page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// Body of `POST /v1/changes`
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ChangesPush {
+    #[serde(default)]
+    pub changes: Vec<Note>,
+    #[serde(default)]
+    pub deltas: Vec<NoteDelta>,
+}
+
+/// Answer to `POST /v1/changes`; every note not listed here was merged
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PushResult {
+    /// Notes whose deltas did not apply and have to be pushed in full
+    pub need_full_copy: Vec<String>,
+    pub rejected: Vec<RejectedNote>,
+}
+
+/// Body of `PUT /v1/notes/{id}`
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NoteWrite {
+    pub title: String,
+    pub content: String,
+    #[serde(default)]
+    pub folder: Option<String>,
+    #[serde(default)]
+    pub is_pinned: bool,
+    /// Shared collection to create the note in; existing notes stay where they are
+    #[serde(default)]
+    pub collection: Option<String>,
+}
+
+/// Pull cursors travel as `{version}:{id}`
+fn encode_cursor(cursor: &ChangeCursor) -> String {
+    format!("{}:{}", cursor.version, cursor.id)
+}
+
+fn decode_cursor(cursor: &str) -> Result<ChangeCursor, ApiError> {
+    let (version, id) = cursor.split_once(':').ok_or(ApiError::InvalidCursor)?;
+    let version = version.parse().map_err(|_| ApiError::InvalidCursor)?;
+    Ok(ChangeCursor { version, id: id.to_string() })
+}
+
+/// Runs `f` on the blocking pool, since note stores block on I/O
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, ApiError> + Send + 'static,
+) -> Result<T, ApiError> {
+    tokio::task::spawn_blocking(f).await.map_err(|e| NotaroError::Io(std::io::Error::other(e)))?
+}
+
+/// `GET /v1/changes`: one page of the notes changed since a feed version
+#[utoipa::path(
+    get,
+    path = "/v1/changes",
+    params(ChangesQuery),
+    responses(
+        (status = 200, description = "A page of changes", body = ChangesPage),
+        (status = 400, description = "Malformed cursor", body = ErrorBody),
+        (status = 401, description = "Missing or revoked token", body = ErrorBody)
+    )
+)]
+pub async fn changes(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Query(query): Query<ChangesQuery>,
+) -> Result<Json<ChangesPage>, ApiError> {
+    let cursor = query.cursor.as_deref().map(decode_cursor).transpose()?;
+    blocking(move || {
+        let store = state.user_db(&user.user_id)?;
+        let db = store.lock().expect("database lock poisoned");
+        let filter = SyncFilter::default();
+        match answer_pull(&*db, query.since, cursor.as_ref(), query.limit, &filter)? {
+            SyncMessage::PullResponse {
+                changes, current_version, has_more, next_cursor, ..
+            } => {
+                let next_cursor = next_cursor.as_ref().map(encode_cursor);
+                Ok(Json(ChangesPage { changes, current_version, has_more, next_cursor }))
+            }
+            other => unreachable!("a pull is answered with a page, not {}", other.kind()),
+        }
+    })
+    .await
+}
+
+/// `POST /v1/changes`: pushes notes exactly like a `PushUpdates` over the socket
+#[utoipa::path(
+    post,
+    path = "/v1/changes",
+    request_body = ChangesPush,
+    responses(
+        (status = 200, description = "Push applied; refused notes are listed", body = PushResult),
+        (status = 401, description = "Missing or revoked token", body = ErrorBody),
+        (status = 413, description = "Body larger than the message size limit")
+    )
+)]
+pub async fn push(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Json(body): Json<ChangesPush>,
+) -> Result<Json<PushResult>, ApiError> {
+    blocking(move || {
+        let batch = PushBatch { changes: body.changes, deltas: body.deltas };
+        let PushOutcome { need_full_copy, rejected } =
+            session::apply_push(&state, &user, None, batch)?;
+        Ok(Json(PushResult { need_full_copy, rejected }))
+    })
+    .await
+}
+
+/// `GET /v1/notes/{id}`
+#[utoipa::path(
+    get,
+    path = "/v1/notes/{id}",
+    params(("id" = String, Path, description = "Note id")),
+    responses(
+        (status = 200, description = "The note", body = Note),
+        (status = 401, description = "Missing or revoked token", body = ErrorBody),
+        (status = 404, description = "No such note, or it was deleted", body = ErrorBody)
+    )
+)]
+pub async fn get_note(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<Note>, ApiError> {
+    blocking(move || live_note(&state, &user.user_id, &id)?.map(Json).ok_or(ApiError::NoteNotFound))
+        .await
+}
+
+/// `PUT /v1/notes/{id}`: creates or replaces a note
+#[utoipa::path(
+    put,
+    path = "/v1/notes/{id}",
+    params(("id" = String, Path, description = "Note id, chosen by the client when creating")),
+    request_body = NoteWrite,
+    responses(
+        (status = 200, description = "The note as stored after the merge", body = Note),
+        (status = 201, description = "The note was created", body = Note),
+        (status = 401, description = "Missing or revoked token", body = ErrorBody),
+        (status = 403, description = "Not allowed to edit the note's collection", body = ErrorBody),
+        (status = 413, description = "Note larger than the note size limit", body = ErrorBody),
+        (status = 507, description = "Storage quota used up", body = ErrorBody)
+    )
+)]
+pub async fn put_note(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    Json(body): Json<NoteWrite>,
+) -> Result<(StatusCode, Json<Note>), ApiError> {
+    blocking(move || {
+        let stored = find_note(&state, &user.user_id, &id)?;
+        let created = stored.as_ref().is_none_or(|note| note.is_deleted);
+        let collection = stored.as_ref().map_or(body.collection, |note| note.collection.clone());
+        let note = next_version(stored, &id, &user.session_id, |note| {
+            note.title = body.title;
+            note.content = body.content;
+            note.folder = body.folder;
+            note.is_pinned = body.is_pinned;
+            note.is_deleted = false;
+            note.collection = collection;
+        });
+        write(&state, &user, note)?;
+
+        let note = live_note(&state, &user.user_id, &id)?.ok_or(ApiError::NoteNotFound)?;
+        let status = if created { StatusCode::CREATED } else { StatusCode::OK };
+        Ok((status, Json(note)))
+    })
+    .await
+}
+
+/// `DELETE /v1/notes/{id}`: deletes a note on every device
+#[utoipa::path(
+    delete,
+    path = "/v1/notes/{id}",
+    params(("id" = String, Path, description = "Note id")),
+    responses(
+        (status = 204, description = "The note was deleted"),
+        (status = 401, description = "Missing or revoked token", body = ErrorBody),
+        (status = 403, description = "Not allowed to edit the note's collection", body = ErrorBody),
+        (status = 404, description = "No such note, or it was already deleted", body = ErrorBody)
+    )
+)]
+pub async fn delete_note(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    blocking(move || {
+        let stored = live_note(&state, &user.user_id, &id)?.ok_or(ApiError::NoteNotFound)?;
+        let note = next_version(Some(stored), &id, &user.session_id, |note| note.is_deleted = true);
+        write(&state, &user, note)?;
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+}
+
+fn find_note(state: &AppState, user: &str, id: &str) -> Result<Option<Note>, ApiError> {
+    let store = state.user_db(user)?;
+    let db = store.lock().expect("database lock poisoned");
+    Ok(db.find_note(id)?)
+}
+
+/// The user's copy of a note, unless it is missing or deleted
+fn live_note(state: &AppState, user: &str, id: &str) -> Result<Option<Note>, ApiError> {
+    Ok(find_note(state, user, id)?.filter(|note| !note.is_deleted))
+}
+
+/// The next version of note `id` as written over HTTP by the device session `device`: the stored
+/// copy (or a new note) with `edit` applied, counted as a write of that device and stamped with
+/// a clock reading past the stored one
+fn next_version(
+    stored: Option<Note>,
+    id: &str,
+    device: &str,
+    edit: impl FnOnce(&mut Note),
+) -> Note {
+    let clock = HybridClock::new(device.to_string());
+    let mut note = match stored {
+        Some(mut note) => {
+            clock.advance_to(&note.hlc);
+            note.version += 1;
+            note
+        }
+        None => Note { id: id.to_string(), ..Note::new(String::new(), String::new(), None) },
+    };
+    edit(&mut note);
+    note.hlc = clock.now();
+    note.updated_at = note.hlc.to_datetime();
+    note.version_vector.increment(device);
+    note
+}
+
+/// Pushes one note written over HTTP, failing if it was refused
+fn write(state: &AppState, user: &AuthUser, note: Note) -> Result<(), ApiError> {
+    let batch = PushBatch { changes: vec![note], deltas: Vec::new() };
+    let PushOutcome { mut rejected, .. } = session::apply_push(state, user, None, batch)?;
+    match rejected.pop() {
+        Some(refused) => Err(ApiError::Rejected(refused)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursors_round_trip() {
+        let cursor = ChangeCursor { version: 12, id: "a:b".into() };
+        assert_eq!(decode_cursor(&encode_cursor(&cursor)).unwrap(), cursor);
+        assert!(matches!(decode_cursor("12"), Err(ApiError::InvalidCursor)));
+        assert!(matches!(decode_cursor("x:id"), Err(ApiError::InvalidCursor)));
+    }
+
+    #[test]
+    fn test_next_version_descends_from_the_stored_copy() {
+        let stored = next_version(None, "n1", "laptop", |note| note.title = "Draft".into());
+        assert_eq!((stored.id.as_str(), stored.version), ("n1", 1));
+
+        let edited =
+            next_version(Some(stored.clone()), "n1", "phone", |note| note.title = "Final".into());
+        assert_eq!(edited.version, 2);
+        assert!(edited.hlc > stored.hlc);
+        assert_eq!(
+            edited.version_vector.compare(&stored.version_vector),
+            notaro_core::version_vector::Causality::Descendant
+        );
+    }
+}
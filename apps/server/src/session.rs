@@ -0,0 +1,462 @@
+use crate::accounts::{AuditEntry, AuditEvent};
+use crate::auth::AuthUser;
+use crate::collections;
+use crate::hub::SessionId;
+use crate::limits::{self, Quota, RateLimiter};
+use crate::metrics::{Direction, Metrics};
+use crate::presence;
+use crate::shutdown::Watch;
+use crate::state::AppState;
+use crate::webhooks;
+use axum::extract::State;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::response::Response;
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use notaro_core::delta::NoteDelta;
+use notaro_core::error::Result;
+use notaro_core::sync::{answer_pull, answer_reconcile};
+use notaro_core::{
+    FrameCodec, Hlc, Limit, MergeReport, NotaroError, Note, NoteStore, RejectedNote, SyncFilter,
+    SyncMessage,
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How often a live socket re-checks that its device session has not been revoked, which
+/// catches revocations made outside this process (e.g. by the admin command)
+const REVALIDATE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Upgrades an authenticated request; the session only ever sees `user`'s notes.
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    user: AuthUser,
+) -> Response {
+    // Watching from before the upgrade keeps a shutdown from finishing without this socket
+    let shutdown = state.shutdown.watch();
+    // Frames up to twice the limit are still read, so they can be refused with `LimitExceeded`;
+    // larger ones close the socket
+    ws.max_message_size(state.config.max_message_size.saturating_mul(2))
+        .on_upgrade(move |socket| run_session(socket, state, user, shutdown))
+}
+
+/// Drives one connected client: answers its requests and forwards fan-out messages queued by
+/// other sessions of the same user. Once the server starts shutting down, the client is sent
+/// `ServerShutdown` and the socket closes.
+///
+/// Text frames are always JSON (the handshake and clients that never negotiate); binary frames use
+/// the codec agreed through `Hello`/`Welcome`.
+async fn run_session(socket: WebSocket, state: AppState, auth: AuthUser, mut shutdown: Watch) {
+    let AuthUser { user_id: user, session_id: device } = &auth;
+    let (session, mut outbox) = state.hub.register(user, device);
+    let mut revalidate = tokio::time::interval(REVALIDATE_INTERVAL);
+    let (mut sink, mut stream) = socket.split();
+    let mut codec = FrameCodec::JSON;
+    let mut filter = SyncFilter::default();
+    let mut limiter = RateLimiter::new(&state.config.limits);
+    let max_size = state.config.max_message_size;
+    state.metrics.session_opened();
+    tracing::debug!(session, user, "session opened");
+
+    loop {
+        let reply = tokio::select! {
+            Some(message) = outbox.recv() => restrict_update(&filter, message),
+            // Only checked between messages, so a push being merged is always finished first
+            () = shutdown.begun() => Some(SyncMessage::ServerShutdown {
+                retry_after_ms: state.config.shutdown.retry_after_ms,
+            }),
+            _ = revalidate.tick() => {
+                let accounts = state.accounts.lock().expect("accounts lock poisoned");
+                match accounts.is_session_active(device) {
+                    Ok(true) => None,
+                    Ok(false) => Some(SyncMessage::SessionRevoked),
+                    Err(e) => {
+                        tracing::error!(session, "failed to check device session: {e}");
+                        None
+                    }
+                }
+            }
+            frame = stream.next() => {
+                let decoded = match frame {
+                    Some(Ok(Message::Text(text))) => match refusal(&state, &mut limiter, text.len()) {
+                        Some(refused) => Err(refused),
+                        None => Ok(FrameCodec::JSON.decode(text.as_bytes(), max_size)),
+                    },
+                    Some(Ok(Message::Binary(bytes))) => {
+                        match refusal(&state, &mut limiter, bytes.len()) {
+                            Some(refused) => Err(refused),
+                            None => Ok(codec.decode(&bytes, max_size)),
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+
+                if let Ok(Ok(message)) = &decoded {
+                    state.metrics.message(Direction::Received, message);
+                }
+                match decoded {
+                    Err(refused) => Some(refused),
+                    Ok(Ok(SyncMessage::Hello { encodings, compression, filter: subscription })) => {
+                        let agreed = FrameCodec::negotiate(&encodings, &compression);
+                        let welcome = SyncMessage::Welcome { codec: agreed };
+                        let json = FrameCodec::JSON;
+                        if send_frame(&mut sink, &state.metrics, json, &welcome).await.is_err() {
+                            break;
+                        }
+                        tracing::debug!(session, ?agreed, ?subscription, "codec negotiated");
+                        codec = agreed;
+                        filter = subscription;
+                        None
+                    }
+                    Ok(Ok(message)) => match handle_blocking(&state, &auth, session, &filter, message)
+                        .await
+                    {
+                        Ok(reply) => reply,
+                        Err(e) => {
+                            tracing::error!(session, "failed to handle message: {e}");
+                            break;
+                        }
+                    },
+                    Ok(Err(NotaroError::FrameTooLarge(max))) => Some(too_large(&state, max)),
+                    Ok(Err(e)) => {
+                        tracing::warn!(session, "ignoring undecodable frame: {e}");
+                        None
+                    }
+                }
+            },
+        };
+
+        if let Some(message) = reply {
+            if let Err(e) = send_frame(&mut sink, &state.metrics, codec, &message).await {
+                tracing::debug!(session, "failed to send frame: {e}");
+                break;
+            }
+            if message == SyncMessage::SessionRevoked {
+                tracing::info!(session, device, "closing socket of revoked device");
+                let _ = sink.close().await;
+                break;
+            }
+            if let SyncMessage::ServerShutdown { .. } = message {
+                tracing::debug!(session, "closing socket for shutdown");
+                let _ = sink.close().await;
+                break;
+            }
+        }
+    }
+
+    state.hub.unregister(user, session);
+    presence::leave(&state, session);
+    state.metrics.session_closed();
+    tracing::debug!(session, user, "session closed");
+}
+
+async fn send_frame(
+    sink: &mut SplitSink<WebSocket, Message>,
+    metrics: &Metrics,
+    codec: FrameCodec,
+    message: &SyncMessage,
+) -> Result<()> {
+    metrics.message(Direction::Sent, message);
+    let frame = codec.encode(message)?;
+    let frame = if codec.is_text() {
+        // JSON output is always valid UTF-8
+        Message::Text(String::from_utf8(frame).expect("JSON frame is UTF-8").into())
+    } else {
+        Message::Binary(frame.into())
+    };
+    sink.send(frame).await.map_err(|e| NotaroError::Io(std::io::Error::other(e)))
+}
+
+/// The `LimitExceeded` answer to a frame of `size` bytes that is too large or over the
+/// connection's rate limit, or `None` if it may be handled
+fn refusal(
+    state: &AppState,
+    limiter: &mut Option<RateLimiter>,
+    size: usize,
+) -> Option<SyncMessage> {
+    let max = state.config.max_message_size;
+    if size > max {
+        return Some(too_large(state, max));
+    }
+    let wait = limiter.as_mut()?.check(Instant::now()).err()?;
+    let message = "too many messages; slow down".to_string();
+    Some(limit_exceeded(state, Limit::RateLimit, message, Some(wait)))
+}
+
+/// The answer to a message over `max` bytes, whether as sent or once decompressed
+fn too_large(state: &AppState, max: usize) -> SyncMessage {
+    let message = format!("messages may be at most {max} bytes");
+    limit_exceeded(state, Limit::MessageSize, message, None)
+}
+
+fn limit_exceeded(
+    state: &AppState,
+    limit: Limit,
+    message: String,
+    retry_after: Option<Duration>,
+) -> SyncMessage {
+    state.metrics.limit_exceeded(limit);
+    SyncMessage::LimitExceeded {
+        limit,
+        message,
+        retry_after_ms: retry_after
+            .map(|wait| (wait.as_secs_f64() * 1000.0).ceil().max(1.0) as u64),
+    }
+}
+
+/// Narrows a fan-out update to the session's subscription: notes outside it are sent as evicted
+/// ids instead, and an update left with nothing to say is dropped.
+fn restrict_update(filter: &SyncFilter, message: SyncMessage) -> Option<SyncMessage> {
+    match message {
+        SyncMessage::PullResponse { changes, current_version, has_more, next_cursor, evicted } => {
+            let (changes, mut outside) = filter.split(changes);
+            outside.extend(evicted);
+            if changes.is_empty() && outside.is_empty() {
+                return None;
+            }
+            Some(SyncMessage::PullResponse {
+                changes,
+                current_version,
+                has_more,
+                next_cursor,
+                evicted: outside,
+            })
+        }
+        other => Some(other),
+    }
+}
+
+/// Notes and deltas pushed together
+#[derive(Debug, Default)]
+pub struct PushBatch {
+    pub changes: Vec<Note>,
+    pub deltas: Vec<NoteDelta>,
+}
+
+impl PushBatch {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.deltas.is_empty()
+    }
+}
+
+/// A push merged into one store
+#[derive(Debug, Default)]
+pub struct Merged {
+    pub report: MergeReport,
+    /// Stored copy of every note that was merged
+    pub notes: Vec<Note>,
+    /// Ids of the notes whose stored copy the push changed
+    pub changed: Vec<String>,
+    /// Ids of the notes that were live and are now deleted
+    pub deleted: Vec<String>,
+    /// Notes whose deltas did not apply and have to be resent in full
+    pub need_full_copy: Vec<String>,
+}
+
+impl Merged {
+    /// Stored copies of the notes the push changed
+    pub fn changed_notes(&self) -> impl Iterator<Item = &Note> {
+        self.notes.iter().filter(|note| self.changed.contains(&note.id))
+    }
+}
+
+/// Merges a push into `db`
+pub fn merge_push(db: &mut dyn NoteStore, batch: PushBatch) -> Result<Merged> {
+    let mut ids: Vec<String> = batch.changes.iter().map(|n| n.id.clone()).collect();
+    let delta_ids: Vec<String> = batch.deltas.iter().map(|d| d.note.id.clone()).collect();
+    let before: HashMap<String, (Hlc, bool)> = ids
+        .iter()
+        .chain(&delta_ids)
+        .filter_map(|id| db.find_note(id).transpose())
+        .map(|note| note.map(|note| (note.id, (note.hlc, note.is_deleted))))
+        .collect::<Result<_>>()?;
+
+    let report = db.merge_changes(batch.changes)?;
+    let need_full_copy = db.merge_deltas(batch.deltas)?;
+    ids.extend(delta_ids.into_iter().filter(|id| !need_full_copy.contains(id)));
+
+    let notes: Vec<Note> = ids.iter().map(|id| db.get_note_by_id(id)).collect::<Result<_>>()?;
+    let changed = notes
+        .iter()
+        .filter(|note| before.get(&note.id).map(|(hlc, _)| hlc) != Some(&note.hlc))
+        .map(|note| note.id.clone())
+        .collect();
+    let deleted = notes
+        .iter()
+        .filter(|note| note.is_deleted && before.get(&note.id).is_some_and(|(_, was)| !was))
+        .map(|note| note.id.clone())
+        .collect();
+    Ok(Merged { report, notes, changed, deleted, need_full_copy })
+}
+
+/// What became of a push that was not merged as sent
+#[derive(Debug, Default)]
+pub struct PushOutcome {
+    /// Notes whose deltas did not apply and have to be resent in full
+    pub need_full_copy: Vec<String>,
+    pub rejected: Vec<RejectedNote>,
+}
+
+/// Applies a push from `pusher`, whether it came over a socket or over HTTP: private notes are
+/// merged into the user's store and shared ones into their collection's, within the limits, and
+/// the merged copies are fanned out to every session concerned except `origin`. Deletions are
+/// recorded in the audit log against the pushing device.
+pub fn apply_push(
+    state: &AppState,
+    pusher: &AuthUser,
+    origin: Option<SessionId>,
+    batch: PushBatch,
+) -> Result<PushOutcome> {
+    let user = pusher.user_id.as_str();
+    let started = Instant::now();
+    let delta_count = batch.deltas.len();
+    let store = state.user_db(user)?;
+    let routed = {
+        let db = store.lock().expect("database lock poisoned");
+        collections::route_push(state, user, &*db, batch)?
+    };
+
+    let mut report = MergeReport::default();
+    let mut need_full_copy = Vec::new();
+    let mut rejected = routed.rejected;
+    if !routed.private.is_empty() {
+        let mut db = store.lock().expect("database lock poisoned");
+        let (batch, refused) =
+            limits::enforce_quota(&*db, &state.config.limits, Quota::Account, routed.private)?;
+        rejected.extend(refused);
+        if !batch.is_empty() {
+            let merged = merge_push(&mut *db, batch)?;
+            let current_version = db.current_version()?;
+            drop(db);
+            webhooks::notify(state, user, merged.changed_notes());
+            audit_deletions(state, pusher, &merged);
+            let update = SyncMessage::PullResponse {
+                changes: merged.notes,
+                current_version,
+                has_more: false,
+                next_cursor: None,
+                evicted: Vec::new(),
+            };
+            state.hub.broadcast_from(user, origin, &update);
+            report.add(merged.report);
+            need_full_copy.extend(merged.need_full_copy);
+        }
+    }
+    for (collection_id, batch) in routed.shared {
+        let (merged, refused) =
+            collections::apply_shared_push(state, &collection_id, origin, batch)?;
+        audit_deletions(state, pusher, &merged);
+        report.add(merged.report);
+        need_full_copy.extend(merged.need_full_copy);
+        rejected.extend(refused);
+    }
+    state.metrics.push(&report, delta_count, need_full_copy.len(), started.elapsed());
+    for limit in rejected.iter().filter_map(|note| note.limit) {
+        state.metrics.limit_exceeded(limit);
+    }
+    Ok(PushOutcome { need_full_copy, rejected })
+}
+
+/// Records the notes a push deleted in the audit log. The deletions are already stored, so a
+/// failure to record them is logged rather than returned.
+fn audit_deletions(state: &AppState, pusher: &AuthUser, merged: &Merged) {
+    if merged.deleted.is_empty() {
+        return;
+    }
+    let accounts = state.accounts.lock().expect("accounts lock poisoned");
+    for note in merged.notes.iter().filter(|note| merged.deleted.contains(&note.id)) {
+        let entry = AuditEntry::new(AuditEvent::NoteDeleted)
+            .by(&pusher.user_id, &pusher.session_id)
+            .target(&note.id)
+            .details(json!({ "title": note.title, "collection": note.collection }));
+        if let Err(e) = accounts.record_audit(&entry) {
+            tracing::error!(note_id = note.id, "failed to audit note deletion: {e}");
+        }
+    }
+}
+
+/// Runs `handle_message` on the blocking pool, since note stores block on I/O
+async fn handle_blocking(
+    state: &AppState,
+    auth: &AuthUser,
+    session: SessionId,
+    filter: &SyncFilter,
+    message: SyncMessage,
+) -> Result<Option<SyncMessage>> {
+    let (state, auth, filter) = (state.clone(), auth.clone(), filter.clone());
+    tokio::task::spawn_blocking(move || handle_message(&state, &auth, session, &filter, message))
+        .await
+        .map_err(|e| NotaroError::Io(std::io::Error::other(e)))?
+}
+
+/// Applies a single client message and returns the direct reply, if any.
+/// Pulls and reconciliation only cover notes inside the session's `filter`.
+/// Accepted pushes are also fanned out to the user's other sessions as a `PullResponse`
+/// carrying the server's merged copy of each pushed note; notes of a shared collection go to
+/// every member's sessions instead. A push whose deltas could not all be applied is answered
+/// with `NeedFullCopy` instead of `Ack`, and one with refused notes with `PushRejected`; notes
+/// that would break a storage quota or the note size limit are refused that way too.
+/// Presence announcements are fanned out to every session with access to the note.
+pub fn handle_message(
+    state: &AppState,
+    auth: &AuthUser,
+    session: SessionId,
+    filter: &SyncFilter,
+    message: SyncMessage,
+) -> Result<Option<SyncMessage>> {
+    let user = auth.user_id.as_str();
+    let store = state.user_db(user)?;
+    match message {
+        SyncMessage::PullRequest { since_version, cursor, limit } => {
+            let started = Instant::now();
+            let db = store.lock().expect("database lock poisoned");
+            let page = answer_pull(&*db, since_version, cursor.as_ref(), limit, filter)?;
+            state.metrics.pull(started.elapsed());
+            Ok(Some(page))
+        }
+        SyncMessage::PushUpdates { changes, deltas } => {
+            let PushOutcome { need_full_copy, rejected } =
+                apply_push(state, auth, Some(session), PushBatch { changes, deltas })?;
+            if !rejected.is_empty() {
+                Ok(Some(SyncMessage::PushRejected { rejected, need_full_copy }))
+            } else if need_full_copy.is_empty() {
+                Ok(Some(SyncMessage::Ack))
+            } else {
+                Ok(Some(SyncMessage::NeedFullCopy { ids: need_full_copy }))
+            }
+        }
+        SyncMessage::ReconcileRequest { ranges } => {
+            let db = store.lock().expect("database lock poisoned");
+            answer_reconcile(&*db, &ranges, filter).map(Some)
+        }
+        SyncMessage::SettingsUpdate { entries } => {
+            let (applied, all) = {
+                let db = store.lock().expect("database lock poisoned");
+                (db.merge_settings(entries)?, db.get_synced_settings()?)
+            };
+            if !applied.is_empty() {
+                let update = SyncMessage::SettingsUpdate { entries: applied };
+                state.hub.broadcast_except(user, session, &update);
+            }
+            Ok(Some(SyncMessage::SettingsUpdate { entries: all }))
+        }
+        SyncMessage::SetPresence { note_id, activity } => {
+            presence::announce(state, auth, session, note_id, activity)
+        }
+        // Server-bound traffic only; anything else is ignored
+        SyncMessage::Hello { .. }
+        | SyncMessage::Welcome { .. }
+        | SyncMessage::PullResponse { .. }
+        | SyncMessage::NeedFullCopy { .. }
+        | SyncMessage::PushRejected { .. }
+        | SyncMessage::Ack
+        | SyncMessage::LimitExceeded { .. }
+        | SyncMessage::SessionRevoked
+        | SyncMessage::ServerShutdown { .. }
+        | SyncMessage::PresenceUpdate { .. }
+        | SyncMessage::ReconcileResponse { .. } => Ok(None),
+    }
+}
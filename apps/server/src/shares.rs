@@ -0,0 +1,490 @@
+use crate::accounts::{AuditEntry, AuditEvent, AuthError, NewShareLink, ShareLink, SharedNote};
+use crate::auth::AuthUser;
+use crate::hub::{Hub, SessionId};
+use crate::limits::RateLimiter;
+use crate::shutdown::Watch;
+use crate::state::AppState;
+use axum::Json;
+use axum::extract::{Form, Path, State};
+use axum::http::header::{
+    CACHE_CONTROL, CONTENT_SECURITY_POLICY, COOKIE, LOCATION, REFERRER_POLICY, RETRY_AFTER,
+    SET_COOKIE,
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{Html, IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt, stream};
+use notaro_core::{Note, SyncMessage};
+use pulldown_cmark::{Options, Parser};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// Cookie holding a link's unlock key once its password was entered
+const UNLOCK_COOKIE: &str = "notaro_share";
+
+/// Password attempts a protected link takes at once, and how many a minute after that
+const UNLOCK_BURST: u32 = 5;
+const UNLOCK_ATTEMPTS_PER_MINUTE: f64 = 10.0;
+
+/// Password attempts per protected link, so that a link's password cannot be guessed quickly
+/// however many addresses the guesses come from
+#[derive(Clone, Default)]
+pub struct UnlockAttempts {
+    links: Arc<Mutex<HashMap<String, RateLimiter>>>,
+}
+
+impl UnlockAttempts {
+    /// Takes an attempt at link `link_id`'s password, or returns how long until one is free
+    pub fn check(&self, link_id: &str, now: Instant) -> Result<(), Duration> {
+        let mut links = self.links.lock().expect("unlock attempts lock poisoned");
+        // Links left alone long enough are back to a full bucket and need no entry
+        links.retain(|_, limiter| !limiter.is_full(now));
+        links
+            .entry(link_id.to_string())
+            .or_insert_with(|| {
+                RateLimiter::with_rate(UNLOCK_ATTEMPTS_PER_MINUTE / 60.0, UNLOCK_BURST)
+            })
+            .check(now)
+    }
+}
+
+/// Body of `POST /notes/{id}/shares`
+#[derive(Debug, Default, Deserialize)]
+pub struct NewShare {
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Visitors have to enter it before the note is shown
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// Form posted from a password protected link's page
+#[derive(Debug, Deserialize)]
+pub struct Unlock {
+    pub password: String,
+}
+
+/// `POST /notes/{id}/shares`: creates a share link to one of the caller's notes and returns its
+/// token, which is not shown again
+pub async fn create(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(note_id): Path<String>,
+    Json(request): Json<NewShare>,
+) -> Result<impl IntoResponse, AuthError> {
+    // Argon2 is deliberately slow; keep it off the async workers
+    let created = tokio::task::spawn_blocking(move || {
+        load_note(&state, &user.user_id, &note_id)?.ok_or(AuthError::NoteNotFound)?;
+        let accounts = state.accounts.lock().expect("accounts lock poisoned");
+        let created = accounts.create_share_link(
+            &user.user_id,
+            &note_id,
+            request.expires_at,
+            request.password.as_deref(),
+        )?;
+        let link = &created.link;
+        accounts.record_audit(
+            &AuditEntry::new(AuditEvent::ShareLinkCreated)
+                .by(&user.user_id, &user.session_id)
+                .target(&link.id)
+                .details(json!({
+                    "note_id": link.note_id,
+                    "expires_at": link.expires_at,
+                    "has_password": link.has_password,
+                })),
+        )?;
+        tracing::info!(user_id = user.user_id, link_id = created.link.id, "share link created");
+        Ok::<NewShareLink, AuthError>(created)
+    })
+    .await
+    .expect("share task panicked")?;
+
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+/// `GET /shares`: the caller's share links that were not revoked
+pub async fn list(
+    State(state): State<AppState>,
+    user: AuthUser,
+) -> Result<Json<Vec<ShareLink>>, AuthError> {
+    let accounts = state.accounts.lock().expect("accounts lock poisoned");
+    Ok(Json(accounts.list_share_links(&user.user_id)?))
+}
+
+/// `DELETE /shares/{id}`: revokes one of the caller's share links; open pages stop updating
+pub async fn revoke(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(link_id): Path<String>,
+) -> Result<StatusCode, AuthError> {
+    let accounts = state.accounts.lock().expect("accounts lock poisoned");
+    accounts.revoke_share_link(&link_id, &user.user_id)?;
+    accounts.record_audit(
+        &AuditEntry::new(AuditEvent::ShareLinkRevoked)
+            .by(&user.user_id, &user.session_id)
+            .target(&link_id),
+    )?;
+    let viewers = state.hub.sign_out_device(&user.user_id, &viewer_device(&link_id));
+    tracing::info!(user_id = user.user_id, link_id, viewers, "share link revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `GET /s/{token}`: the shared note as a web page, or the password prompt of a protected link
+pub async fn page(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, Unavailable> {
+    let shared = open_link(&state, &token, &headers)?;
+    let note = visible_note(&state, &shared).await?;
+    Ok(html(StatusCode::OK, note_page(&note)))
+}
+
+/// `POST /s/{token}`: checks a protected link's password and, if it matches, remembers that in
+/// a cookie scoped to the link
+pub async fn unlock(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Form(form): Form<Unlock>,
+) -> Result<Response, Unavailable> {
+    let shared = resolve(&state, &token)?;
+    // Refused before Argon2 runs, so guessing is slow and cheap to turn away
+    if let Err(wait) = state.unlock_attempts.check(&shared.link.id, Instant::now()) {
+        let seconds = wait.as_secs() + 1;
+        let message = format!("Too many attempts. Try again in {seconds} seconds.");
+        let mut response = html(StatusCode::TOO_MANY_REQUESTS, password_page(Some(&message)));
+        response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(seconds));
+        return Ok(response);
+    }
+    let checked = tokio::task::spawn_blocking(move || {
+        let matches = shared.check_password(&form.password);
+        (shared, matches)
+    });
+    let (shared, matches) = checked.await.expect("share task panicked");
+    if !matches {
+        return Ok(html(StatusCode::UNAUTHORIZED, password_page(Some("Wrong password."))));
+    }
+
+    let location = format!("/s/{token}");
+    let mut response = (StatusCode::SEE_OTHER, [(LOCATION, location.clone())]).into_response();
+    if let Some(key) = shared.unlock_key() {
+        let cookie = format!("{UNLOCK_COOKIE}={key}; Path={location}; HttpOnly; SameSite=Strict");
+        let cookie = HeaderValue::from_str(&cookie).expect("cookie is plain ASCII");
+        response.headers_mut().insert(SET_COOKIE, cookie);
+    }
+    Ok(response)
+}
+
+/// `GET /s/{token}/events`: server-sent events that keep an open page current. Each `update`
+/// carries the note's title and rendered HTML, starting with the current copy; a final `gone`
+/// says why the note is no longer shown.
+pub async fn events(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, Unavailable> {
+    let shared = open_link(&state, &token, &headers)?;
+    // Registering before reading the note means no change can slip in between
+    let (session, rx) = state.hub.register(&shared.user_id, &viewer_device(&shared.link.id));
+    let viewer = Viewer {
+        hub: state.hub.clone(),
+        user_id: shared.user_id.clone(),
+        session,
+        rx,
+        note_id: shared.link.note_id.clone(),
+        expires_at: shared.link.expires_at,
+    };
+    let shutdown = state.shutdown.watch();
+    let retry_after = Duration::from_millis(state.config.shutdown.retry_after_ms);
+    let note = visible_note(&state, &shared).await?;
+
+    let first = stream::once(async move { Ok(update_event(&note)) });
+    let stream = first.chain(viewer.into_stream(shutdown, retry_after));
+    Ok((no_store_headers(), Sse::new(stream).keep_alive(KeepAlive::default())).into_response())
+}
+
+/// Hub device a link's viewers register as, so revoking the link signs them out
+fn viewer_device(link_id: &str) -> String {
+    format!("share:{link_id}")
+}
+
+/// Follows the hub on behalf of one open page
+struct Viewer {
+    hub: Hub,
+    user_id: String,
+    session: SessionId,
+    rx: UnboundedReceiver<SyncMessage>,
+    note_id: String,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Viewer {
+    /// Events for each new copy of the note. When the server shuts down the stream ends, asking
+    /// the browser to reconnect after `retry_after`, which it does by itself.
+    fn into_stream(
+        self,
+        shutdown: Watch,
+        retry_after: Duration,
+    ) -> impl Stream<Item = Result<Event, Infallible>> {
+        stream::unfold(Some((self, shutdown)), move |following| async move {
+            let (mut viewer, mut shutdown) = following?;
+            let next = tokio::select! {
+                next = viewer.next_change() => next,
+                () = shutdown.begun() => {
+                    let event = Event::default().retry(retry_after).comment("shutting down");
+                    return Some((Ok(event), None));
+                }
+            };
+            match next {
+                Ok(note) => Some((Ok(update_event(&note)), Some((viewer, shutdown)))),
+                Err(reason) => Some((Ok(Event::default().event("gone").data(reason)), None)),
+            }
+        })
+    }
+
+    /// Waits for the next copy of the note, or for the reason it is no longer shared
+    async fn next_change(&mut self) -> Result<Note, &'static str> {
+        let expiry = self.expires_at.map(|expires_at| {
+            let left = (expires_at - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::Instant::now() + left
+        });
+        loop {
+            let message = match expiry {
+                Some(deadline) => tokio::select! {
+                    message = self.rx.recv() => message,
+                    () = tokio::time::sleep_until(deadline) => return Err("This link has expired."),
+                },
+                None => self.rx.recv().await,
+            };
+            match message {
+                None | Some(SyncMessage::SessionRevoked) => {
+                    return Err("This link was revoked.");
+                }
+                Some(SyncMessage::PullResponse { changes, evicted, .. }) => {
+                    if evicted.contains(&self.note_id) {
+                        return Err("This note is no longer available.");
+                    }
+                    if let Some(note) = changes.into_iter().find(|note| note.id == self.note_id) {
+                        if note.is_deleted {
+                            return Err("This note is no longer available.");
+                        }
+                        return Ok(note);
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+impl Drop for Viewer {
+    fn drop(&mut self) {
+        self.hub.unregister(&self.user_id, self.session);
+    }
+}
+
+/// Why a share link shows a notice instead of its note
+#[derive(Debug)]
+pub enum Unavailable {
+    /// Unknown, revoked, or its owner is disabled
+    NotFound,
+    Expired,
+    /// Password protected, and the visitor has not entered the password yet
+    Locked,
+    /// The note was deleted or left its owner's store
+    Gone,
+    Failed,
+}
+
+impl IntoResponse for Unavailable {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            Unavailable::NotFound => {
+                (StatusCode::NOT_FOUND, "This link does not exist or was revoked.")
+            }
+            Unavailable::Expired => (StatusCode::GONE, "This link has expired."),
+            Unavailable::Locked => return html(StatusCode::UNAUTHORIZED, password_page(None)),
+            Unavailable::Gone => (StatusCode::NOT_FOUND, "This note is no longer available."),
+            Unavailable::Failed => (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong."),
+        };
+        html(status, layout("Notaro", &format!("<p>{message}</p>"), ""))
+    }
+}
+
+/// Resolves a token to a link that has not expired
+fn resolve(state: &AppState, token: &str) -> Result<SharedNote, Unavailable> {
+    let resolved = state.accounts.lock().expect("accounts lock poisoned").resolve_share_link(token);
+    match resolved {
+        Ok(shared) if shared.link.is_expired() => Err(Unavailable::Expired),
+        Ok(shared) => Ok(shared),
+        Err(AuthError::ShareNotFound) => Err(Unavailable::NotFound),
+        Err(e) => {
+            tracing::error!("cannot resolve a share link: {e}");
+            Err(Unavailable::Failed)
+        }
+    }
+}
+
+/// Like `resolve`, also requiring the unlock cookie of a password protected link
+fn open_link(
+    state: &AppState,
+    token: &str,
+    headers: &HeaderMap,
+) -> Result<SharedNote, Unavailable> {
+    let shared = resolve(state, token)?;
+    match shared.unlock_key() {
+        Some(key) if cookie(headers, UNLOCK_COOKIE) != Some(key.as_str()) => {
+            Err(Unavailable::Locked)
+        }
+        _ => Ok(shared),
+    }
+}
+
+/// The shared note, unless it was deleted or left its owner's store
+async fn visible_note(state: &AppState, shared: &SharedNote) -> Result<Note, Unavailable> {
+    let state = state.clone();
+    let (user_id, note_id) = (shared.user_id.clone(), shared.link.note_id.clone());
+    let loaded = tokio::task::spawn_blocking(move || load_note(&state, &user_id, &note_id))
+        .await
+        .expect("share task panicked");
+    match loaded {
+        Ok(Some(note)) => Ok(note),
+        Ok(None) => Err(Unavailable::Gone),
+        Err(e) => {
+            tracing::error!("cannot load a shared note: {e}");
+            Err(Unavailable::Failed)
+        }
+    }
+}
+
+/// A live note of `user_id`'s
+fn load_note(state: &AppState, user_id: &str, note_id: &str) -> Result<Option<Note>, AuthError> {
+    let db = state.user_db(user_id)?;
+    let note = db.lock().expect("database lock poisoned").find_note(note_id)?;
+    Ok(note.filter(|note| !note.is_deleted))
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find_map(|(key, value)| (key == name).then_some(value))
+}
+
+/// Markdown to HTML, sanitized so a note cannot run scripts in its visitors' browsers
+pub fn render_markdown(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES;
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, Parser::new_ext(markdown, options));
+    ammonia::clean(&html)
+}
+
+#[derive(Serialize)]
+struct RenderedNote {
+    title: String,
+    html: String,
+}
+
+fn update_event(note: &Note) -> Event {
+    let rendered =
+        RenderedNote { title: title(note).to_string(), html: render_markdown(&note.content) };
+    Event::default().event("update").json_data(rendered).expect("rendered notes serialize")
+}
+
+fn title(note: &Note) -> &str {
+    if note.title.trim().is_empty() { "Untitled note" } else { &note.title }
+}
+
+/// Headers on every share response: nothing is cached, and the token in the URL is not leaked
+/// through the referrer or indexed
+fn no_store_headers() -> [(HeaderName, &'static str); 3] {
+    [
+        (CACHE_CONTROL, "no-store"),
+        (REFERRER_POLICY, "no-referrer"),
+        (HeaderName::from_static("x-robots-tag"), "noindex, nofollow"),
+    ]
+}
+
+/// A page that may only run the script carrying `nonce`
+fn html(status: StatusCode, page: Page) -> Response {
+    let policy = format!(
+        "default-src 'none'; script-src 'nonce-{}'; style-src 'unsafe-inline'; img-src https: data:; \
+         connect-src 'self'; form-action 'self'; base-uri 'none'; frame-ancestors 'none'",
+        page.nonce
+    );
+    (status, no_store_headers(), [(CONTENT_SECURITY_POLICY, policy)], Html(page.body))
+        .into_response()
+}
+
+struct Page {
+    nonce: String,
+    body: String,
+}
+
+const STYLE: &str = "body{font-family:system-ui,sans-serif;line-height:1.6;max-width:46rem;\
+    margin:2rem auto;padding:0 1rem;color:#222}pre{background:#f4f4f4;padding:1rem;\
+    overflow:auto}img{max-width:100%}table{border-collapse:collapse}td,th{border:1px solid #ccc;\
+    padding:.25rem .5rem}#status{color:#666}";
+
+/// Swaps in each `update` and stops at `gone`
+const LIVE_SCRIPT: &str = r#"
+const events = new EventSource(location.pathname.replace(/\/$/, "") + "/events");
+events.addEventListener("update", (event) => {
+  const note = JSON.parse(event.data);
+  document.title = note.title;
+  document.getElementById("title").textContent = note.title;
+  document.getElementById("content").innerHTML = note.html;
+});
+events.addEventListener("gone", (event) => {
+  events.close();
+  document.getElementById("content").replaceChildren();
+  document.getElementById("status").textContent = event.data;
+});
+"#;
+
+fn layout(title: &str, body: &str, script: &str) -> Page {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    let nonce: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    let title = ammonia::clean_text(title);
+    let body = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>{title}</title><style>{STYLE}</style></head>\
+         <body>{body}<script nonce=\"{nonce}\">{script}</script></body></html>\n"
+    );
+    Page { nonce, body }
+}
+
+fn note_page(note: &Note) -> Page {
+    let title = title(note);
+    let body = format!(
+        "<h1 id=\"title\">{}</h1><p id=\"status\"></p><article id=\"content\">{}</article>",
+        ammonia::clean_text(title),
+        render_markdown(&note.content)
+    );
+    layout(title, &body, LIVE_SCRIPT)
+}
+
+fn password_page(error: Option<&str>) -> Page {
+    let error = error.map(|e| format!("<p id=\"status\">{e}</p>")).unwrap_or_default();
+    let body = format!(
+        "<h1>This note is password protected</h1>{error}<form method=\"post\">\
+         <input type=\"password\" name=\"password\" autofocus required> \
+         <button type=\"submit\">Open</button></form>"
+    );
+    layout("Password required", &body, "")
+}
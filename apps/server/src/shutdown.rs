@@ -0,0 +1,94 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Starts and follows a graceful shutdown. Every socket and open share page holds a `Watch` for
+/// as long as it runs, so the server can tell when all of them have finished.
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self { tx: Arc::new(watch::Sender::new(false)) }
+    }
+}
+
+impl Shutdown {
+    /// Asks every connection to finish the message it is handling and close
+    pub fn begin(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn has_begun(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// A handle to hold while a connection is open
+    pub fn watch(&self) -> Watch {
+        Watch(self.tx.subscribe())
+    }
+
+    /// Resolves once every `Watch` has been dropped
+    pub async fn drained(&self) {
+        self.tx.closed().await
+    }
+
+    /// Number of `Watch`es still held
+    pub fn open(&self) -> usize {
+        self.tx.receiver_count()
+    }
+}
+
+/// Held by an open connection; see `Shutdown::watch`
+pub struct Watch(watch::Receiver<bool>);
+
+impl Watch {
+    /// Resolves once the shutdown has begun, right away if it already has
+    pub async fn begun(&mut self) {
+        // The sender lives in the app state, which outlives every connection
+        let _ = self.0.wait_for(|begun| *begun).await;
+    }
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM, which is what service managers and container runtimes send
+pub async fn signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_watches_see_the_shutdown_and_drain() {
+        let shutdown = Shutdown::default();
+        let mut early = shutdown.watch();
+        assert!(timeout(Duration::from_millis(20), early.begun()).await.is_err());
+
+        shutdown.begin();
+        early.begun().await;
+        let mut late = shutdown.watch();
+        late.begun().await;
+        assert_eq!(shutdown.open(), 2);
+
+        drop(early);
+        assert!(timeout(Duration::from_millis(20), shutdown.drained()).await.is_err());
+        drop(late);
+        shutdown.drained().await;
+        assert!(shutdown.has_begun());
+    }
+}
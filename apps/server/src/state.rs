@@ -0,0 +1,52 @@
+use crate::accounts::AccountStore;
+use crate::config::Config;
+use crate::hub::Hub;
+use crate::metrics::Metrics;
+use crate::presence::Presence;
+use crate::shares::UnlockAttempts;
+use crate::shutdown::Shutdown;
+use crate::store::{UserDb, UserStores};
+use notaro_core::error::Result;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Shared state handed to every request handler
+#[derive(Clone)]
+pub struct AppState {
+    pub accounts: Arc<Mutex<AccountStore>>,
+    pub stores: UserStores,
+    pub hub: Hub,
+    pub config: Arc<Config>,
+    pub metrics: Arc<Metrics>,
+    /// Woken whenever webhook deliveries are queued
+    pub webhook_queue: Arc<Notify>,
+    pub shutdown: Shutdown,
+    pub presence: Presence,
+    pub unlock_attempts: UnlockAttempts,
+}
+
+impl AppState {
+    pub fn new(accounts: AccountStore, stores: UserStores) -> Self {
+        Self {
+            accounts: Arc::new(Mutex::new(accounts)),
+            stores,
+            hub: Hub::default(),
+            config: Arc::default(),
+            metrics: Arc::default(),
+            webhook_queue: Arc::default(),
+            shutdown: Shutdown::default(),
+            presence: Presence::default(),
+            unlock_attempts: UnlockAttempts::default(),
+        }
+    }
+
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = Arc::new(config);
+        self
+    }
+
+    /// The note database of an authenticated user
+    pub fn user_db(&self, user_id: &str) -> Result<UserDb> {
+        self.stores.get(user_id)
+    }
+}
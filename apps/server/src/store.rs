@@ -0,0 +1,135 @@
+use notaro_core::error::Result;
+use notaro_core::postgres::PostgresDatabase;
+use notaro_core::{DatabaseConnection, NoteStore};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Device id the server uses in every user database, so its HLC stamps name it
+pub const SERVER_DEVICE_ID: &str = "server";
+
+/// One user's notes, shared by every session of that user
+pub type UserDb = Arc<Mutex<dyn NoteStore>>;
+
+#[derive(Clone)]
+enum Backend {
+    /// Directory holding `{user_id}.db` files; `None` keeps every database in memory
+    Sqlite(Option<PathBuf>),
+    Postgres(PostgresDatabase),
+}
+
+/// Key under which a shared collection's canonical notes are stored, next to the users' own
+pub fn collection_key(collection_id: &str) -> String {
+    format!("collection-{collection_id}")
+}
+
+/// One note store per user (and per shared collection), opened on first use and kept open.
+/// With SQLite every user gets their own file, so a query can never reach another user's notes;
+/// with PostgreSQL every query is scoped to the user's id.
+///
+/// Stores block on I/O; async code calls them from `spawn_blocking`.
+#[derive(Clone)]
+pub struct UserStores {
+    backend: Backend,
+    open: Arc<Mutex<HashMap<String, UserDb>>>,
+}
+
+impl UserStores {
+    pub fn at(dir: impl Into<PathBuf>) -> Self {
+        Self { backend: Backend::Sqlite(Some(dir.into())), open: Arc::default() }
+    }
+
+    pub fn in_memory() -> Self {
+        Self { backend: Backend::Sqlite(None), open: Arc::default() }
+    }
+
+    pub fn postgres(database: PostgresDatabase) -> Self {
+        Self { backend: Backend::Postgres(database), open: Arc::default() }
+    }
+
+    /// The note store of `user_id`, created if it does not exist yet
+    pub fn get(&self, user_id: &str) -> Result<UserDb> {
+        let mut open = self.open.lock().expect("store lock poisoned");
+        if let Some(db) = open.get(user_id) {
+            return Ok(db.clone());
+        }
+
+        let db: UserDb = match &self.backend {
+            Backend::Sqlite(Some(dir)) => {
+                std::fs::create_dir_all(dir)?;
+                Arc::new(Mutex::new(DatabaseConnection::with_device_id(
+                    dir.join(format!("{user_id}.db")),
+                    SERVER_DEVICE_ID,
+                )?))
+            }
+            Backend::Sqlite(None) => Arc::new(Mutex::new(DatabaseConnection::with_device_id(
+                ":memory:",
+                SERVER_DEVICE_ID,
+            )?)),
+            Backend::Postgres(database) => {
+                Arc::new(Mutex::new(database.store(user_id, SERVER_DEVICE_ID)?))
+            }
+        };
+        open.insert(user_id.to_string(), db.clone());
+        Ok(db)
+    }
+
+    /// The canonical copy of a shared collection's notes, which every member's store mirrors
+    pub fn get_collection(&self, collection_id: &str) -> Result<UserDb> {
+        self.get(&collection_key(collection_id))
+    }
+
+    /// Like `get`, but `None` for a user who has never synced instead of creating a store
+    pub fn get_existing(&self, user_id: &str) -> Result<Option<UserDb>> {
+        let exists = self.open.lock().expect("store lock poisoned").contains_key(user_id)
+            || match &self.backend {
+                Backend::Sqlite(Some(dir)) => dir.join(format!("{user_id}.db")).exists(),
+                Backend::Sqlite(None) => false,
+                Backend::Postgres(database) => database.has_owner(user_id)?,
+            };
+        if exists { self.get(user_id).map(Some) } else { Ok(None) }
+    }
+
+    /// Where `user_id`'s notes (or a collection's, by `collection_key`) are kept, for messages to
+    /// operators
+    pub fn location(&self, user_id: &str) -> String {
+        match &self.backend {
+            Backend::Sqlite(_) => format!("users/{user_id}.db"),
+            Backend::Postgres(_) => format!("postgres notes of {user_id}"),
+        }
+    }
+
+    /// Whether notes live outside the data directory
+    pub fn is_postgres(&self) -> bool {
+        matches!(self.backend, Backend::Postgres(_))
+    }
+
+    /// Fails if the directory for note databases cannot be created or PostgreSQL does not answer
+    pub fn check(&self) -> Result<()> {
+        match &self.backend {
+            Backend::Sqlite(Some(dir)) => Ok(std::fs::create_dir_all(dir)?),
+            Backend::Sqlite(None) => Ok(()),
+            Backend::Postgres(database) => database.ping(),
+        }
+    }
+
+    /// Bytes the note databases use on disk; 0 when they are kept in memory
+    pub fn disk_usage(&self) -> Result<u64> {
+        match &self.backend {
+            Backend::Sqlite(Some(dir)) if dir.exists() => Ok(dir_size(dir)?),
+            Backend::Sqlite(_) => Ok(0),
+            Backend::Postgres(database) => database.size_bytes(),
+        }
+    }
+}
+
+/// Total size of the files under `dir`
+pub(crate) fn dir_size(dir: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        size += if meta.is_dir() { dir_size(&entry.path())? } else { meta.len() };
+    }
+    Ok(size)
+}
@@ -0,0 +1,164 @@
+use crate::config::TlsConfig;
+use rustls::ServerConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+
+/// How long a client gets to finish the TLS handshake before it is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Finished handshakes waiting for the server to pick them up
+const ACCEPT_BACKLOG: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("cannot read {path}: {source}")]
+    Io { path: PathBuf, source: std::io::Error },
+
+    #[error("{path}: {message}")]
+    Pem { path: PathBuf, message: String },
+
+    #[error("TLS configuration error: {0}")]
+    Rustls(#[from] rustls::Error),
+}
+
+pub type Result<T> = std::result::Result<T, TlsError>;
+
+/// The certificate and key the server presents, read from the configured PEM files.
+/// `reload` swaps in new files for future handshakes; open connections keep their session.
+pub struct Certificates {
+    paths: TlsConfig,
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl Certificates {
+    pub fn load(paths: TlsConfig) -> Result<Arc<Self>> {
+        let current = RwLock::new(server_config(&paths)?);
+        Ok(Arc::new(Self { paths, current }))
+    }
+
+    /// Re-reads the PEM files. On error the previous certificate stays in use.
+    pub fn reload(&self) -> Result<()> {
+        let config = server_config(&self.paths)?;
+        *self.current.write().expect("certificate lock poisoned") = config;
+        Ok(())
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().expect("certificate lock poisoned").clone())
+    }
+}
+
+fn server_config(paths: &TlsConfig) -> Result<Arc<ServerConfig>> {
+    let certs = read_pem(&paths.cert_path, |pem| {
+        CertificateDer::pem_slice_iter(pem).collect::<std::result::Result<Vec<_>, _>>()
+    })?;
+    if certs.is_empty() {
+        return Err(pem_error(&paths.cert_path, "no certificate found"));
+    }
+    let key = read_pem(&paths.key_path, PrivateKeyDer::from_pem_slice)?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+fn read_pem<T, E: std::fmt::Display>(
+    path: &Path,
+    parse: impl FnOnce(&[u8]) -> std::result::Result<T, E>,
+) -> Result<T> {
+    let pem = std::fs::read(path).map_err(|source| TlsError::Io { path: path.into(), source })?;
+    parse(&pem).map_err(|e| pem_error(path, e))
+}
+
+fn pem_error(path: &Path, message: impl std::fmt::Display) -> TlsError {
+    TlsError::Pem { path: path.into(), message: message.to_string() }
+}
+
+/// Reloads the certificates whenever the process receives SIGHUP, e.g. after a renewal
+#[cfg(unix)]
+pub async fn reload_on_sighup(certificates: Arc<Certificates>) -> std::io::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        match certificates.reload() {
+            Ok(()) => tracing::info!("reloaded TLS certificates"),
+            Err(e) => tracing::error!("keeping the previous TLS certificates: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// A TCP listener that hands out connections once their TLS handshake has finished.
+/// Handshakes run in their own tasks so a slow client cannot hold up the others.
+pub struct TlsListener {
+    incoming: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(tcp: TcpListener, certificates: Arc<Certificates>) -> std::io::Result<Self> {
+        let local_addr = tcp.local_addr()?;
+        let (tx, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = tokio::select! {
+                    _ = tx.closed() => break,
+                    accepted = tcp.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            tracing::warn!("accept failed: {e}");
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    },
+                };
+
+                let acceptor = certificates.acceptor();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, peer)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!(%peer, "TLS handshake failed: {e}"),
+                        Err(_) => tracing::debug!(%peer, "TLS handshake timed out"),
+                    }
+                });
+            }
+        });
+
+        Ok(Self { incoming, local_addr })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.incoming.recv().await {
+            Some(accepted) => accepted,
+            // The accept task only stops once this listener is dropped
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
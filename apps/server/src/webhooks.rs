@@ -0,0 +1,442 @@
+use crate::accounts::{
+    AttemptOutcome, AuthError, Delivery, DueDelivery, NewWebhook, Webhook, to_hex,
+};
+use crate::auth::AuthUser;
+use crate::config;
+use crate::state::AppState;
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use notaro_core::{Note, SyncFilter};
+use reqwest::Url;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Header carrying the payload signature, `t={unix seconds},v1={hex HMAC-SHA256}`
+pub const SIGNATURE_HEADER: &str = "x-notaro-signature";
+/// Header naming the event, e.g. `note.updated`
+pub const EVENT_HEADER: &str = "x-notaro-event";
+/// Header carrying the delivery id, which stays the same across retries
+pub const DELIVERY_HEADER: &str = "x-notaro-delivery";
+
+/// Deliveries sent at once
+const BATCH_SIZE: usize = 16;
+/// Longest wait between two attempts at a delivery
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// How long the worker sleeps when nothing is queued and nothing wakes it
+const IDLE_POLL: Duration = Duration::from_secs(60);
+/// Entries returned from a webhook's delivery log
+const DELIVERY_LOG_LIMIT: usize = 100;
+
+/// Body of `POST /webhooks`
+#[derive(Debug, Deserialize)]
+pub struct NewWebhookRequest {
+    pub url: String,
+    /// Folders and tags to include or exclude, as in a sync subscription; empty for every note
+    #[serde(flatten)]
+    pub filter: SyncFilter,
+}
+
+/// JSON body delivered for one changed note
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    event: &'static str,
+    webhook_id: &'a str,
+    occurred_at: DateTime<Utc>,
+    /// The note as stored after the change
+    note: &'a Note,
+}
+
+/// `POST /webhooks`: subscribes a URL to changes of the caller's notes and returns the secret
+/// that signs deliveries, which is not shown again
+pub async fn create(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Json(request): Json<NewWebhookRequest>,
+) -> Result<impl IntoResponse, AuthError> {
+    let url = Url::parse(&request.url).map_err(|_| AuthError::InvalidWebhookUrl)?;
+    if !matches!(url.scheme(), "http" | "https") || !url.has_host() {
+        return Err(AuthError::InvalidWebhookUrl);
+    }
+    if let Err(reason) = check_destination(&state.config.webhooks, &url).await {
+        tracing::info!(user_id = user.user_id, url = url.as_str(), "webhook refused: {reason}");
+        return Err(AuthError::PrivateWebhookUrl);
+    }
+
+    let accounts = state.accounts.lock().expect("accounts lock poisoned");
+    let created: NewWebhook =
+        accounts.create_webhook(&user.user_id, url.as_str(), &request.filter)?;
+    tracing::info!(user_id = user.user_id, webhook_id = created.webhook.id, "webhook created");
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+/// `GET /webhooks`: the caller's webhooks
+pub async fn list(
+    State(state): State<AppState>,
+    user: AuthUser,
+) -> Result<Json<Vec<Webhook>>, AuthError> {
+    let accounts = state.accounts.lock().expect("accounts lock poisoned");
+    Ok(Json(accounts.list_webhooks(&user.user_id)?))
+}
+
+/// `DELETE /webhooks/{id}`: deletes one of the caller's webhooks; pending deliveries are dropped
+pub async fn delete(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(webhook_id): Path<String>,
+) -> Result<StatusCode, AuthError> {
+    let accounts = state.accounts.lock().expect("accounts lock poisoned");
+    accounts.delete_webhook(&webhook_id, &user.user_id)?;
+    tracing::info!(user_id = user.user_id, webhook_id, "webhook deleted");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `GET /webhooks/{id}/deliveries`: the webhook's most recent deliveries and how they went
+pub async fn deliveries(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(webhook_id): Path<String>,
+) -> Result<Json<Vec<Delivery>>, AuthError> {
+    let accounts = state.accounts.lock().expect("accounts lock poisoned");
+    Ok(Json(accounts.list_deliveries(&webhook_id, &user.user_id, DELIVERY_LOG_LIMIT)?))
+}
+
+/// Queues a delivery to each of `user`'s webhooks for every changed note its filter lets
+/// through. The change is already stored, so a failure to queue is logged rather than returned.
+pub fn notify<'a>(state: &AppState, user: &str, notes: impl IntoIterator<Item = &'a Note>) {
+    let notes: Vec<&Note> = notes.into_iter().collect();
+    if notes.is_empty() {
+        return;
+    }
+
+    let accounts = state.accounts.lock().expect("accounts lock poisoned");
+    let queue = || -> Result<usize, AuthError> {
+        let mut queued = 0;
+        for webhook in accounts.list_webhooks(user)? {
+            for note in notes.iter().filter(|note| webhook.filter.matches(note)) {
+                let event = if note.is_deleted { "note.deleted" } else { "note.updated" };
+                let payload =
+                    Payload { event, webhook_id: &webhook.id, occurred_at: note.updated_at, note };
+                let payload = serde_json::to_string(&payload).expect("payloads serialize");
+                accounts.enqueue_delivery(&webhook.id, event, &note.id, &payload)?;
+                queued += 1;
+            }
+        }
+        Ok(queued)
+    };
+    match queue() {
+        Ok(0) => {}
+        Ok(_) => state.webhook_queue.notify_one(),
+        Err(e) => tracing::error!(user, "failed to queue webhook deliveries: {e}"),
+    }
+}
+
+/// Sends queued deliveries until the process exits. Due deliveries go out right away; one that
+/// fails is retried after a backoff that doubles with every attempt, until `max_attempts`.
+/// The queue lives in the accounts database, so deliveries survive restarts.
+pub async fn deliver(state: AppState) {
+    let config = state.config.webhooks.clone();
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .dns_resolver(Arc::new(PublicResolver(config.clone())))
+        .redirect(reqwest::redirect::Policy::none())
+        .user_agent(concat!("notaro-webhooks/", env!("CARGO_PKG_VERSION")))
+        .build();
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("cannot deliver webhooks: {e}");
+            return;
+        }
+    };
+
+    loop {
+        let due = {
+            let accounts = state.accounts.lock().expect("accounts lock poisoned");
+            accounts.due_deliveries(Utc::now(), BATCH_SIZE).and_then(|due| {
+                let next = if due.is_empty() { accounts.next_delivery_at()? } else { None };
+                Ok((due, next))
+            })
+        };
+        let (due, next) = match due {
+            Ok(found) => found,
+            Err(e) => {
+                tracing::error!("failed to read the webhook queue: {e}");
+                (Vec::new(), None)
+            }
+        };
+        if due.is_empty() {
+            let wait = next.map_or(IDLE_POLL, |at| {
+                (at - Utc::now()).to_std().unwrap_or_default().min(IDLE_POLL)
+            });
+            tokio::select! {
+                _ = state.webhook_queue.notified() => {}
+                _ = tokio::time::sleep(wait) => {}
+            }
+            continue;
+        }
+
+        let outcomes =
+            futures_util::future::join_all(due.iter().map(|d| send(&client, &config, d))).await;
+        let accounts = state.accounts.lock().expect("accounts lock poisoned");
+        for (delivery, outcome) in due.iter().zip(outcomes) {
+            let attempts = delivery.attempts + 1;
+            let retry_at = match outcome {
+                AttemptOutcome::Delivered(_) => None,
+                _ if attempts >= config.max_attempts => None,
+                _ => Some(Utc::now() + backoff(&config, attempts)),
+            };
+            let result = match (&outcome, retry_at) {
+                (AttemptOutcome::Delivered(_), _) => "delivered",
+                (_, Some(_)) => "retrying",
+                (_, None) => "failed",
+            };
+            state.metrics.webhook_attempt(result);
+            tracing::debug!(delivery = delivery.id, attempts, ?outcome, "webhook attempt");
+            if let Err(e) = accounts.record_attempt(&delivery.id, &outcome, retry_at) {
+                tracing::error!(delivery = delivery.id, "failed to record webhook attempt: {e}");
+            }
+        }
+    }
+}
+
+async fn send(
+    client: &reqwest::Client,
+    config: &config::Webhooks,
+    delivery: &DueDelivery,
+) -> AttemptOutcome {
+    // Host names are checked by `PublicResolver` as the connection is made; addresses written
+    // into the URL never reach it
+    if let Ok(url) = Url::parse(&delivery.url)
+        && literal_ip(&url).is_some()
+        && let Err(reason) = check_destination(config, &url).await
+    {
+        return AttemptOutcome::Failed(reason);
+    }
+    let signature = sign(&delivery.secret, Utc::now().timestamp(), &delivery.payload);
+    let response = client
+        .post(&delivery.url)
+        .header(CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, &delivery.event)
+        .header(DELIVERY_HEADER, &delivery.id)
+        .header(SIGNATURE_HEADER, signature)
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+    match response {
+        Ok(response) if response.status().is_success() => {
+            AttemptOutcome::Delivered(response.status().as_u16())
+        }
+        Ok(response) => AttemptOutcome::Refused(response.status().as_u16()),
+        Err(e) => AttemptOutcome::Failed(e.to_string()),
+    }
+}
+
+/// Refuses a webhook URL whose host is, or resolves to, an address in the server's own network,
+/// unless the configuration allows it
+async fn check_destination(
+    config: &config::Webhooks,
+    url: &Url,
+) -> std::result::Result<(), String> {
+    let host = url.host_str().ok_or("the URL has no host")?;
+    if config.allows_any_address(host) {
+        return Ok(());
+    }
+    let port = url.port_or_known_default().unwrap_or(80);
+    match literal_ip(url) {
+        Some(ip) => public_addrs(host, vec![SocketAddr::new(ip, port)]),
+        None => resolve_public(host, port).await,
+    }
+    .map(|_| ())
+}
+
+/// The address written in place of a host name, as in `http://10.0.0.1/` or `http://[::1]/`
+fn literal_ip(url: &Url) -> Option<IpAddr> {
+    let host = url.host_str()?;
+    host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host).parse().ok()
+}
+
+/// Addresses of `host`, provided every one of them is public
+async fn resolve_public(host: &str, port: u16) -> std::result::Result<Vec<SocketAddr>, String> {
+    let addrs = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("cannot resolve {host}: {e}"))?;
+    public_addrs(host, addrs.collect())
+}
+
+fn public_addrs(
+    host: &str,
+    addrs: Vec<SocketAddr>,
+) -> std::result::Result<Vec<SocketAddr>, String> {
+    if addrs.is_empty() {
+        return Err(format!("{host} has no address"));
+    }
+    match addrs.iter().find(|addr| !is_public(addr.ip())) {
+        Some(addr) => Err(format!("{host} resolves to {}, which is not public", addr.ip())),
+        None => Ok(addrs),
+    }
+}
+
+/// False for loopback, private, link-local, unspecified, multicast and other addresses that
+/// lead into the server's own network rather than the internet
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, third, _] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // "This network" and carrier-grade NAT
+                || first == 0
+                || (first == 100 && (64..128).contains(&second))
+                // Protocol assignments, benchmarking and the reserved 240.0.0.0/4
+                || (first == 192 && second == 0 && third == 0)
+                || (first == 198 && (second & 0xfe) == 18)
+                || first >= 240)
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(embedded) => is_public(embedded.into()),
+            None => {
+                let [first, second, third, ..] = ip.segments();
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+                    // Local-use NAT64, Teredo and documentation
+                    || (first == 0x64 && second == 0xff9b && third == 1)
+                    || (first == 0x2001 && (second == 0 || second == 0xdb8)))
+            }
+        },
+    }
+}
+
+/// The IPv4 address an IPv6 address reaches: IPv4-mapped and -compatible addresses, NAT64
+/// (64:ff9b::/96) and 6to4 (2002::/16)
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let ipv4 = |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0 | 0xffff, high, low] | [0x64, 0xff9b, 0, 0, 0, 0, high, low] => {
+            Some(ipv4(high, low))
+        }
+        [0x2002, high, low, ..] => Some(ipv4(high, low)),
+        _ => None,
+    }
+}
+
+/// Resolves receiver hosts for the delivery client, refusing those `check_destination` would.
+/// Checking again as each connection is made stops a host that passed at creation from later
+/// resolving into the server's network.
+struct PublicResolver(config::Webhooks);
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let allowed = self.0.allows_any_address(&host);
+        Box::pin(async move {
+            // The client fills in the port of the URL
+            let addrs = if allowed {
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect()
+            } else {
+                resolve_public(&host, 0).await?
+            };
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Signature header value for `payload` sent at `timestamp` (Unix seconds): an HMAC-SHA256 over
+/// `"{timestamp}.{payload}"`, so receivers can reject both forged and replayed deliveries
+pub fn sign(secret: &str, timestamp: i64, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{timestamp}.{payload}").as_bytes());
+    format!("t={timestamp},v1={}", to_hex(&mac.finalize().into_bytes()))
+}
+
+/// Wait before the attempt after the `attempts`th
+fn backoff(config: &config::Webhooks, attempts: u32) -> Duration {
+    let factor = 1u64 << attempts.saturating_sub(1).min(20);
+    Duration::from_millis(config.retry_backoff_ms.saturating_mul(factor)).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_is_hmac_sha256_of_timestamp_and_payload() {
+        assert_eq!(
+            sign("secret", 1700000000, r#"{"event":"note.updated"}"#),
+            "t=1700000000,v1=1add5ba9e95da6775a6d81578b766e60493587c4979394b1530d75ab3dc383fe"
+        );
+    }
+
+    #[test]
+    fn test_only_public_addresses_are_receivers() {
+        let refused = [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "100.64.0.1",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+            "::ffff:127.0.0.1",
+            "100.127.255.255",
+            "192.0.0.8",
+            "192.0.2.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "240.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b:1::1",
+            "2002:c0a8:101::1",
+            "2002:7f00:1::",
+            "2001::1",
+            "2001:db8::1",
+        ];
+        for ip in refused {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        let public = [
+            "93.184.215.14",
+            "100.128.0.1",
+            "198.20.0.1",
+            "2606:2800:21f:cb07:6820:80da:af6b:8b2c",
+            "64:ff9b::5db8:d70e",
+            "2002:5db8:d70e::1",
+        ];
+        for ip in public {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_an_hour() {
+        let config = config::Webhooks { retry_backoff_ms: 1000, ..Default::default() };
+        let waits: Vec<u64> = (1..=4).map(|n| backoff(&config, n).as_secs()).collect();
+        assert_eq!(waits, [1, 2, 4, 8]);
+        assert_eq!(backoff(&config, 40), MAX_BACKOFF);
+    }
+}
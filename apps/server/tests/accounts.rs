@@ -0,0 +1,113 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{
+    Client, PASSWORD, authorized, call, connect, connect_with_token, create_account, post, recv,
+    send, spawn_server, try_recv,
+};
+use notaro_core::sync::{continue_reconcile, start_reconcile};
+use notaro_core::{DatabaseConnection, Note, SyncFilter, SyncMessage};
+use notaro_server::config::{Config, Registration};
+use serde_json::json;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Error as WsError;
+
+async fn pull_all(client: &mut Client) -> Vec<Note> {
+    send(client, &SyncMessage::PullRequest { since_version: 0, cursor: None, limit: None }).await;
+    match recv(client).await {
+        SyncMessage::PullResponse { changes, .. } => changes,
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+}
+
+fn assert_unauthorized(result: Result<Client, WsError>) {
+    match result {
+        Err(WsError::Http(response)) => assert_eq!(response.status(), StatusCode::UNAUTHORIZED),
+        Err(e) => panic!("Expected 401, got {e}"),
+        Ok(_) => panic!("Expected 401, got a socket"),
+    }
+}
+
+#[tokio::test]
+async fn test_users_never_see_each_others_notes() {
+    let server = spawn_server().await;
+    let bob = create_account(&server.state, "bob");
+
+    let mut alice_laptop = connect(&server).await;
+    let mut bob_laptop = connect_with_token(server.addr, Some(&bob.token)).await.unwrap();
+    assert!(pull_all(&mut bob_laptop).await.is_empty());
+
+    let secret = Note::new("Secret".into(), "Alice only".into(), None);
+    send(&mut alice_laptop, &SyncMessage::PushUpdates { changes: vec![secret], deltas: vec![] })
+        .await;
+    assert_eq!(recv(&mut alice_laptop).await, SyncMessage::Ack);
+
+    // No fan-out across users, and nothing to pull or reconcile
+    assert!(try_recv(&mut bob_laptop, Duration::from_millis(200)).await.is_none());
+    assert!(pull_all(&mut bob_laptop).await.is_empty());
+
+    let mut bob_db = DatabaseConnection::new(":memory:").unwrap();
+    let all = SyncFilter::default();
+    send(&mut bob_laptop, &start_reconcile(&bob_db, &all).unwrap()).await;
+    let SyncMessage::ReconcileResponse { digests, leaves, notes } = recv(&mut bob_laptop).await
+    else {
+        panic!("Expected ReconcileResponse");
+    };
+    assert!(notes.is_empty());
+    let step = continue_reconcile(&mut bob_db, &all, &digests, &leaves, notes).unwrap();
+    assert!(step.next.is_none() && step.push.is_empty());
+
+    // Each user's notes live in their own database
+    assert_eq!(server.db().lock().unwrap().get_all_notes().unwrap().len(), 1);
+    let bob_store = server.state.user_db(&bob.user_id).unwrap();
+    assert!(bob_store.lock().unwrap().get_all_notes().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_sync_requires_a_valid_token() {
+    let server = spawn_server().await;
+
+    assert_unauthorized(connect_with_token(server.addr, None).await);
+    assert_unauthorized(connect_with_token(server.addr, Some("forged")).await);
+    assert!(connect_with_token(server.addr, Some(&server.token)).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_register_login_and_logout_over_http() {
+    let server = spawn_server().await;
+    let credentials = json!({ "username": "carol", "password": "hunter22" });
+
+    let (status, body) = call(&server, post("/accounts", credentials.clone())).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let user_id = body["user_id"].as_str().unwrap().to_string();
+
+    let (status, _) = call(&server, post("/accounts", credentials.clone())).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let wrong = json!({ "username": "carol", "password": "hunter23" });
+    assert_eq!(call(&server, post("/sessions", wrong)).await.0, StatusCode::UNAUTHORIZED);
+
+    let (status, body) = call(&server, post("/sessions", credentials)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["user_id"], user_id);
+    let token = body["token"].as_str().unwrap().to_string();
+    assert!(connect_with_token(server.addr, Some(&token)).await.is_ok());
+
+    let logout = authorized(Method::DELETE, "/sessions/current", &token);
+    assert_eq!(call(&server, logout).await.0, StatusCode::NO_CONTENT);
+    assert_unauthorized(connect_with_token(server.addr, Some(&token)).await);
+}
+
+#[tokio::test]
+async fn test_closed_registration_still_allows_login() {
+    let mut server = spawn_server().await;
+    let config = Config { registration: Registration::Closed, ..Default::default() };
+    server.state = server.state.clone().with_config(config);
+
+    let credentials = json!({ "username": "carol", "password": "hunter22" });
+    let (status, body) = call(&server, post("/accounts", credentials)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error"], "registration is closed on this server");
+
+    let existing = json!({ "username": "alice", "password": PASSWORD });
+    assert_eq!(call(&server, post("/sessions", existing)).await.0, StatusCode::OK);
+}
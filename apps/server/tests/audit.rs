@@ -0,0 +1,124 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{
+    PASSWORD, authorized, authorized_json, call, connect_with_token, create_account, post, recv,
+    send, spawn_server,
+};
+use notaro_core::{Note, SyncMessage};
+use notaro_server::accounts::{AuditQuery, AuditRecord};
+use serde_json::json;
+
+fn audit_log(server: &common::TestServer, event: &str) -> Vec<AuditRecord> {
+    let query = AuditQuery { event: Some(event.to_string()), ..Default::default() };
+    server.state.accounts.lock().unwrap().audit_log(&query).unwrap()
+}
+
+#[tokio::test]
+async fn test_deletions_are_recorded_with_the_device_that_made_them() {
+    let server = spawn_server().await;
+    let body = json!({ "username": "alice", "password": PASSWORD, "device_name": "Phone" });
+    let (status, phone) = call(&server, post("/sessions", body)).await;
+    assert_eq!(status, StatusCode::OK);
+    let phone_token = phone["token"].as_str().unwrap();
+    let logins = audit_log(&server, "session.login");
+    assert_eq!(logins.len(), 1);
+    assert_eq!(logins[0].device_name.as_deref(), Some("Phone"));
+
+    // Deleted from the phone's socket
+    let mut note = Note::new("Passwords".into(), "".into(), None);
+    let mut socket = connect_with_token(server.addr, Some(phone_token)).await.unwrap();
+    let push =
+        |note: &Note| SyncMessage::PushUpdates { changes: vec![note.clone()], deltas: vec![] };
+    send(&mut socket, &push(&note)).await;
+    assert_eq!(recv(&mut socket).await, SyncMessage::Ack);
+    note.is_deleted = true;
+    note.version += 1;
+    note.version_vector.increment("phone");
+    send(&mut socket, &push(&note)).await;
+    assert_eq!(recv(&mut socket).await, SyncMessage::Ack);
+    // Resending the tombstone deletes nothing
+    send(&mut socket, &push(&note)).await;
+    assert_eq!(recv(&mut socket).await, SyncMessage::Ack);
+
+    // ...and from the laptop over HTTP
+    let uri = "/v1/notes/minutes";
+    let body = json!({ "title": "Minutes", "content": "" });
+    call(&server, authorized_json(Method::PUT, uri, &server.token, body)).await;
+    let (status, _) = call(&server, authorized(Method::DELETE, uri, &server.token)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let deletions = audit_log(&server, "note.deleted");
+    let summary: Vec<_> = deletions
+        .iter()
+        .map(|record| {
+            (
+                record.target.as_deref().unwrap(),
+                record.device_name.as_deref().unwrap(),
+                record.details["title"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [(note.id.as_str(), "Phone", "Passwords"), ("minutes", "Test device", "Minutes")]
+    );
+    assert!(deletions.iter().all(|record| record.username.as_deref() == Some("alice")));
+}
+
+#[tokio::test]
+async fn test_sharing_and_revocations_are_recorded() {
+    let server = spawn_server().await;
+    create_account(&server.state, "bob");
+
+    let request =
+        authorized_json(Method::POST, "/collections", &server.token, json!({ "name": "Team" }));
+    let collection = call(&server, request).await.1["id"].as_str().unwrap().to_string();
+    let uri = format!("/collections/{collection}/members");
+    let invite = json!({ "username": "bob", "role": "editor" });
+    call(&server, authorized_json(Method::POST, &uri, &server.token, invite)).await;
+    let set = audit_log(&server, "collection.member_set");
+    assert_eq!(set[0].target.as_deref(), Some(collection.as_str()));
+    assert_eq!(
+        (&set[0].details["role"], &set[0].details["joined"]),
+        (&json!("editor"), &json!(true))
+    );
+
+    let note = server.db().lock().unwrap().create_note("Plan".into(), "".into(), None).unwrap();
+    let uri = format!("/notes/{}/shares", note.id);
+    let (status, link) =
+        call(&server, authorized_json(Method::POST, &uri, &server.token, json!({}))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let created = audit_log(&server, "share_link.created");
+    assert_eq!(created[0].target.as_ref(), link["id"].as_str().map(str::to_string).as_ref());
+    assert_eq!(created[0].details["note_id"], json!(note.id));
+
+    let (status, _) =
+        call(&server, authorized(Method::DELETE, "/sessions/current", &server.token)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let revoked = audit_log(&server, "session.revoked");
+    assert_eq!(revoked.len(), 1);
+    assert_eq!(revoked[0].session_id, revoked[0].target);
+    assert_eq!(revoked[0].device_name.as_deref(), Some("Test device"));
+}
+
+#[tokio::test]
+async fn test_failed_logins_are_recorded_against_the_account() {
+    let server = spawn_server().await;
+    for username in ["alice", "mallory"] {
+        let body = json!({ "username": username, "password": "guess123", "device_name": "Bot" });
+        let (status, _) = call(&server, post("/sessions", body)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    let failed = audit_log(&server, "session.login_failed");
+    let summary: Vec<_> = failed
+        .iter()
+        .map(|record| (record.target.as_deref().unwrap(), record.username.as_deref()))
+        .collect();
+    assert_eq!(summary, [("alice", Some("alice")), ("mallory", None)]);
+    assert_eq!(failed[0].user_id.as_deref(), Some(server.user_id.as_str()));
+    assert_eq!(failed[0].details["reason"], "invalid username or password");
+    assert!(failed.iter().all(|record| record.session_id.is_none()));
+    assert!(audit_log(&server, "session.login").is_empty());
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use common::{connect, connect_negotiated, recv, recv_with, send, send_with, spawn_server};
+use notaro_core::codec::{Compression, Encoding};
+use notaro_core::{FrameCodec, Note, SyncMessage};
+
+#[tokio::test]
+async fn test_binary_client_syncs_with_json_client() {
+    let server = spawn_server().await;
+
+    let (mut binary, codec) = connect_negotiated(
+        &server,
+        &[Encoding::MessagePack, Encoding::Json],
+        &[Compression::Zstd, Compression::None],
+    )
+    .await;
+    assert_eq!(
+        codec,
+        FrameCodec { encoding: Encoding::MessagePack, compression: Compression::Zstd }
+    );
+
+    // A legacy client that never says Hello keeps talking JSON
+    let mut legacy = connect(&server).await;
+    send(&mut legacy, &SyncMessage::PullRequest { since_version: 0, cursor: None, limit: None })
+        .await;
+    recv(&mut legacy).await;
+
+    let note = Note::new("Packed".into(), "Body".into(), None);
+    send_with(
+        &mut binary,
+        codec,
+        &SyncMessage::PushUpdates { changes: vec![note.clone()], deltas: vec![] },
+    )
+    .await;
+    assert_eq!(recv_with(&mut binary, codec).await, SyncMessage::Ack);
+
+    match recv(&mut legacy).await {
+        SyncMessage::PullResponse { changes, .. } => assert_eq!(changes, vec![note.clone()]),
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+
+    // And the binary client reads the same data back through its codec
+    send_with(
+        &mut binary,
+        codec,
+        &SyncMessage::PullRequest { since_version: 0, cursor: None, limit: None },
+    )
+    .await;
+    match recv_with(&mut binary, codec).await {
+        SyncMessage::PullResponse { changes, .. } => assert_eq!(changes, vec![note]),
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_unknown_offers_fall_back_to_json() {
+    let server = spawn_server().await;
+    let (_client, codec) = connect_negotiated(&server, &[], &[]).await;
+    assert_eq!(codec, FrameCodec::JSON);
+}
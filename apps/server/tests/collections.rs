@@ -0,0 +1,180 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{
+    Client, TestServer, authorized, authorized_json, call, connect, connect_with_token,
+    create_account, recv, send, spawn_server, try_recv,
+};
+use notaro_core::{Note, SyncMessage};
+use serde_json::json;
+use std::time::Duration;
+
+async fn push(client: &mut Client, note: &Note) -> SyncMessage {
+    send(client, &SyncMessage::PushUpdates { changes: vec![note.clone()], deltas: vec![] }).await;
+    recv(client).await
+}
+
+/// Pulls once, which also makes sure the socket is registered for fan-out
+async fn pull(client: &mut Client) -> Vec<Note> {
+    send(client, &SyncMessage::PullRequest { since_version: 0, cursor: None, limit: None }).await;
+    match recv(client).await {
+        SyncMessage::PullResponse { changes, .. } => changes,
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+}
+
+async fn create_collection(server: &TestServer, name: &str) -> String {
+    let request =
+        authorized_json(Method::POST, "/collections", &server.token, json!({ "name": name }));
+    let (status, body) = call(server, request).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body["role"], "owner");
+    body["id"].as_str().unwrap().to_string()
+}
+
+async fn invite(server: &TestServer, token: &str, collection: &str, username: &str, role: &str) {
+    let uri = format!("/collections/{collection}/members");
+    let body = json!({ "username": username, "role": role });
+    let (status, body) = call(server, authorized_json(Method::POST, &uri, token, body)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT, "{body}");
+}
+
+#[tokio::test]
+async fn test_members_share_notes_and_viewers_cannot_edit() {
+    let server = spawn_server().await;
+    let bob = create_account(&server.state, "bob");
+    let carol = create_account(&server.state, "carol");
+    let runbooks = create_collection(&server, "Runbooks").await;
+
+    let mut alice_socket = connect(&server).await;
+    let mut bob_socket = connect_with_token(server.addr, Some(&bob.token)).await.unwrap();
+    let mut carol_socket = connect_with_token(server.addr, Some(&carol.token)).await.unwrap();
+    pull(&mut bob_socket).await;
+    pull(&mut carol_socket).await;
+
+    let mut restart = Note::new("Restart the API".into(), "systemctl restart api".into(), None);
+    restart.collection = Some(runbooks.clone());
+    assert_eq!(push(&mut alice_socket, &restart).await, SyncMessage::Ack);
+    assert!(try_recv(&mut bob_socket, Duration::from_millis(200)).await.is_none());
+
+    // Joining hands the collection to every device of the new member
+    invite(&server, &server.token, &runbooks, "bob", "editor").await;
+    invite(&server, &server.token, &runbooks, "carol", "viewer").await;
+    for socket in [&mut bob_socket, &mut carol_socket] {
+        match recv(socket).await {
+            SyncMessage::PullResponse { changes, .. } => assert_eq!(changes, vec![restart.clone()]),
+            other => panic!("Expected PullResponse, got {other:?}"),
+        }
+    }
+
+    // An editor's change reaches the other members
+    let mut edited = restart.clone();
+    edited.content = "systemctl restart api && journalctl -fu api".into();
+    edited.version = 2;
+    assert_eq!(push(&mut bob_socket, &edited).await, SyncMessage::Ack);
+    for socket in [&mut alice_socket, &mut carol_socket] {
+        match recv(socket).await {
+            SyncMessage::PullResponse { changes, .. } => assert_eq!(changes, vec![edited.clone()]),
+            other => panic!("Expected PullResponse, got {other:?}"),
+        }
+    }
+
+    // A viewer's edit is refused per note, while the rest of the push goes through
+    let mut vandalized = edited.clone();
+    vandalized.content = "rm -rf /".into();
+    vandalized.version = 3;
+    let private = Note::new("Carol's todo".into(), "".into(), None);
+    send(
+        &mut carol_socket,
+        &SyncMessage::PushUpdates { changes: vec![vandalized, private.clone()], deltas: vec![] },
+    )
+    .await;
+    match recv(&mut carol_socket).await {
+        SyncMessage::PushRejected { rejected, need_full_copy } => {
+            assert_eq!(rejected.len(), 1);
+            assert_eq!(rejected[0].id, restart.id);
+            assert_eq!(
+                rejected[0].reason,
+                "you can only view \"Runbooks\"; ask its owner for editor access"
+            );
+            assert!(need_full_copy.is_empty());
+        }
+        other => panic!("Expected PushRejected, got {other:?}"),
+    }
+    assert!(try_recv(&mut alice_socket, Duration::from_millis(200)).await.is_none());
+    let collection = server.state.stores.get_collection(&runbooks).unwrap();
+    assert_eq!(collection.lock().unwrap().get_note_by_id(&restart.id).unwrap(), edited);
+    let carol_notes = pull(&mut carol_socket).await;
+    assert!(carol_notes.contains(&private) && carol_notes.contains(&edited));
+
+    // Private notes stay with their owner
+    assert!(!pull(&mut alice_socket).await.contains(&private));
+}
+
+#[tokio::test]
+async fn test_removed_members_lose_the_collection() {
+    let server = spawn_server().await;
+    let bob = create_account(&server.state, "bob");
+    let runbooks = create_collection(&server, "Runbooks").await;
+    invite(&server, &server.token, &runbooks, "bob", "editor").await;
+
+    let mut alice_socket = connect(&server).await;
+    let mut bob_socket = connect_with_token(server.addr, Some(&bob.token)).await.unwrap();
+    pull(&mut bob_socket).await;
+    let mut note = Note::new("Failover".into(), "Promote the replica".into(), None);
+    note.collection = Some(runbooks.clone());
+    assert_eq!(push(&mut alice_socket, &note).await, SyncMessage::Ack);
+    recv(&mut bob_socket).await;
+
+    // Only the owner manages members
+    let uri = format!("/collections/{runbooks}/members");
+    let body = json!({ "username": "alice", "role": "viewer" });
+    let (status, _) = call(&server, authorized_json(Method::POST, &uri, &bob.token, body)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, members) = call(&server, authorized(Method::GET, &uri, &bob.token)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(members[0]["username"], "alice");
+    assert_eq!(members[1]["role"], "editor");
+
+    let uri = format!("/collections/{runbooks}/members/{}", bob.user_id);
+    let (status, _) = call(&server, authorized(Method::DELETE, &uri, &server.token)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    match recv(&mut bob_socket).await {
+        SyncMessage::PullResponse { changes, evicted, .. } => {
+            assert!(changes.is_empty());
+            assert_eq!(evicted, vec![note.id.clone()]);
+        }
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+    assert!(pull(&mut bob_socket).await.is_empty());
+
+    // A stale copy pushed from an offline device is refused
+    let mut stale = note.clone();
+    stale.version = 2;
+    match push(&mut bob_socket, &stale).await {
+        SyncMessage::PushRejected { rejected, .. } => {
+            assert_eq!(rejected[0].reason, "you are not a member of this note's collection");
+        }
+        other => panic!("Expected PushRejected, got {other:?}"),
+    }
+
+    // ...and so is taking a shared note private
+    let mut unshared = note.clone();
+    unshared.collection = None;
+    unshared.version = 2;
+    match push(&mut alice_socket, &unshared).await {
+        SyncMessage::PushRejected { rejected, .. } => {
+            assert_eq!(
+                rejected[0].reason,
+                "notes cannot leave the shared collection they belong to"
+            );
+        }
+        other => panic!("Expected PushRejected, got {other:?}"),
+    }
+
+    let (status, listed) = call(&server, authorized(Method::GET, "/collections", &bob.token)).await;
+    assert_eq!((status, listed), (StatusCode::OK, json!([])));
+    let uri = format!("/collections/{runbooks}/members");
+    let (status, _) = call(&server, authorized(Method::GET, &uri, &bob.token)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
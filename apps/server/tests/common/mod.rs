@@ -0,0 +1,214 @@
+#![allow(dead_code)]
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode, header};
+use futures_util::{SinkExt, StreamExt};
+use http_body_util::BodyExt;
+use notaro_core::codec::{Compression, Encoding};
+use notaro_core::{FrameCodec, SyncFilter, SyncMessage};
+use notaro_server::accounts::{AccountStore, Login};
+use notaro_server::store::UserDb;
+use notaro_server::{AppState, Config, UserStores};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+use tower::ServiceExt;
+
+pub type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Password given to every test account
+pub const PASSWORD: &str = "correct horse";
+
+/// A running server and the account tests connect as by default
+#[derive(Clone)]
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub state: AppState,
+    pub user_id: String,
+    pub token: String,
+}
+
+impl TestServer {
+    /// Note database of the default account
+    pub fn db(&self) -> UserDb {
+        self.state.user_db(&self.user_id).unwrap()
+    }
+}
+
+/// Starts a server with in-memory databases on an ephemeral port, with one account logged in.
+pub async fn spawn_server() -> TestServer {
+    spawn_server_with(UserStores::in_memory()).await
+}
+
+/// Like `spawn_server`, keeping notes in `stores`
+pub async fn spawn_server_with(stores: UserStores) -> TestServer {
+    spawn(AppState::new(AccountStore::in_memory().unwrap(), stores)).await
+}
+
+/// Like `spawn_server`, with `config` in place of the defaults
+pub async fn spawn_configured(config: Config) -> TestServer {
+    let state = AppState::new(AccountStore::in_memory().unwrap(), UserStores::in_memory());
+    spawn(state.with_config(config)).await
+}
+
+async fn spawn(state: AppState) -> TestServer {
+    let Login { user_id, token, .. } = create_account(&state, "alice");
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(notaro_server::serve(listener, state.clone()));
+    TestServer { addr, state, user_id, token }
+}
+
+/// Creates an account directly in the store and logs it in
+pub fn create_account(state: &AppState, username: &str) -> Login {
+    let accounts = state.accounts.lock().unwrap();
+    accounts.create_user(username, PASSWORD).unwrap();
+    accounts.login(username, PASSWORD, "Test device").unwrap()
+}
+
+/// Sends one HTTP request through the router and returns the status and JSON body
+pub async fn call(server: &TestServer, request: Request<Body>) -> (StatusCode, Value) {
+    let response = notaro_server::router(server.state.clone()).oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+/// Like `call`, for endpoints that answer in plain text
+pub async fn get_text(server: &TestServer, uri: &str) -> (StatusCode, String) {
+    let request = Request::get(uri).body(Body::empty()).unwrap();
+    let response = notaro_server::router(server.state.clone()).oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+pub fn post(uri: &str, body: Value) -> Request<Body> {
+    Request::post(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// A bodyless request authenticated with `token`
+pub fn authorized(method: Method, uri: &str, token: &str) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {token}"))
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// A JSON request authenticated with `token`
+pub fn authorized_json(method: Method, uri: &str, token: &str, body: Value) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {token}"))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Opens a sync socket as the server's default account
+pub async fn connect(server: &TestServer) -> Client {
+    connect_with_token(server.addr, Some(&server.token)).await.unwrap()
+}
+
+/// Opens a sync socket with the given bearer token, or none at all
+pub async fn connect_with_token(
+    addr: SocketAddr,
+    token: Option<&str>,
+) -> Result<Client, tokio_tungstenite::tungstenite::Error> {
+    let mut request = format!("ws://{addr}/sync").into_client_request()?;
+    if let Some(token) = token {
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {token}")).unwrap());
+    }
+    let (client, _) = connect_async(request).await?;
+    Ok(client)
+}
+
+/// Connects and runs the `Hello`/`Welcome` handshake, returning the agreed codec.
+pub async fn connect_negotiated(
+    server: &TestServer,
+    encodings: &[Encoding],
+    compression: &[Compression],
+) -> (Client, FrameCodec) {
+    let mut client = connect(server).await;
+    let hello = SyncMessage::Hello {
+        encodings: encodings.to_vec(),
+        compression: compression.to_vec(),
+        filter: SyncFilter::default(),
+    };
+    send(&mut client, &hello).await;
+    match recv(&mut client).await {
+        SyncMessage::Welcome { codec } => (client, codec),
+        other => panic!("Expected Welcome, got {other:?}"),
+    }
+}
+
+/// Connects with a plain JSON handshake that declares a subscription filter.
+pub async fn connect_filtered(server: &TestServer, filter: SyncFilter) -> Client {
+    let mut client = connect(server).await;
+    send(&mut client, &SyncMessage::Hello { encodings: vec![], compression: vec![], filter }).await;
+    match recv(&mut client).await {
+        SyncMessage::Welcome { .. } => client,
+        other => panic!("Expected Welcome, got {other:?}"),
+    }
+}
+
+pub async fn send(client: &mut Client, message: &SyncMessage) {
+    send_with(client, FrameCodec::JSON, message).await
+}
+
+pub async fn send_with(client: &mut Client, codec: FrameCodec, message: &SyncMessage) {
+    let frame = codec.encode(message).unwrap();
+    let frame = if codec.is_text() {
+        Message::Text(String::from_utf8(frame).unwrap().into())
+    } else {
+        Message::Binary(frame.into())
+    };
+    client.send(frame).await.unwrap();
+}
+
+/// Waits for the next sync message, failing the test after a short timeout.
+pub async fn recv(client: &mut Client) -> SyncMessage {
+    recv_with(client, FrameCodec::JSON).await
+}
+
+pub async fn recv_with(client: &mut Client, codec: FrameCodec) -> SyncMessage {
+    try_recv_with(client, codec, Duration::from_secs(5))
+        .await
+        .expect("timed out waiting for message")
+}
+
+/// Returns the next sync message, or `None` if nothing arrives within `wait`.
+pub async fn try_recv(client: &mut Client, wait: Duration) -> Option<SyncMessage> {
+    try_recv_with(client, FrameCodec::JSON, wait).await
+}
+
+pub async fn try_recv_with(
+    client: &mut Client,
+    codec: FrameCodec,
+    wait: Duration,
+) -> Option<SyncMessage> {
+    loop {
+        let frame = tokio::time::timeout(wait, client.next()).await.ok()??.unwrap();
+        match frame {
+            Message::Text(text) => {
+                return Some(FrameCodec::JSON.decode(text.as_bytes(), usize::MAX).unwrap());
+            }
+            Message::Binary(bytes) => return Some(codec.decode(&bytes, usize::MAX).unwrap()),
+            _ => continue,
+        }
+    }
+}
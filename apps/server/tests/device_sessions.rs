@@ -0,0 +1,81 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{
+    PASSWORD, authorized, call, connect_with_token, create_account, recv, spawn_server, try_recv,
+};
+use futures_util::StreamExt;
+use notaro_core::NotaroError;
+use notaro_core::sync::check_signed_in;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Error as WsError;
+
+#[tokio::test]
+async fn test_revoking_a_device_disconnects_it() {
+    let server = spawn_server().await;
+    let laptop = server.token.clone();
+    let phone = server.state.accounts.lock().unwrap().login("alice", PASSWORD, "Phone").unwrap();
+
+    let mut laptop_socket = connect_with_token(server.addr, Some(&laptop)).await.unwrap();
+    let mut phone_socket = connect_with_token(server.addr, Some(&phone.token)).await.unwrap();
+
+    // The phone sees both devices and which one it is
+    let (status, sessions) =
+        call(&server, authorized(Method::GET, "/sessions", &phone.token)).await;
+    assert_eq!(status, StatusCode::OK);
+    let sessions = sessions.as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    let current: Vec<_> = sessions.iter().filter(|s| s["current"] == true).collect();
+    assert_eq!(current[0]["device_name"], "Phone");
+
+    // ...and signs the lost laptop out
+    let uri = format!("/sessions/{}", session_of(&server, &laptop));
+    let (status, _) = call(&server, authorized(Method::DELETE, &uri, &phone.token)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let err = check_signed_in(recv(&mut laptop_socket).await).unwrap_err();
+    assert!(matches!(err, NotaroError::SignedOut));
+    assert_eq!(err.to_string(), "this device was signed out");
+    assert!(matches!(laptop_socket.next().await, Some(Ok(_)) | None));
+    assert!(try_recv(&mut phone_socket, Duration::from_millis(200)).await.is_none());
+
+    // Reconnecting fails with the same reason
+    match connect_with_token(server.addr, Some(&laptop)).await {
+        Err(WsError::Http(response)) => {
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            let body: serde_json::Value =
+                serde_json::from_slice(response.body().as_deref().unwrap()).unwrap();
+            assert_eq!(body["error"], "this device was signed out");
+        }
+        other => panic!("Expected 401, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[tokio::test]
+async fn test_devices_revoked_from_the_shell_are_disconnected() {
+    let server = spawn_server().await;
+    let mut socket = connect_with_token(server.addr, Some(&server.token)).await.unwrap();
+
+    // The admin command revokes in the database only, without telling the running server
+    let session_id = session_of(&server, &server.token);
+    server.state.accounts.lock().unwrap().revoke_session(&session_id, None).unwrap();
+
+    let err = check_signed_in(recv(&mut socket).await).unwrap_err();
+    assert!(matches!(err, NotaroError::SignedOut));
+}
+
+#[tokio::test]
+async fn test_cannot_revoke_another_users_device() {
+    let server = spawn_server().await;
+    let mallory = create_account(&server.state, "mallory");
+
+    let uri = format!("/sessions/{}", session_of(&server, &server.token));
+    let (status, _) = call(&server, authorized(Method::DELETE, &uri, &mallory.token)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(connect_with_token(server.addr, Some(&server.token)).await.is_ok());
+}
+
+/// Device session id behind a token
+fn session_of(server: &common::TestServer, token: &str) -> String {
+    server.state.accounts.lock().unwrap().authenticate(token, None).unwrap().session_id
+}
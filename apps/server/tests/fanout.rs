@@ -0,0 +1,87 @@
+mod common;
+
+use common::{connect, recv, send, spawn_server, try_recv};
+use notaro_core::delta::NoteDelta;
+use notaro_core::{Note, SyncMessage};
+use std::time::Duration;
+
+#[tokio::test]
+async fn test_device_b_receives_push_from_device_a() {
+    let server = spawn_server().await;
+    let mut device_a = connect(&server).await;
+    let mut device_b = connect(&server).await;
+
+    // Make sure both sessions are registered before pushing
+    send(&mut device_b, &SyncMessage::PullRequest { since_version: 0, cursor: None, limit: None })
+        .await;
+    assert!(matches!(recv(&mut device_b).await, SyncMessage::PullResponse { .. }));
+
+    let note = Note::new("Groceries".into(), "Milk".into(), None);
+    send(&mut device_a, &SyncMessage::PushUpdates { changes: vec![note.clone()], deltas: vec![] })
+        .await;
+    assert_eq!(recv(&mut device_a).await, SyncMessage::Ack);
+
+    // Device B never asked, but gets the edit
+    match recv(&mut device_b).await {
+        SyncMessage::PullResponse { changes, current_version, has_more, .. } => {
+            assert_eq!(changes, vec![note]);
+            assert_eq!(current_version, 1);
+            assert!(!has_more);
+        }
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+
+    // The pushing device is not echoed its own change
+    assert!(try_recv(&mut device_a, Duration::from_millis(200)).await.is_none());
+    assert_eq!(server.state.hub.session_count(&server.user_id), 2);
+}
+
+#[tokio::test]
+async fn test_fanout_carries_merged_copy() {
+    let server = spawn_server().await;
+
+    // Server already holds a newer version than the one device A pushes
+    let mut newer = Note::new("Plan".into(), "v5".into(), None);
+    newer.version = 5;
+    server.db().lock().unwrap().merge_changes(vec![newer.clone()]).unwrap();
+
+    let mut device_a = connect(&server).await;
+    let mut device_b = connect(&server).await;
+    send(&mut device_b, &SyncMessage::PullRequest { since_version: 5, cursor: None, limit: None })
+        .await;
+    recv(&mut device_b).await;
+
+    let mut stale = newer.clone();
+    stale.content = "v2".into();
+    stale.version = 2;
+    send(&mut device_a, &SyncMessage::PushUpdates { changes: vec![stale], deltas: vec![] }).await;
+    assert_eq!(recv(&mut device_a).await, SyncMessage::Ack);
+
+    match recv(&mut device_b).await {
+        SyncMessage::PullResponse { changes, .. } => assert_eq!(changes, vec![newer]),
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_delta_push_with_unknown_base_requests_full_copy() {
+    let server = spawn_server().await;
+    let mut device_a = connect(&server).await;
+
+    let base = Note::new("Draft".into(), "word ".repeat(100), None);
+    let mut edited = base.clone();
+    edited.content.push('!');
+    edited.version = 2;
+    let delta = NoteDelta::between(1, &base.content, &edited).unwrap();
+
+    // Server has never seen the base
+    send(&mut device_a, &SyncMessage::PushUpdates { changes: vec![], deltas: vec![delta.clone()] })
+        .await;
+    assert_eq!(recv(&mut device_a).await, SyncMessage::NeedFullCopy { ids: vec![base.id.clone()] });
+
+    // Once it holds the base, the same delta applies
+    server.db().lock().unwrap().merge_changes(vec![base.clone()]).unwrap();
+    send(&mut device_a, &SyncMessage::PushUpdates { changes: vec![], deltas: vec![delta] }).await;
+    assert_eq!(recv(&mut device_a).await, SyncMessage::Ack);
+    assert_eq!(server.db().lock().unwrap().get_note_by_id(&base.id).unwrap(), edited);
+}
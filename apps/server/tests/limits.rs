@@ -0,0 +1,125 @@
+mod common;
+
+use common::{
+    Client, connect, connect_negotiated, get_text, recv, recv_with, send, send_with,
+    spawn_configured,
+};
+use notaro_core::codec::{Compression, Encoding};
+use notaro_core::{Limit, Note, SyncMessage};
+use notaro_server::Config;
+use notaro_server::config::{Limits, MIN_MESSAGE_SIZE};
+use std::time::Duration;
+
+async fn push(client: &mut Client, changes: Vec<Note>) -> SyncMessage {
+    send(client, &SyncMessage::PushUpdates { changes, deltas: vec![] }).await;
+    recv(client).await
+}
+
+fn pull() -> SyncMessage {
+    SyncMessage::PullRequest { since_version: 0, cursor: None, limit: None }
+}
+
+#[tokio::test]
+async fn test_pushes_past_a_quota_are_refused_per_note() {
+    let limits = Limits { max_notes: Some(2), max_note_size: 1024, ..Default::default() };
+    let server = spawn_configured(Config { limits, ..Default::default() }).await;
+    let mut client = connect(&server).await;
+
+    let notes: Vec<Note> =
+        (0..3).map(|i| Note::new(format!("Note {i}"), "".into(), None)).collect();
+    let huge = Note::new("Huge".into(), "x".repeat(2048), None);
+    let mut changes = notes.clone();
+    changes.push(huge.clone());
+    match push(&mut client, changes).await {
+        SyncMessage::PushRejected { rejected, need_full_copy } => {
+            let refused: Vec<_> = rejected.iter().map(|r| (r.id.clone(), r.limit)).collect();
+            assert_eq!(
+                refused,
+                [
+                    (notes[2].id.clone(), Some(Limit::NoteCount)),
+                    (huge.id.clone(), Some(Limit::NoteSize)),
+                ]
+            );
+            assert_eq!(rejected[0].reason, "the quota of 2 notes is used up");
+            assert!(need_full_copy.is_empty());
+        }
+        other => panic!("Expected PushRejected, got {other:?}"),
+    }
+    assert_eq!(server.db().lock().unwrap().get_all_notes().unwrap().len(), 2);
+
+    // Deleting a note frees room for the refused one
+    let mut deleted = notes[0].clone();
+    deleted.is_deleted = true;
+    deleted.version = 2;
+    assert_eq!(push(&mut client, vec![deleted, notes[2].clone()]).await, SyncMessage::Ack);
+
+    let (_, metrics) = get_text(&server, "/metrics").await;
+    assert!(metrics.contains("notaro_limit_rejections_total{limit=\"note_count\"} 1"), "{metrics}");
+    assert!(metrics.contains("notaro_limit_rejections_total{limit=\"note_size\"} 1"), "{metrics}");
+}
+
+#[tokio::test]
+async fn test_oversized_and_too_frequent_messages_are_refused() {
+    let limits =
+        Limits { max_note_size: 1024, messages_per_second: 5, burst: 2, ..Default::default() };
+    let config = Config { max_message_size: MIN_MESSAGE_SIZE, limits, ..Default::default() };
+    let server = spawn_configured(config).await;
+    let mut client = connect(&server).await;
+
+    for _ in 0..2 {
+        send(&mut client, &pull()).await;
+        assert!(matches!(recv(&mut client).await, SyncMessage::PullResponse { .. }));
+    }
+    send(&mut client, &pull()).await;
+    let retry_after = match recv(&mut client).await {
+        SyncMessage::LimitExceeded {
+            limit: Limit::RateLimit, retry_after_ms: Some(ms), ..
+        } => ms,
+        other => panic!("Expected LimitExceeded, got {other:?}"),
+    };
+    assert!(retry_after <= 200, "{retry_after}");
+
+    // Waiting as told lets the client carry on
+    tokio::time::sleep(Duration::from_millis(retry_after)).await;
+    send(&mut client, &pull()).await;
+    assert!(matches!(recv(&mut client).await, SyncMessage::PullResponse { .. }));
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let huge = Note::new("Huge".into(), "x".repeat(MIN_MESSAGE_SIZE), None);
+    match push(&mut client, vec![huge]).await {
+        SyncMessage::LimitExceeded { limit, message, retry_after_ms } => {
+            assert_eq!(limit, Limit::MessageSize);
+            assert_eq!(message, format!("messages may be at most {MIN_MESSAGE_SIZE} bytes"));
+            assert_eq!(retry_after_ms, None);
+        }
+        other => panic!("Expected LimitExceeded, got {other:?}"),
+    }
+    assert!(server.db().lock().unwrap().get_all_notes().unwrap().is_empty());
+
+    let (_, metrics) = get_text(&server, "/metrics").await;
+    assert!(metrics.contains("notaro_limit_rejections_total{limit=\"rate_limit\"} 1"), "{metrics}");
+}
+
+#[tokio::test]
+async fn test_compressed_messages_are_limited_by_their_decoded_size() {
+    let limits = Limits { max_note_size: 1024, ..Default::default() };
+    let config = Config { max_message_size: MIN_MESSAGE_SIZE, limits, ..Default::default() };
+    let server = spawn_configured(config).await;
+    let (mut client, codec) =
+        connect_negotiated(&server, &[Encoding::MessagePack], &[Compression::Zstd]).await;
+
+    // Compresses to far below the limit, but would not fit once decompressed
+    let huge = Note::new("Huge".into(), "x".repeat(16 * MIN_MESSAGE_SIZE), None);
+    let message = SyncMessage::PushUpdates { changes: vec![huge], deltas: vec![] };
+    assert!(codec.encode(&message).unwrap().len() < MIN_MESSAGE_SIZE);
+    send_with(&mut client, codec, &message).await;
+    match recv_with(&mut client, codec).await {
+        SyncMessage::LimitExceeded { limit, message, retry_after_ms } => {
+            assert_eq!(limit, Limit::MessageSize);
+            assert_eq!(message, format!("messages may be at most {MIN_MESSAGE_SIZE} bytes"));
+            assert_eq!(retry_after_ms, None);
+        }
+        other => panic!("Expected LimitExceeded, got {other:?}"),
+    }
+    assert!(server.db().lock().unwrap().get_all_notes().unwrap().is_empty());
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use common::{call, connect, get_text, recv, send, spawn_server};
+use notaro_core::{Note, SyncMessage};
+use notaro_server::UserStores;
+use uuid::Uuid;
+
+#[tokio::test]
+async fn test_health_endpoints() {
+    let mut server = spawn_server().await;
+    assert_eq!(get_text(&server, "/healthz").await, (StatusCode::OK, "ok".to_string()));
+    let (status, body) = call(&server, Request::get("/readyz").body(Body::empty()).unwrap()).await;
+    assert_eq!((status, body["status"].as_str()), (StatusCode::OK, Some("ready")));
+
+    // A note directory that cannot be created makes the server unready
+    let blocker = std::env::temp_dir().join(format!("notaro-ready-{}", Uuid::new_v4()));
+    std::fs::write(&blocker, "").unwrap();
+    server.state.stores = UserStores::at(blocker.join("users"));
+    let (status, body) = call(&server, Request::get("/readyz").body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["check"], "notes");
+}
+
+#[tokio::test]
+async fn test_metrics_count_sessions_messages_and_merges() {
+    let server = spawn_server().await;
+    let mut client = connect(&server).await;
+
+    let note = Note::new("Metrics".into(), "".into(), None);
+    send(&mut client, &SyncMessage::PushUpdates { changes: vec![note], deltas: vec![] }).await;
+    assert_eq!(recv(&mut client).await, SyncMessage::Ack);
+    send(&mut client, &SyncMessage::PullRequest { since_version: 0, cursor: None, limit: None })
+        .await;
+    recv(&mut client).await;
+
+    let (status, text) = get_text(&server, "/metrics").await;
+    assert_eq!(status, StatusCode::OK);
+    for line in [
+        "notaro_sessions 1",
+        r#"notaro_messages_total{message_type="PushUpdates",direction="received"} 1"#,
+        r#"notaro_messages_total{message_type="Ack",direction="sent"} 1"#,
+        r#"notaro_merge_outcomes_total{outcome="inserted"} 1"#,
+        "notaro_push_duration_seconds_count 1",
+        "notaro_pull_duration_seconds_count 1",
+        "notaro_database_bytes 0",
+    ] {
+        assert!(text.lines().any(|l| l == line), "missing `{line}` in:\n{text}");
+    }
+}
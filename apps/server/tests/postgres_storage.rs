@@ -0,0 +1,47 @@
+//! Ignored by default; run with `--ignored` and `NOTARO_TEST_POSTGRES_URL` naming a PostgreSQL
+//! database.
+
+mod common;
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use common::{call, connect, recv, send, spawn_server_with};
+use notaro_core::postgres::PostgresDatabase;
+use notaro_core::tls::ServerTrust;
+use notaro_core::{Note, SyncMessage};
+use notaro_server::UserStores;
+
+#[tokio::test]
+#[ignore = "needs NOTARO_TEST_POSTGRES_URL"]
+async fn test_sync_over_postgres_storage() {
+    let url = std::env::var("NOTARO_TEST_POSTGRES_URL")
+        .expect("NOTARO_TEST_POSTGRES_URL names the database to test against");
+    // The Postgres client blocks, so it cannot connect from the async test itself
+    let database = tokio::task::spawn_blocking(move || {
+        PostgresDatabase::connect(&url, 4, &ServerTrust::default())
+    })
+    .await
+    .unwrap()
+    .unwrap();
+    let server = spawn_server_with(UserStores::postgres(database)).await;
+    let mut laptop = connect(&server).await;
+    let mut phone = connect(&server).await;
+
+    let note = Note::new("Stored in Postgres".into(), "".into(), None);
+    send(&mut laptop, &SyncMessage::PushUpdates { changes: vec![note.clone()], deltas: vec![] })
+        .await;
+    assert_eq!(recv(&mut laptop).await, SyncMessage::Ack);
+
+    send(&mut phone, &SyncMessage::PullRequest { since_version: 0, cursor: None, limit: None })
+        .await;
+    match recv(&mut phone).await {
+        SyncMessage::PullResponse { changes, current_version, .. } => {
+            assert_eq!(changes, vec![note]);
+            assert_eq!(current_version, 1);
+        }
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+
+    let (status, _) = call(&server, Request::get("/readyz").body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::OK);
+}
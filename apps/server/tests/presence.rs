@@ -0,0 +1,131 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{
+    Client, TestServer, authorized_json, call, connect, connect_with_token, create_account, recv,
+    send, spawn_server, try_recv,
+};
+use notaro_core::sync::presence_device_id;
+use notaro_core::{Note, NoteActivity, NotePresence, SyncMessage};
+use serde_json::json;
+use std::time::Duration;
+
+async fn push(client: &mut Client, note: &Note) {
+    send(client, &SyncMessage::PushUpdates { changes: vec![note.clone()], deltas: vec![] }).await;
+    assert_eq!(recv(client).await, SyncMessage::Ack);
+}
+
+/// Pulls once, which also makes sure the socket is registered for fan-out
+async fn pull(client: &mut Client) {
+    send(client, &SyncMessage::PullRequest { since_version: 0, cursor: None, limit: None }).await;
+    assert!(matches!(recv(client).await, SyncMessage::PullResponse { .. }));
+}
+
+async fn set_presence(client: &mut Client, note: &Note, activity: NoteActivity) {
+    send(client, &SyncMessage::SetPresence { note_id: note.id.clone(), activity }).await;
+}
+
+/// Devices listed by the next `PresenceUpdate`, as (username, typing)
+async fn presence(client: &mut Client, note: &Note) -> Vec<(String, bool)> {
+    match recv(client).await {
+        SyncMessage::PresenceUpdate { note_id, present } => {
+            assert_eq!(note_id, note.id);
+            present
+                .into_iter()
+                .map(|NotePresence { username, typing, .. }| (username, typing))
+                .collect()
+        }
+        other => panic!("Expected PresenceUpdate, got {other:?}"),
+    }
+}
+
+async fn share_with_bob(server: &TestServer, note: &mut Note) {
+    let request =
+        authorized_json(Method::POST, "/collections", &server.token, json!({ "name": "Plans" }));
+    let (status, body) = call(server, request).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let collection = body["id"].as_str().unwrap().to_string();
+    let uri = format!("/collections/{collection}/members");
+    let body = json!({ "username": "bob", "role": "editor" });
+    let (status, _) = call(server, authorized_json(Method::POST, &uri, &server.token, body)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    note.collection = Some(collection);
+}
+
+#[tokio::test]
+async fn test_teammates_see_who_has_a_shared_note_open() {
+    let server = spawn_server().await;
+    let bob = create_account(&server.state, "bob");
+    let mut alice = connect(&server).await;
+    let mut bob_socket = connect_with_token(server.addr, Some(&bob.token)).await.unwrap();
+    pull(&mut bob_socket).await;
+
+    let mut plan = Note::new("Q3 plan".into(), "".into(), None);
+    share_with_bob(&server, &mut plan).await;
+    push(&mut alice, &plan).await;
+    assert!(matches!(recv(&mut bob_socket).await, SyncMessage::PullResponse { .. }));
+
+    // Opening is answered with who is there, and announced to the others
+    set_presence(&mut alice, &plan, NoteActivity::Opened).await;
+    assert_eq!(presence(&mut alice, &plan).await, vec![("alice".into(), false)]);
+    match recv(&mut bob_socket).await {
+        // Bob sees an id for Alice's device, not her session
+        SyncMessage::PresenceUpdate { present, .. } => {
+            let session_id =
+                server.state.accounts.lock().unwrap().list_sessions(&server.user_id).unwrap()[0]
+                    .id
+                    .clone();
+            assert_eq!(present.len(), 1);
+            assert_eq!(present[0].device, presence_device_id(&session_id, &server.user_id, &plan));
+            assert_ne!(present[0].device, session_id);
+            assert_eq!(present[0].device_name, "Test device");
+        }
+        other => panic!("Expected PresenceUpdate, got {other:?}"),
+    }
+
+    set_presence(&mut bob_socket, &plan, NoteActivity::Opened).await;
+    let both = vec![("alice".to_string(), false), ("bob".to_string(), false)];
+    assert_eq!(presence(&mut bob_socket, &plan).await, both);
+    assert_eq!(presence(&mut alice, &plan).await, both);
+
+    set_presence(&mut bob_socket, &plan, NoteActivity::Typing).await;
+    let typing = vec![("alice".to_string(), false), ("bob".to_string(), true)];
+    assert_eq!(presence(&mut alice, &plan).await, typing);
+    // Renewing without a visible change tells nobody
+    set_presence(&mut bob_socket, &plan, NoteActivity::Typing).await;
+    assert!(try_recv(&mut alice, Duration::from_millis(200)).await.is_none());
+
+    set_presence(&mut alice, &plan, NoteActivity::Closed).await;
+    assert_eq!(presence(&mut bob_socket, &plan).await, vec![("bob".into(), true)]);
+
+    // Disconnecting closes everything the socket had open
+    set_presence(&mut alice, &plan, NoteActivity::Opened).await;
+    presence(&mut alice, &plan).await;
+    presence(&mut bob_socket, &plan).await;
+    drop(bob_socket);
+    assert_eq!(presence(&mut alice, &plan).await, vec![("alice".into(), false)]);
+}
+
+#[tokio::test]
+async fn test_private_notes_stay_private() {
+    let server = spawn_server().await;
+    let bob = create_account(&server.state, "bob");
+    let mut laptop = connect(&server).await;
+    let mut phone = connect(&server).await;
+    let mut bob_socket = connect_with_token(server.addr, Some(&bob.token)).await.unwrap();
+    pull(&mut phone).await;
+    pull(&mut bob_socket).await;
+
+    let diary = Note::new("Diary".into(), "".into(), None);
+    push(&mut laptop, &diary).await;
+    assert!(matches!(recv(&mut phone).await, SyncMessage::PullResponse { .. }));
+
+    // Bob has no copy of the note, so his announcement goes nowhere
+    set_presence(&mut bob_socket, &diary, NoteActivity::Opened).await;
+    assert!(try_recv(&mut bob_socket, Duration::from_millis(200)).await.is_none());
+
+    set_presence(&mut laptop, &diary, NoteActivity::Opened).await;
+    assert_eq!(presence(&mut laptop, &diary).await, vec![("alice".into(), false)]);
+    assert_eq!(presence(&mut phone, &diary).await, vec![("alice".into(), false)]);
+    assert!(try_recv(&mut bob_socket, Duration::from_millis(200)).await.is_none());
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use common::{connect, recv, send, spawn_server};
+use notaro_core::sync::{continue_reconcile, start_reconcile};
+use notaro_core::{DatabaseConnection, SyncFilter, SyncMessage};
+
+#[tokio::test]
+async fn test_reconcile_repairs_rolled_back_replica() {
+    let server = spawn_server().await;
+    let mut client_db = DatabaseConnection::new(":memory:").unwrap();
+
+    // The client synced everything, then was restored from a backup taken halfway through
+    {
+        let server_db = server.db();
+        let server_db = server_db.lock().unwrap();
+        for i in 0..50 {
+            server_db.create_note(format!("Note {i}"), "Body".into(), None).unwrap();
+            if i == 24 {
+                client_db.merge_changes(server_db.get_changes_since(0).unwrap()).unwrap();
+            }
+        }
+    }
+    let offline = client_db.create_note("Offline".into(), "Body".into(), None).unwrap();
+
+    // Its cursor claims it is up to date, so a plain pull would find nothing to repair
+    let all = SyncFilter::default();
+    let mut client = connect(&server).await;
+    let mut request = start_reconcile(&client_db, &all).unwrap();
+    loop {
+        send(&mut client, &request).await;
+        let SyncMessage::ReconcileResponse { digests, leaves, notes } = recv(&mut client).await
+        else {
+            panic!("Expected ReconcileResponse");
+        };
+
+        let step = continue_reconcile(&mut client_db, &all, &digests, &leaves, notes).unwrap();
+        if !step.push.is_empty() {
+            send(&mut client, &SyncMessage::PushUpdates { changes: step.push, deltas: vec![] })
+                .await;
+            assert_eq!(recv(&mut client).await, SyncMessage::Ack);
+        }
+        match step.next {
+            Some(next) => request = next,
+            None => break,
+        }
+    }
+
+    let server_db = server.db();
+    let server_db = server_db.lock().unwrap();
+    assert_eq!(client_db.get_all_notes().unwrap().len(), 51);
+    assert_eq!(server_db.get_note_by_id(&offline.id).unwrap().title, "Offline");
+    assert_eq!(
+        client_db.merkle_tree(&all).unwrap().root(),
+        server_db.merkle_tree(&all).unwrap().root()
+    );
+}
@@ -0,0 +1,291 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode};
+use common::{
+    TestServer, authorized, authorized_json, call, connect, create_account, recv, send,
+    spawn_configured, spawn_server,
+};
+use notaro_core::{Note, SyncMessage};
+use notaro_server::Config;
+use notaro_server::config::Limits;
+use serde_json::{Value, json};
+
+/// The OpenAPI document as served, to check answers against
+async fn document(server: &TestServer) -> Value {
+    let (status, doc) =
+        call(server, Request::get("/v1/openapi.json").body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::OK);
+    doc
+}
+
+/// Sends a request for the operation at `path` (as written in the document) and checks the answer
+/// against the document: its status must be documented, and a JSON body must match the schema
+/// documented for that status.
+async fn checked(
+    server: &TestServer,
+    doc: &Value,
+    path: &str,
+    request: Request<Body>,
+) -> (StatusCode, Value) {
+    let method = request.method().as_str().to_lowercase();
+    let (status, body) = call(server, request).await;
+    let operation = &doc["paths"][path][&method];
+    assert!(operation.is_object(), "{method} {path} is not documented");
+    let response = &operation["responses"][status.as_str()];
+    assert!(response.is_object(), "{method} {path} answered an undocumented {status}: {body}");
+    match response["content"]["application/json"].get("schema") {
+        Some(schema) => {
+            if let Err(e) = conforms(doc, schema, &body) {
+                panic!("{method} {path} answered {status} off schema at {e}: {body}");
+            }
+        }
+        None => assert_eq!(body, Value::Null, "{method} {path} answered an undocumented body"),
+    }
+    (status, body)
+}
+
+/// Checks `value` against the subset of JSON Schema the document uses
+fn conforms(doc: &Value, schema: &Value, value: &Value) -> Result<(), String> {
+    if let Some(reference) = schema["$ref"].as_str() {
+        let name = reference.strip_prefix("#/components/schemas/").unwrap();
+        let resolved = &doc["components"]["schemas"][name];
+        assert!(resolved.is_object(), "dangling reference {reference}");
+        return conforms(doc, resolved, value).map_err(|e| format!("{name}{e}"));
+    }
+    if let Some(options) = schema["oneOf"].as_array()
+        && !options.iter().any(|option| conforms(doc, option, value).is_ok())
+    {
+        return Err(": matches none of oneOf".to_string());
+    }
+    for part in schema["allOf"].as_array().into_iter().flatten() {
+        conforms(doc, part, value)?;
+    }
+    if let Some(allowed) = schema["enum"].as_array()
+        && !allowed.contains(value)
+    {
+        return Err(format!(": {value} is not one of {allowed:?}"));
+    }
+
+    let types: Vec<&str> = match &schema["type"] {
+        Value::String(ty) => vec![ty.as_str()],
+        Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+        _ => return Ok(()),
+    };
+    let typed = types.iter().any(|ty| match *ty {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        other => panic!("unexpected schema type {other}"),
+    });
+    if !typed {
+        return Err(format!(": expected {types:?}"));
+    }
+
+    if let Some(items) = value.as_array() {
+        for (i, item) in items.iter().enumerate() {
+            conforms(doc, &schema["items"], item).map_err(|e| format!("[{i}]{e}"))?;
+        }
+    }
+    if let Some(fields) = value.as_object() {
+        for required in schema["required"].as_array().into_iter().flatten() {
+            let required = required.as_str().unwrap();
+            if !fields.contains_key(required) {
+                return Err(format!(".{required}: missing"));
+            }
+        }
+        for (name, field) in fields {
+            let property = match schema["properties"].get(name) {
+                Some(property) => property,
+                None => match &schema["additionalProperties"] {
+                    Value::Bool(false) => return Err(format!(".{name}: not documented")),
+                    Value::Object(_) => &schema["additionalProperties"],
+                    _ if schema["properties"].is_object() => {
+                        return Err(format!(".{name}: not documented"));
+                    }
+                    _ => continue,
+                },
+            };
+            conforms(doc, property, field).map_err(|e| format!(".{name}{e}"))?;
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_document_covers_every_endpoint() {
+    let server = spawn_server().await;
+    let doc = document(&server).await;
+    assert_eq!(doc, serde_json::to_value(notaro_server::rest::openapi()).unwrap());
+
+    let mut operations: Vec<String> = doc["paths"]
+        .as_object()
+        .unwrap()
+        .iter()
+        .flat_map(|(path, item)| {
+            item.as_object().unwrap().keys().map(move |m| format!("{m} {path}"))
+        })
+        .collect();
+    operations.sort();
+    assert_eq!(
+        operations,
+        [
+            "delete /v1/notes/{id}",
+            "get /v1/changes",
+            "get /v1/notes/{id}",
+            "post /v1/changes",
+            "put /v1/notes/{id}",
+        ]
+    );
+    assert_eq!(doc["components"]["securitySchemes"]["bearer"]["scheme"], "bearer");
+
+    // Requests without a token are refused the documented way
+    let request = Request::get("/v1/changes").body(Body::empty()).unwrap();
+    assert_eq!(checked(&server, &doc, "/v1/changes", request).await.0, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_note_writes_over_http_reach_connected_devices() {
+    let server = spawn_server().await;
+    let doc = document(&server).await;
+    let mut socket = connect(&server).await;
+    send(&mut socket, &SyncMessage::PullRequest { since_version: 0, cursor: None, limit: None })
+        .await;
+    recv(&mut socket).await;
+
+    let note = |method: Method, body: Value| {
+        authorized_json(method, "/v1/notes/groceries", &server.token, body)
+    };
+    let path = "/v1/notes/{id}";
+    let body = json!({ "title": "Groceries", "content": "Milk", "is_pinned": true });
+    let (status, created) = checked(&server, &doc, path, note(Method::PUT, body)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(
+        (created["version"].as_i64(), created["is_pinned"].as_bool()),
+        (Some(1), Some(true))
+    );
+    match recv(&mut socket).await {
+        SyncMessage::PullResponse { changes, .. } => assert_eq!(changes[0].content, "Milk"),
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+
+    // An edit from the socket and one over HTTP build on each other
+    let stored: Note = serde_json::from_value(created).unwrap();
+    let mut edited = stored.clone();
+    edited.content = "Milk, eggs".into();
+    edited.version = 2;
+    edited.version_vector.increment("laptop");
+    send(&mut socket, &SyncMessage::PushUpdates { changes: vec![edited], deltas: vec![] }).await;
+    assert_eq!(recv(&mut socket).await, SyncMessage::Ack);
+
+    let body = json!({ "title": "Groceries", "content": "Milk, eggs, bread" });
+    let (status, replaced) = checked(&server, &doc, path, note(Method::PUT, body)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(replaced["version"], 3);
+    let (_, fetched) =
+        checked(&server, &doc, path, authorized(Method::GET, "/v1/notes/groceries", &server.token))
+            .await;
+    assert_eq!(fetched, replaced);
+    match recv(&mut socket).await {
+        SyncMessage::PullResponse { changes, .. } => {
+            assert_eq!(changes[0].content, "Milk, eggs, bread")
+        }
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+
+    let delete = || authorized(Method::DELETE, "/v1/notes/groceries", &server.token);
+    assert_eq!(checked(&server, &doc, path, delete()).await.0, StatusCode::NO_CONTENT);
+    match recv(&mut socket).await {
+        SyncMessage::PullResponse { changes, .. } => assert!(changes[0].is_deleted),
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+    assert_eq!(checked(&server, &doc, path, delete()).await.0, StatusCode::NOT_FOUND);
+    let get = authorized(Method::GET, "/v1/notes/groceries", &server.token);
+    assert_eq!(checked(&server, &doc, path, get).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_changes_feed_pages_and_pushes_like_the_socket() {
+    let server = spawn_server().await;
+    let doc = document(&server).await;
+    let path = "/v1/changes";
+
+    let first = Note::new("First".into(), "1".into(), None);
+    let second = Note::new("Second".into(), "2".into(), None);
+    let mut foreign = Note::new("Elsewhere".into(), "".into(), None);
+    foreign.collection = Some("not-mine".into());
+    let body = json!({ "changes": [first, second, foreign] });
+    let request = authorized_json(Method::POST, path, &server.token, body);
+    let (status, result) = checked(&server, &doc, path, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(result["rejected"].as_array().unwrap().len(), 1);
+    assert_eq!(result["rejected"][0]["id"], foreign.id.as_str());
+
+    // Two pages of one note each, then nothing new
+    let mut seen = Vec::new();
+    let mut uri = format!("{path}?since=0&limit=1");
+    let current_version = loop {
+        let (status, page) =
+            checked(&server, &doc, path, authorized(Method::GET, &uri, &server.token)).await;
+        assert_eq!(status, StatusCode::OK);
+        seen.extend(page["changes"].as_array().unwrap().iter().map(|n| n["title"].clone()));
+        if page["has_more"] == false {
+            break page["current_version"].as_i64().unwrap();
+        }
+        let cursor = page["next_cursor"].as_str().unwrap();
+        uri = format!("{path}?since=0&limit=1&cursor={cursor}");
+    };
+    // Notes of the same version come in id order
+    seen.sort_by_key(|title| title.to_string());
+    assert_eq!(seen, [json!("First"), json!("Second")]);
+    let uri = format!("{path}?since={current_version}");
+    let (_, page) =
+        checked(&server, &doc, path, authorized(Method::GET, &uri, &server.token)).await;
+    assert_eq!(page["changes"], json!([]));
+
+    let uri = format!("{path}?cursor=nonsense");
+    let (status, _) =
+        checked(&server, &doc, path, authorized(Method::GET, &uri, &server.token)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_http_writes_respect_roles_and_limits() {
+    let limits = Limits { max_notes: Some(1), max_note_size: 64, ..Default::default() };
+    let server = spawn_configured(Config { limits, ..Default::default() }).await;
+    let doc = document(&server).await;
+    let path = "/v1/notes/{id}";
+    let bob = create_account(&server.state, "bob");
+
+    let request =
+        authorized_json(Method::POST, "/collections", &server.token, json!({ "name": "Runbooks" }));
+    let collection = call(&server, request).await.1["id"].as_str().unwrap().to_string();
+    let uri = format!("/collections/{collection}/members");
+    let invite = json!({ "username": "bob", "role": "viewer" });
+    call(&server, authorized_json(Method::POST, &uri, &server.token, invite)).await;
+
+    let body = json!({ "title": "Restart", "content": "", "collection": collection });
+    let request = authorized_json(Method::PUT, "/v1/notes/restart", &bob.token, body);
+    let (status, error) = checked(&server, &doc, path, request).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(error["error"].as_str().unwrap().contains("you can only view"), "{error}");
+
+    let put = |id: &str, content: String| {
+        let uri = format!("/v1/notes/{id}");
+        authorized_json(
+            Method::PUT,
+            &uri,
+            &server.token,
+            json!({ "title": id, "content": content }),
+        )
+    };
+    let (status, error) = checked(&server, &doc, path, put("long", "x".repeat(100))).await;
+    assert_eq!((status, &error["limit"]), (StatusCode::PAYLOAD_TOO_LARGE, &json!("note_size")));
+    assert_eq!(checked(&server, &doc, path, put("one", "".into())).await.0, StatusCode::CREATED);
+    let (status, error) = checked(&server, &doc, path, put("two", "".into())).await;
+    assert_eq!((status, &error["limit"]), (StatusCode::INSUFFICIENT_STORAGE, &json!("note_count")));
+}
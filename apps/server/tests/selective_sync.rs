@@ -0,0 +1,80 @@
+mod common;
+
+use common::{connect, connect_filtered, recv, send, spawn_server, try_recv};
+use notaro_core::{Note, SyncFilter, SyncMessage};
+use std::time::Duration;
+
+fn work_only() -> SyncFilter {
+    SyncFilter { include_folders: vec!["Work".into()], ..Default::default() }
+}
+
+async fn push(client: &mut common::Client, note: &Note) {
+    send(client, &SyncMessage::PushUpdates { changes: vec![note.clone()], deltas: vec![] }).await;
+    assert_eq!(recv(client).await, SyncMessage::Ack);
+}
+
+#[tokio::test]
+async fn test_fanout_follows_subscription() {
+    let server = spawn_server().await;
+    let mut laptop = connect(&server).await;
+    let mut work_laptop = connect_filtered(&server, work_only()).await;
+
+    // Personal notes never reach the work laptop; at most it is told to drop its copy
+    let diary = Note::new("Diary".into(), "Dear diary".into(), Some("Personal".into()));
+    push(&mut laptop, &diary).await;
+    match recv(&mut work_laptop).await {
+        SyncMessage::PullResponse { changes, evicted, .. } => {
+            assert!(changes.is_empty());
+            assert_eq!(evicted, vec![diary.id.clone()]);
+        }
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+
+    let mut standup = Note::new("Standup".into(), "Notes".into(), Some("Work".into()));
+    push(&mut laptop, &standup).await;
+    match recv(&mut work_laptop).await {
+        SyncMessage::PullResponse { changes, evicted, .. } => {
+            assert_eq!(changes, vec![standup.clone()]);
+            assert!(evicted.is_empty());
+        }
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+
+    // Moving a note out of the subscription evicts it
+    standup.folder = Some("Personal".into());
+    standup.version = 2;
+    push(&mut laptop, &standup).await;
+    match recv(&mut work_laptop).await {
+        SyncMessage::PullResponse { changes, evicted, .. } => {
+            assert!(changes.is_empty());
+            assert_eq!(evicted, vec![standup.id.clone()]);
+        }
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+    assert!(try_recv(&mut work_laptop, Duration::from_millis(200)).await.is_none());
+}
+
+#[tokio::test]
+async fn test_pull_only_returns_subscribed_notes() {
+    let server = spawn_server().await;
+    {
+        let db = server.db();
+        let db = db.lock().unwrap();
+        db.create_note("Standup".into(), "".into(), Some("Work".into())).unwrap();
+        db.create_note("Diary".into(), "".into(), Some("Personal".into())).unwrap();
+        db.create_note("Recipe".into(), "Share with the team #work".into(), None).unwrap();
+    }
+
+    let filter = SyncFilter { include_tags: vec!["work".into()], ..work_only() };
+    let mut client = connect_filtered(&server, filter).await;
+    send(&mut client, &SyncMessage::PullRequest { since_version: 0, cursor: None, limit: None })
+        .await;
+    match recv(&mut client).await {
+        SyncMessage::PullResponse { changes, .. } => {
+            let mut titles: Vec<String> = changes.into_iter().map(|n| n.title).collect();
+            titles.sort();
+            assert_eq!(titles, vec!["Recipe", "Standup"]);
+        }
+        other => panic!("Expected PullResponse, got {other:?}"),
+    }
+}
@@ -0,0 +1,35 @@
+mod common;
+
+use common::{connect, recv, send, spawn_server};
+use notaro_core::{DatabaseConnection, SyncMessage, UserSettings};
+
+#[tokio::test]
+async fn test_settings_follow_the_user() {
+    let server = spawn_server().await;
+    let laptop_db = DatabaseConnection::with_device_id(":memory:", "laptop").unwrap();
+    let desktop_db = DatabaseConnection::with_device_id(":memory:", "desktop").unwrap();
+    let mut laptop = connect(&server).await;
+    let mut desktop = connect(&server).await;
+
+    // The desktop announces its (empty) settings so the session is registered
+    send(&mut desktop, &SyncMessage::SettingsUpdate { entries: vec![] }).await;
+    assert_eq!(recv(&mut desktop).await, SyncMessage::SettingsUpdate { entries: vec![] });
+
+    let settings = UserSettings { theme_mode: "dark".into(), font_size: 20, ..Default::default() };
+    laptop_db.update_settings(&settings).unwrap();
+    let entries = laptop_db.get_synced_settings().unwrap();
+    send(&mut laptop, &SyncMessage::SettingsUpdate { entries: entries.clone() }).await;
+    assert_eq!(recv(&mut laptop).await, SyncMessage::SettingsUpdate { entries: entries.clone() });
+
+    match recv(&mut desktop).await {
+        SyncMessage::SettingsUpdate { entries: forwarded } => {
+            assert_eq!(forwarded, entries);
+            desktop_db.merge_settings(forwarded).unwrap();
+        }
+        other => panic!("Expected SettingsUpdate, got {other:?}"),
+    }
+
+    let on_desktop = desktop_db.get_settings().unwrap();
+    assert_eq!(on_desktop.theme_mode, "dark");
+    assert_eq!(on_desktop.font_size, UserSettings::default().font_size);
+}
@@ -0,0 +1,227 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{HeaderMap, Method, Request, StatusCode, header};
+use chrono::{Duration, Utc};
+use common::{TestServer, authorized, authorized_json, call, connect, recv, send, spawn_server};
+use http_body_util::BodyExt;
+use notaro_core::{Note, SyncMessage};
+use serde_json::{Value, json};
+use tower::ServiceExt;
+
+/// Sends a request through the router and returns the status, headers and body as text
+async fn fetch(server: &TestServer, request: Request<Body>) -> (StatusCode, HeaderMap, String) {
+    let response = notaro_server::router(server.state.clone()).oneshot(request).await.unwrap();
+    let (parts, body) = response.into_parts();
+    let body = body.collect().await.unwrap().to_bytes();
+    (parts.status, parts.headers, String::from_utf8(body.to_vec()).unwrap())
+}
+
+fn get(uri: &str) -> Request<Body> {
+    Request::get(uri).body(Body::empty()).unwrap()
+}
+
+async fn share(server: &TestServer, note_id: &str, body: Value) -> Value {
+    let uri = format!("/notes/{note_id}/shares");
+    let (status, body) =
+        call(server, authorized_json(Method::POST, &uri, &server.token, body)).await;
+    assert_eq!(status, StatusCode::CREATED, "{body}");
+    body
+}
+
+/// Reads server-sent events off a response body
+struct Events {
+    body: Body,
+    buffer: String,
+}
+
+impl Events {
+    async fn open(server: &TestServer, path: &str) -> Self {
+        let uri = format!("{path}/events");
+        let response =
+            notaro_server::router(server.state.clone()).oneshot(get(&uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        Self { body: response.into_body(), buffer: String::new() }
+    }
+
+    /// The next event's name and data, skipping keep-alive comments
+    async fn next(&mut self) -> (String, String) {
+        loop {
+            if let Some(end) = self.buffer.find("\n\n") {
+                let block: String = self.buffer.drain(..end + 2).collect();
+                let field = |name: &str| {
+                    block.lines().find_map(|line| line.strip_prefix(name)).map(str::to_string)
+                };
+                if let (Some(event), Some(data)) = (field("event: "), field("data: ")) {
+                    return (event, data);
+                }
+                continue;
+            }
+            let frame = tokio::time::timeout(std::time::Duration::from_secs(5), self.body.frame())
+                .await
+                .expect("timed out waiting for an event")
+                .expect("event stream ended")
+                .unwrap();
+            if let Ok(data) = frame.into_data() {
+                self.buffer.push_str(std::str::from_utf8(&data).unwrap());
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_share_link_renders_sanitized_html_until_revoked() {
+    let server = spawn_server().await;
+    let content =
+        "## Steps\n\n1. Build\n\n<script>alert(1)</script>\n\n[docs](javascript:alert(2))";
+    let note =
+        server.db().lock().unwrap().create_note("Deploy".into(), content.into(), None).unwrap();
+
+    let link = share(&server, &note.id, json!({})).await;
+    assert_eq!(link["has_password"], false);
+    let path = link["path"].as_str().unwrap().to_string();
+    assert_eq!(path, format!("/s/{}", link["token"].as_str().unwrap()));
+
+    let (status, headers, page) = fetch(&server, get(&path)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(page.contains("<title>Deploy</title>") && page.contains("<h2>Steps</h2>"), "{page}");
+    assert!(!page.contains("alert("), "{page}");
+    assert_eq!(headers[header::REFERRER_POLICY], "no-referrer");
+    assert!(headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap().contains("'nonce-"));
+
+    // Listing never shows the token again
+    let (status, links) = call(&server, authorized(Method::GET, "/shares", &server.token)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(links.as_array().unwrap().len(), 1);
+    assert_eq!(links[0]["note_id"], note.id.as_str());
+    assert!(links[0].get("token").is_none());
+
+    let request = authorized_json(Method::POST, "/notes/missing/shares", &server.token, json!({}));
+    assert_eq!(call(&server, request).await.0, StatusCode::NOT_FOUND);
+
+    let uri = format!("/shares/{}", link["id"].as_str().unwrap());
+    let (status, _) = call(&server, authorized(Method::DELETE, &uri, &server.token)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = call(&server, authorized(Method::DELETE, &uri, &server.token)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(fetch(&server, get(&path)).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(fetch(&server, get("/s/not-a-token")).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_password_protected_link_needs_the_password_once() {
+    let server = spawn_server().await;
+    let note =
+        server.db().lock().unwrap().create_note("Secret".into(), "Hunter2".into(), None).unwrap();
+
+    let uri = format!("/notes/{}/shares", note.id);
+    let past = json!({ "expires_at": Utc::now() - Duration::hours(1) });
+    let request = authorized_json(Method::POST, &uri, &server.token, past);
+    assert_eq!(call(&server, request).await.0, StatusCode::BAD_REQUEST);
+
+    let expires_at = Utc::now() + Duration::hours(1);
+    let link =
+        share(&server, &note.id, json!({ "password": "open sesame", "expires_at": expires_at }))
+            .await;
+    assert_eq!(link["has_password"], true);
+    let path = link["path"].as_str().unwrap();
+
+    let (status, _, page) = fetch(&server, get(path)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(page.contains("type=\"password\"") && !page.contains("Hunter2"));
+    let events = fetch(&server, get(&format!("{path}/events"))).await;
+    assert_eq!(events.0, StatusCode::UNAUTHORIZED);
+
+    let unlock = |password: &str| {
+        Request::post(path)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(format!("password={password}")))
+            .unwrap()
+    };
+    let (status, _, page) = fetch(&server, unlock("open+barley")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(page.contains("Wrong password."));
+
+    let (status, headers, _) = fetch(&server, unlock("open+sesame")).await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(headers[header::LOCATION], path);
+    let set_cookie = headers[header::SET_COOKIE].to_str().unwrap();
+    assert!(set_cookie.contains(&format!("Path={path}")) && set_cookie.contains("HttpOnly"));
+    let cookie = set_cookie.split(';').next().unwrap();
+
+    let request = Request::get(path).header(header::COOKIE, cookie).body(Body::empty()).unwrap();
+    let (status, _, page) = fetch(&server, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(page.contains("Hunter2"));
+}
+
+#[tokio::test]
+async fn test_password_guesses_are_rate_limited_per_link() {
+    let server = spawn_server().await;
+    let db = server.db();
+    let secret = db.lock().unwrap().create_note("Secret".into(), "Hunter2".into(), None).unwrap();
+    let other = db.lock().unwrap().create_note("Other".into(), "".into(), None).unwrap();
+    let link = share(&server, &secret.id, json!({ "password": "open sesame" })).await;
+    let other_link = share(&server, &other.id, json!({ "password": "open sesame" })).await;
+    let unlock = |path: &str, password: &str| {
+        Request::post(path)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(format!("password={password}")))
+            .unwrap()
+    };
+
+    let path = link["path"].as_str().unwrap();
+    for _ in 0..5 {
+        assert_eq!(fetch(&server, unlock(path, "guess")).await.0, StatusCode::UNAUTHORIZED);
+    }
+    // Even the right password waits once the link's attempts are used up
+    let (status, headers, page) = fetch(&server, unlock(path, "open+sesame")).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(headers.contains_key(header::RETRY_AFTER));
+    assert!(page.contains("Too many attempts."), "{page}");
+
+    // Other links keep their own attempts
+    let other_path = other_link["path"].as_str().unwrap();
+    assert_eq!(fetch(&server, unlock(other_path, "open+sesame")).await.0, StatusCode::SEE_OTHER);
+}
+
+#[tokio::test]
+async fn test_open_pages_follow_the_note_live() {
+    let server = spawn_server().await;
+    let mut socket = connect(&server).await;
+    let note = Note::new("Status".into(), "All *green*".into(), None);
+    send(&mut socket, &SyncMessage::PushUpdates { changes: vec![note.clone()], deltas: vec![] })
+        .await;
+    assert_eq!(recv(&mut socket).await, SyncMessage::Ack);
+
+    let link = share(&server, &note.id, json!({})).await;
+    let mut events = Events::open(&server, link["path"].as_str().unwrap()).await;
+    let (event, data) = events.next().await;
+    assert_eq!(event, "update");
+    let rendered: Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(rendered, json!({ "title": "Status", "html": "<p>All <em>green</em></p>\n" }));
+
+    // An edit synced from any device reaches the page
+    let mut edited = note.clone();
+    edited.content = "Partial **outage**".into();
+    edited.version = 2;
+    send(&mut socket, &SyncMessage::PushUpdates { changes: vec![edited], deltas: vec![] }).await;
+    assert_eq!(recv(&mut socket).await, SyncMessage::Ack);
+    let (event, data) = events.next().await;
+    assert_eq!(event, "update");
+    let rendered: Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(rendered["html"], "<p>Partial <strong>outage</strong></p>\n");
+
+    let uri = format!("/shares/{}", link["id"].as_str().unwrap());
+    call(&server, authorized(Method::DELETE, &uri, &server.token)).await;
+    assert_eq!(events.next().await, ("gone".to_string(), "This link was revoked.".to_string()));
+
+    // Pages of an expiring link are told when it runs out
+    let expires_at = Utc::now() + Duration::seconds(1);
+    let link = share(&server, &note.id, json!({ "expires_at": expires_at })).await;
+    let path = link["path"].as_str().unwrap();
+    let mut events = Events::open(&server, path).await;
+    assert_eq!(events.next().await.0, "update");
+    assert_eq!(events.next().await, ("gone".to_string(), "This link has expired.".to_string()));
+    assert_eq!(fetch(&server, get(path)).await.0, StatusCode::GONE);
+}
@@ -0,0 +1,62 @@
+mod common;
+
+use common::{connect_with_token, create_account, recv, send};
+use futures_util::StreamExt;
+use notaro_core::{Note, SyncMessage};
+use notaro_server::accounts::AccountStore;
+use notaro_server::config::Shutdown;
+use notaro_server::{AppState, Config, UserStores};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Starts a server, keeping hold of its task to see when it stops
+async fn start(shutdown: Shutdown) -> (AppState, SocketAddr, JoinHandle<std::io::Result<()>>) {
+    let state = AppState::new(AccountStore::in_memory().unwrap(), UserStores::in_memory())
+        .with_config(Config { shutdown, ..Default::default() });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(notaro_server::serve(listener, state.clone()));
+    (state, addr, server)
+}
+
+#[tokio::test]
+async fn test_sockets_are_told_to_come_back_and_the_server_stops() {
+    let (state, addr, server) = start(Shutdown { deadline_secs: 10, retry_after_ms: 1500 }).await;
+    let login = create_account(&state, "alice");
+    let mut laptop = connect_with_token(addr, Some(&login.token)).await.unwrap();
+    let mut phone = connect_with_token(addr, Some(&login.token)).await.unwrap();
+
+    let note = Note::new("Upgrade".into(), "at noon".into(), None);
+    let push = SyncMessage::PushUpdates { changes: vec![note.clone()], deltas: vec![] };
+    send(&mut laptop, &push).await;
+    assert_eq!(recv(&mut laptop).await, SyncMessage::Ack);
+    assert!(matches!(recv(&mut phone).await, SyncMessage::PullResponse { .. }));
+
+    state.shutdown.begin();
+    for socket in [&mut laptop, &mut phone] {
+        assert_eq!(recv(socket).await, SyncMessage::ServerShutdown { retry_after_ms: 1500 });
+        assert!(matches!(socket.next().await, Some(Ok(Message::Close(_))) | None));
+    }
+
+    let stopped = tokio::time::timeout(Duration::from_secs(5), server).await;
+    stopped.expect("server did not stop").unwrap().unwrap();
+    assert!(connect_with_token(addr, Some(&login.token)).await.is_err());
+    let db = state.user_db(&login.user_id).unwrap();
+    assert_eq!(db.lock().unwrap().get_note_by_id(&note.id).unwrap().content, "at noon");
+}
+
+#[tokio::test]
+async fn test_shutdown_gives_up_waiting_at_the_deadline() {
+    let (state, _, server) = start(Shutdown { deadline_secs: 1, retry_after_ms: 1000 }).await;
+    // Stands in for a connection that never finishes
+    let _stuck = state.shutdown.watch();
+
+    let started = Instant::now();
+    state.shutdown.begin();
+    let stopped = tokio::time::timeout(Duration::from_secs(5), server).await;
+    stopped.expect("server did not stop").unwrap().unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(900), "{:?}", started.elapsed());
+}
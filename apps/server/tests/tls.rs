@@ -0,0 +1,138 @@
+mod common;
+
+use common::create_account;
+use futures_util::{SinkExt, StreamExt};
+use notaro_core::SyncMessage;
+use notaro_core::tls::{ServerTrust, fingerprint};
+use notaro_server::accounts::{AccountStore, Login};
+use notaro_server::config::TlsConfig;
+use notaro_server::tls::Certificates;
+use notaro_server::{AppState, TlsListener, UserStores};
+use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, DnType, IsCa, KeyPair};
+use rustls::pki_types::ServerName;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::{WebSocketStream, client_async};
+use uuid::Uuid;
+
+type TlsClient = WebSocketStream<TlsStream<TcpStream>>;
+
+struct TlsServer {
+    addr: SocketAddr,
+    state: AppState,
+    login: Login,
+    certificates: Arc<Certificates>,
+    paths: TlsConfig,
+    /// Of the certificate the server started with
+    fingerprint: String,
+}
+
+fn test_ca() -> CertifiedIssuer<'static, KeyPair> {
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, "Notaro test CA");
+    CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap()
+}
+
+/// Issues a fresh `localhost` certificate into `paths` and returns its fingerprint
+fn issue(ca: &CertifiedIssuer<'static, KeyPair>, paths: &TlsConfig) -> String {
+    let key = KeyPair::generate().unwrap();
+    let cert =
+        CertificateParams::new(vec!["localhost".to_string()]).unwrap().signed_by(&key, ca).unwrap();
+    std::fs::write(&paths.cert_path, cert.pem()).unwrap();
+    std::fs::write(&paths.key_path, key.serialize_pem()).unwrap();
+    fingerprint(cert.der())
+}
+
+async fn spawn_tls_server(dir: &Path, ca: &CertifiedIssuer<'static, KeyPair>) -> TlsServer {
+    std::fs::create_dir_all(dir).unwrap();
+    let paths = TlsConfig { cert_path: dir.join("cert.pem"), key_path: dir.join("key.pem") };
+    let fingerprint = issue(ca, &paths);
+
+    let state = AppState::new(AccountStore::in_memory().unwrap(), UserStores::in_memory());
+    let login = create_account(&state, "alice");
+    let certificates = Certificates::load(paths.clone()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let listener = TlsListener::new(listener, certificates.clone()).unwrap();
+    tokio::spawn(notaro_server::serve_tls(listener, state.clone()));
+    TlsServer { addr, state, login, certificates, paths, fingerprint }
+}
+
+async fn connect_tls(server: &TlsServer, trust: ServerTrust) -> Result<TlsClient, String> {
+    let connector = TlsConnector::from(trust.client_config().map_err(|e| e.to_string())?);
+    let tcp = TcpStream::connect(server.addr).await.unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    let tls = connector.connect(name, tcp).await.map_err(|e| e.to_string())?;
+
+    let mut request =
+        format!("wss://localhost:{}/sync", server.addr.port()).into_client_request().unwrap();
+    let bearer = HeaderValue::from_str(&format!("Bearer {}", server.login.token)).unwrap();
+    request.headers_mut().insert(AUTHORIZATION, bearer);
+    let (client, _) = client_async(request, tls).await.map_err(|e| e.to_string())?;
+    Ok(client)
+}
+
+async fn pull(client: &mut TlsClient) -> SyncMessage {
+    let request = SyncMessage::PullRequest { since_version: 0, cursor: None, limit: None };
+    client.send(Message::Text(serde_json::to_string(&request).unwrap().into())).await.unwrap();
+    match client.next().await {
+        Some(Ok(Message::Text(text))) => serde_json::from_str(&text).unwrap(),
+        other => panic!("Expected a text frame, got {other:?}"),
+    }
+}
+
+fn temp_dir() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("notaro-tls-{}", Uuid::new_v4()))
+}
+
+#[tokio::test]
+async fn test_clients_trust_a_custom_ca_or_a_pinned_certificate() {
+    let ca = test_ca();
+    let server = spawn_tls_server(&temp_dir(), &ca).await;
+
+    let mut client = connect_tls(&server, ServerTrust::CustomCa(ca.pem())).await.unwrap();
+    assert!(matches!(pull(&mut client).await, SyncMessage::PullResponse { .. }));
+
+    // Device sessions still record where TLS clients connect from
+    let sessions =
+        server.state.accounts.lock().unwrap().list_sessions(&server.login.user_id).unwrap();
+    assert_eq!(sessions[0].last_ip.as_deref(), Some("127.0.0.1"));
+
+    let err = connect_tls(&server, ServerTrust::PublicRoots).await.unwrap_err();
+    assert!(err.contains("UnknownIssuer"), "{err}");
+
+    let pinned = ServerTrust::Fingerprint(server.fingerprint.clone());
+    assert!(connect_tls(&server, pinned).await.is_ok());
+    let wrong = ServerTrust::Fingerprint(fingerprint(b"another certificate"));
+    let err = connect_tls(&server, wrong).await.unwrap_err();
+    assert!(err.contains("pinned fingerprint"), "{err}");
+}
+
+#[tokio::test]
+async fn test_reload_serves_the_renewed_certificate() {
+    let ca = test_ca();
+    let server = spawn_tls_server(&temp_dir(), &ca).await;
+    let old = server.fingerprint.clone();
+    let mut open = connect_tls(&server, ServerTrust::Fingerprint(old.clone())).await.unwrap();
+
+    let renewed = issue(&ca, &server.paths);
+    server.certificates.reload().unwrap();
+    assert!(connect_tls(&server, ServerTrust::Fingerprint(old)).await.is_err());
+    assert!(connect_tls(&server, ServerTrust::Fingerprint(renewed.clone())).await.is_ok());
+    // Connections made before the reload carry on
+    assert!(matches!(pull(&mut open).await, SyncMessage::PullResponse { .. }));
+
+    // A broken file is rejected and the current certificate stays in use
+    std::fs::write(&server.paths.key_path, "not a key").unwrap();
+    assert!(server.certificates.reload().is_err());
+    assert!(connect_tls(&server, ServerTrust::Fingerprint(renewed)).await.is_ok());
+}
@@ -0,0 +1,225 @@
+mod common;
+
+use axum::Router;
+use axum::extract::State;
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::routing::post;
+use common::{
+    Client, TestServer, authorized, authorized_json, call, connect, create_account, get_text, recv,
+    send, spawn_configured, spawn_server,
+};
+use notaro_core::{Note, SyncMessage};
+use notaro_server::Config;
+use notaro_server::config::Webhooks;
+use notaro_server::webhooks::{DELIVERY_HEADER, EVENT_HEADER, SIGNATURE_HEADER, sign};
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// A request the receiver got
+struct Received {
+    headers: HeaderMap,
+    body: String,
+}
+
+#[derive(Clone)]
+struct Receiver {
+    /// Statuses to answer with, in order; 200 once they run out
+    statuses: Arc<Mutex<VecDeque<StatusCode>>>,
+    tx: UnboundedSender<Received>,
+}
+
+/// Starts a local HTTP server standing in for a webhook receiver. Returns its URL and the
+/// requests it gets.
+async fn receiver(statuses: &[StatusCode]) -> (String, UnboundedReceiver<Received>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let state = Receiver { statuses: Arc::new(Mutex::new(statuses.iter().copied().collect())), tx };
+    let app = Router::new()
+        .route(
+            "/hook",
+            post(|State(receiver): State<Receiver>, headers: HeaderMap, body: String| async move {
+                let _ = receiver.tx.send(Received { headers, body });
+                receiver.statuses.lock().unwrap().pop_front().unwrap_or(StatusCode::OK)
+            }),
+        )
+        .with_state(state);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    (url, rx)
+}
+
+async fn next_request(rx: &mut UnboundedReceiver<Received>) -> Received {
+    tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("timed out waiting for a delivery")
+        .unwrap()
+}
+
+async fn spawn_with_fast_retries() -> TestServer {
+    // The receivers run on this machine
+    let webhooks = Webhooks {
+        max_attempts: 3,
+        retry_backoff_ms: 50,
+        timeout_secs: 2,
+        allowed_hosts: vec!["127.0.0.1".into()],
+        ..Default::default()
+    };
+    spawn_configured(Config { webhooks, ..Default::default() }).await
+}
+
+async fn subscribe(server: &TestServer, body: Value) -> Value {
+    let request = authorized_json(Method::POST, "/webhooks", &server.token, body);
+    let (status, webhook) = call(server, request).await;
+    assert_eq!(status, StatusCode::CREATED, "{webhook}");
+    webhook
+}
+
+/// Polls a webhook's delivery log until every entry is finished
+async fn finished_deliveries(server: &TestServer, webhook_id: &str) -> Vec<Value> {
+    let uri = format!("/webhooks/{webhook_id}/deliveries");
+    for _ in 0..100 {
+        let (status, log) = call(server, authorized(Method::GET, &uri, &server.token)).await;
+        assert_eq!(status, StatusCode::OK);
+        let log = log.as_array().unwrap().clone();
+        if !log.is_empty() && log.iter().all(|delivery| delivery["status"] != "pending") {
+            return log;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("deliveries of {webhook_id} did not finish");
+}
+
+async fn push(socket: &mut Client, note: &Note) {
+    send(socket, &SyncMessage::PushUpdates { changes: vec![note.clone()], deltas: vec![] }).await;
+    assert_eq!(recv(socket).await, SyncMessage::Ack);
+}
+
+#[tokio::test]
+async fn test_changes_in_matching_folders_are_delivered_signed() {
+    let server = spawn_with_fast_retries().await;
+    let (url, mut requests) = receiver(&[]).await;
+    let webhook = subscribe(&server, json!({ "url": url, "include_folders": ["Docs"] })).await;
+    let secret = webhook["secret"].as_str().unwrap();
+    assert_eq!(webhook["filter"], json!({ "include_folders": ["Docs"] }));
+
+    let request =
+        authorized_json(Method::POST, "/webhooks", &server.token, json!({ "url": "ftp://x/" }));
+    assert_eq!(call(&server, request).await.0, StatusCode::BAD_REQUEST);
+
+    let mut socket = connect(&server).await;
+    let guide = Note::new("Guide".into(), "Install it".into(), Some("Docs".into()));
+    push(&mut socket, &Note::new("Todo".into(), "".into(), Some("Inbox".into()))).await;
+    push(&mut socket, &guide).await;
+
+    let delivery = next_request(&mut requests).await;
+    assert_eq!(delivery.headers[EVENT_HEADER], "note.updated");
+    let payload: Value = serde_json::from_str(&delivery.body).unwrap();
+    assert_eq!(payload["webhook_id"], webhook["id"]);
+    assert_eq!(
+        (&payload["note"]["id"], &payload["note"]["content"]),
+        (&json!(guide.id), &json!("Install it"))
+    );
+    let signature = delivery.headers[SIGNATURE_HEADER].to_str().unwrap();
+    let timestamp: i64 = signature[2..signature.find(',').unwrap()].parse().unwrap();
+    assert_eq!(signature, sign(secret, timestamp, &delivery.body));
+
+    // Pushing a copy the server already has changes nothing and notifies nobody
+    push(&mut socket, &guide).await;
+    let uri = format!("/v1/notes/{}", guide.id);
+    let (status, _) = call(&server, authorized(Method::DELETE, &uri, &server.token)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let deletion = next_request(&mut requests).await;
+    assert_eq!(deletion.headers[EVENT_HEADER], "note.deleted");
+    let payload: Value = serde_json::from_str(&deletion.body).unwrap();
+    assert_eq!(payload["note"]["is_deleted"], true);
+
+    let log = finished_deliveries(&server, webhook["id"].as_str().unwrap()).await;
+    let summary: Vec<_> = log
+        .iter()
+        .map(|d| {
+            (d["event"].as_str().unwrap(), d["status"].as_str().unwrap(), d["attempts"].as_u64())
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [("note.deleted", "delivered", Some(1)), ("note.updated", "delivered", Some(1))]
+    );
+    assert_eq!(log[1]["id"], delivery.headers[DELIVERY_HEADER].to_str().unwrap());
+    assert!(requests.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_failed_deliveries_are_retried_then_given_up() {
+    let server = spawn_with_fast_retries().await;
+    let (url, mut requests) =
+        receiver(&[StatusCode::INTERNAL_SERVER_ERROR, StatusCode::SERVICE_UNAVAILABLE]).await;
+    let flaky = subscribe(&server, json!({ "url": url })).await;
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dead_url = format!("http://{}/hook", closed.local_addr().unwrap());
+    drop(closed);
+    let dead = subscribe(&server, json!({ "url": dead_url })).await;
+
+    let mut socket = connect(&server).await;
+    push(&mut socket, &Note::new("Release".into(), "v2".into(), None)).await;
+
+    // The same delivery is retried until the receiver takes it
+    let ids: Vec<String> = [
+        next_request(&mut requests).await,
+        next_request(&mut requests).await,
+        next_request(&mut requests).await,
+    ]
+    .iter()
+    .map(|r| r.headers[DELIVERY_HEADER].to_str().unwrap().to_string())
+    .collect();
+    assert!(ids.iter().all(|id| *id == ids[0]));
+
+    let log = finished_deliveries(&server, flaky["id"].as_str().unwrap()).await;
+    assert_eq!((&log[0]["status"], &log[0]["attempts"]), (&json!("delivered"), &json!(3)));
+    assert_eq!(log[0]["last_status"], 200);
+    let log = finished_deliveries(&server, dead["id"].as_str().unwrap()).await;
+    assert_eq!((&log[0]["status"], &log[0]["attempts"]), (&json!("failed"), &json!(3)));
+    assert!(log[0]["last_error"].is_string(), "{}", log[0]);
+    let (_, metrics) = get_text(&server, "/metrics").await;
+    assert!(metrics.contains(r#"notaro_webhook_attempts_total{outcome="failed"} 1"#), "{metrics}");
+
+    // Webhooks are private to their owner
+    let bob = create_account(&server.state, "bob");
+    let uri = format!("/webhooks/{}", flaky["id"].as_str().unwrap());
+    let (status, _) = call(&server, authorized(Method::DELETE, &uri, &bob.token)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) =
+        call(&server, authorized(Method::GET, &format!("{uri}/deliveries"), &bob.token)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = call(&server, authorized(Method::DELETE, &uri, &server.token)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (_, webhooks) = call(&server, authorized(Method::GET, "/webhooks", &server.token)).await;
+    assert_eq!(webhooks.as_array().unwrap().len(), 1);
+    assert_eq!(webhooks[0]["id"], dead["id"]);
+    assert!(webhooks[0].get("secret").is_none());
+}
+
+#[tokio::test]
+async fn test_receivers_in_the_servers_network_are_refused() {
+    let server = spawn_server().await;
+    let (url, _requests) = receiver(&[]).await;
+    let private = [
+        url.as_str(),
+        "http://localhost:8080/hook",
+        "http://[::1]/hook",
+        "http://169.254.169.254/latest/meta-data/",
+        "https://10.0.0.7/hook",
+    ];
+    for url in private {
+        let request =
+            authorized_json(Method::POST, "/webhooks", &server.token, json!({ "url": url }));
+        let (status, body) = call(&server, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{url}");
+        assert_eq!(body["error"], "webhook URL must point to a public address", "{url}");
+    }
+    let (_, webhooks) = call(&server, authorized(Method::GET, "/webhooks", &server.token)).await;
+    assert_eq!(webhooks, json!([]));
+}
@@ -0,0 +1,51 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use notaro_core::delta::{NoteDelta, TextDelta};
+use notaro_core::{FrameCodec, Note, SyncMessage};
+use std::hint::black_box;
+
+/// Roughly 1 MB of Markdown-ish text
+fn large_body() -> String {
+    let line = "- [ ] follow up on the sync delta benchmark with realistic content\n";
+    line.repeat(1024 * 1024 / line.len())
+}
+
+fn bench_one_char_edit(c: &mut Criterion) {
+    let base = large_body();
+    let mut target = base.clone();
+    target.insert(base.len() / 2, '!');
+
+    let mut note = Note::new("Big".into(), target.clone(), None);
+    note.version = 2;
+    let delta = NoteDelta::between(1, &base, &note).expect("delta is smaller than the body");
+
+    // Print the wire sizes once so the saving is visible next to the timings
+    let full = FrameCodec::JSON
+        .encode(&SyncMessage::PushUpdates { changes: vec![note.clone()], deltas: vec![] })
+        .unwrap();
+    let small = FrameCodec::JSON
+        .encode(&SyncMessage::PushUpdates { changes: vec![], deltas: vec![delta.clone()] })
+        .unwrap();
+    println!(
+        "1 MB note, one-char edit: full push {} bytes, delta push {} bytes",
+        full.len(),
+        small.len()
+    );
+
+    c.bench_function("diff 1MB one-char edit", |b| {
+        b.iter(|| TextDelta::diff(black_box(&base), black_box(&target)))
+    });
+    c.bench_function("apply 1MB one-char edit", |b| {
+        b.iter(|| delta.apply(black_box(&base)).unwrap())
+    });
+    c.bench_function("encode full 1MB push", |b| {
+        let message = SyncMessage::PushUpdates { changes: vec![note.clone()], deltas: vec![] };
+        b.iter(|| FrameCodec::JSON.encode(black_box(&message)).unwrap())
+    });
+    c.bench_function("encode delta 1MB push", |b| {
+        let message = SyncMessage::PushUpdates { changes: vec![], deltas: vec![delta.clone()] };
+        b.iter(|| FrameCodec::JSON.encode(black_box(&message)).unwrap())
+    });
+}
+
+criterion_group!(benches, bench_one_char_edit);
+criterion_main!(benches);
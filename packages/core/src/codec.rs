@@ -0,0 +1,305 @@
+use crate::error::{NotaroError, Result};
+use crate::models::SyncMessage;
+use flate2::Compression as DeflateLevel;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// Zstd level used for frame compression; favours speed over ratio
+const ZSTD_LEVEL: i32 = 3;
+
+/// How a `SyncMessage` is serialized on the wire
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Json,
+    /// Compact binary encoding (MessagePack with field names kept, so optional fields still work)
+    MessagePack,
+}
+
+/// Optional compression applied to each encoded frame
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    None,
+    Deflate,
+    Zstd,
+}
+
+/// The encoding and compression agreed for one connection.
+/// Negotiated through `SyncMessage::Hello`/`SyncMessage::Welcome`, which are always exchanged as
+/// plain JSON so that both sides can read them before anything is agreed.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCodec {
+    pub encoding: Encoding,
+    pub compression: Compression,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::JSON
+    }
+}
+
+impl FrameCodec {
+    /// Uncompressed JSON, used for the handshake and for peers that never send `Hello`
+    pub const JSON: Self = Self { encoding: Encoding::Json, compression: Compression::None };
+
+    /// Every combination this build can speak, most preferred first
+    pub const SUPPORTED_ENCODINGS: [Encoding; 2] = [Encoding::MessagePack, Encoding::Json];
+    pub const SUPPORTED_COMPRESSION: [Compression; 3] =
+        [Compression::Zstd, Compression::Deflate, Compression::None];
+
+    /// Picks the most preferred encoding and compression that the peer also offered.
+    /// Falls back to uncompressed JSON when there is no overlap.
+    pub fn negotiate(encodings: &[Encoding], compression: &[Compression]) -> Self {
+        let encoding = Self::SUPPORTED_ENCODINGS
+            .into_iter()
+            .find(|e| encodings.contains(e))
+            .unwrap_or(Encoding::Json);
+        let compression = Self::SUPPORTED_COMPRESSION
+            .into_iter()
+            .find(|c| compression.contains(c))
+            .unwrap_or(Compression::None);
+        Self { encoding, compression }
+    }
+
+    /// Uncompressed JSON frames are sent as WebSocket text frames, everything else as binary
+    pub fn is_text(&self) -> bool {
+        *self == Self::JSON
+    }
+
+    pub fn encode(&self, message: &SyncMessage) -> Result<Vec<u8>> {
+        let encoded = match self.encoding {
+            Encoding::Json => serde_json::to_vec(message)?,
+            Encoding::MessagePack => rmp_serde::to_vec_named(message)?,
+        };
+
+        Ok(match self.compression {
+            Compression::None => encoded,
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), DeflateLevel::fast());
+                encoder.write_all(&encoded)?;
+                encoder.finish()?
+            }
+            Compression::Zstd => zstd::encode_all(encoded.as_slice(), ZSTD_LEVEL)?,
+        })
+    }
+
+    /// Decodes one frame, refusing with `NotaroError::FrameTooLarge` if it is more than
+    /// `max_len` bytes once decompressed. Decompression stops there, so a small frame that
+    /// inflates to gigabytes is never held in memory.
+    pub fn decode(&self, frame: &[u8], max_len: usize) -> Result<SyncMessage> {
+        let decompressed;
+        let bytes = match self.compression {
+            Compression::None if frame.len() > max_len => {
+                return Err(NotaroError::FrameTooLarge(max_len));
+            }
+            Compression::None => frame,
+            Compression::Deflate => {
+                decompressed = read_bounded(DeflateDecoder::new(frame), max_len)?;
+                &decompressed
+            }
+            Compression::Zstd => {
+                decompressed = read_bounded(zstd::Decoder::new(frame)?, max_len)?;
+                &decompressed
+            }
+        };
+
+        Ok(match self.encoding {
+            Encoding::Json => serde_json::from_slice(bytes)?,
+            Encoding::MessagePack => rmp_serde::from_slice(bytes)?,
+        })
+    }
+}
+
+/// Reads `reader` to the end, failing once it yields more than `max_len` bytes
+fn read_bounded(reader: impl Read, max_len: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.take((max_len as u64).saturating_add(1)).read_to_end(&mut buf)?;
+    if buf.len() > max_len {
+        return Err(NotaroError::FrameTooLarge(max_len));
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::NoteDelta;
+    use crate::filter::SyncFilter;
+    use crate::hlc::Hlc;
+    use crate::merkle::RangeDigest;
+    use crate::models::{
+        ChangeCursor, Limit, Note, NoteActivity, NotePresence, RejectedNote, SettingEntry,
+    };
+
+    fn all_codecs() -> Vec<FrameCodec> {
+        let mut codecs = Vec::new();
+        for encoding in FrameCodec::SUPPORTED_ENCODINGS {
+            for compression in FrameCodec::SUPPORTED_COMPRESSION {
+                codecs.push(FrameCodec { encoding, compression });
+            }
+        }
+        codecs
+    }
+
+    fn sample_messages() -> Vec<SyncMessage> {
+        let mut note = Note::new(
+            "Title".into(),
+            "Body ✓ with unicode, long enough to be worth a delta".into(),
+            Some("Work".into()),
+        );
+        note.is_deleted = true;
+        let plain = Note::new("Other".into(), String::new(), None);
+
+        vec![
+            SyncMessage::Hello {
+                encodings: FrameCodec::SUPPORTED_ENCODINGS.to_vec(),
+                compression: FrameCodec::SUPPORTED_COMPRESSION.to_vec(),
+                filter: SyncFilter {
+                    exclude_folders: vec!["Personal".into()],
+                    ..Default::default()
+                },
+            },
+            SyncMessage::Welcome { codec: FrameCodec::JSON },
+            SyncMessage::PullRequest { since_version: 3, cursor: None, limit: Some(10) },
+            SyncMessage::PullResponse {
+                changes: vec![note.clone(), plain.clone()],
+                current_version: 7,
+                has_more: true,
+                next_cursor: Some(ChangeCursor::after(&plain)),
+                evicted: vec!["moved-away".into()],
+            },
+            SyncMessage::PushUpdates {
+                changes: vec![note.clone()],
+                deltas: vec![NoteDelta::between(1, &format!("{}!", note.content), &note).unwrap()],
+            },
+            SyncMessage::NeedFullCopy { ids: vec![plain.id.clone()] },
+            SyncMessage::PushRejected {
+                rejected: vec![
+                    RejectedNote { id: note.id.clone(), reason: "read only".into(), limit: None },
+                    RejectedNote {
+                        id: plain.id.clone(),
+                        reason: "too large".into(),
+                        limit: Some(Limit::NoteSize),
+                    },
+                ],
+                need_full_copy: vec![plain.id.clone()],
+            },
+            SyncMessage::PushRejected {
+                rejected: vec![RejectedNote {
+                    id: plain.id.clone(),
+                    reason: "read only".into(),
+                    limit: None,
+                }],
+                need_full_copy: vec![],
+            },
+            SyncMessage::Ack,
+            SyncMessage::LimitExceeded {
+                limit: Limit::RateLimit,
+                message: "slow down".into(),
+                retry_after_ms: Some(250),
+            },
+            SyncMessage::LimitExceeded {
+                limit: Limit::MessageSize,
+                message: "too big".into(),
+                retry_after_ms: None,
+            },
+            SyncMessage::ReconcileRequest {
+                ranges: vec![RangeDigest { prefix: String::new(), hash: "ab12".into() }],
+            },
+            SyncMessage::ReconcileResponse {
+                digests: vec![RangeDigest { prefix: "3".into(), hash: "cd34".into() }],
+                leaves: vec!["3f".into()],
+                notes: vec![plain.clone()],
+            },
+            SyncMessage::SessionRevoked,
+            SyncMessage::ServerShutdown { retry_after_ms: 5000 },
+            SyncMessage::SetPresence { note_id: note.id.clone(), activity: NoteActivity::Opened },
+            SyncMessage::PresenceUpdate {
+                note_id: note.id.clone(),
+                present: vec![NotePresence {
+                    device: "d1".into(),
+                    device_name: "Laptop".into(),
+                    username: "alice".into(),
+                    typing: true,
+                }],
+            },
+            SyncMessage::SettingsUpdate {
+                entries: vec![SettingEntry {
+                    key: "accent_hue".into(),
+                    value: 120.into(),
+                    hlc: Hlc { wall_ms: 1_700_000_000_000, counter: 3, node: "laptop".into() },
+                }],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_all_codecs_round_trip_to_identical_values() {
+        for codec in all_codecs() {
+            for message in sample_messages() {
+                let frame = codec.encode(&message).unwrap();
+                let decoded = codec.decode(&frame, usize::MAX).unwrap();
+                assert_eq!(decoded, message, "round trip through {codec:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_stops_at_the_size_limit() {
+        let note = Note::new("Bomb".into(), "a".repeat(1 << 20), None);
+        let message = SyncMessage::PushUpdates { changes: vec![note], deltas: vec![] };
+        for codec in all_codecs() {
+            let frame = codec.encode(&message).unwrap();
+            let err = codec.decode(&frame, 64 * 1024).unwrap_err();
+            assert!(matches!(err, NotaroError::FrameTooLarge(_)), "{codec:?} gave {err:?}");
+            assert_eq!(codec.decode(&frame, 2 << 20).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_binary_encoding_is_smaller_than_json() {
+        let notes = (0..50)
+            .map(|i| Note::new(format!("Note {i}"), "lorem ipsum ".repeat(40), None))
+            .collect();
+        let message = SyncMessage::PushUpdates { changes: notes, deltas: vec![] };
+
+        let json = FrameCodec::JSON.encode(&message).unwrap();
+        let packed = FrameCodec { encoding: Encoding::MessagePack, compression: Compression::None }
+            .encode(&message)
+            .unwrap();
+        let compressed =
+            FrameCodec { encoding: Encoding::MessagePack, compression: Compression::Zstd }
+                .encode(&message)
+                .unwrap();
+
+        assert!(packed.len() < json.len());
+        assert!(compressed.len() < packed.len());
+    }
+
+    #[test]
+    fn test_negotiate_prefers_binary_and_falls_back_to_json() {
+        let both = FrameCodec::negotiate(
+            &[Encoding::Json, Encoding::MessagePack],
+            &[Compression::Deflate, Compression::Zstd],
+        );
+        assert_eq!(
+            both,
+            FrameCodec { encoding: Encoding::MessagePack, compression: Compression::Zstd }
+        );
+
+        assert_eq!(FrameCodec::negotiate(&[], &[]), FrameCodec::JSON);
+        assert!(FrameCodec::negotiate(&[Encoding::Json], &[Compression::None]).is_text());
+    }
+
+    #[test]
+    fn test_decode_with_wrong_codec_fails() {
+        let frame = FrameCodec { encoding: Encoding::MessagePack, compression: Compression::Zstd }
+            .encode(&SyncMessage::Ack)
+            .unwrap();
+        assert!(FrameCodec::JSON.decode(&frame, usize::MAX).is_err());
+    }
+}
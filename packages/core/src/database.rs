@@ -1,20 +1,168 @@
+use crate::delta::NoteDelta;
 use crate::error::Result;
-use crate::models::{Note, UserSettings};
-use chrono::{DateTime, Utc};
-use rusqlite::{Connection, OptionalExtension, params};
+use crate::filter::SyncFilter;
+use crate::hlc::{Hlc, HybridClock};
+use crate::merge::{MergeReport, Resolution, merge_concurrent, resolve};
+use crate::merkle::{MerkleTree, range_of};
+use crate::models::{ChangeCursor, KnownDevice, Note, SettingEntry, UserSettings};
+use crate::store::rebuild_from_deltas;
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::types::Type;
+use rusqlite::{Connection, OptionalExtension, Row, params};
 use std::path::Path;
+use uuid::Uuid;
 
 pub struct DatabaseConnection {
     conn: Connection,
+    clock: HybridClock,
+}
+
+/// A slice of the change feed returned by `DatabaseConnection::get_changes_page`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangePage {
+    pub changes: Vec<Note>,
+    /// True when more changes remain after `next_cursor`
+    pub has_more: bool,
+    pub next_cursor: Option<ChangeCursor>,
+}
+
+/// Fills a `ChangePage` from notes offered in change-feed order, up to `limit` notes and
+/// `max_bytes` of JSON (but always at least one note)
+pub(crate) struct PageBuilder {
+    limit: usize,
+    max_bytes: usize,
+    bytes: usize,
+    changes: Vec<Note>,
+    has_more: bool,
+}
+
+impl PageBuilder {
+    pub(crate) fn new(limit: usize, max_bytes: usize) -> Self {
+        Self { limit: limit.max(1), max_bytes, bytes: 0, changes: Vec::new(), has_more: false }
+    }
+
+    /// Adds `note` to the page, or returns false if the page is already full
+    pub(crate) fn offer(&mut self, note: Note) -> Result<bool> {
+        let size = serde_json::to_vec(&note)?.len();
+        if self.changes.len() == self.limit
+            || (!self.changes.is_empty() && self.bytes.saturating_add(size) > self.max_bytes)
+        {
+            self.has_more = true;
+            return Ok(false);
+        }
+        self.bytes += size;
+        self.changes.push(note);
+        Ok(true)
+    }
+
+    pub(crate) fn finish(self) -> ChangePage {
+        let next_cursor =
+            if self.has_more { self.changes.last().map(ChangeCursor::after) } else { None };
+        ChangePage { changes: self.changes, has_more: self.has_more, next_cursor }
+    }
+}
+
+/// How many notes a database holds, split into live ones and deletion tombstones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NoteCounts {
+    pub live: usize,
+    pub tombstones: usize,
+    /// `Note::size` summed over the live notes
+    pub bytes: u64,
+    /// How many of the live notes belong to a shared collection
+    pub shared: usize,
+    /// `Note::size` summed over those
+    pub shared_bytes: u64,
+}
+
+/// Maps a row selected as `id, title, content, folder, is_pinned, created_at, updated_at,
+/// version, is_deleted, hlc, version_vector, collection` into a `Note`.
+fn note_from_row(row: &Row) -> rusqlite::Result<Note> {
+    Ok(Note {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        content: row.get(2)?,
+        folder: row.get(3)?,
+        is_pinned: row.get(4)?,
+        created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(5)?)
+            .map_err(|_| rusqlite::Error::ExecuteReturnedResults)?
+            .with_timezone(&Utc),
+        updated_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(6)?)
+            .map_err(|_| rusqlite::Error::ExecuteReturnedResults)?
+            .with_timezone(&Utc),
+        version: row.get(7)?,
+        is_deleted: row.get(8)?,
+        hlc: row
+            .get::<_, String>(9)?
+            .parse()
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(9, Type::Text, Box::new(e)))?,
+        version_vector: serde_json::from_str(&row.get::<_, String>(10)?)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(10, Type::Text, Box::new(e)))?,
+        collection: row.get(11)?,
+    })
+}
+
+/// Inserts or fully overwrites a note row
+fn write_note(conn: &Connection, note: &Note) -> Result<()> {
+    store_note(conn, "INSERT OR REPLACE", note)
+}
+
+/// Writes every column of `note` with the given insert statement verb
+fn store_note(conn: &Connection, insert: &str, note: &Note) -> Result<()> {
+    conn.execute(
+        &format!(
+            "{insert} INTO notes (id, title, content, folder, is_pinned, created_at, updated_at, version, is_deleted, hlc, version_vector, collection)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)"
+        ),
+        params![
+            note.id,
+            note.title,
+            note.content,
+            note.folder,
+            note.is_pinned,
+            note.created_at.to_rfc3339(),
+            note.updated_at.to_rfc3339(),
+            note.version,
+            note.is_deleted,
+            note.hlc.to_string(),
+            serde_json::to_string(&note.version_vector)?,
+            note.collection
+        ],
+    )?;
+    Ok(())
 }
 
 impl DatabaseConnection {
     /// Initializes the database connection and runs migrations
     /// To use an in-memory database for testing, pass ":memory:"
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open(path, None)
+    }
+
+    /// Like `new`, but identifies the replica as `device_id` instead of the generated id.
+    /// Meant for tests and simulations that need reproducible device ids.
+    pub fn with_device_id<P: AsRef<Path>>(path: P, device_id: &str) -> Result<Self> {
+        Self::open(path, Some(device_id))
+    }
+
+    fn open<P: AsRef<Path>>(path: P, device_id: Option<&str>) -> Result<Self> {
         let conn = Connection::open(path)?;
-        let db = Self { conn };
+        let mut db = Self { conn, clock: HybridClock::new(String::new()) };
         db.migrate()?;
+        if let Some(id) = device_id {
+            db.conn.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES ('device_id', ?1)",
+                params![id],
+            )?;
+        }
+        db.clock = HybridClock::new(db.load_device_id()?);
+
+        // Never hand out stamps older than what is already stored
+        let latest: Option<String> =
+            db.conn.query_row("SELECT MAX(hlc) FROM notes", [], |row| row.get(0))?;
+        if let Some(latest) = latest.and_then(|h| h.parse::<Hlc>().ok()) {
+            db.clock.advance_to(&latest);
+        }
         Ok(db)
     }
 
@@ -30,10 +178,32 @@ impl DatabaseConnection {
                 updated_at TEXT NOT NULL,
                 version INTEGER NOT NULL,
                 is_deleted BOOLEAN NOT NULL DEFAULT 0,
-                is_pinned BOOLEAN NOT NULL DEFAULT 0
+                is_pinned BOOLEAN NOT NULL DEFAULT 0,
+                hlc TEXT NOT NULL DEFAULT '',
+                version_vector TEXT NOT NULL DEFAULT '{}',
+                collection TEXT
             )",
             [],
         )?;
+        self.add_column_if_missing("notes", "version_vector", "TEXT NOT NULL DEFAULT '{}'")?;
+        self.add_column_if_missing("notes", "collection", "TEXT")?;
+
+        if self.add_column_if_missing("notes", "hlc", "TEXT NOT NULL DEFAULT ''")? {
+            // Backfill stamps for notes written before the clock existed
+            let mut stmt = self.conn.prepare("SELECT id, updated_at FROM notes")?;
+            let rows =
+                stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+            for row in rows {
+                let (id, updated_at) = row?;
+                let hlc = DateTime::parse_from_rfc3339(&updated_at)
+                    .map(|t| Hlc::from_datetime(t.with_timezone(&Utc)))
+                    .unwrap_or_default();
+                self.conn.execute(
+                    "UPDATE notes SET hlc = ?1 WHERE id = ?2",
+                    params![hlc.to_string(), id],
+                )?;
+            }
+        }
 
         self.conn.execute(
             "CREATE TABLE IF NOT EXISTS settings (
@@ -46,9 +216,121 @@ impl DatabaseConnection {
             [],
         )?;
 
+        // Synced settings, one row per key, each stamped for last-writer-wins
+        let had_synced_settings = self.table_exists("synced_settings")?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS synced_settings (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL,
+                hlc TEXT NOT NULL
+            )",
+            [],
+        )?;
+        // Choices saved before settings synced get the zero stamp: they reach devices that have
+        // none, but lose to any real edit
+        if !had_synced_settings && let Some(legacy) = self.read_local_settings()? {
+            for key in UserSettings::SYNCED_KEYS {
+                if let Some(value) = legacy.synced_value(key) {
+                    self.write_setting_entry(&SettingEntry {
+                        key: key.to_string(),
+                        value,
+                        hlc: Hlc::default(),
+                    })?;
+                }
+            }
+        }
+
+        // Per-replica values such as the device id
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS meta (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        // Other devices this replica has received writes from
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS devices (
+                device_id TEXT PRIMARY KEY,
+                last_seen_ms INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // Last body known to be held by the sync peer, used as the base for content deltas
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS sync_bases (
+                id TEXT PRIMARY KEY,
+                version INTEGER NOT NULL,
+                content TEXT NOT NULL
+            )",
+            [],
+        )?;
+
         Ok(())
     }
 
+    /// Whether the database already has `table`
+    fn table_exists(&self, table: &str) -> Result<bool> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            params![table],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    /// Adds a column to a table created by an older version of the schema.
+    /// Returns true if the column was missing.
+    fn add_column_if_missing(&self, table: &str, column: &str, definition: &str) -> Result<bool> {
+        let mut stmt = self.conn.prepare(&format!("PRAGMA table_info({table})"))?;
+        let exists = stmt
+            .query_map([], |row| row.get::<_, String>(1))?
+            .collect::<rusqlite::Result<Vec<_>>>()?
+            .iter()
+            .any(|name| name == column);
+
+        if !exists {
+            self.conn
+                .execute(&format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"), [])?;
+        }
+        Ok(!exists)
+    }
+
+    /// Reads this replica's device id, generating and persisting one on first open
+    fn load_device_id(&self) -> Result<String> {
+        let existing: Option<String> = self
+            .conn
+            .query_row("SELECT value FROM meta WHERE key = 'device_id'", [], |row| row.get(0))
+            .optional()?;
+        if let Some(id) = existing {
+            return Ok(id);
+        }
+
+        let id = Uuid::new_v4().to_string();
+        self.conn.execute("INSERT INTO meta (key, value) VALUES ('device_id', ?1)", params![id])?;
+        Ok(id)
+    }
+
+    /// Stable identifier of this replica, also used as its HLC node and version vector entry
+    pub fn device_id(&self) -> &str {
+        self.clock.node()
+    }
+
+    /// The replica's hybrid logical clock
+    pub fn clock(&self) -> &HybridClock {
+        &self.clock
+    }
+
+    /// Bumps the version, clock stamp and this device's vector entry for a local write
+    fn stamp_local_write(&self, note: &mut Note) {
+        note.hlc = self.clock.now();
+        note.updated_at = note.hlc.to_datetime();
+        note.version += 1;
+        note.version_vector.increment(self.device_id());
+    }
+
     // --- CRUD Operations ---
 
     pub fn create_note(
@@ -57,49 +339,36 @@ impl DatabaseConnection {
         content: String,
         folder: Option<String>,
     ) -> Result<Note> {
-        let note = Note::new(title, content, folder);
-        self.conn.execute(
-            "INSERT INTO notes (id, title, content, folder, is_pinned, created_at, updated_at, version, is_deleted)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
-            params![
-                note.id,
-                note.title,
-                note.content,
-                note.folder,
-                note.is_pinned,
-                note.created_at.to_rfc3339(),
-                note.updated_at.to_rfc3339(),
-                note.version,
-                note.is_deleted
-            ],
-        )?;
+        self.create_note_with_id(Uuid::new_v4().to_string(), title, content, folder)
+    }
+
+    /// Creates a note under a caller-chosen id, e.g. when the id was assigned elsewhere.
+    /// Fails if a note with that id already exists.
+    pub fn create_note_with_id(
+        &self,
+        id: String,
+        title: String,
+        content: String,
+        folder: Option<String>,
+    ) -> Result<Note> {
+        let mut note = Note::new(title, content, folder);
+        note.id = id;
+        note.hlc = self.clock.now();
+        note.created_at = note.hlc.to_datetime();
+        note.updated_at = note.created_at;
+        note.version_vector.increment(self.device_id());
+        store_note(&self.conn, "INSERT", &note)?;
         Ok(note)
     }
 
     pub fn get_all_notes(&self) -> Result<Vec<Note>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, title, content, folder, is_pinned, created_at, updated_at, version, is_deleted
+            "SELECT id, title, content, folder, is_pinned, created_at, updated_at, version, is_deleted, hlc, version_vector, collection
              FROM notes
-             ORDER BY is_pinned DESC, updated_at DESC",
+             ORDER BY is_pinned DESC, hlc DESC",
         )?;
 
-        let note_iter = stmt.query_map([], |row| {
-            Ok(Note {
-                id: row.get(0)?,
-                title: row.get(1)?,
-                content: row.get(2)?,
-                folder: row.get(3)?,
-                is_pinned: row.get(4)?,
-                created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(5)?)
-                    .map_err(|_| rusqlite::Error::ExecuteReturnedResults)?
-                    .with_timezone(&Utc),
-                updated_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(6)?)
-                    .map_err(|_| rusqlite::Error::ExecuteReturnedResults)?
-                    .with_timezone(&Utc),
-                version: row.get(7)?,
-                is_deleted: row.get(8)?,
-            })
-        })?;
+        let note_iter = stmt.query_map([], note_from_row)?;
 
         let mut notes = Vec::new();
         for note in note_iter {
@@ -116,107 +385,190 @@ impl DatabaseConnection {
         folder: Option<String>,
         is_pinned: bool,
     ) -> Result<Note> {
-        let now = Utc::now();
+        let mut note = self.get_note_by_id(id)?;
 
         // Only allow updates if note is not deleted (optional safeguard, or allow editing trash)
         // For now, we allow updates, but usually UI blocks it.
-        self.conn.execute(
-            "UPDATE notes
-             SET title = ?1, content = ?2, folder = ?3, is_pinned = ?4, updated_at = ?5, version = version + 1
-             WHERE id = ?6",
-            params![title, content, folder, is_pinned, now.to_rfc3339(), id],
-        )?;
+        note.title = title;
+        note.content = content;
+        note.folder = folder;
+        note.is_pinned = is_pinned;
+        self.stamp_local_write(&mut note);
+        write_note(&self.conn, &note)?;
 
-        self.get_note_by_id(id)
+        Ok(note)
     }
 
     /// Deletes a note.
     /// 1. If the note is active, it is soft-deleted (moved to trash).
     /// 2. If the note is already soft-deleted, it is permanently removed.
     pub fn delete_note(&self, id: &str) -> Result<()> {
-        let note = self.get_note_by_id(id)?;
+        let mut note = self.get_note_by_id(id)?;
 
         if note.is_deleted {
             // Hard Delete
             self.conn.execute("DELETE FROM notes WHERE id = ?1", params![id])?;
+            self.conn.execute("DELETE FROM sync_bases WHERE id = ?1", params![id])?;
         } else {
             // Soft Delete
-            let now = Utc::now();
-            self.conn.execute(
-                "UPDATE notes
-                 SET is_deleted = 1, updated_at = ?1, version = version + 1
-                 WHERE id = ?2",
-                params![now.to_rfc3339(), id],
-            )?;
+            note.is_deleted = true;
+            self.stamp_local_write(&mut note);
+            write_note(&self.conn, &note)?;
         }
         Ok(())
     }
 
     pub fn restore_note(&self, id: &str) -> Result<()> {
-        let now = Utc::now();
-        self.conn.execute(
-            "UPDATE notes
-             SET is_deleted = 0, updated_at = ?1, version = version + 1
-             WHERE id = ?2",
-            params![now.to_rfc3339(), id],
-        )?;
+        if let Some(mut note) = self.find_note(id)? {
+            note.is_deleted = false;
+            self.stamp_local_write(&mut note);
+            write_note(&self.conn, &note)?;
+        }
         Ok(())
     }
 
-    fn get_note_by_id(&self, id: &str) -> Result<Note> {
+    /// Moves a private note into the shared collection `collection_id`.
+    /// The server only accepts the move from an owner or editor of the collection, and a note
+    /// never leaves its collection again.
+    pub fn move_to_collection(&self, id: &str, collection_id: &str) -> Result<Note> {
+        let mut note = self.get_note_by_id(id)?;
+        note.collection = Some(collection_id.to_string());
+        self.stamp_local_write(&mut note);
+        write_note(&self.conn, &note)?;
+        Ok(note)
+    }
+
+    pub fn get_note_by_id(&self, id: &str) -> Result<Note> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, title, content, folder, is_pinned, created_at, updated_at, version, is_deleted
+            "SELECT id, title, content, folder, is_pinned, created_at, updated_at, version, is_deleted, hlc, version_vector, collection
              FROM notes WHERE id = ?1",
         )?;
 
-        stmt.query_row(params![id], |row| {
-            Ok(Note {
-                id: row.get(0)?,
-                title: row.get(1)?,
-                content: row.get(2)?,
-                folder: row.get(3)?,
-                is_pinned: row.get(4)?,
-                created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(5)?)
-                    .map_err(|_| rusqlite::Error::ExecuteReturnedResults)?
-                    .with_timezone(&Utc),
-                updated_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(6)?)
-                    .map_err(|_| rusqlite::Error::ExecuteReturnedResults)?
-                    .with_timezone(&Utc),
-                version: row.get(7)?,
-                is_deleted: row.get(8)?,
-            })
-        })
-        .map_err(Into::into)
+        stmt.query_row(params![id], note_from_row).map_err(Into::into)
     }
 
-    pub fn get_settings(&self) -> Result<UserSettings> {
+    pub(crate) fn find_note(&self, id: &str) -> Result<Option<Note>> {
         let mut stmt = self.conn.prepare(
-            "SELECT theme_mode, accent_hue, font_family, font_size FROM settings WHERE id = 1",
+            "SELECT id, title, content, folder, is_pinned, created_at, updated_at, version, is_deleted, hlc, version_vector, collection
+             FROM notes WHERE id = ?1",
         )?;
 
-        let settings_iter = stmt.query_map([], |row| {
-            Ok(UserSettings {
-                theme_mode: row.get(0)?,
-                accent_hue: row.get(1)?,
-                font_family: row.get(2)?,
-                font_size: row.get(3)?,
-            })
-        });
+        stmt.query_row(params![id], note_from_row).optional().map_err(Into::into)
+    }
 
-        // If table is empty or query fails, return default
-        if let Ok(mut rows) = settings_iter
-            && let Some(Ok(settings)) = rows.next()
-        {
-            return Ok(settings);
-        }
+    /// Device-local settings with the synced keys overlaid from their latest entries
+    pub fn get_settings(&self) -> Result<UserSettings> {
+        let mut settings = match self.read_local_settings()? {
+            Some(settings) => settings,
+            None => {
+                // Initialize default if missing
+                let default = UserSettings::default();
+                self.write_local_settings(&default)?;
+                default
+            }
+        };
 
-        // Initialize default if missing
-        let default = UserSettings::default();
-        self.update_settings(&default)?;
-        Ok(default)
+        for entry in self.get_synced_settings()? {
+            settings.apply_synced(&entry.key, &entry.value);
+        }
+        Ok(settings)
     }
 
+    /// Saves settings. Each synced key whose value changed gets a fresh HLC stamp so the edit
+    /// wins over older writes from other devices.
     pub fn update_settings(&self, settings: &UserSettings) -> Result<()> {
+        let current = self.get_settings()?;
+        self.write_local_settings(settings)?;
+
+        for key in UserSettings::SYNCED_KEYS {
+            let value = settings.synced_value(key);
+            if let Some(value) = value
+                && Some(&value) != current.synced_value(key).as_ref()
+            {
+                let hlc = self.clock.now();
+                self.write_setting_entry(&SettingEntry { key: key.to_string(), value, hlc })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Every stamped synced setting, ordered by key
+    pub fn get_synced_settings(&self) -> Result<Vec<SettingEntry>> {
+        let mut stmt =
+            self.conn.prepare("SELECT key, value, hlc FROM synced_settings ORDER BY key")?;
+        let entries = stmt
+            .query_map([], |row| {
+                let value: String = row.get(1)?;
+                let hlc: String = row.get(2)?;
+                Ok(SettingEntry {
+                    key: row.get(0)?,
+                    value: serde_json::from_str(&value).map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(1, Type::Text, Box::new(e))
+                    })?,
+                    hlc: hlc.parse().map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(2, Type::Text, Box::new(e))
+                    })?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+
+    /// Merges remote settings key by key; the later HLC stamp wins.
+    /// Returns the entries that replaced the local value.
+    pub fn merge_settings(&self, entries: Vec<SettingEntry>) -> Result<Vec<SettingEntry>> {
+        let mut applied = Vec::new();
+        for entry in entries {
+            self.clock.observe(&entry.hlc);
+
+            let local: Option<String> = self
+                .conn
+                .query_row(
+                    "SELECT hlc FROM synced_settings WHERE key = ?1",
+                    params![entry.key],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let newer = match local {
+                Some(local) => local.parse::<Hlc>().map_or(true, |local| entry.hlc > local),
+                None => true,
+            };
+
+            if newer {
+                self.write_setting_entry(&entry)?;
+                applied.push(entry);
+            }
+        }
+        Ok(applied)
+    }
+
+    fn write_setting_entry(&self, entry: &SettingEntry) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO synced_settings (key, value, hlc) VALUES (?1, ?2, ?3)",
+            params![entry.key, serde_json::to_string(&entry.value)?, entry.hlc.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn read_local_settings(&self) -> Result<Option<UserSettings>> {
+        self.conn
+            .query_row(
+                "SELECT theme_mode, accent_hue, font_family, font_size FROM settings WHERE id = 1",
+                [],
+                |row| {
+                    Ok(UserSettings {
+                        theme_mode: row.get(0)?,
+                        accent_hue: row.get(1)?,
+                        font_family: row.get(2)?,
+                        font_size: row.get(3)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(Into::into)
+    }
+
+    fn write_local_settings(&self, settings: &UserSettings) -> Result<()> {
         self.conn.execute(
             "INSERT OR REPLACE INTO settings (id, theme_mode, accent_hue, font_family, font_size)
              VALUES (1, ?1, ?2, ?3, ?4)",
@@ -234,99 +586,319 @@ impl DatabaseConnection {
 
     /// Get all notes (including deleted ones) that have a version higher than the provided version.
     pub fn get_changes_since(&self, version: i32) -> Result<Vec<Note>> {
+        let mut notes = Vec::new();
+        self.for_each_change_since(version, |note| {
+            notes.push(note);
+            Ok(true)
+        })?;
+        Ok(notes)
+    }
+
+    /// Streaming variant of `get_changes_since`.
+    /// Rows are handed to `visit` one at a time in change-feed order `(version, id)`, so callers
+    /// can serialize or forward them without holding the whole result set in memory.
+    /// Returning `Ok(false)` from `visit` stops the scan early.
+    pub fn for_each_change_since<F>(&self, version: i32, visit: F) -> Result<()>
+    where
+        F: FnMut(Note) -> Result<bool>,
+    {
+        self.scan_changes(version, None, visit)
+    }
+
+    /// Returns one page of the change feed, starting after `cursor` (or at the beginning when
+    /// `None`). A page holds at most `limit` notes and stops early once the JSON size of its
+    /// notes would exceed `max_bytes`. At least one note is always returned, so a single
+    /// oversized note cannot stall the feed.
+    pub fn get_changes_page(
+        &self,
+        since_version: i32,
+        cursor: Option<&ChangeCursor>,
+        limit: usize,
+        max_bytes: usize,
+    ) -> Result<ChangePage> {
+        let mut page = PageBuilder::new(limit, max_bytes);
+        self.scan_changes(since_version, cursor, |note| page.offer(note))?;
+        Ok(page.finish())
+    }
+
+    /// Highest version currently stored, or 0 for an empty database.
+    pub fn current_version(&self) -> Result<i32> {
+        self.conn
+            .query_row("SELECT COALESCE(MAX(version), 0) FROM notes", [], |row| row.get(0))
+            .map_err(Into::into)
+    }
+
+    fn scan_changes<F>(
+        &self,
+        version: i32,
+        cursor: Option<&ChangeCursor>,
+        mut visit: F,
+    ) -> Result<()>
+    where
+        F: FnMut(Note) -> Result<bool>,
+    {
+        let (cursor_version, cursor_id) = match cursor {
+            Some(c) => (c.version, c.id.as_str()),
+            None => (i32::MIN, ""),
+        };
+
         let mut stmt = self.conn.prepare(
-            "SELECT id, title, content, folder, is_pinned, created_at, updated_at, version, is_deleted
+            "SELECT id, title, content, folder, is_pinned, created_at, updated_at, version, is_deleted, hlc, version_vector, collection
              FROM notes
-             WHERE version > ?1",
+             WHERE version > ?1 AND (version, id) > (?2, ?3)
+             ORDER BY version, id",
         )?;
 
-        let note_iter = stmt.query_map(params![version], |row| {
-            Ok(Note {
-                id: row.get(0)?,
-                title: row.get(1)?,
-                content: row.get(2)?,
-                folder: row.get(3)?,
-                is_pinned: row.get(4)?,
-                created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(5)?)
-                    .map_err(|_| rusqlite::Error::ExecuteReturnedResults)?
-                    .with_timezone(&Utc),
-                updated_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(6)?)
-                    .map_err(|_| rusqlite::Error::ExecuteReturnedResults)?
-                    .with_timezone(&Utc),
-                version: row.get(7)?,
-                is_deleted: row.get(8)?,
-            })
-        })?;
+        let mut rows = stmt.query(params![version, cursor_version, cursor_id])?;
+        while let Some(row) = rows.next()? {
+            if !visit(note_from_row(row)?)? {
+                break;
+            }
+        }
+        Ok(())
+    }
 
+    /// Like `get_changes_since`, but splits the result for a push: notes whose previous body the
+    /// peer is known to hold (see `record_sync_base`) are sent as deltas when that is smaller,
+    /// everything else as full notes.
+    pub fn get_changes_for_push(&self, version: i32) -> Result<(Vec<Note>, Vec<NoteDelta>)> {
+        let mut bases =
+            self.conn.prepare("SELECT version, content FROM sync_bases WHERE id = ?1")?;
         let mut notes = Vec::new();
-        for note in note_iter {
-            notes.push(note?);
+        let mut deltas = Vec::new();
+
+        self.for_each_change_since(version, |note| {
+            let base: Option<(i32, String)> = bases
+                .query_row(params![note.id], |row| Ok((row.get(0)?, row.get(1)?)))
+                .optional()?;
+
+            match base.filter(|(base_version, _)| *base_version < note.version).and_then(
+                |(base_version, content)| NoteDelta::between(base_version, &content, &note),
+            ) {
+                Some(delta) => deltas.push(delta),
+                None => notes.push(note),
+            }
+            Ok(true)
+        })?;
+
+        Ok((notes, deltas))
+    }
+
+    /// Remembers that the sync peer now holds these versions, so later edits can be pushed as
+    /// deltas against them. Call after a push is acknowledged or a pull is applied.
+    pub fn record_sync_base(&self, notes: &[Note]) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "INSERT OR REPLACE INTO sync_bases (id, version, content) VALUES (?1, ?2, ?3)",
+        )?;
+        for note in notes {
+            stmt.execute(params![note.id, note.version, note.content])?;
         }
-        Ok(notes)
+        Ok(())
+    }
+
+    /// Applies content deltas whose base matches the local copy (same version and body hash)
+    /// and merges the rebuilt notes like `merge_changes`.
+    /// Returns the ids of notes whose base did not match; the sender must resend them in full.
+    pub fn merge_deltas(&mut self, deltas: Vec<NoteDelta>) -> Result<Vec<String>> {
+        let (rebuilt, need_full_copy) = rebuild_from_deltas(deltas, |id| self.find_note(id))?;
+        self.merge_changes(rebuilt)?;
+        Ok(need_full_copy)
     }
 
     /// Merges remote changes into the local database.
-    /// Strategy: Last Write Wins based on Version number.
-    pub fn merge_changes(&mut self, remote_changes: Vec<Note>) -> Result<()> {
+    /// Version vectors decide whether the remote copy descends from, precedes or is concurrent
+    /// with the local one (see `merge::resolve`); concurrent edits are settled by HLC stamp so
+    /// that every replica picks the same winner.
+    pub fn merge_changes(&mut self, remote_changes: Vec<Note>) -> Result<MergeReport> {
         let tx = self.conn.transaction()?;
+        let mut report = MergeReport::default();
 
         for remote_note in remote_changes {
+            // Every stamp we see moves our clock forward, even if the change itself loses
+            self.clock.observe(&remote_note.hlc);
+
+            let author = &remote_note.hlc.node;
+            if !author.is_empty() && author != self.clock.node() {
+                tx.execute(
+                    "INSERT INTO devices (device_id, last_seen_ms) VALUES (?1, ?2)
+                     ON CONFLICT(device_id) DO UPDATE SET last_seen_ms = MAX(last_seen_ms, excluded.last_seen_ms)",
+                    params![author, remote_note.hlc.wall_ms],
+                )?;
+            }
+
             // check if we have this note
-            let local_version: Option<i32> = tx
+            let local: Option<Note> = tx
                 .query_row(
-                    "SELECT version FROM notes WHERE id = ?1",
+                    "SELECT id, title, content, folder, is_pinned, created_at, updated_at, version, is_deleted, hlc, version_vector, collection
+                     FROM notes WHERE id = ?1",
                     params![remote_note.id],
-                    |row| row.get(0),
+                    note_from_row,
                 )
                 .optional()?;
 
-            match local_version {
-                Some(v) => {
-                    // If remote version is higher, we overwrite local
-                    if remote_note.version > v {
-                        // UPDATE with new fields
-                        tx.execute(
-                            "UPDATE notes
-                             SET title = ?1, content = ?2, folder = ?3, is_pinned = ?4, created_at = ?5, updated_at = ?6, version = ?7, is_deleted = ?8
-                             WHERE id = ?9",
-                            params![
-                                remote_note.title,
-                                remote_note.content,
-                                remote_note.folder,
-                                remote_note.is_pinned,
-                                remote_note.created_at.to_rfc3339(),
-                                remote_note.updated_at.to_rfc3339(),
-                                remote_note.version,
-                                remote_note.is_deleted,
-                                remote_note.id
-                            ]
-                        )?;
-                    }
-                    // If local version is higher or equal, we ignore the remote change
-                }
+            match local {
                 None => {
-                    // INSERT with new fields
-                    tx.execute(
-                        "INSERT INTO notes (id, title, content, folder, is_pinned, created_at, updated_at, version, is_deleted)
-                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
-                        params![
-                            remote_note.id,
-                            remote_note.title,
-                            remote_note.content,
-                            remote_note.folder,
-                            remote_note.is_pinned,
-                            remote_note.created_at.to_rfc3339(),
-                            remote_note.updated_at.to_rfc3339(),
-                            remote_note.version,
-                            remote_note.is_deleted
-                        ],
-                    )?;
+                    write_note(&tx, &remote_note)?;
+                    report.inserted += 1;
                 }
+                Some(local) => match resolve(&local, &remote_note) {
+                    Resolution::TakeRemote => {
+                        write_note(&tx, &remote_note)?;
+                        report.updated += 1;
+                    }
+                    Resolution::KeepLocal => report.ignored += 1,
+                    Resolution::Concurrent { remote_wins } => {
+                        write_note(&tx, &merge_concurrent(&local, &remote_note, remote_wins))?;
+                        report.concurrent.push(remote_note.id);
+                    }
+                },
             }
         }
 
         tx.commit()?;
-        Ok(())
+        Ok(report)
+    }
+
+    /// Devices this replica has received writes from, most recently seen first
+    pub fn list_known_devices(&self) -> Result<Vec<KnownDevice>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT device_id, last_seen_ms FROM devices ORDER BY last_seen_ms DESC")?;
+
+        let devices = stmt
+            .query_map([], |row| {
+                Ok(KnownDevice {
+                    device_id: row.get(0)?,
+                    last_seen: Utc.timestamp_millis_opt(row.get(1)?).single().unwrap_or_default(),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(devices)
+    }
+
+    /// Drops local copies of notes that left this replica's sync filter.
+    /// Unlike `delete_note` this is not an edit and is never synced. Returns how many were removed.
+    pub fn evict_notes(&self, ids: &[String]) -> Result<usize> {
+        let mut removed = 0;
+        for id in ids {
+            removed += self.conn.execute("DELETE FROM notes WHERE id = ?1", params![id])?;
+            self.conn.execute("DELETE FROM sync_bases WHERE id = ?1", params![id])?;
+        }
+        Ok(removed)
+    }
+
+    // --- Anti-entropy ---
+
+    /// Range-hash summary over `(id, version)` of every stored note inside `filter`, deleted
+    /// ones included
+    pub fn merkle_tree(&self, filter: &SyncFilter) -> Result<MerkleTree> {
+        if !filter.is_empty() {
+            let mut entries = Vec::new();
+            self.for_each_change_since(0, |note| {
+                if filter.matches(&note) {
+                    entries.push((note.id, note.version));
+                }
+                Ok(true)
+            })?;
+            return Ok(MerkleTree::build(entries));
+        }
+
+        let mut stmt = self.conn.prepare("SELECT id, version FROM notes")?;
+        let entries = stmt
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i32>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(MerkleTree::build(entries))
+    }
+
+    /// Every stored note whose id falls into one of the given Merkle leaf ranges
+    pub fn get_notes_in_ranges(&self, prefixes: &[String]) -> Result<Vec<Note>> {
+        if prefixes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut stmt = self.conn.prepare("SELECT id FROM notes ORDER BY id")?;
+        let ids = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        ids.iter()
+            .filter(|id| {
+                let range = range_of(id);
+                prefixes.iter().any(|prefix| range.starts_with(prefix.as_str()))
+            })
+            .map(|id| self.get_note_by_id(id))
+            .collect()
+    }
+
+    // --- Maintenance ---
+
+    pub fn note_counts(&self) -> Result<NoteCounts> {
+        self.conn
+            .query_row(
+                "SELECT COALESCE(SUM(NOT is_deleted), 0), COALESCE(SUM(is_deleted), 0),
+                        COALESCE(SUM(CASE WHEN is_deleted THEN 0
+                            ELSE length(CAST(title AS BLOB)) + length(CAST(content AS BLOB)) END), 0),
+                        COALESCE(SUM(NOT is_deleted AND collection IS NOT NULL), 0),
+                        COALESCE(SUM(CASE WHEN is_deleted OR collection IS NULL THEN 0
+                            ELSE length(CAST(title AS BLOB)) + length(CAST(content AS BLOB)) END), 0)
+                 FROM notes",
+                [],
+                |row| {
+                    Ok(NoteCounts {
+                        live: row.get(0)?,
+                        tombstones: row.get(1)?,
+                        bytes: row.get(2)?,
+                        shared: row.get(3)?,
+                        shared_bytes: row.get(4)?,
+                    })
+                },
+            )
+            .map_err(Into::into)
+    }
+
+    /// Permanently removes tombstones last written before `before` and returns how many.
+    /// A replica that has not synced since then never learns of the deletion and may push the
+    /// note back, so the cutoff should be well past how long devices stay offline.
+    pub fn purge_tombstones(&self, before: DateTime<Utc>) -> Result<usize> {
+        let mut stmt =
+            self.conn.prepare("SELECT id, updated_at FROM notes WHERE is_deleted = 1")?;
+        let expired = stmt
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .filter_map(|row| match row {
+                Ok((id, updated_at)) => DateTime::parse_from_rfc3339(&updated_at)
+                    .is_ok_and(|t| t < before)
+                    .then_some(Ok(id)),
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        for id in &expired {
+            self.conn.execute("DELETE FROM notes WHERE id = ?1", params![id])?;
+            self.conn.execute("DELETE FROM sync_bases WHERE id = ?1", params![id])?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Runs SQLite's integrity check and also verifies every note row still parses.
+    /// Returns the problems found; empty means healthy.
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare("PRAGMA integrity_check")?;
+        let mut problems = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        problems.retain(|line| line != "ok");
+
+        let mut stmt = self.conn.prepare(
+            "SELECT id, title, content, folder, is_pinned, created_at, updated_at, version, is_deleted, hlc, version_vector, collection
+             FROM notes",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            if let Err(e) = note_from_row(row) {
+                problems.push(format!("note {}: {e}", row.get::<_, String>(0)?));
+            }
+        }
+        Ok(problems)
     }
 }
 
@@ -358,7 +930,7 @@ mod tests {
             db.update_note(&note.id, "Hello 2".into(), "Updated".into(), None, true).unwrap();
         assert_eq!(updated.title, "Hello 2");
         assert_eq!(updated.folder, None);
-        assert_eq!(updated.is_pinned, true);
+        assert!(updated.is_pinned);
         assert_eq!(updated.version, 2); // Version should bump
 
         // 4. Soft Delete
@@ -394,6 +966,112 @@ mod tests {
         assert_eq!(changes_since_1[0].version, 2);
     }
 
+    #[test]
+    fn test_changes_page_cursor_and_limit() {
+        let db = get_mem_db();
+        for i in 0..5 {
+            db.create_note(format!("Note {i}"), "Body".into(), None).unwrap();
+        }
+
+        let first = db.get_changes_page(0, None, 2, usize::MAX).unwrap();
+        assert_eq!(first.changes.len(), 2);
+        assert!(first.has_more);
+
+        let cursor = first.next_cursor.clone().unwrap();
+        assert_eq!(cursor, ChangeCursor::after(first.changes.last().unwrap()));
+
+        let rest = db.get_changes_page(0, Some(&cursor), 10, usize::MAX).unwrap();
+        assert_eq!(rest.changes.len(), 3);
+        assert!(!rest.has_more);
+        assert!(rest.next_cursor.is_none());
+
+        // Pages never overlap
+        assert!(rest.changes.iter().all(|n| first.changes.iter().all(|f| f.id != n.id)));
+    }
+
+    #[test]
+    fn test_changes_page_byte_cap() {
+        let db = get_mem_db();
+        for _ in 0..3 {
+            db.create_note("Big".into(), "x".repeat(1_000), None).unwrap();
+        }
+
+        // Cap fits a single note per page
+        let page = db.get_changes_page(0, None, 100, 1_500).unwrap();
+        assert_eq!(page.changes.len(), 1);
+        assert!(page.has_more);
+
+        // An oversized note is still delivered on its own
+        let page = db.get_changes_page(0, None, 100, 10).unwrap();
+        assert_eq!(page.changes.len(), 1);
+    }
+
+    #[test]
+    fn test_for_each_change_stops_early() {
+        let db = get_mem_db();
+        for i in 0..4 {
+            db.create_note(format!("Note {i}"), "Body".into(), None).unwrap();
+        }
+
+        let mut seen = 0;
+        db.for_each_change_since(0, |_| {
+            seen += 1;
+            Ok(seen < 2)
+        })
+        .unwrap();
+        assert_eq!(seen, 2);
+        assert_eq!(db.current_version().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_push_changes_use_delta_against_sync_base() {
+        let db = get_mem_db();
+        let body = "line\n".repeat(200);
+        let note = db.create_note("Doc".into(), body.clone(), None).unwrap();
+
+        // Nothing recorded yet: full note
+        let (notes, deltas) = db.get_changes_for_push(0).unwrap();
+        assert_eq!((notes.len(), deltas.len()), (1, 0));
+
+        db.record_sync_base(std::slice::from_ref(&note)).unwrap();
+        let edited = db.update_note(&note.id, "Doc".into(), body + "x", None, false).unwrap();
+
+        let (notes, deltas) = db.get_changes_for_push(0).unwrap();
+        assert!(notes.is_empty());
+        assert_eq!(deltas.len(), 1);
+        assert_eq!(deltas[0].base_version, 1);
+        assert_eq!(deltas[0].note.version, edited.version);
+    }
+
+    #[test]
+    fn test_merge_deltas_applies_or_requests_full_copy() {
+        let sender = get_mem_db();
+        let mut receiver = get_mem_db();
+        let body = "paragraph ".repeat(100);
+
+        let note = sender.create_note("Doc".into(), body.clone(), None).unwrap();
+        receiver.merge_changes(vec![note.clone()]).unwrap();
+        sender.record_sync_base(std::slice::from_ref(&note)).unwrap();
+
+        let edited =
+            sender.update_note(&note.id, "Doc".into(), format!("{body}!"), None, false).unwrap();
+        let (_, deltas) = sender.get_changes_for_push(0).unwrap();
+
+        let missing = receiver.merge_deltas(deltas.clone()).unwrap();
+        assert!(missing.is_empty());
+        assert_eq!(receiver.get_note_by_id(&note.id).unwrap(), edited);
+
+        // A receiver holding a different body under the same base version asks for a full copy
+        let mut diverged = get_mem_db();
+        let mut other = note.clone();
+        other.content = "something else".into();
+        diverged.merge_changes(vec![other]).unwrap();
+        assert_eq!(diverged.merge_deltas(deltas.clone()).unwrap(), vec![note.id.clone()]);
+
+        // As does one that never saw the note
+        assert_eq!(get_mem_db().merge_deltas(deltas).unwrap(), vec![note.id]);
+    }
+
     #[test]
     fn test_merge_remote_newer_wins() {
         let mut db = get_mem_db();
@@ -441,6 +1119,175 @@ mod tests {
         assert_eq!(current.title, "New Remote");
     }
 
+    #[test]
+    fn test_merge_equal_versions_uses_hlc() {
+        let mut db = get_mem_db();
+        let note = db.create_note("Local".into(), "Local".into(), None).unwrap();
+
+        let mut later = note.clone();
+        later.title = "Later".into();
+        later.hlc = Hlc { wall_ms: note.hlc.wall_ms + 1, counter: 0, node: "other".into() };
+        let mut earlier = note.clone();
+        earlier.title = "Earlier".into();
+        earlier.hlc = Hlc { wall_ms: note.hlc.wall_ms - 1, counter: 0, node: "other".into() };
+
+        db.merge_changes(vec![earlier]).unwrap();
+        assert_eq!(db.get_note_by_id(&note.id).unwrap().title, "Local");
+
+        db.merge_changes(vec![later.clone()]).unwrap();
+        assert_eq!(db.get_note_by_id(&note.id).unwrap(), later);
+
+        // The clock has moved past what it observed
+        assert!(db.clock().now() > later.hlc);
+    }
+
+    #[test]
+    fn test_listing_order_survives_clock_skew() {
+        let fast = get_mem_db();
+        fast.clock().set_skew_ms(60 * 60 * 1000);
+        let mut slow = get_mem_db();
+
+        let from_fast = fast.create_note("From fast clock".into(), "".into(), None).unwrap();
+        slow.merge_changes(vec![from_fast.clone()]).unwrap();
+
+        // Written afterwards on a device whose clock is an hour behind
+        let from_slow = slow.create_note("From slow clock".into(), "".into(), None).unwrap();
+        assert!(from_slow.hlc > from_fast.hlc);
+        assert!(from_slow.updated_at >= from_fast.updated_at);
+
+        let listed = slow.get_all_notes().unwrap();
+        assert_eq!(listed[0].id, from_slow.id);
+        assert_eq!(listed[1].id, from_fast.id);
+    }
+
+    #[test]
+    fn test_migrates_notes_without_hlc() {
+        let path = std::env::temp_dir().join(format!("notaro-{}.db", Uuid::new_v4()));
+        {
+            let legacy = Connection::open(&path).unwrap();
+            legacy
+                .execute_batch(
+                    "CREATE TABLE notes (
+                        id TEXT PRIMARY KEY, folder TEXT, title TEXT NOT NULL, content TEXT NOT NULL,
+                        created_at TEXT NOT NULL, updated_at TEXT NOT NULL, version INTEGER NOT NULL,
+                        is_deleted BOOLEAN NOT NULL DEFAULT 0, is_pinned BOOLEAN NOT NULL DEFAULT 0
+                    );
+                    INSERT INTO notes (id, title, content, created_at, updated_at, version)
+                    VALUES ('old', 'Old', 'Body', '2030-01-01T00:00:00+00:00', '2030-01-01T00:00:00+00:00', 4);",
+                )
+                .unwrap();
+        }
+
+        let db = DatabaseConnection::new(&path).unwrap();
+        let old = db.get_note_by_id("old").unwrap();
+        assert_eq!(old.hlc.to_datetime(), old.updated_at);
+        assert!(old.hlc.node.is_empty());
+        assert_eq!(old.collection, None);
+
+        // New stamps are never older than the migrated ones
+        let fresh = db.create_note("New".into(), "".into(), None).unwrap();
+        assert!(fresh.hlc > old.hlc);
+
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_device_id_persists_across_reopen() {
+        let path = std::env::temp_dir().join(format!("notaro-{}.db", Uuid::new_v4()));
+        let first = DatabaseConnection::new(&path).unwrap().device_id().to_string();
+        let second = DatabaseConnection::new(&path).unwrap().device_id().to_string();
+        assert_eq!(first, second);
+        assert_ne!(first, get_mem_db().device_id());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_merge_report_distinguishes_causality() {
+        let laptop = get_mem_db();
+        let mut phone = get_mem_db();
+
+        let note = laptop.create_note("Trip".into(), "Base".into(), None).unwrap();
+        let report = phone.merge_changes(vec![note.clone()]).unwrap();
+        assert_eq!(report.inserted, 1);
+
+        // Descendant: laptop edits, phone has not touched it
+        let edited = laptop.update_note(&note.id, "Trip".into(), "L1".into(), None, false).unwrap();
+        assert_eq!(phone.merge_changes(vec![edited.clone()]).unwrap().updated, 1);
+
+        // Ancestor: the original copy arrives late
+        assert_eq!(phone.merge_changes(vec![note.clone()]).unwrap().ignored, 1);
+        assert_eq!(phone.get_note_by_id(&note.id).unwrap().content, "L1");
+
+        // Concurrent: both edit the same version independently
+        let on_laptop =
+            laptop.update_note(&note.id, "Trip".into(), "L2".into(), None, false).unwrap();
+        let on_phone =
+            phone.update_note(&note.id, "Trip".into(), "P2".into(), None, false).unwrap();
+        let report = phone.merge_changes(vec![on_laptop.clone()]).unwrap();
+        assert_eq!(report.concurrent, vec![note.id.clone()]);
+
+        let merged = phone.get_note_by_id(&note.id).unwrap();
+        assert_eq!(merged.version_vector, on_laptop.version_vector.join(&on_phone.version_vector));
+        assert_eq!(merged.version_vector.get(laptop.device_id()), 3);
+        assert_eq!(merged.version_vector.get(phone.device_id()), 1);
+    }
+
+    #[test]
+    fn test_list_known_devices() {
+        let laptop = get_mem_db();
+        let phone = get_mem_db();
+        let mut tablet = get_mem_db();
+
+        let a = laptop.create_note("A".into(), "".into(), None).unwrap();
+        let b = phone.create_note("B".into(), "".into(), None).unwrap();
+        tablet.merge_changes(vec![a.clone(), b.clone()]).unwrap();
+        // Own writes do not register the local device
+        let own = tablet.create_note("C".into(), "".into(), None).unwrap();
+        tablet.merge_changes(vec![own]).unwrap();
+
+        let devices = tablet.list_known_devices().unwrap();
+        let ids: Vec<&str> = devices.iter().map(|d| d.device_id.as_str()).collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&laptop.device_id()) && ids.contains(&phone.device_id()));
+
+        let seen_laptop = devices.iter().find(|d| d.device_id == laptop.device_id()).unwrap();
+        assert_eq!(seen_laptop.last_seen, a.hlc.to_datetime());
+    }
+
+    #[test]
+    fn test_evict_notes_leaves_no_tombstone() {
+        let db = get_mem_db();
+        let kept = db.create_note("Kept".into(), "".into(), None).unwrap();
+        let evicted = db.create_note("Evicted".into(), "".into(), None).unwrap();
+
+        assert_eq!(db.evict_notes(&[evicted.id.clone(), "missing".into()]).unwrap(), 1);
+        assert!(db.get_note_by_id(&evicted.id).is_err());
+        assert_eq!(db.get_changes_since(0).unwrap(), vec![kept]);
+    }
+
+    #[test]
+    fn test_purge_tombstones_keeps_recent_deletions() {
+        let db = get_mem_db();
+        let live = db.create_note("Live".into(), "".into(), None).unwrap();
+        let old = db.create_note("Old".into(), "".into(), None).unwrap();
+        let recent = db.create_note("Recent".into(), "".into(), None).unwrap();
+        db.delete_note(&old.id).unwrap();
+        db.delete_note(&recent.id).unwrap();
+        let cutoff = Utc::now() - chrono::Duration::days(30);
+        let mut expired = db.get_note_by_id(&old.id).unwrap();
+        expired.updated_at = cutoff - chrono::Duration::days(1);
+        write_note(&db.conn, &expired).unwrap();
+
+        let counts = db.note_counts().unwrap();
+        assert_eq!((counts.live, counts.tombstones), (1, 2));
+        assert_eq!(db.purge_tombstones(cutoff).unwrap(), 1);
+        assert!(db.get_note_by_id(&old.id).is_err());
+        assert!(db.get_note_by_id(&recent.id).unwrap().is_deleted);
+        assert!(!db.get_note_by_id(&live.id).unwrap().is_deleted);
+        assert!(db.integrity_check().unwrap().is_empty());
+    }
+
     #[test]
     fn test_settings_persistence() {
         let db = get_mem_db();
@@ -464,4 +1311,74 @@ mod tests {
         assert_eq!(fetched.accent_hue, 120);
         assert_eq!(fetched.font_family, "mono");
     }
+
+    #[test]
+    fn test_settings_merge_per_key() {
+        let laptop = DatabaseConnection::with_device_id(":memory:", "laptop").unwrap();
+        let desktop = DatabaseConnection::with_device_id(":memory:", "desktop").unwrap();
+
+        let mut on_laptop = laptop.get_settings().unwrap();
+        on_laptop.theme_mode = "dark".into();
+        on_laptop.font_size = 18;
+        laptop.update_settings(&on_laptop).unwrap();
+
+        let mut on_desktop = desktop.get_settings().unwrap();
+        on_desktop.font_family = "serif".into();
+        on_desktop.font_size = 12;
+        desktop.update_settings(&on_desktop).unwrap();
+
+        let applied = desktop.merge_settings(laptop.get_synced_settings().unwrap()).unwrap();
+        assert_eq!(applied.len(), 1);
+        laptop.merge_settings(desktop.get_synced_settings().unwrap()).unwrap();
+
+        let (a, b) = (laptop.get_settings().unwrap(), desktop.get_settings().unwrap());
+        assert_eq!((a.theme_mode.as_str(), a.font_family.as_str()), ("dark", "serif"));
+        assert_eq!((b.theme_mode.as_str(), b.font_family.as_str()), ("dark", "serif"));
+        // Font size is device-local and never synced
+        assert_eq!((a.font_size, b.font_size), (18, 12));
+        assert_eq!(laptop.get_synced_settings().unwrap(), desktop.get_synced_settings().unwrap());
+    }
+
+    #[test]
+    fn test_settings_older_write_loses() {
+        let laptop = DatabaseConnection::with_device_id(":memory:", "laptop").unwrap();
+        let desktop = DatabaseConnection::with_device_id(":memory:", "desktop").unwrap();
+
+        let mut settings = UserSettings { theme_mode: "light".into(), ..Default::default() };
+        laptop.update_settings(&settings).unwrap();
+        let stale = laptop.get_synced_settings().unwrap();
+
+        settings.theme_mode = "dark".into();
+        desktop.clock().advance_to(&stale[0].hlc);
+        desktop.update_settings(&settings).unwrap();
+
+        assert!(desktop.merge_settings(stale).unwrap().is_empty());
+        assert_eq!(desktop.get_settings().unwrap().theme_mode, "dark");
+    }
+
+    #[test]
+    fn test_legacy_settings_become_zero_stamped_entries() {
+        let path = std::env::temp_dir().join(format!("notaro-{}.db", Uuid::new_v4()));
+        {
+            let legacy = Connection::open(&path).unwrap();
+            legacy
+                .execute_batch(
+                    "CREATE TABLE settings (
+                        id INTEGER PRIMARY KEY CHECK (id = 1), theme_mode TEXT NOT NULL,
+                        accent_hue INTEGER NOT NULL, font_family TEXT NOT NULL, font_size INTEGER NOT NULL
+                    );
+                    INSERT INTO settings VALUES (1, 'dark', 30, 'mono', 16);",
+                )
+                .unwrap();
+        }
+
+        let db = DatabaseConnection::new(&path).unwrap();
+        let entries = db.get_synced_settings().unwrap();
+        assert_eq!(entries.len(), UserSettings::SYNCED_KEYS.len());
+        assert!(entries.iter().all(|e| e.hlc == Hlc::default()));
+        assert_eq!(db.get_settings().unwrap().accent_hue, 30);
+
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,205 @@
+use crate::models::Note;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// One step of a `TextDelta`. Lengths are in bytes of the UTF-8 base text and always fall on
+/// character boundaries.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum DeltaOp {
+    /// Copy the next `n` bytes of the base unchanged
+    Retain(usize),
+    /// Skip the next `n` bytes of the base
+    Delete(usize),
+    /// Insert new text
+    Insert(String),
+}
+
+/// Edit script turning one version of a note body into another.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct TextDelta {
+    pub ops: Vec<DeltaOp>,
+}
+
+impl TextDelta {
+    /// Computes a delta from `base` to `target`.
+    /// Trims the common prefix and suffix and replaces what is left, which is linear in the size
+    /// of the note and exact for the typical single-region edit.
+    pub fn diff(base: &str, target: &str) -> Self {
+        let prefix = common_prefix_len(base, target);
+        let suffix = common_suffix_len(&base[prefix..], &target[prefix..]);
+
+        let deleted = base.len() - prefix - suffix;
+        let inserted = &target[prefix..target.len() - suffix];
+
+        let mut ops = Vec::new();
+        if prefix > 0 {
+            ops.push(DeltaOp::Retain(prefix));
+        }
+        if deleted > 0 {
+            ops.push(DeltaOp::Delete(deleted));
+        }
+        if !inserted.is_empty() {
+            ops.push(DeltaOp::Insert(inserted.to_string()));
+        }
+        if suffix > 0 {
+            ops.push(DeltaOp::Retain(suffix));
+        }
+        Self { ops }
+    }
+
+    /// Applies the delta to `base`. Returns `None` if the ops do not line up with `base`.
+    pub fn apply(&self, base: &str) -> Option<String> {
+        let mut out = String::with_capacity(base.len() + self.inserted_len());
+        let mut pos: usize = 0;
+
+        for op in &self.ops {
+            match op {
+                DeltaOp::Retain(n) => {
+                    out.push_str(base.get(pos..pos.checked_add(*n)?)?);
+                    pos += n;
+                }
+                DeltaOp::Delete(n) => {
+                    base.get(pos..pos.checked_add(*n)?)?;
+                    pos += n;
+                }
+                DeltaOp::Insert(text) => out.push_str(text),
+            }
+        }
+
+        // Anything not mentioned by the ops is kept
+        out.push_str(base.get(pos..)?);
+        Some(out)
+    }
+
+    fn inserted_len(&self) -> usize {
+        self.ops
+            .iter()
+            .map(|op| match op {
+                DeltaOp::Insert(text) => text.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Rough wire cost of the delta, used to decide whether sending it beats the full body
+    pub fn payload_len(&self) -> usize {
+        self.inserted_len() + self.ops.len() * 8
+    }
+}
+
+/// A new version of a note expressed as a delta against a version the receiver already has.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct NoteDelta {
+    /// The new version with every field set except `content`, which is left empty and rebuilt
+    /// from `delta`
+    pub note: Note,
+    pub base_version: i32,
+    /// `content_hash` of the base body. Versions alone are not unique across devices, so the
+    /// receiver only applies the delta if its copy hashes the same.
+    pub base_hash: String,
+    pub delta: TextDelta,
+}
+
+impl NoteDelta {
+    /// Builds a delta from `base_content` (known to be held by the peer at `base_version`) to
+    /// `note`. Returns `None` when the delta would not be smaller than the full body.
+    pub fn between(base_version: i32, base_content: &str, note: &Note) -> Option<Self> {
+        let delta = TextDelta::diff(base_content, &note.content);
+        if delta.payload_len() >= note.content.len() {
+            return None;
+        }
+
+        let mut header = note.clone();
+        header.content = String::new();
+        Some(Self { note: header, base_version, base_hash: content_hash(base_content), delta })
+    }
+
+    /// Rebuilds the full note from the receiver's copy of the base body.
+    pub fn apply(&self, base_content: &str) -> Option<Note> {
+        let mut note = self.note.clone();
+        note.content = self.delta.apply(base_content)?;
+        Some(note)
+    }
+}
+
+/// Hex-encoded SHA-256 of a note body
+pub fn content_hash(content: &str) -> String {
+    Sha256::digest(content.as_bytes()).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn common_prefix_len(a: &str, b: &str) -> usize {
+    let mut len = a.bytes().zip(b.bytes()).take_while(|(x, y)| x == y).count();
+    while !a.is_char_boundary(len) {
+        len -= 1;
+    }
+    len
+}
+
+fn common_suffix_len(a: &str, b: &str) -> usize {
+    let mut len = a.bytes().rev().zip(b.bytes().rev()).take_while(|(x, y)| x == y).count();
+    while !a.is_char_boundary(a.len() - len) || !b.is_char_boundary(b.len() - len) {
+        len -= 1;
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_round_trips() {
+        let cases = [
+            ("", ""),
+            ("", "new"),
+            ("old", ""),
+            ("hello world", "hello brave world"),
+            ("hello world", "hello"),
+            ("abcabc", "abc"),
+            ("same", "same"),
+            ("naïve café", "naive café"),
+            ("🦀🦀", "🦀🐍🦀"),
+        ];
+
+        for (base, target) in cases {
+            let delta = TextDelta::diff(base, target);
+            assert_eq!(delta.apply(base).as_deref(), Some(target), "{base:?} -> {target:?}");
+        }
+    }
+
+    #[test]
+    fn test_single_char_edit_is_small() {
+        let base = "a".repeat(10_000);
+        let mut target = base.clone();
+        target.insert(5_000, 'b');
+
+        let delta = TextDelta::diff(&base, &target);
+        assert_eq!(
+            delta.ops,
+            vec![DeltaOp::Retain(5_000), DeltaOp::Insert("b".into()), DeltaOp::Retain(5_000)]
+        );
+    }
+
+    #[test]
+    fn test_apply_rejects_mismatched_base() {
+        let delta = TextDelta::diff("hello world", "hello there world");
+        assert!(delta.apply("hi").is_none());
+    }
+
+    #[test]
+    fn test_note_delta_skips_when_not_smaller() {
+        let mut note = Note::new("T".into(), "xy".into(), None);
+        assert!(NoteDelta::between(1, "ab", &note).is_none());
+
+        note.content = format!("{}!", "body ".repeat(100));
+        let base = "body ".repeat(100);
+        let delta = NoteDelta::between(1, &base, &note).unwrap();
+        assert!(delta.note.content.is_empty());
+        assert_eq!(delta.base_hash, content_hash(&base));
+        assert_eq!(delta.apply(&base).unwrap(), note);
+    }
+}
@@ -8,9 +8,29 @@ pub enum NotaroError {
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
 
+    #[error("Encoding error: {0}")]
+    Encode(#[from] rmp_serde::encode::Error),
+
+    #[error("Decoding error: {0}")]
+    Decode(#[from] rmp_serde::decode::Error),
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
+    /// A frame that would decode to more than the receiver accepts, compressed or not
+    #[error("frame is larger than {0} bytes once decompressed")]
+    FrameTooLarge(usize),
+
+    /// The server revoked this device's session; the user has to log in again
+    #[error("this device was signed out")]
+    SignedOut,
+
+    #[error("TLS error: {0}")]
+    Tls(String),
+
+    #[error("Postgres error: {0}")]
+    Postgres(String),
+
     #[error("Unknown error occurred")]
     Unknown,
 }
@@ -34,6 +54,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn test_serialization_error_conversion() {
         // Trigger a serde error by parsing invalid JSON
         let err_result = serde_json::from_str::<serde_json::Value>("{invalid_json");
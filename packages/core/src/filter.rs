@@ -0,0 +1,96 @@
+use crate::models::Note;
+use serde::{Deserialize, Serialize};
+
+/// Which notes a replica wants to receive, declared by the client in `Hello`.
+///
+/// A note is excluded if its folder or any of its tags is excluded. Otherwise, when no include
+/// list is set everything is in; when one is set, the note must match an included folder or
+/// included tag. Folders compare exactly, tags case-insensitively.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub struct SyncFilter {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include_folders: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_folders: Vec<String>,
+    /// Tags without the leading `#`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include_tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_tags: Vec<String>,
+}
+
+impl SyncFilter {
+    /// True for the filter that lets every note through
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn matches(&self, note: &Note) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        let folder = note.folder.as_deref();
+        let tags = note.tags();
+        let has_tag = |list: &[String]| list.iter().any(|t| tags.contains(&t.to_lowercase()));
+
+        if folder.is_some_and(|f| self.exclude_folders.iter().any(|x| x == f))
+            || has_tag(&self.exclude_tags)
+        {
+            return false;
+        }
+        if self.include_folders.is_empty() && self.include_tags.is_empty() {
+            return true;
+        }
+        folder.is_some_and(|f| self.include_folders.iter().any(|x| x == f))
+            || has_tag(&self.include_tags)
+    }
+
+    /// Separates notes the subscriber should hold from the ids of those it should evict
+    pub fn split(&self, notes: Vec<Note>) -> (Vec<Note>, Vec<String>) {
+        let (kept, evicted): (Vec<Note>, Vec<Note>) =
+            notes.into_iter().partition(|note| self.matches(note));
+        (kept, evicted.into_iter().map(|note| note.id).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(folder: Option<&str>, content: &str) -> Note {
+        Note::new("T".into(), content.into(), folder.map(Into::into))
+    }
+
+    #[test]
+    fn test_empty_filter_matches_everything() {
+        assert!(SyncFilter::default().matches(&note(None, "")));
+        assert!(SyncFilter::default().matches(&note(Some("Personal"), "#diary")));
+    }
+
+    #[test]
+    fn test_include_and_exclude() {
+        let filter = SyncFilter {
+            include_folders: vec!["Work".into()],
+            include_tags: vec!["Shared".into()],
+            exclude_tags: vec!["private".into()],
+            ..Default::default()
+        };
+
+        assert!(filter.matches(&note(Some("Work"), "Standup")));
+        assert!(filter.matches(&note(Some("Personal"), "Groceries #shared")));
+        assert!(!filter.matches(&note(Some("Personal"), "Diary")));
+        assert!(!filter.matches(&note(None, "Loose note")));
+        assert!(!filter.matches(&note(Some("Work"), "Review #Private")));
+    }
+
+    #[test]
+    fn test_exclude_only_keeps_the_rest() {
+        let filter = SyncFilter { exclude_folders: vec!["Personal".into()], ..Default::default() };
+        let (kept, evicted) =
+            filter.split(vec![note(Some("Work"), ""), note(Some("Personal"), "")]);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(evicted.len(), 1);
+        assert!(filter.matches(&note(None, "")));
+    }
+}
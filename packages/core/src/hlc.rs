@@ -0,0 +1,230 @@
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fmt;
+use std::str::FromStr;
+
+/// A hybrid logical clock timestamp.
+///
+/// Orders first by physical milliseconds, then by a logical counter for events that share (or
+/// appear to precede) a millisecond, then by the id of the node that produced it, so any two
+/// stamps from different nodes compare deterministically.
+///
+/// Encoded as `"{wall_ms:016x}-{counter:08x}-{node}"`, which sorts the same way as the struct and
+/// can therefore be used directly in `ORDER BY`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Hlc {
+    pub wall_ms: i64,
+    pub counter: u32,
+    pub node: String,
+}
+
+impl Hlc {
+    /// Physical component as a wall-clock time
+    pub fn to_datetime(&self) -> DateTime<Utc> {
+        Utc.timestamp_millis_opt(self.wall_ms).single().unwrap_or_default()
+    }
+
+    /// Stamp for data written before clocks existed: its own timestamp and no node
+    pub fn from_datetime(time: DateTime<Utc>) -> Self {
+        Self { wall_ms: time.timestamp_millis(), counter: 0, node: String::new() }
+    }
+}
+
+impl fmt::Display for Hlc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}-{:08x}-{}", self.wall_ms, self.counter, self.node)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseHlcError(String);
+
+impl fmt::Display for ParseHlcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid HLC timestamp: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseHlcError {}
+
+impl FromStr for Hlc {
+    type Err = ParseHlcError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseHlcError(s.to_string());
+        let mut parts = s.splitn(3, '-');
+        // Parsed as unsigned so the two's complement form of pre-1970 stamps reads back
+        let wall_ms = parts
+            .next()
+            .and_then(|p| u64::from_str_radix(p, 16).ok())
+            .map(|v| v as i64)
+            .ok_or_else(err)?;
+        let counter = parts.next().and_then(|p| u32::from_str_radix(p, 16).ok()).ok_or_else(err)?;
+        let node = parts.next().ok_or_else(err)?.to_string();
+        Ok(Self { wall_ms, counter, node })
+    }
+}
+
+impl From<Hlc> for String {
+    fn from(hlc: Hlc) -> Self {
+        hlc.to_string()
+    }
+}
+
+impl TryFrom<String> for Hlc {
+    type Error = ParseHlcError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Per-replica hybrid logical clock.
+/// `now` is called for every local write and `observe` for every remote stamp merged in, which
+/// keeps stamps monotonic and causally ordered even when device wall clocks disagree.
+#[derive(Debug)]
+pub struct HybridClock {
+    node: String,
+    last: Cell<(i64, u32)>,
+    skew_ms: Cell<i64>,
+    pinned_ms: Cell<Option<i64>>,
+}
+
+impl HybridClock {
+    pub fn new(node: String) -> Self {
+        Self { node, last: Cell::new((0, 0)), skew_ms: Cell::new(0), pinned_ms: Cell::new(None) }
+    }
+
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Offsets the physical clock, modelling a device whose wall clock is wrong
+    pub fn set_skew_ms(&self, skew_ms: i64) {
+        self.skew_ms.set(skew_ms);
+    }
+
+    /// Replaces the system clock with a fixed physical time (skew still applies), or restores
+    /// it with `None`. Lets simulations run on virtual time.
+    pub fn set_physical_ms(&self, ms: Option<i64>) {
+        self.pinned_ms.set(ms);
+    }
+
+    /// Stamp for a local event
+    pub fn now(&self) -> Hlc {
+        let physical = self.physical_ms();
+        let (last_ms, last_counter) = self.last.get();
+
+        let next = if physical > last_ms { (physical, 0) } else { (last_ms, last_counter + 1) };
+        self.stamp(next)
+    }
+
+    /// Folds a remote stamp into the clock and returns a stamp ordered after both it and every
+    /// earlier local event
+    pub fn observe(&self, remote: &Hlc) -> Hlc {
+        let physical = self.physical_ms();
+        let (last_ms, last_counter) = self.last.get();
+        let wall_ms = physical.max(last_ms).max(remote.wall_ms);
+
+        let counter = if wall_ms == last_ms && wall_ms == remote.wall_ms {
+            last_counter.max(remote.counter) + 1
+        } else if wall_ms == last_ms {
+            last_counter + 1
+        } else if wall_ms == remote.wall_ms {
+            remote.counter + 1
+        } else {
+            0
+        };
+        self.stamp((wall_ms, counter))
+    }
+
+    /// Moves the clock forward to at least `hlc` without producing a new event, e.g. when
+    /// reloading stamps from disk
+    pub fn advance_to(&self, hlc: &Hlc) {
+        if (hlc.wall_ms, hlc.counter) > self.last.get() {
+            self.last.set((hlc.wall_ms, hlc.counter));
+        }
+    }
+
+    fn stamp(&self, (wall_ms, counter): (i64, u32)) -> Hlc {
+        self.last.set((wall_ms, counter));
+        Hlc { wall_ms, counter, node: self.node.clone() }
+    }
+
+    fn physical_ms(&self) -> i64 {
+        let physical = self.pinned_ms.get().unwrap_or_else(|| Utc::now().timestamp_millis());
+        physical + self.skew_ms.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string_encoding_round_trips_and_sorts() {
+        let a = Hlc { wall_ms: 1_700_000_000_000, counter: 2, node: "node-a".into() };
+        let b = Hlc { wall_ms: 1_700_000_000_000, counter: 10, node: "node-a".into() };
+        let c = Hlc { wall_ms: 1_700_000_000_001, counter: 0, node: "a".into() };
+
+        assert_eq!(a.to_string().parse::<Hlc>().unwrap(), a);
+        assert!(a < b && b < c);
+        assert!(a.to_string() < b.to_string() && b.to_string() < c.to_string());
+        assert!("garbage".parse::<Hlc>().is_err());
+
+        let json = serde_json::to_string(&a).unwrap();
+        assert_eq!(serde_json::from_str::<Hlc>(&json).unwrap(), a);
+    }
+
+    #[test]
+    fn test_now_is_monotonic() {
+        let clock = HybridClock::new("n".into());
+        let mut previous = clock.now();
+        for _ in 0..1_000 {
+            let next = clock.now();
+            assert!(next > previous);
+            previous = next;
+        }
+    }
+
+    #[test]
+    fn test_observe_orders_after_skewed_remote() {
+        let ahead = HybridClock::new("ahead".into());
+        ahead.set_skew_ms(60 * 60 * 1000);
+        let behind = HybridClock::new("behind".into());
+
+        let remote = ahead.now();
+        let received = behind.observe(&remote);
+        let local_edit = behind.now();
+
+        // Even with a wall clock an hour behind, later events sort after what was seen
+        assert!(received > remote);
+        assert!(local_edit > received);
+        assert_eq!(local_edit.wall_ms, remote.wall_ms);
+    }
+
+    #[test]
+    fn test_pinned_physical_time() {
+        let clock = HybridClock::new("n".into());
+        clock.set_physical_ms(Some(1_000));
+        clock.set_skew_ms(5);
+
+        assert_eq!(clock.now(), Hlc { wall_ms: 1_005, counter: 0, node: "n".into() });
+        assert_eq!(clock.now(), Hlc { wall_ms: 1_005, counter: 1, node: "n".into() });
+        clock.set_physical_ms(Some(2_000));
+        assert_eq!(clock.now(), Hlc { wall_ms: 2_005, counter: 0, node: "n".into() });
+    }
+
+    #[test]
+    fn test_advance_to_never_moves_backwards() {
+        let clock = HybridClock::new("n".into());
+        let future = Hlc { wall_ms: i64::MAX / 2, counter: 5, node: "x".into() };
+        clock.advance_to(&future);
+        clock.advance_to(&Hlc::default());
+
+        let next = clock.now();
+        assert_eq!((next.wall_ms, next.counter), (future.wall_ms, 6));
+    }
+}
@@ -1,11 +1,34 @@
+pub mod codec;
 pub mod database;
+pub mod delta;
 pub mod error;
+pub mod filter;
+pub mod hlc;
+pub mod merge;
+pub mod merkle;
 pub mod models;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod simulation;
+pub mod store;
+pub mod sync;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod version_vector;
 
 // Re-export for easier access
-pub use database::DatabaseConnection;
+pub use codec::FrameCodec;
+pub use database::{ChangePage, DatabaseConnection, NoteCounts};
 pub use error::NotaroError;
-pub use models::{Note, SyncMessage, UserSettings};
+pub use filter::SyncFilter;
+pub use hlc::Hlc;
+pub use merge::MergeReport;
+pub use models::{
+    ChangeCursor, KnownDevice, Limit, Note, NoteActivity, NotePresence, RejectedNote, SettingEntry,
+    SyncMessage, UserSettings,
+};
+pub use store::NoteStore;
+pub use version_vector::VersionVector;
 
 pub fn core_entrypoint() -> String {
     "Notaro Core initialized.".to_string()
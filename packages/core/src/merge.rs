@@ -0,0 +1,118 @@
+use crate::models::Note;
+use crate::version_vector::Causality;
+
+/// Summary of a `merge_changes` call
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    /// Notes the replica did not have yet
+    pub inserted: usize,
+    /// Existing notes replaced by a causally newer remote copy
+    pub updated: usize,
+    /// Remote copies that were stale or already known
+    pub ignored: usize,
+    /// Ids of notes that had been edited concurrently on both sides
+    pub concurrent: Vec<String>,
+}
+
+impl MergeReport {
+    /// Adds the outcome of another merge to this one
+    pub fn add(&mut self, other: MergeReport) {
+        self.inserted += other.inserted;
+        self.updated += other.updated;
+        self.ignored += other.ignored;
+        self.concurrent.extend(other.concurrent);
+    }
+}
+
+/// What to do with an incoming remote copy of a note the replica already has
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// The local copy already includes the remote write (or is the same write)
+    KeepLocal,
+    /// The remote copy descends from the local one
+    TakeRemote,
+    /// Both sides were edited independently. The copy with the later HLC stamp wins, and the
+    /// stored version vector becomes the join of both so the conflict is not seen again.
+    Concurrent { remote_wins: bool },
+}
+
+/// Decides how a remote note merges into a local one.
+///
+/// Version vectors settle causality when both sides carry one. If they are equal (or either side
+/// predates vectors), the note falls back to comparing `version` and then the HLC stamp.
+pub fn resolve(local: &Note, remote: &Note) -> Resolution {
+    if !local.version_vector.is_empty() && !remote.version_vector.is_empty() {
+        match remote.version_vector.compare(&local.version_vector) {
+            Causality::Descendant => return Resolution::TakeRemote,
+            Causality::Ancestor => return Resolution::KeepLocal,
+            Causality::Concurrent => {
+                return Resolution::Concurrent { remote_wins: remote.hlc > local.hlc };
+            }
+            Causality::Equal => {}
+        }
+    }
+
+    if (remote.version, &remote.hlc) > (local.version, &local.hlc) {
+        Resolution::TakeRemote
+    } else {
+        Resolution::KeepLocal
+    }
+}
+
+/// The note stored after merging two concurrent copies: the winner's fields with the joined
+/// version vector and the higher of the two versions, identical on every replica that merges the
+/// same pair.
+pub fn merge_concurrent(local: &Note, remote: &Note, remote_wins: bool) -> Note {
+    let mut merged = if remote_wins { remote.clone() } else { local.clone() };
+    merged.version_vector = local.version_vector.join(&remote.version_vector);
+    merged.version = local.version.max(remote.version);
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hlc::Hlc;
+    use crate::version_vector::VersionVector;
+
+    fn note_with(vv: VersionVector, version: i32, wall_ms: i64) -> Note {
+        let mut note = Note::new("T".into(), "C".into(), None);
+        note.version_vector = vv;
+        note.version = version;
+        note.hlc = Hlc { wall_ms, counter: 0, node: "n".into() };
+        note
+    }
+
+    #[test]
+    fn test_vectors_decide_causality() {
+        let base = note_with(VersionVector::from([("a", 1)]), 1, 10);
+        let child = note_with(VersionVector::from([("a", 1), ("b", 1)]), 2, 5);
+
+        // Lower HLC, but causally newer
+        assert_eq!(resolve(&base, &child), Resolution::TakeRemote);
+        assert_eq!(resolve(&child, &base), Resolution::KeepLocal);
+    }
+
+    #[test]
+    fn test_concurrent_merge_is_symmetric() {
+        let a = note_with(VersionVector::from([("a", 2)]), 2, 10);
+        let b = note_with(VersionVector::from([("a", 1), ("b", 1)]), 2, 20);
+
+        assert_eq!(resolve(&a, &b), Resolution::Concurrent { remote_wins: true });
+        assert_eq!(resolve(&b, &a), Resolution::Concurrent { remote_wins: false });
+
+        let on_a = merge_concurrent(&a, &b, true);
+        let on_b = merge_concurrent(&b, &a, false);
+        assert_eq!(on_a, on_b);
+        assert_eq!(on_a.version_vector, VersionVector::from([("a", 2), ("b", 1)]));
+    }
+
+    #[test]
+    fn test_falls_back_to_version_without_vectors() {
+        let old = note_with(VersionVector::default(), 1, 10);
+        let new = note_with(VersionVector::default(), 3, 5);
+        assert_eq!(resolve(&old, &new), Resolution::TakeRemote);
+        assert_eq!(resolve(&new, &old), Resolution::KeepLocal);
+        assert_eq!(resolve(&new, &new), Resolution::KeepLocal);
+    }
+}
@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+
+/// Hex digits of the id hash that select a leaf range; the tree has `16^MERKLE_DEPTH` leaves
+pub const MERKLE_DEPTH: usize = 3;
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+/// Hash of a node with no notes below it
+const EMPTY: [u8; 32] = [0; 32];
+
+/// Hash of one range of the id space, as exchanged during reconciliation
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RangeDigest {
+    /// Hex prefix of `sha256(id)` selecting the range; empty for the whole tree
+    pub prefix: String,
+    /// Hex SHA-256 over every `(id, version)` in the range
+    pub hash: String,
+}
+
+/// Range-hash summary of a replica's notes over `(id, version)`.
+///
+/// Notes are bucketed by the hex SHA-256 of their id, so ranges stay balanced whatever the ids
+/// look like. Each node hashes its 16 children, so two replicas find where they differ by
+/// descending only into ranges whose hashes disagree.
+#[derive(Debug, Clone, Default)]
+pub struct MerkleTree {
+    /// Hashes of every non-empty node, keyed by prefix
+    nodes: HashMap<String, [u8; 32]>,
+}
+
+impl MerkleTree {
+    pub fn build<I, S>(entries: I) -> Self
+    where
+        I: IntoIterator<Item = (S, i32)>,
+        S: AsRef<str>,
+    {
+        let mut leaves: HashMap<String, Vec<(String, i32)>> = HashMap::new();
+        for (id, version) in entries {
+            let id = id.as_ref();
+            leaves.entry(range_of(id)).or_default().push((id.to_string(), version));
+        }
+
+        let mut nodes = HashMap::new();
+        for (prefix, mut entries) in leaves {
+            entries.sort();
+            let mut hasher = Sha256::new();
+            for (id, version) in &entries {
+                // Length-prefixed so no two entry lists hash the same bytes
+                hasher.update((id.len() as u64).to_le_bytes());
+                hasher.update(id.as_bytes());
+                hasher.update(version.to_le_bytes());
+            }
+            nodes.insert(prefix, hasher.finalize().into());
+        }
+
+        for depth in (0..MERKLE_DEPTH).rev() {
+            let parents: BTreeSet<String> = nodes
+                .keys()
+                .filter(|prefix| prefix.len() == depth + 1)
+                .map(|prefix| prefix[..depth].to_string())
+                .collect();
+            for parent in parents {
+                let mut hasher = Sha256::new();
+                for child in Self::children(&parent) {
+                    hasher.update(nodes.get(&child).unwrap_or(&EMPTY));
+                }
+                nodes.insert(parent, hasher.finalize().into());
+            }
+        }
+
+        Self { nodes }
+    }
+
+    /// Digest of the whole tree
+    pub fn root(&self) -> RangeDigest {
+        self.digest("")
+    }
+
+    pub fn digest(&self, prefix: &str) -> RangeDigest {
+        let hash = self.nodes.get(prefix).unwrap_or(&EMPTY);
+        RangeDigest { prefix: prefix.to_string(), hash: to_hex(hash) }
+    }
+
+    /// The ranges among `theirs` whose hash differs from ours
+    pub fn differing<'a>(
+        &'a self,
+        theirs: &'a [RangeDigest],
+    ) -> impl Iterator<Item = &'a RangeDigest> + 'a {
+        theirs.iter().filter(|range| self.digest(&range.prefix).hash != range.hash)
+    }
+
+    /// True for ranges that hold notes directly rather than sub-ranges
+    pub fn is_leaf(prefix: &str) -> bool {
+        prefix.len() >= MERKLE_DEPTH
+    }
+
+    /// The 16 sub-ranges of an inner range
+    pub fn children(prefix: &str) -> impl Iterator<Item = String> + '_ {
+        HEX.iter().map(move |&digit| format!("{prefix}{}", digit as char))
+    }
+}
+
+/// Leaf range a note id falls into
+pub fn range_of(id: &str) -> String {
+    to_hex(&Sha256::digest(id.as_bytes()))[..MERKLE_DEPTH].to_string()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(n: usize) -> Vec<(String, i32)> {
+        (0..n).map(|i| (format!("note-{i}"), 1)).collect()
+    }
+
+    #[test]
+    fn test_root_ignores_insertion_order() {
+        let forward = MerkleTree::build(entries(100));
+        let backward = MerkleTree::build(entries(100).into_iter().rev());
+        assert_eq!(forward.root(), backward.root());
+        assert_ne!(forward.root(), MerkleTree::default().root());
+    }
+
+    #[test]
+    fn test_version_change_is_found_by_descending() {
+        let ours = MerkleTree::build(entries(500));
+        let mut changed = entries(500);
+        changed[42].1 = 2;
+        let theirs = MerkleTree::build(changed);
+
+        let mut ranges = vec![theirs.root()];
+        let mut rounds = 0;
+        while !MerkleTree::is_leaf(&ranges[0].prefix) {
+            let differing: Vec<RangeDigest> = ours.differing(&ranges).cloned().collect();
+            assert_eq!(differing.len(), 1);
+            ranges =
+                MerkleTree::children(&differing[0].prefix).map(|p| theirs.digest(&p)).collect();
+            rounds += 1;
+        }
+
+        let leaf: Vec<&RangeDigest> = ours.differing(&ranges).collect();
+        assert_eq!(rounds, MERKLE_DEPTH);
+        assert_eq!(leaf.len(), 1);
+        assert_eq!(leaf[0].prefix, range_of("note-42"));
+    }
+}
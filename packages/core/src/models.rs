@@ -1,8 +1,15 @@
+use crate::codec::{Compression, Encoding, FrameCodec};
+use crate::delta::NoteDelta;
+use crate::filter::SyncFilter;
+use crate::hlc::Hlc;
+use crate::merkle::RangeDigest;
+use crate::version_vector::VersionVector;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct Note {
     pub id: String,
     pub title: String,
@@ -15,6 +22,18 @@ pub struct Note {
     pub version: i32,
     /// Soft delete flag
     pub is_deleted: bool,
+    /// Hybrid logical clock stamp of the last write; orders notes across devices with skewed
+    /// clocks and breaks ties between equal versions
+    #[serde(default)]
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
+    pub hlc: Hlc,
+    /// Per-device write counters, used to tell causally newer versions from concurrent edits
+    #[serde(default)]
+    #[cfg_attr(feature = "openapi", schema(value_type = std::collections::BTreeMap<String, u64>))]
+    pub version_vector: VersionVector,
+    /// Shared collection the note belongs to; `None` for a private note
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
 }
 
 impl Note {
@@ -30,10 +49,39 @@ impl Note {
             updated_at: now,
             version: 1,
             is_deleted: false,
+            hlc: Hlc::from_datetime(now),
+            version_vector: VersionVector::default(),
+            collection: None,
         }
     }
+
+    /// Bytes of the title and body, as counted against storage quotas
+    pub fn size(&self) -> usize {
+        self.title.len() + self.content.len()
+    }
+
+    /// Lowercased `#hashtags` in the note body, in order of first appearance.
+    /// A tag starts at a `#` at the beginning of a word, so Markdown headings (`# Title`) are not
+    /// tags.
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> = Vec::new();
+        for word in self.content.split_whitespace() {
+            let Some(rest) = word.strip_prefix('#') else { continue };
+            let tag: String = rest
+                .chars()
+                .take_while(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '/'))
+                .flat_map(char::to_lowercase)
+                .collect();
+            if !tag.is_empty() && !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        tags
+    }
 }
 
+/// Appearance preferences. `theme_mode`, `accent_hue` and `font_family` follow the user between
+/// devices; `font_size` depends on the screen and stays device-local.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct UserSettings {
     /// "system", "light", or "dark"
@@ -57,18 +105,261 @@ impl Default for UserSettings {
     }
 }
 
+impl UserSettings {
+    /// Keys synced across devices, each merged on its own by last-writer-wins
+    pub const SYNCED_KEYS: [&str; 3] = ["theme_mode", "accent_hue", "font_family"];
+
+    /// Current value of a synced key, or `None` for keys that are not synced
+    pub fn synced_value(&self, key: &str) -> Option<serde_json::Value> {
+        match key {
+            "theme_mode" => Some(self.theme_mode.clone().into()),
+            "accent_hue" => Some(self.accent_hue.into()),
+            "font_family" => Some(self.font_family.clone().into()),
+            _ => None,
+        }
+    }
+
+    /// Sets a synced key. Unknown keys (e.g. from a newer client) and mistyped values are ignored.
+    pub fn apply_synced(&mut self, key: &str, value: &serde_json::Value) {
+        let value = value.clone();
+        match key {
+            "theme_mode" => {
+                if let Ok(theme_mode) = serde_json::from_value(value) {
+                    self.theme_mode = theme_mode;
+                }
+            }
+            "accent_hue" => {
+                if let Ok(accent_hue) = serde_json::from_value(value) {
+                    self.accent_hue = accent_hue;
+                }
+            }
+            "font_family" => {
+                if let Ok(font_family) = serde_json::from_value(value) {
+                    self.font_family = font_family;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// One synced setting and the HLC stamp of the write that set it
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SettingEntry {
+    pub key: String,
+    pub value: serde_json::Value,
+    pub hlc: Hlc,
+}
+
+/// Another device this replica has received writes from
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct KnownDevice {
+    pub device_id: String,
+    /// Time of the most recent write seen from the device, by its hybrid logical clock
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Position in the change feed, which is ordered by `(version, id)`.
+/// Handed back in a `PullResponse` so the next `PullRequest` resumes where the last page ended.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ChangeCursor {
+    pub version: i32,
+    pub id: String,
+}
+
+impl ChangeCursor {
+    /// Cursor pointing just past the given note
+    pub fn after(note: &Note) -> Self {
+        Self { version: note.version, id: note.id.clone() }
+    }
+}
+
+/// A pushed note the server refused to store, and why
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct RejectedNote {
+    pub id: String,
+    pub reason: String,
+    /// Set when the note broke a server limit rather than a permission
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<Limit>,
+}
+
+/// A server limit that a client ran into
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Limit {
+    /// Most live notes an account or shared collection may hold
+    NoteCount,
+    /// Most bytes of titles and bodies an account or shared collection may hold
+    StorageBytes,
+    /// Largest single note
+    NoteSize,
+    /// Largest single message
+    MessageSize,
+    /// Messages per second on one connection
+    RateLimit,
+}
+
+impl Limit {
+    /// The name used on the wire
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NoteCount => "note_count",
+            Self::StorageBytes => "storage_bytes",
+            Self::NoteSize => "note_size",
+            Self::MessageSize => "message_size",
+            Self::RateLimit => "rate_limit",
+        }
+    }
+}
+
+/// What a device is doing with a note, as announced through `SetPresence`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum NoteActivity {
+    /// The note is open; repeated while it stays open
+    Opened,
+    /// The note is being edited right now
+    Typing,
+    Closed,
+}
+
+/// A device that has a note open
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct NotePresence {
+    /// Stands for the device's session without revealing it; see `sync::presence_device_id`
+    pub device: String,
+    pub device_name: String,
+    pub username: String,
+    /// Edited the note within the last `sync::TYPING_TIMEOUT`
+    #[serde(default)]
+    pub typing: bool,
+}
+
 /// Message structure for WebSocket communication
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(tag = "type", content = "payload")]
 pub enum SyncMessage {
+    /// First message a client sends, listing the wire formats it understands.
+    /// Clients that skip the handshake are spoken to in plain JSON.
+    Hello {
+        #[serde(default)]
+        encodings: Vec<Encoding>,
+        #[serde(default)]
+        compression: Vec<Compression>,
+        /// Notes this replica wants; the rest are withheld and evicted
+        #[serde(default, skip_serializing_if = "SyncFilter::is_empty")]
+        filter: SyncFilter,
+    },
+    /// Server's answer to `Hello`; every later frame in both directions uses `codec`
+    Welcome { codec: FrameCodec },
     /// Client asking server for changes since a specific version/timestamp
-    PullRequest { since_version: i32 },
+    PullRequest {
+        since_version: i32,
+        /// Resume after this position (from the previous page's `next_cursor`)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cursor: Option<ChangeCursor>,
+        /// Maximum number of notes per page; the server applies its own default and cap
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<u32>,
+    },
     /// Server sending updates to client
-    PullResponse { changes: Vec<Note>, current_version: i32 },
+    PullResponse {
+        changes: Vec<Note>,
+        current_version: i32,
+        /// More pages follow; request them with `next_cursor`
+        #[serde(default)]
+        has_more: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next_cursor: Option<ChangeCursor>,
+        /// Notes that changed but fall outside the subscriber's filter (e.g. moved to an
+        /// unsubscribed folder); the client should drop its local copies, if it has any
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        evicted: Vec<String>,
+    },
     /// Client pushing local changes to server
-    PushUpdates { changes: Vec<Note> },
+    PushUpdates {
+        changes: Vec<Note>,
+        /// Edits sent as deltas against a version the server is known to hold
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        deltas: Vec<NoteDelta>,
+    },
+    /// Server could not apply some deltas because its copy differs from their base; the
+    /// client should push these notes again in full. The rest of the push was applied.
+    NeedFullCopy { ids: Vec<String> },
+    /// Server refused some notes of a push, e.g. edits to a shared collection the user may only
+    /// read. Sent instead of `Ack`/`NeedFullCopy`; the rest of the push was applied.
+    PushRejected {
+        rejected: Vec<RejectedNote>,
+        /// As in `NeedFullCopy`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        need_full_copy: Vec<String>,
+    },
     /// Server acknowledging receipt
     Ack,
+    /// Server refused a whole message for breaking one of its limits; nothing in it was applied.
+    /// After `RateLimit` the client should wait `retry_after_ms` before sending again.
+    LimitExceeded {
+        limit: Limit,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+    },
+    /// Client asking the server to compare range hashes, starting from the tree root.
+    /// Used to repair a replica whose cursor can no longer be trusted.
+    ReconcileRequest { ranges: Vec<RangeDigest> },
+    /// Server's answer to one reconciliation round
+    ReconcileResponse {
+        /// Server digests of the sub-ranges of every inner range that differed
+        digests: Vec<RangeDigest>,
+        /// Leaf ranges that differed; `notes` holds every server note in them
+        leaves: Vec<String>,
+        notes: Vec<Note>,
+    },
+    /// Server telling a socket that the device session it logged in with was revoked; the
+    /// connection closes right after. Clients report this as `NotaroError::SignedOut`.
+    SessionRevoked,
+    /// Server telling a socket that it is shutting down, e.g. for an upgrade; the connection
+    /// closes right after. Every message answered before it was applied in full, and anything
+    /// sent since was not, so the client should resend unanswered pushes once it reconnects,
+    /// no sooner than `retry_after_ms`.
+    ServerShutdown { retry_after_ms: u64 },
+    /// Synced settings. Sent by a client with its entries; the server merges them per key,
+    /// answers with its full set and forwards the entries that won to the user's other devices.
+    SettingsUpdate { entries: Vec<SettingEntry> },
+    /// Client telling the server it opened, is typing in or closed a note. An open note is
+    /// forgotten after `sync::PRESENCE_TIMEOUT` unless `Opened` is sent again, and typing lapses
+    /// after `sync::TYPING_TIMEOUT`. `Opened` is answered with the note's `PresenceUpdate`.
+    SetPresence { note_id: String, activity: NoteActivity },
+    /// Server listing every device that has a note open, the recipient's own included, whenever
+    /// that changes. Sent to every session with access to the note; an empty list means nobody.
+    PresenceUpdate { note_id: String, present: Vec<NotePresence> },
+}
+
+impl SyncMessage {
+    /// The variant name, as sent in the `type` field
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Hello { .. } => "Hello",
+            Self::Welcome { .. } => "Welcome",
+            Self::PullRequest { .. } => "PullRequest",
+            Self::PullResponse { .. } => "PullResponse",
+            Self::PushUpdates { .. } => "PushUpdates",
+            Self::NeedFullCopy { .. } => "NeedFullCopy",
+            Self::PushRejected { .. } => "PushRejected",
+            Self::Ack => "Ack",
+            Self::LimitExceeded { .. } => "LimitExceeded",
+            Self::ReconcileRequest { .. } => "ReconcileRequest",
+            Self::ReconcileResponse { .. } => "ReconcileResponse",
+            Self::SessionRevoked => "SessionRevoked",
+            Self::ServerShutdown { .. } => "ServerShutdown",
+            Self::SettingsUpdate { .. } => "SettingsUpdate",
+            Self::SetPresence { .. } => "SetPresence",
+            Self::PresenceUpdate { .. } => "PresenceUpdate",
+        }
+    }
 }
 
 #[cfg(test)]
@@ -76,6 +367,51 @@ mod tests {
     use super::*;
     use serde_json::json;
 
+    #[test]
+    fn test_note_tags() {
+        let note = Note::new(
+            "T".into(),
+            "# Heading\n#Work and #work again, #side-project. Not a#tag, ## nor this".into(),
+            None,
+        );
+        assert_eq!(note.tags(), vec!["work", "side-project"]);
+    }
+
+    #[test]
+    fn test_kind_matches_wire_tag() {
+        let messages = [
+            SyncMessage::Ack,
+            SyncMessage::NeedFullCopy { ids: vec![] },
+            SyncMessage::PushRejected { rejected: vec![], need_full_copy: vec![] },
+            SyncMessage::SettingsUpdate { entries: vec![] },
+            SyncMessage::ServerShutdown { retry_after_ms: 5000 },
+            SyncMessage::SetPresence { note_id: "n".into(), activity: NoteActivity::Typing },
+            SyncMessage::PresenceUpdate { note_id: "n".into(), present: vec![] },
+            SyncMessage::LimitExceeded {
+                limit: Limit::RateLimit,
+                message: "slow down".into(),
+                retry_after_ms: Some(250),
+            },
+        ];
+        for message in messages {
+            assert_eq!(serde_json::to_value(&message).unwrap()["type"], message.kind());
+        }
+    }
+
+    #[test]
+    fn test_apply_synced_ignores_bad_values() {
+        let mut settings = UserSettings::default();
+        settings.apply_synced("accent_hue", &json!(120));
+        settings.apply_synced("accent_hue", &json!("not a number"));
+        settings.apply_synced("font_size", &json!(30));
+        settings.apply_synced("added_later", &json!(true));
+
+        assert_eq!(settings.accent_hue, 120);
+        assert_eq!(settings.font_size, UserSettings::default().font_size);
+        assert_eq!(settings.synced_value("accent_hue"), Some(json!(120)));
+        assert_eq!(settings.synced_value("font_size"), None);
+    }
+
     #[test]
     fn test_note_creation() {
         let title = "Test Note".to_string();
@@ -84,17 +420,17 @@ mod tests {
 
         assert_eq!(note.title, title);
         assert_eq!(note.folder, Some("Work".to_string()));
-        assert_eq!(note.is_pinned, false);
+        assert!(!note.is_pinned);
         assert_eq!(note.content, content);
         assert_eq!(note.version, 1);
-        assert_eq!(note.is_deleted, false);
+        assert!(!note.is_deleted);
         assert!(!note.id.is_empty());
     }
 
     #[test]
     fn test_sync_message_serialization() {
         // Test PullRequest
-        let msg = SyncMessage::PullRequest { since_version: 5 };
+        let msg = SyncMessage::PullRequest { since_version: 5, cursor: None, limit: None };
         let json = serde_json::to_value(&msg).unwrap();
 
         assert_eq!(
@@ -118,11 +454,36 @@ mod tests {
 
         let deserialized: SyncMessage = serde_json::from_value(json_input).unwrap();
         match deserialized {
-            SyncMessage::PushUpdates { changes } => {
+            SyncMessage::PushUpdates { changes, deltas } => {
+                assert!(deltas.is_empty());
                 assert_eq!(changes.len(), 1);
                 assert_eq!(changes[0].title, "A");
             }
             _ => panic!("Wrong variant deserialized"),
         }
     }
+
+    #[test]
+    fn test_pull_response_pagination_fields_default() {
+        // Frames from peers that predate pagination carry no cursor fields
+        let json_input = json!({
+            "type": "PullResponse",
+            "payload": {
+                "changes": [],
+                "current_version": 3
+            }
+        });
+
+        let deserialized: SyncMessage = serde_json::from_value(json_input).unwrap();
+        assert_eq!(
+            deserialized,
+            SyncMessage::PullResponse {
+                changes: vec![],
+                current_version: 3,
+                has_more: false,
+                next_cursor: None,
+                evicted: vec![]
+            }
+        );
+    }
 }
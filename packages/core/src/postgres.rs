@@ -0,0 +1,554 @@
+use crate::database::{ChangePage, NoteCounts, PageBuilder};
+use crate::delta::NoteDelta;
+use crate::error::{NotaroError, Result};
+use crate::filter::SyncFilter;
+use crate::hlc::{Hlc, HybridClock};
+use crate::merge::{MergeReport, Resolution, merge_concurrent, resolve};
+use crate::merkle::{MerkleTree, range_of};
+use crate::models::{ChangeCursor, Note, SettingEntry};
+use crate::store::{NoteStore, rebuild_from_deltas};
+use crate::tls::ServerTrust;
+use chrono::{DateTime, Utc};
+use postgres::{GenericClient, Row};
+use r2d2_postgres::PostgresConnectionManager;
+use tokio_postgres_rustls::MakeRustlsConnect;
+use uuid::Uuid;
+
+type Pool = r2d2::Pool<PostgresConnectionManager<MakeRustlsConnect>>;
+type Connection = r2d2::PooledConnection<PostgresConnectionManager<MakeRustlsConnect>>;
+
+/// Key of the advisory lock that serializes schema migrations across servers
+const MIGRATION_LOCK: i64 = 0x6e6f7461726f;
+
+const NOTE_COLUMNS: &str = "id, title, content, folder, is_pinned, created_at, updated_at, version, is_deleted, hlc, version_vector, collection";
+
+/// Every user's rows live in the same tables, keyed by `owner`. Ids and stamps use the "C"
+/// collation so they sort bytewise, as they do in SQLite.
+const SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS notes (
+        owner TEXT NOT NULL,
+        id TEXT COLLATE "C" NOT NULL,
+        folder TEXT,
+        title TEXT NOT NULL,
+        content TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        updated_at TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        is_deleted BOOLEAN NOT NULL DEFAULT FALSE,
+        is_pinned BOOLEAN NOT NULL DEFAULT FALSE,
+        hlc TEXT COLLATE "C" NOT NULL,
+        version_vector TEXT NOT NULL DEFAULT '{}',
+        collection TEXT,
+        PRIMARY KEY (owner, id)
+    );
+    -- Added with shared collections
+    ALTER TABLE notes ADD COLUMN IF NOT EXISTS collection TEXT;
+    CREATE INDEX IF NOT EXISTS notes_change_feed ON notes (owner, version, id);
+    CREATE TABLE IF NOT EXISTS synced_settings (
+        owner TEXT NOT NULL,
+        key TEXT COLLATE "C" NOT NULL,
+        value TEXT NOT NULL,
+        hlc TEXT COLLATE "C" NOT NULL,
+        PRIMARY KEY (owner, key)
+    );
+"#;
+
+impl From<postgres::Error> for NotaroError {
+    fn from(e: postgres::Error) -> Self {
+        // The cause (refused connection, server message, ...) is only in the source
+        match std::error::Error::source(&e) {
+            Some(cause) => NotaroError::Postgres(format!("{e}: {cause}")),
+            None => NotaroError::Postgres(e.to_string()),
+        }
+    }
+}
+
+impl From<r2d2::Error> for NotaroError {
+    fn from(e: r2d2::Error) -> Self {
+        NotaroError::Postgres(e.to_string())
+    }
+}
+
+/// Connection pool to the PostgreSQL database that holds every user's notes
+#[derive(Clone)]
+pub struct PostgresDatabase {
+    pool: Pool,
+}
+
+impl PostgresDatabase {
+    /// Connects to `url` (a `postgres://` URL or `key=value` string) with up to
+    /// `max_connections` connections and creates the tables if needed.
+    /// TLS follows the URL's `sslmode` (`prefer` by default), trusting certificates per `trust`.
+    ///
+    /// Blocks, so async code has to call it (and every `PostgresStore` method) from
+    /// `spawn_blocking`.
+    pub fn connect(url: &str, max_connections: u32, trust: &ServerTrust) -> Result<Self> {
+        let config: postgres::Config = url.parse()?;
+        let tls = MakeRustlsConnect::new(trust.client_config()?.as_ref().clone());
+
+        // Migrate over a plain connection first, so a bad URL fails once with the real error
+        // instead of after the pool has retried every slot
+        let mut client = config.connect(tls.clone())?;
+        let mut tx = client.transaction()?;
+        tx.batch_execute("SET LOCAL client_min_messages = warning")?;
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])?;
+        tx.batch_execute(SCHEMA)?;
+        tx.commit()?;
+
+        let manager = PostgresConnectionManager::new(config, tls);
+        let pool =
+            r2d2::Pool::builder().max_size(max_connections).min_idle(Some(1)).build(manager)?;
+        Ok(Self { pool })
+    }
+
+    /// The notes of `owner`, written as replica `device_id`
+    pub fn store(&self, owner: &str, device_id: &str) -> Result<PostgresStore> {
+        let store = PostgresStore {
+            pool: self.pool.clone(),
+            owner: owner.to_string(),
+            clock: HybridClock::new(device_id.to_string()),
+        };
+
+        // Never hand out stamps older than what is already stored
+        let latest: Option<String> = store
+            .conn()?
+            .query_one("SELECT MAX(hlc) FROM notes WHERE owner = $1", &[&owner])?
+            .get(0);
+        if let Some(latest) = latest.and_then(|h| h.parse::<Hlc>().ok()) {
+            store.clock.advance_to(&latest);
+        }
+        Ok(store)
+    }
+
+    /// Whether `owner` has any notes or settings stored
+    pub fn has_owner(&self, owner: &str) -> Result<bool> {
+        let row = self.pool.get()?.query_one(
+            "SELECT EXISTS (SELECT 1 FROM notes WHERE owner = $1)
+                 OR EXISTS (SELECT 1 FROM synced_settings WHERE owner = $1)",
+            &[&owner],
+        )?;
+        Ok(row.get(0))
+    }
+
+    /// Fails unless the database answers a query
+    pub fn ping(&self) -> Result<()> {
+        self.pool.get()?.simple_query("SELECT 1")?;
+        Ok(())
+    }
+
+    /// Bytes the note tables and their indexes take up on disk
+    pub fn size_bytes(&self) -> Result<u64> {
+        let row = self.pool.get()?.query_one(
+            "SELECT pg_total_relation_size('notes') + pg_total_relation_size('synced_settings')",
+            &[],
+        )?;
+        Ok(row.get::<_, i64>(0).try_into().unwrap_or(0))
+    }
+}
+
+/// One user's notes in a `PostgresDatabase`
+pub struct PostgresStore {
+    pool: Pool,
+    owner: String,
+    clock: HybridClock,
+}
+
+/// Maps a row selected as `NOTE_COLUMNS` into a `Note`
+fn note_from_row(row: &Row) -> Result<Note> {
+    let id: String = row.try_get(0)?;
+    let corrupt = |column: &str, e: &dyn std::fmt::Display| {
+        NotaroError::Postgres(format!("note {id}: invalid {column}: {e}"))
+    };
+    let timestamp = |column: usize, name: &str| -> Result<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(row.try_get(column)?)
+            .map(|t| t.with_timezone(&Utc))
+            .map_err(|e| corrupt(name, &e))
+    };
+
+    Ok(Note {
+        title: row.try_get(1)?,
+        content: row.try_get(2)?,
+        folder: row.try_get(3)?,
+        is_pinned: row.try_get(4)?,
+        created_at: timestamp(5, "created_at")?,
+        updated_at: timestamp(6, "updated_at")?,
+        version: row.try_get(7)?,
+        is_deleted: row.try_get(8)?,
+        hlc: row.try_get::<_, &str>(9)?.parse().map_err(|e| corrupt("hlc", &e))?,
+        version_vector: serde_json::from_str(row.try_get(10)?)
+            .map_err(|e| corrupt("version_vector", &e))?,
+        collection: row.try_get(11)?,
+        id,
+    })
+}
+
+/// Inserts a note, or with `upsert` fully overwrites an existing one
+fn store_note(
+    client: &mut impl GenericClient,
+    owner: &str,
+    note: &Note,
+    upsert: bool,
+) -> Result<()> {
+    let conflict = if upsert {
+        "ON CONFLICT (owner, id) DO UPDATE SET title = excluded.title, content = excluded.content,
+             folder = excluded.folder, is_pinned = excluded.is_pinned,
+             created_at = excluded.created_at, updated_at = excluded.updated_at,
+             version = excluded.version, is_deleted = excluded.is_deleted, hlc = excluded.hlc,
+             version_vector = excluded.version_vector, collection = excluded.collection"
+    } else {
+        ""
+    };
+    client.execute(
+        &format!(
+            "INSERT INTO notes (owner, {NOTE_COLUMNS})
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13) {conflict}"
+        ),
+        &[
+            &owner,
+            &note.id,
+            &note.title,
+            &note.content,
+            &note.folder,
+            &note.is_pinned,
+            &note.created_at.to_rfc3339(),
+            &note.updated_at.to_rfc3339(),
+            &note.version,
+            &note.is_deleted,
+            &note.hlc.to_string(),
+            &serde_json::to_string(&note.version_vector)?,
+            &note.collection,
+        ],
+    )?;
+    Ok(())
+}
+
+impl PostgresStore {
+    fn conn(&self) -> Result<Connection> {
+        self.pool.get().map_err(Into::into)
+    }
+
+    /// Bumps the version, clock stamp and this device's vector entry for a local write
+    fn stamp_local_write(&self, note: &mut Note) {
+        note.hlc = self.clock.now();
+        note.updated_at = note.hlc.to_datetime();
+        note.version += 1;
+        note.version_vector.increment(self.clock.node());
+    }
+
+    fn delete_rows(&self, ids: &[String]) -> Result<usize> {
+        let removed = self
+            .conn()?
+            .execute("DELETE FROM notes WHERE owner = $1 AND id = ANY($2)", &[&self.owner, &ids])?;
+        Ok(removed as usize)
+    }
+}
+
+impl NoteStore for PostgresStore {
+    fn device_id(&self) -> &str {
+        self.clock.node()
+    }
+
+    fn create_note(&self, title: String, content: String, folder: Option<String>) -> Result<Note> {
+        let mut note = Note::new(title, content, folder);
+        note.id = Uuid::new_v4().to_string();
+        note.hlc = self.clock.now();
+        note.created_at = note.hlc.to_datetime();
+        note.updated_at = note.created_at;
+        note.version_vector.increment(self.clock.node());
+        store_note(&mut *self.conn()?, &self.owner, &note, false)?;
+        Ok(note)
+    }
+
+    fn get_all_notes(&self) -> Result<Vec<Note>> {
+        self.conn()?
+            .query(
+                &format!(
+                    "SELECT {NOTE_COLUMNS} FROM notes WHERE owner = $1
+                     ORDER BY is_pinned DESC, hlc DESC"
+                ),
+                &[&self.owner],
+            )?
+            .iter()
+            .map(note_from_row)
+            .collect()
+    }
+
+    fn get_note_by_id(&self, id: &str) -> Result<Note> {
+        self.find_note(id)?.ok_or_else(|| NotaroError::Postgres(format!("no note with id {id}")))
+    }
+
+    fn find_note(&self, id: &str) -> Result<Option<Note>> {
+        self.conn()?
+            .query_opt(
+                &format!("SELECT {NOTE_COLUMNS} FROM notes WHERE owner = $1 AND id = $2"),
+                &[&self.owner, &id],
+            )?
+            .map(|row| note_from_row(&row))
+            .transpose()
+    }
+
+    fn update_note(
+        &self,
+        id: &str,
+        title: String,
+        content: String,
+        folder: Option<String>,
+        is_pinned: bool,
+    ) -> Result<Note> {
+        let mut note = self.get_note_by_id(id)?;
+        note.title = title;
+        note.content = content;
+        note.folder = folder;
+        note.is_pinned = is_pinned;
+        self.stamp_local_write(&mut note);
+        store_note(&mut *self.conn()?, &self.owner, &note, true)?;
+        Ok(note)
+    }
+
+    fn delete_note(&self, id: &str) -> Result<()> {
+        let mut note = self.get_note_by_id(id)?;
+        if note.is_deleted {
+            self.delete_rows(&[note.id])?;
+        } else {
+            note.is_deleted = true;
+            self.stamp_local_write(&mut note);
+            store_note(&mut *self.conn()?, &self.owner, &note, true)?;
+        }
+        Ok(())
+    }
+
+    fn get_changes_page(
+        &self,
+        since_version: i32,
+        cursor: Option<&ChangeCursor>,
+        limit: usize,
+        max_bytes: usize,
+    ) -> Result<ChangePage> {
+        let (cursor_version, cursor_id) = match cursor {
+            Some(c) => (c.version, c.id.as_str()),
+            None => (i32::MIN, ""),
+        };
+        // One row past the limit tells whether more remain
+        let fetch = i64::try_from(limit.max(1)).unwrap_or(i64::MAX).saturating_add(1);
+
+        let rows = self.conn()?.query(
+            &format!(
+                "SELECT {NOTE_COLUMNS} FROM notes
+                 WHERE owner = $1 AND version > $2 AND (version, id) > ($3, $4)
+                 ORDER BY version, id
+                 LIMIT $5"
+            ),
+            &[&self.owner, &since_version, &cursor_version, &cursor_id, &fetch],
+        )?;
+
+        let mut page = PageBuilder::new(limit, max_bytes);
+        for row in &rows {
+            if !page.offer(note_from_row(row)?)? {
+                break;
+            }
+        }
+        Ok(page.finish())
+    }
+
+    fn current_version(&self) -> Result<i32> {
+        let row = self.conn()?.query_one(
+            "SELECT COALESCE(MAX(version), 0) FROM notes WHERE owner = $1",
+            &[&self.owner],
+        )?;
+        Ok(row.get(0))
+    }
+
+    fn merge_changes(&mut self, remote_changes: Vec<Note>) -> Result<MergeReport> {
+        let mut conn = self.conn()?;
+        let mut tx = conn.transaction()?;
+        let mut report = MergeReport::default();
+
+        for remote_note in remote_changes {
+            // Every stamp we see moves our clock forward, even if the change itself loses
+            self.clock.observe(&remote_note.hlc);
+
+            let local = tx
+                .query_opt(
+                    &format!(
+                        "SELECT {NOTE_COLUMNS} FROM notes WHERE owner = $1 AND id = $2 FOR UPDATE"
+                    ),
+                    &[&self.owner, &remote_note.id],
+                )?
+                .map(|row| note_from_row(&row))
+                .transpose()?;
+
+            match local {
+                None => {
+                    store_note(&mut tx, &self.owner, &remote_note, true)?;
+                    report.inserted += 1;
+                }
+                Some(local) => match resolve(&local, &remote_note) {
+                    Resolution::TakeRemote => {
+                        store_note(&mut tx, &self.owner, &remote_note, true)?;
+                        report.updated += 1;
+                    }
+                    Resolution::KeepLocal => report.ignored += 1,
+                    Resolution::Concurrent { remote_wins } => {
+                        let merged = merge_concurrent(&local, &remote_note, remote_wins);
+                        store_note(&mut tx, &self.owner, &merged, true)?;
+                        report.concurrent.push(remote_note.id);
+                    }
+                },
+            }
+        }
+
+        tx.commit()?;
+        Ok(report)
+    }
+
+    fn merge_deltas(&mut self, deltas: Vec<NoteDelta>) -> Result<Vec<String>> {
+        let (rebuilt, need_full_copy) = rebuild_from_deltas(deltas, |id| self.find_note(id))?;
+        self.merge_changes(rebuilt)?;
+        Ok(need_full_copy)
+    }
+
+    fn evict_notes(&self, ids: &[String]) -> Result<usize> {
+        self.delete_rows(ids)
+    }
+
+    fn merkle_tree(&self, filter: &SyncFilter) -> Result<MerkleTree> {
+        if !filter.is_empty() {
+            let entries = self
+                .get_changes_since(0)?
+                .into_iter()
+                .filter(|note| filter.matches(note))
+                .map(|note| (note.id, note.version));
+            return Ok(MerkleTree::build(entries));
+        }
+
+        let rows =
+            self.conn()?.query("SELECT id, version FROM notes WHERE owner = $1", &[&self.owner])?;
+        let entries = rows.iter().map(|row| (row.get::<_, String>(0), row.get::<_, i32>(1)));
+        Ok(MerkleTree::build(entries))
+    }
+
+    fn get_notes_in_ranges(&self, prefixes: &[String]) -> Result<Vec<Note>> {
+        if prefixes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut conn = self.conn()?;
+        let ids: Vec<String> = conn
+            .query("SELECT id FROM notes WHERE owner = $1", &[&self.owner])?
+            .iter()
+            .map(|row| row.get::<_, String>(0))
+            .filter(|id| {
+                let range = range_of(id);
+                prefixes.iter().any(|prefix| range.starts_with(prefix.as_str()))
+            })
+            .collect();
+
+        conn.query(
+            &format!(
+                "SELECT {NOTE_COLUMNS} FROM notes WHERE owner = $1 AND id = ANY($2) ORDER BY id"
+            ),
+            &[&self.owner, &ids],
+        )?
+        .iter()
+        .map(note_from_row)
+        .collect()
+    }
+
+    fn get_synced_settings(&self) -> Result<Vec<SettingEntry>> {
+        self.conn()?
+            .query(
+                "SELECT key, value, hlc FROM synced_settings WHERE owner = $1 ORDER BY key",
+                &[&self.owner],
+            )?
+            .iter()
+            .map(|row| {
+                let key: String = row.try_get(0)?;
+                let hlc = row.try_get::<_, &str>(2)?.parse().map_err(|e| {
+                    NotaroError::Postgres(format!("setting {key}: invalid hlc: {e}"))
+                })?;
+                Ok(SettingEntry { value: serde_json::from_str(row.try_get(1)?)?, key, hlc })
+            })
+            .collect()
+    }
+
+    fn merge_settings(&self, entries: Vec<SettingEntry>) -> Result<Vec<SettingEntry>> {
+        let mut conn = self.conn()?;
+        let mut tx = conn.transaction()?;
+        let mut applied = Vec::new();
+
+        for entry in entries {
+            self.clock.observe(&entry.hlc);
+
+            let local: Option<String> = tx
+                .query_opt(
+                    "SELECT hlc FROM synced_settings WHERE owner = $1 AND key = $2 FOR UPDATE",
+                    &[&self.owner, &entry.key],
+                )?
+                .map(|row| row.get(0));
+            let newer = match local {
+                Some(local) => local.parse::<Hlc>().map_or(true, |local| entry.hlc > local),
+                None => true,
+            };
+
+            if newer {
+                tx.execute(
+                    "INSERT INTO synced_settings (owner, key, value, hlc) VALUES ($1, $2, $3, $4)
+                     ON CONFLICT (owner, key)
+                     DO UPDATE SET value = excluded.value, hlc = excluded.hlc",
+                    &[
+                        &self.owner,
+                        &entry.key,
+                        &serde_json::to_string(&entry.value)?,
+                        &entry.hlc.to_string(),
+                    ],
+                )?;
+                applied.push(entry);
+            }
+        }
+
+        tx.commit()?;
+        Ok(applied)
+    }
+
+    fn note_counts(&self) -> Result<NoteCounts> {
+        let row = self.conn()?.query_one(
+            "SELECT COUNT(*) FILTER (WHERE NOT is_deleted), COUNT(*) FILTER (WHERE is_deleted),
+                    COALESCE(SUM(octet_length(title) + octet_length(content))
+                        FILTER (WHERE NOT is_deleted), 0),
+                    COUNT(*) FILTER (WHERE NOT is_deleted AND collection IS NOT NULL),
+                    COALESCE(SUM(octet_length(title) + octet_length(content))
+                        FILTER (WHERE NOT is_deleted AND collection IS NOT NULL), 0)
+             FROM notes WHERE owner = $1",
+            &[&self.owner],
+        )?;
+        Ok(NoteCounts {
+            live: row.get::<_, i64>(0) as usize,
+            tombstones: row.get::<_, i64>(1) as usize,
+            bytes: row.get::<_, i64>(2) as u64,
+            shared: row.get::<_, i64>(3) as usize,
+            shared_bytes: row.get::<_, i64>(4) as u64,
+        })
+    }
+
+    fn purge_tombstones(&self, before: DateTime<Utc>) -> Result<usize> {
+        let expired: Vec<String> = self
+            .conn()?
+            .query(
+                "SELECT id, updated_at FROM notes WHERE owner = $1 AND is_deleted",
+                &[&self.owner],
+            )?
+            .iter()
+            .filter(|row| DateTime::parse_from_rfc3339(row.get(1)).is_ok_and(|t| t < before))
+            .map(|row| row.get(0))
+            .collect();
+        self.delete_rows(&expired)
+    }
+
+    /// Verifies every note row still parses; Postgres checks its own storage
+    fn integrity_check(&self) -> Result<Vec<String>> {
+        let rows = self
+            .conn()?
+            .query(&format!("SELECT {NOTE_COLUMNS} FROM notes WHERE owner = $1"), &[&self.owner])?;
+        Ok(rows.iter().filter_map(|row| note_from_row(row).err()).map(|e| e.to_string()).collect())
+    }
+}
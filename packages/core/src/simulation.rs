@@ -0,0 +1,489 @@
+//! Deterministic multi-device sync simulation.
+//!
+//! Spins up in-memory replicas and a simulated server, drives a seeded random schedule of local
+//! edits, pushes, pulls and message deliveries over a lossy network, then settles every replica
+//! and checks that they converged without losing edits.
+
+use crate::database::DatabaseConnection;
+use crate::error::Result;
+use crate::filter::SyncFilter;
+use crate::models::{ChangeCursor, Note, SyncMessage};
+use crate::sync::answer_pull;
+use crate::version_vector::Causality;
+use std::collections::VecDeque;
+
+/// Page size used by simulated pulls, small so pagination is exercised
+const SIM_PULL_LIMIT: u32 = 4;
+/// Virtual time at which every simulation starts (2025-01-01T00:00:00Z)
+const SIM_EPOCH_MS: i64 = 1_735_689_600_000;
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub replicas: usize,
+    /// Number of randomized operations before settling
+    pub steps: usize,
+    /// Probability that a delivered message is dropped
+    pub loss_rate: f64,
+    /// Probability that a delivered message is also queued again
+    pub duplicate_rate: f64,
+    /// Deliver in-flight messages in random order instead of FIFO
+    pub reorder: bool,
+    /// Each replica's wall clock is offset by up to this much in either direction
+    pub max_clock_skew_ms: i64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            replicas: 3,
+            steps: 300,
+            loss_rate: 0.1,
+            duplicate_rate: 0.05,
+            reorder: true,
+            max_clock_skew_ms: 60 * 60 * 1000,
+        }
+    }
+}
+
+/// Counters describing one run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimReport {
+    pub local_writes: usize,
+    pub messages_sent: usize,
+    pub messages_dropped: usize,
+    pub messages_duplicated: usize,
+    /// Merges that found concurrent edits, on replicas and server combined
+    pub conflicts: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Endpoint {
+    Server,
+    Replica(usize),
+}
+
+#[derive(Debug, Clone)]
+struct Envelope {
+    from: Endpoint,
+    to: Endpoint,
+    message: SyncMessage,
+}
+
+/// A local write as it looked right after it was made
+#[derive(Debug, Clone)]
+struct WriteRecord {
+    replica: usize,
+    note: Note,
+}
+
+/// SplitMix64: tiny, seedable and stable across platforms and dependency upgrades
+#[derive(Debug, Clone)]
+struct SimRng(u64);
+
+impl SimRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+pub struct Simulation {
+    config: SimConfig,
+    rng: SimRng,
+    /// Virtual wall-clock time; advanced a little on every step
+    now_ms: i64,
+    server: DatabaseConnection,
+    replicas: Vec<DatabaseConnection>,
+    in_flight: VecDeque<Envelope>,
+    /// Every note id in creation order, so choices do not depend on random UUIDs
+    note_ids: Vec<String>,
+    writes: Vec<WriteRecord>,
+    report: SimReport,
+}
+
+impl Simulation {
+    pub fn new(seed: u64, config: SimConfig) -> Result<Self> {
+        let mut rng = SimRng(seed);
+        let server = DatabaseConnection::with_device_id(":memory:", "server")?;
+        let mut replicas = Vec::with_capacity(config.replicas);
+        for i in 0..config.replicas {
+            let replica = DatabaseConnection::with_device_id(":memory:", &format!("replica-{i}"))?;
+            if config.max_clock_skew_ms > 0 {
+                let span = 2 * config.max_clock_skew_ms as u64 + 1;
+                let skew = (rng.next_u64() % span) as i64 - config.max_clock_skew_ms;
+                replica.clock().set_skew_ms(skew);
+            }
+            replicas.push(replica);
+        }
+
+        let sim = Self {
+            config,
+            rng,
+            now_ms: SIM_EPOCH_MS,
+            server,
+            replicas,
+            in_flight: VecDeque::new(),
+            note_ids: Vec::new(),
+            writes: Vec::new(),
+            report: SimReport::default(),
+        };
+        sim.set_clocks();
+        Ok(sim)
+    }
+
+    /// Pins every replica's physical clock to the simulation's virtual time
+    fn set_clocks(&self) {
+        self.server.clock().set_physical_ms(Some(self.now_ms));
+        for replica in &self.replicas {
+            replica.clock().set_physical_ms(Some(self.now_ms));
+        }
+    }
+
+    /// Runs the randomized phase followed by `settle`.
+    pub fn run(&mut self) -> Result<SimReport> {
+        for _ in 0..self.config.steps {
+            self.step()?;
+        }
+        self.settle()?;
+        Ok(self.report.clone())
+    }
+
+    pub fn replicas(&self) -> &[DatabaseConnection] {
+        &self.replicas
+    }
+
+    pub fn server(&self) -> &DatabaseConnection {
+        &self.server
+    }
+
+    fn step(&mut self) -> Result<()> {
+        self.now_ms += self.rng.below(3) as i64;
+        self.set_clocks();
+
+        let replica = self.rng.below(self.replicas.len());
+        match self.rng.below(100) {
+            0..15 => self.create(replica),
+            15..45 => self.update(replica),
+            45..53 => self.delete(replica),
+            53..58 => self.restore(replica),
+            58..70 => {
+                self.push(replica)?;
+                Ok(())
+            }
+            70..80 => {
+                self.pull(replica, None);
+                Ok(())
+            }
+            _ => self.deliver_one(false),
+        }
+    }
+
+    fn create(&mut self, replica: usize) -> Result<()> {
+        let n = self.note_ids.len();
+        let note = self.replicas[replica].create_note_with_id(
+            format!("note-{n:04}"),
+            format!("Note {n}"),
+            format!("Created on replica {replica}"),
+            None,
+        )?;
+        self.note_ids.push(note.id.clone());
+        self.record(replica, note);
+        Ok(())
+    }
+
+    fn update(&mut self, replica: usize) -> Result<()> {
+        let Some(note) = self.pick_note(replica, |n| !n.is_deleted)? else { return Ok(()) };
+        let edit = self.report.local_writes;
+        let updated = self.replicas[replica].update_note(
+            &note.id,
+            note.title,
+            format!("Edit {edit} on replica {replica}"),
+            note.folder,
+            self.rng.chance(0.2),
+        )?;
+        self.record(replica, updated);
+        Ok(())
+    }
+
+    fn delete(&mut self, replica: usize) -> Result<()> {
+        // Only soft deletes: a hard delete removes the row and is resurrected by the next merge
+        let Some(note) = self.pick_note(replica, |n| !n.is_deleted)? else { return Ok(()) };
+        self.replicas[replica].delete_note(&note.id)?;
+        let deleted = self.replicas[replica].get_note_by_id(&note.id)?;
+        self.record(replica, deleted);
+        Ok(())
+    }
+
+    fn restore(&mut self, replica: usize) -> Result<()> {
+        let Some(note) = self.pick_note(replica, |n| n.is_deleted)? else { return Ok(()) };
+        self.replicas[replica].restore_note(&note.id)?;
+        let restored = self.replicas[replica].get_note_by_id(&note.id)?;
+        self.record(replica, restored);
+        Ok(())
+    }
+
+    fn record(&mut self, replica: usize, note: Note) {
+        self.report.local_writes += 1;
+        self.writes.push(WriteRecord { replica, note });
+    }
+
+    /// Picks a random note the replica holds that matches `filter`
+    fn pick_note(
+        &mut self,
+        replica: usize,
+        filter: impl Fn(&Note) -> bool,
+    ) -> Result<Option<Note>> {
+        let held = self.replicas[replica].get_all_notes()?;
+        let mut candidates: Vec<Note> = self
+            .note_ids
+            .iter()
+            .filter_map(|id| held.iter().find(|n| &n.id == id))
+            .filter(|n| filter(n))
+            .cloned()
+            .collect();
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+        let i = self.rng.below(candidates.len());
+        Ok(Some(candidates.swap_remove(i)))
+    }
+
+    fn push(&mut self, replica: usize) -> Result<()> {
+        let changes = self.replicas[replica].get_changes_since(0)?;
+        self.send(
+            Endpoint::Replica(replica),
+            Endpoint::Server,
+            SyncMessage::PushUpdates { changes, deltas: vec![] },
+        );
+        Ok(())
+    }
+
+    fn pull(&mut self, replica: usize, cursor: Option<ChangeCursor>) {
+        self.send(
+            Endpoint::Replica(replica),
+            Endpoint::Server,
+            SyncMessage::PullRequest { since_version: 0, cursor, limit: Some(SIM_PULL_LIMIT) },
+        );
+    }
+
+    fn send(&mut self, from: Endpoint, to: Endpoint, message: SyncMessage) {
+        self.report.messages_sent += 1;
+        self.in_flight.push_back(Envelope { from, to, message });
+    }
+
+    /// Delivers one in-flight message. Unless `reliable`, it may be dropped or duplicated.
+    fn deliver_one(&mut self, reliable: bool) -> Result<()> {
+        let index = if self.config.reorder && !reliable && !self.in_flight.is_empty() {
+            self.rng.below(self.in_flight.len())
+        } else {
+            0
+        };
+        let Some(envelope) = self.in_flight.remove(index) else { return Ok(()) };
+
+        if !reliable {
+            if self.rng.chance(self.config.loss_rate) {
+                self.report.messages_dropped += 1;
+                return Ok(());
+            }
+            if self.rng.chance(self.config.duplicate_rate) {
+                self.report.messages_duplicated += 1;
+                self.in_flight.push_back(envelope.clone());
+            }
+        }
+
+        match envelope.to {
+            Endpoint::Server => self.server_receive(envelope.from, envelope.message),
+            Endpoint::Replica(replica) => self.replica_receive(replica, envelope.message),
+        }
+    }
+
+    /// Mirrors the sync server: answers pulls, merges pushes and fans them out
+    fn server_receive(&mut self, from: Endpoint, message: SyncMessage) -> Result<()> {
+        match message {
+            SyncMessage::PullRequest { since_version, cursor, limit } => {
+                let response = answer_pull(
+                    &self.server,
+                    since_version,
+                    cursor.as_ref(),
+                    limit,
+                    &SyncFilter::default(),
+                )?;
+                self.send(Endpoint::Server, from, response);
+            }
+            SyncMessage::PushUpdates { changes, .. } => {
+                let ids: Vec<String> = changes.iter().map(|n| n.id.clone()).collect();
+                let report = self.server.merge_changes(changes)?;
+                self.report.conflicts += report.concurrent.len();
+
+                let merged = ids
+                    .iter()
+                    .map(|id| self.server.get_note_by_id(id))
+                    .collect::<Result<Vec<_>>>()?;
+                let current_version = self.server.current_version()?;
+                for replica in 0..self.replicas.len() {
+                    if Endpoint::Replica(replica) != from {
+                        let update = SyncMessage::PullResponse {
+                            changes: merged.clone(),
+                            current_version,
+                            has_more: false,
+                            next_cursor: None,
+                            evicted: Vec::new(),
+                        };
+                        self.send(Endpoint::Server, Endpoint::Replica(replica), update);
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn replica_receive(&mut self, replica: usize, message: SyncMessage) -> Result<()> {
+        if let SyncMessage::PullResponse { changes, has_more, next_cursor, evicted, .. } = message {
+            self.replicas[replica].evict_notes(&evicted)?;
+            let report = self.replicas[replica].merge_changes(changes)?;
+            self.report.conflicts += report.concurrent.len();
+            if has_more {
+                self.pull(replica, next_cursor);
+            }
+        }
+        Ok(())
+    }
+
+    /// Heals the network: delivers everything still in flight without loss, then has every
+    /// replica push and pull its full state.
+    pub fn settle(&mut self) -> Result<()> {
+        self.drain()?;
+        for replica in 0..self.replicas.len() {
+            self.push(replica)?;
+            self.drain()?;
+        }
+        for replica in 0..self.replicas.len() {
+            self.pull(replica, None);
+            self.drain()?;
+        }
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        while !self.in_flight.is_empty() {
+            self.deliver_one(true)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the settled state. Returns a description of every problem found; an empty list
+    /// means all replicas match the server and no edit was lost.
+    ///
+    /// An edit counts as lost if the final version vector does not include it, or if it was the
+    /// only maximal write to its note (every other write was causally before it) and the final
+    /// note differs from it.
+    pub fn verify(&self) -> Result<Vec<String>> {
+        let mut problems = Vec::new();
+        let expected = sorted_notes(&self.server)?;
+
+        for (i, replica) in self.replicas.iter().enumerate() {
+            let notes = sorted_notes(replica)?;
+            if notes.len() != expected.len() {
+                problems.push(format!(
+                    "replica {i} has {} notes, server has {}",
+                    notes.len(),
+                    expected.len()
+                ));
+            }
+            for (ours, theirs) in notes.iter().zip(&expected) {
+                if !same_state(ours, theirs) {
+                    problems.push(format!("replica {i} diverged on note {}", ours.id));
+                }
+            }
+        }
+
+        for id in &self.note_ids {
+            let Some(final_note) = expected.iter().find(|n| &n.id == id) else {
+                problems.push(format!("note {id} missing on server"));
+                continue;
+            };
+            let writes: Vec<&WriteRecord> =
+                self.writes.iter().filter(|w| &w.note.id == id).collect();
+
+            for write in &writes {
+                let included = matches!(
+                    write.note.version_vector.compare(&final_note.version_vector),
+                    Causality::Ancestor | Causality::Equal
+                );
+                if !included {
+                    problems.push(format!(
+                        "write by replica {} to note {id} is missing from the final version vector",
+                        write.replica
+                    ));
+                }
+            }
+
+            let maximal: Vec<&&WriteRecord> = writes
+                .iter()
+                .filter(|w| {
+                    !writes.iter().any(|other| {
+                        w.note.version_vector.compare(&other.note.version_vector)
+                            == Causality::Ancestor
+                    })
+                })
+                .collect();
+            if let [only] = maximal.as_slice()
+                && !same_state(&only.note, final_note)
+            {
+                problems.push(format!(
+                    "non-conflicting edit by replica {} to note {id} was lost",
+                    only.replica
+                ));
+            }
+        }
+
+        Ok(problems)
+    }
+}
+
+fn sorted_notes(db: &DatabaseConnection) -> Result<Vec<Note>> {
+    let mut notes = db.get_all_notes()?;
+    notes.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(notes)
+}
+
+/// Compares the user-visible content and causal history of two copies of a note
+fn same_state(a: &Note, b: &Note) -> bool {
+    a.id == b.id
+        && a.title == b.title
+        && a.content == b.content
+        && a.folder == b.folder
+        && a.is_pinned == b.is_pinned
+        && a.is_deleted == b.is_deleted
+        && a.version_vector == b.version_vector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_is_seeded() {
+        let mut a = SimRng(42);
+        let mut b = SimRng(42);
+        let mut c = SimRng(43);
+        let seq_a: Vec<u64> = (0..8).map(|_| a.next_u64()).collect();
+        let seq_b: Vec<u64> = (0..8).map(|_| b.next_u64()).collect();
+        let seq_c: Vec<u64> = (0..8).map(|_| c.next_u64()).collect();
+        assert_eq!(seq_a, seq_b);
+        assert_ne!(seq_a, seq_c);
+        assert!((0..1_000).all(|_| a.below(7) < 7));
+    }
+}
@@ -0,0 +1,214 @@
+use crate::database::{ChangePage, DatabaseConnection, NoteCounts};
+use crate::delta::{NoteDelta, content_hash};
+use crate::error::Result;
+use crate::filter::SyncFilter;
+use crate::merge::MergeReport;
+use crate::merkle::MerkleTree;
+use crate::models::{ChangeCursor, Note, SettingEntry};
+use chrono::{DateTime, Utc};
+
+/// Storage for one replica's notes and synced settings.
+///
+/// `DatabaseConnection` (SQLite) is the default implementation; the server can also keep notes in
+/// PostgreSQL (see `postgres::PostgresStore`, behind the `postgres` feature). Every
+/// implementation has to pass the conformance suite in `tests/note_store.rs`.
+pub trait NoteStore: Send {
+    /// Stable identifier of this replica, also used as its HLC node and version vector entry
+    fn device_id(&self) -> &str;
+
+    // --- CRUD Operations ---
+
+    fn create_note(&self, title: String, content: String, folder: Option<String>) -> Result<Note>;
+
+    /// Every note, deleted ones included, pinned first and then most recently written first
+    fn get_all_notes(&self) -> Result<Vec<Note>>;
+
+    fn get_note_by_id(&self, id: &str) -> Result<Note>;
+
+    /// Like `get_note_by_id`, but `None` when the note is not stored
+    fn find_note(&self, id: &str) -> Result<Option<Note>>;
+
+    fn update_note(
+        &self,
+        id: &str,
+        title: String,
+        content: String,
+        folder: Option<String>,
+        is_pinned: bool,
+    ) -> Result<Note>;
+
+    /// Soft-deletes a live note and permanently removes one that is already deleted
+    fn delete_note(&self, id: &str) -> Result<()>;
+
+    // --- Sync Logic ---
+
+    /// Every note (deleted ones included) with a version above `version`, in `(version, id)`
+    /// order
+    fn get_changes_since(&self, version: i32) -> Result<Vec<Note>> {
+        Ok(self.get_changes_page(version, None, usize::MAX, usize::MAX)?.changes)
+    }
+
+    /// One page of the change feed; see `DatabaseConnection::get_changes_page`
+    fn get_changes_page(
+        &self,
+        since_version: i32,
+        cursor: Option<&ChangeCursor>,
+        limit: usize,
+        max_bytes: usize,
+    ) -> Result<ChangePage>;
+
+    /// Highest version currently stored, or 0 when empty
+    fn current_version(&self) -> Result<i32>;
+
+    /// Merges remote changes; see `DatabaseConnection::merge_changes`
+    fn merge_changes(&mut self, remote_changes: Vec<Note>) -> Result<MergeReport>;
+
+    /// Applies content deltas whose base matches the stored copy and returns the ids of the
+    /// notes that must be resent in full
+    fn merge_deltas(&mut self, deltas: Vec<NoteDelta>) -> Result<Vec<String>>;
+
+    /// Removes notes without leaving tombstones, so the removal is never synced; returns how
+    /// many were stored
+    fn evict_notes(&self, ids: &[String]) -> Result<usize>;
+
+    /// Range-hash summary of every stored note inside `filter`
+    fn merkle_tree(&self, filter: &SyncFilter) -> Result<MerkleTree>;
+
+    /// Every stored note whose id falls into one of the given Merkle leaf ranges
+    fn get_notes_in_ranges(&self, prefixes: &[String]) -> Result<Vec<Note>>;
+
+    /// Every stamped synced setting, ordered by key
+    fn get_synced_settings(&self) -> Result<Vec<SettingEntry>>;
+
+    /// Merges remote settings key by key and returns the entries that replaced the local value
+    fn merge_settings(&self, entries: Vec<SettingEntry>) -> Result<Vec<SettingEntry>>;
+
+    // --- Maintenance ---
+
+    fn note_counts(&self) -> Result<NoteCounts>;
+
+    /// Permanently removes tombstones last written before `before` and returns how many
+    fn purge_tombstones(&self, before: DateTime<Utc>) -> Result<usize>;
+
+    /// Problems found in the stored data; empty means healthy
+    fn integrity_check(&self) -> Result<Vec<String>>;
+}
+
+/// Splits `deltas` into the notes they rebuild on top of the stored copy (same version and body
+/// hash) and the ids whose base did not match
+pub(crate) fn rebuild_from_deltas(
+    deltas: Vec<NoteDelta>,
+    find_note: impl Fn(&str) -> Result<Option<Note>>,
+) -> Result<(Vec<Note>, Vec<String>)> {
+    let mut rebuilt = Vec::new();
+    let mut need_full_copy = Vec::new();
+
+    for delta in deltas {
+        let note = find_note(&delta.note.id)?
+            .filter(|base| {
+                base.version == delta.base_version && content_hash(&base.content) == delta.base_hash
+            })
+            .and_then(|base| delta.apply(&base.content));
+
+        match note {
+            Some(note) => rebuilt.push(note),
+            None => need_full_copy.push(delta.note.id),
+        }
+    }
+    Ok((rebuilt, need_full_copy))
+}
+
+impl NoteStore for DatabaseConnection {
+    fn device_id(&self) -> &str {
+        DatabaseConnection::device_id(self)
+    }
+
+    fn create_note(&self, title: String, content: String, folder: Option<String>) -> Result<Note> {
+        DatabaseConnection::create_note(self, title, content, folder)
+    }
+
+    fn get_all_notes(&self) -> Result<Vec<Note>> {
+        DatabaseConnection::get_all_notes(self)
+    }
+
+    fn get_note_by_id(&self, id: &str) -> Result<Note> {
+        DatabaseConnection::get_note_by_id(self, id)
+    }
+
+    fn find_note(&self, id: &str) -> Result<Option<Note>> {
+        DatabaseConnection::find_note(self, id)
+    }
+
+    fn update_note(
+        &self,
+        id: &str,
+        title: String,
+        content: String,
+        folder: Option<String>,
+        is_pinned: bool,
+    ) -> Result<Note> {
+        DatabaseConnection::update_note(self, id, title, content, folder, is_pinned)
+    }
+
+    fn delete_note(&self, id: &str) -> Result<()> {
+        DatabaseConnection::delete_note(self, id)
+    }
+
+    fn get_changes_since(&self, version: i32) -> Result<Vec<Note>> {
+        DatabaseConnection::get_changes_since(self, version)
+    }
+
+    fn get_changes_page(
+        &self,
+        since_version: i32,
+        cursor: Option<&ChangeCursor>,
+        limit: usize,
+        max_bytes: usize,
+    ) -> Result<ChangePage> {
+        DatabaseConnection::get_changes_page(self, since_version, cursor, limit, max_bytes)
+    }
+
+    fn current_version(&self) -> Result<i32> {
+        DatabaseConnection::current_version(self)
+    }
+
+    fn merge_changes(&mut self, remote_changes: Vec<Note>) -> Result<MergeReport> {
+        DatabaseConnection::merge_changes(self, remote_changes)
+    }
+
+    fn merge_deltas(&mut self, deltas: Vec<NoteDelta>) -> Result<Vec<String>> {
+        DatabaseConnection::merge_deltas(self, deltas)
+    }
+
+    fn evict_notes(&self, ids: &[String]) -> Result<usize> {
+        DatabaseConnection::evict_notes(self, ids)
+    }
+
+    fn merkle_tree(&self, filter: &SyncFilter) -> Result<MerkleTree> {
+        DatabaseConnection::merkle_tree(self, filter)
+    }
+
+    fn get_notes_in_ranges(&self, prefixes: &[String]) -> Result<Vec<Note>> {
+        DatabaseConnection::get_notes_in_ranges(self, prefixes)
+    }
+
+    fn get_synced_settings(&self) -> Result<Vec<SettingEntry>> {
+        DatabaseConnection::get_synced_settings(self)
+    }
+
+    fn merge_settings(&self, entries: Vec<SettingEntry>) -> Result<Vec<SettingEntry>> {
+        DatabaseConnection::merge_settings(self, entries)
+    }
+
+    fn note_counts(&self) -> Result<NoteCounts> {
+        DatabaseConnection::note_counts(self)
+    }
+
+    fn purge_tombstones(&self, before: DateTime<Utc>) -> Result<usize> {
+        DatabaseConnection::purge_tombstones(self, before)
+    }
+
+    fn integrity_check(&self) -> Result<Vec<String>> {
+        DatabaseConnection::integrity_check(self)
+    }
+}
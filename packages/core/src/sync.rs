@@ -0,0 +1,278 @@
+use crate::error::{NotaroError, Result};
+use crate::filter::SyncFilter;
+use crate::merge::MergeReport;
+use crate::merkle::{MerkleTree, RangeDigest};
+use crate::models::{ChangeCursor, Note, SyncMessage};
+use crate::store::NoteStore;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Page size used when a `PullRequest` does not specify a limit
+pub const DEFAULT_PULL_LIMIT: u32 = 500;
+/// Upper bound on the page size a client may request
+pub const MAX_PULL_LIMIT: u32 = 5_000;
+/// Soft cap on the serialized size of the notes carried by one `PullResponse` frame
+pub const MAX_PULL_FRAME_BYTES: usize = 4 * 1024 * 1024;
+/// Most ranges the server compares in one reconciliation round
+pub const MAX_RECONCILE_RANGES: usize = 4_096;
+/// How long the server keeps a note open for a device after its last `SetPresence`
+pub const PRESENCE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a device counts as typing after its last `NoteActivity::Typing`
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// The id a device is listed under in the `PresenceUpdate`s of `note`: a hash of the device
+/// session it logged in with, salted with the note's collection or, for a private note, with
+/// `owner`. Other users learn neither the session id nor which devices they meet in two
+/// collections. A client finds itself in the list by computing its own.
+pub fn presence_device_id(session_id: &str, owner: &str, note: &Note) -> String {
+    let scope = match &note.collection {
+        Some(collection) => format!("collection:{collection}"),
+        None => format!("user:{owner}"),
+    };
+    let digest = Sha256::digest(format!("{scope}\n{session_id}").as_bytes());
+    digest[..16].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Builds the `PullResponse` for a single page of a (possibly paginated) pull.
+/// Changed notes outside `filter` are listed as evicted rather than sent, except on an initial
+/// pull (`since_version` 0), where the replica has nothing to evict.
+pub fn answer_pull(
+    db: &dyn NoteStore,
+    since_version: i32,
+    cursor: Option<&ChangeCursor>,
+    limit: Option<u32>,
+    filter: &SyncFilter,
+) -> Result<SyncMessage> {
+    let limit = limit.unwrap_or(DEFAULT_PULL_LIMIT).clamp(1, MAX_PULL_LIMIT);
+    let page = db.get_changes_page(since_version, cursor, limit as usize, MAX_PULL_FRAME_BYTES)?;
+    let (changes, mut evicted) = filter.split(page.changes);
+    if since_version == 0 {
+        evicted.clear();
+    }
+
+    Ok(SyncMessage::PullResponse {
+        changes,
+        current_version: db.current_version()?,
+        has_more: page.has_more,
+        next_cursor: page.next_cursor,
+        evicted,
+    })
+}
+
+/// Screens a message received from the server: `SessionRevoked` ends the session as
+/// `NotaroError::SignedOut`, anything else is passed through.
+pub fn check_signed_in(message: SyncMessage) -> Result<SyncMessage> {
+    match message {
+        SyncMessage::SessionRevoked => Err(NotaroError::SignedOut),
+        other => Ok(other),
+    }
+}
+
+/// First message of a reconciliation: the client's root digest.
+pub fn start_reconcile(db: &dyn NoteStore, filter: &SyncFilter) -> Result<SyncMessage> {
+    Ok(SyncMessage::ReconcileRequest { ranges: vec![db.merkle_tree(filter)?.root()] })
+}
+
+/// Server side of one reconciliation round.
+/// For each client range whose hash differs, an inner range is answered with the server's digests
+/// of its children and a leaf range with every server note in it. Only notes inside `filter` are
+/// summarized or sent.
+pub fn answer_reconcile(
+    db: &dyn NoteStore,
+    ranges: &[RangeDigest],
+    filter: &SyncFilter,
+) -> Result<SyncMessage> {
+    let tree = db.merkle_tree(filter)?;
+    let mut digests = Vec::new();
+    let mut leaves = Vec::new();
+
+    for range in tree.differing(ranges).take(MAX_RECONCILE_RANGES) {
+        if MerkleTree::is_leaf(&range.prefix) {
+            leaves.push(range.prefix.clone());
+        } else {
+            digests.extend(MerkleTree::children(&range.prefix).map(|p| tree.digest(&p)));
+        }
+    }
+
+    let (notes, _) = filter.split(db.get_notes_in_ranges(&leaves)?);
+    Ok(SyncMessage::ReconcileResponse { digests, leaves, notes })
+}
+
+/// Outcome of applying a `ReconcileResponse` on the client
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconcileStep {
+    /// Effect of merging the server's notes from the differing leaves
+    pub report: MergeReport,
+    /// Local notes in those leaves that the server lacks or holds another version of; push them
+    pub push: Vec<Note>,
+    /// The next round, or `None` once no range differs
+    pub next: Option<SyncMessage>,
+}
+
+/// Client side of one reconciliation round: merges the server's leaf notes, collects the local
+/// notes the server is missing, and asks about every sub-range that still differs.
+pub fn continue_reconcile(
+    db: &mut dyn NoteStore,
+    filter: &SyncFilter,
+    digests: &[RangeDigest],
+    leaves: &[String],
+    notes: Vec<Note>,
+) -> Result<ReconcileStep> {
+    let server_versions: HashMap<String, i32> =
+        notes.iter().map(|note| (note.id.clone(), note.version)).collect();
+    let report = db.merge_changes(notes)?;
+
+    let push = db
+        .get_notes_in_ranges(leaves)?
+        .into_iter()
+        .filter(|note| filter.matches(note) && server_versions.get(&note.id) != Some(&note.version))
+        .collect();
+
+    let tree = db.merkle_tree(filter)?;
+    let ranges: Vec<RangeDigest> =
+        tree.differing(digests).map(|range| tree.digest(&range.prefix)).collect();
+    let next = (!ranges.is_empty()).then_some(SyncMessage::ReconcileRequest { ranges });
+
+    Ok(ReconcileStep { report, push, next })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::DatabaseConnection;
+
+    /// Root plus one round per tree level
+    const MERKLE_DEPTH_ROUNDS: usize = crate::merkle::MERKLE_DEPTH + 1;
+
+    #[test]
+    fn test_answer_pull_walks_all_pages() {
+        let db = DatabaseConnection::new(":memory:").unwrap();
+        for i in 0..7 {
+            db.create_note(format!("Note {i}"), "Body".into(), None).unwrap();
+        }
+
+        let mut cursor = None;
+        let mut received = 0;
+        let mut pages = 0;
+        loop {
+            let SyncMessage::PullResponse {
+                changes, current_version, has_more, next_cursor, ..
+            } = answer_pull(&db, 0, cursor.as_ref(), Some(3), &SyncFilter::default()).unwrap()
+            else {
+                panic!("Expected PullResponse");
+            };
+            assert_eq!(current_version, 1);
+            received += changes.len();
+            pages += 1;
+            if !has_more {
+                assert!(next_cursor.is_none());
+                break;
+            }
+            cursor = next_cursor;
+        }
+
+        assert_eq!(received, 7);
+        assert_eq!(pages, 3);
+    }
+
+    #[test]
+    fn test_session_revoked_surfaces_as_signed_out() {
+        let err = check_signed_in(SyncMessage::SessionRevoked).unwrap_err();
+        assert!(matches!(err, NotaroError::SignedOut));
+        assert_eq!(err.to_string(), "this device was signed out");
+        assert_eq!(check_signed_in(SyncMessage::Ack).unwrap(), SyncMessage::Ack);
+    }
+
+    #[test]
+    fn test_presence_device_id_differs_per_audience() {
+        let private = Note::new("Diary".into(), "".into(), None);
+        let mut shared = private.clone();
+        shared.collection = Some("team".into());
+
+        let id = presence_device_id("session-1", "alice", &shared);
+        assert_eq!(id.len(), 32);
+        assert!(!id.contains("session-1"));
+        assert_eq!(id, presence_device_id("session-1", "bob", &shared));
+        assert_ne!(id, presence_device_id("session-2", "alice", &shared));
+        assert_ne!(id, presence_device_id("session-1", "alice", &private));
+    }
+
+    #[test]
+    fn test_filtered_pull_evicts_notes_that_left_the_subscription() {
+        let db = DatabaseConnection::new(":memory:").unwrap();
+        let work = db.create_note("Standup".into(), "".into(), Some("Work".into())).unwrap();
+        let diary = db.create_note("Diary".into(), "".into(), Some("Personal".into())).unwrap();
+        let filter = SyncFilter { include_folders: vec!["Work".into()], ..Default::default() };
+
+        let SyncMessage::PullResponse { changes, evicted, .. } =
+            answer_pull(&db, 0, None, None, &filter).unwrap()
+        else {
+            panic!("Expected PullResponse");
+        };
+        assert_eq!(changes, vec![work.clone()]);
+        assert!(evicted.is_empty());
+
+        // Moving the note out of Work evicts it; moving the diary in makes it appear
+        db.update_note(&work.id, work.title.clone(), "".into(), Some("Personal".into()), false)
+            .unwrap();
+        db.update_note(&diary.id, diary.title.clone(), "".into(), Some("Work".into()), false)
+            .unwrap();
+        let SyncMessage::PullResponse { changes, evicted, .. } =
+            answer_pull(&db, 1, None, None, &filter).unwrap()
+        else {
+            panic!("Expected PullResponse");
+        };
+        assert_eq!(changes.iter().map(|n| &n.id).collect::<Vec<_>>(), vec![&diary.id]);
+        assert_eq!(evicted, vec![work.id]);
+    }
+
+    #[test]
+    fn test_reconcile_repairs_both_sides() {
+        let mut server = DatabaseConnection::new(":memory:").unwrap();
+        let mut client = DatabaseConnection::new(":memory:").unwrap();
+        for i in 0..200 {
+            server.create_note(format!("Note {i}"), "Body".into(), None).unwrap();
+        }
+        client.merge_changes(server.get_changes_since(0).unwrap()).unwrap();
+
+        // Both sides change behind the other's back
+        let edited = server.get_all_notes().unwrap()[0].id.clone();
+        server.update_note(&edited, "Edited".into(), "Body".into(), None, false).unwrap();
+        let created = client.create_note("Offline".into(), "Body".into(), None).unwrap();
+
+        let all = SyncFilter::default();
+        let mut request = start_reconcile(&client, &all).unwrap();
+        let mut rounds = 0;
+        let mut transferred = 0;
+        loop {
+            let SyncMessage::ReconcileRequest { ranges } = request else {
+                panic!("Expected ReconcileRequest");
+            };
+            let SyncMessage::ReconcileResponse { digests, leaves, notes } =
+                answer_reconcile(&server, &ranges, &all).unwrap()
+            else {
+                panic!("Expected ReconcileResponse");
+            };
+            transferred += notes.len();
+
+            let step = continue_reconcile(&mut client, &all, &digests, &leaves, notes).unwrap();
+            transferred += step.push.len();
+            server.merge_changes(step.push).unwrap();
+            rounds += 1;
+            match step.next {
+                Some(next) => request = next,
+                None => break,
+            }
+        }
+
+        assert!(rounds <= MERKLE_DEPTH_ROUNDS);
+        assert!(transferred < 10, "sent {transferred} notes");
+        assert_eq!(
+            client.merkle_tree(&all).unwrap().root(),
+            server.merkle_tree(&all).unwrap().root()
+        );
+        assert_eq!(client.get_note_by_id(&edited).unwrap().title, "Edited");
+        assert_eq!(server.get_note_by_id(&created.id).unwrap().title, "Offline");
+    }
+}
@@ -0,0 +1,150 @@
+use crate::error::{NotaroError, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// How a sync client decides whether to trust the server's certificate
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ServerTrust {
+    /// Certificates issued by a public CA, checked against the Mozilla root set
+    #[default]
+    PublicRoots,
+    /// Certificates issued by this PEM-encoded CA, for servers using a private CA
+    CustomCa(String),
+    /// Exactly the certificate with this SHA-256 fingerprint (hex, colons optional), whoever
+    /// issued it. Self-signed certificates work this way; the server name is not checked.
+    Fingerprint(String),
+}
+
+impl ServerTrust {
+    /// Builds the rustls configuration to open `wss://` connections with
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+
+        let config = match self {
+            Self::PublicRoots => {
+                let roots =
+                    RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                builder.with_root_certificates(roots).with_no_client_auth()
+            }
+            Self::CustomCa(pem) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_slice_iter(pem.as_bytes()) {
+                    roots.add(cert.map_err(tls_error)?).map_err(tls_error)?;
+                }
+                if roots.is_empty() {
+                    return Err(NotaroError::Tls("no certificate in the CA PEM".into()));
+                }
+                builder.with_root_certificates(roots).with_no_client_auth()
+            }
+            Self::Fingerprint(pin) => {
+                let verifier = PinnedCertificate { fingerprint: parse_fingerprint(pin)?, provider };
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(verifier))
+                    .with_no_client_auth()
+            }
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+/// Lowercase hex SHA-256 of a DER certificate, the form `ServerTrust::Fingerprint` expects
+pub fn fingerprint(cert_der: &[u8]) -> String {
+    Sha256::digest(cert_der).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn parse_fingerprint(pin: &str) -> Result<String> {
+    let hex: String =
+        pin.chars().filter(|c| *c != ':' && !c.is_whitespace()).collect::<String>().to_lowercase();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(NotaroError::Tls(format!("`{pin}` is not a SHA-256 fingerprint")));
+    }
+    Ok(hex)
+}
+
+fn tls_error(e: impl std::fmt::Display) -> NotaroError {
+    NotaroError::Tls(e.to_string())
+}
+
+/// Accepts only the pinned end-entity certificate, but still checks the handshake signatures
+/// so the server has to hold its private key
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("certificate does not match the pinned fingerprint".into()))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_accepts_colons_and_case() {
+        let pin = fingerprint(b"certificate");
+        let pairs: Vec<&str> =
+            pin.as_bytes().chunks(2).map(|c| std::str::from_utf8(c).unwrap()).collect();
+        assert_eq!(parse_fingerprint(&pairs.join(":").to_uppercase()).unwrap(), pin);
+        assert!(ServerTrust::Fingerprint(pin).client_config().is_ok());
+    }
+
+    #[test]
+    fn test_rejects_bad_trust_settings() {
+        assert!(matches!(
+            ServerTrust::Fingerprint("abc".into()).client_config(),
+            Err(NotaroError::Tls(_))
+        ));
+        assert!(matches!(
+            ServerTrust::CustomCa("not a pem".into()).client_config(),
+            Err(NotaroError::Tls(_))
+        ));
+        assert!(ServerTrust::PublicRoots.client_config().is_ok());
+    }
+}
@@ -0,0 +1,114 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// How two version vectors relate causally
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Equal,
+    /// Self has seen everything the other has, and more
+    Descendant,
+    /// The other has seen everything self has, and more
+    Ancestor,
+    /// Each side has writes the other has not seen
+    Concurrent,
+}
+
+/// Per-device write counters for a single note.
+/// Each device increments its own entry on every local write, so comparing two vectors tells
+/// whether one version was derived from the other or both were edited independently.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(transparent)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+impl VersionVector {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, device: &str) -> u64 {
+        self.0.get(device).copied().unwrap_or(0)
+    }
+
+    /// Records one more write by `device`
+    pub fn increment(&mut self, device: &str) {
+        *self.0.entry(device.to_string()).or_default() += 1;
+    }
+
+    /// Pointwise maximum: the smallest vector that descends from both
+    pub fn join(&self, other: &Self) -> Self {
+        let mut joined = self.0.clone();
+        for (device, &count) in &other.0 {
+            let entry = joined.entry(device.clone()).or_default();
+            *entry = (*entry).max(count);
+        }
+        Self(joined)
+    }
+
+    pub fn compare(&self, other: &Self) -> Causality {
+        let mut ahead = false;
+        let mut behind = false;
+
+        for device in self.0.keys().chain(other.0.keys()) {
+            match self.get(device).cmp(&other.get(device)) {
+                Ordering::Greater => ahead = true,
+                Ordering::Less => behind = true,
+                Ordering::Equal => {}
+            }
+        }
+
+        match (ahead, behind) {
+            (false, false) => Causality::Equal,
+            (true, false) => Causality::Descendant,
+            (false, true) => Causality::Ancestor,
+            (true, true) => Causality::Concurrent,
+        }
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+}
+
+impl<const N: usize> From<[(&str, u64); N]> for VersionVector {
+    fn from(entries: [(&str, u64); N]) -> Self {
+        Self(entries.into_iter().map(|(d, c)| (d.to_string(), c)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let base = VersionVector::from([("a", 1)]);
+        let a2 = VersionVector::from([("a", 2)]);
+        let b1 = VersionVector::from([("a", 1), ("b", 1)]);
+
+        assert_eq!(base.compare(&base), Causality::Equal);
+        assert_eq!(a2.compare(&base), Causality::Descendant);
+        assert_eq!(base.compare(&b1), Causality::Ancestor);
+        assert_eq!(a2.compare(&b1), Causality::Concurrent);
+        assert_eq!(VersionVector::default().compare(&base), Causality::Ancestor);
+    }
+
+    #[test]
+    fn test_join_descends_from_both() {
+        let a = VersionVector::from([("a", 3), ("b", 1)]);
+        let b = VersionVector::from([("b", 2), ("c", 5)]);
+        let joined = a.join(&b);
+
+        assert_eq!(joined, VersionVector::from([("a", 3), ("b", 2), ("c", 5)]));
+        assert_eq!(joined.compare(&a), Causality::Descendant);
+        assert_eq!(joined.compare(&b), Causality::Descendant);
+    }
+
+    #[test]
+    fn test_serializes_as_plain_map() {
+        let mut vv = VersionVector::default();
+        vv.increment("laptop");
+        vv.increment("laptop");
+        assert_eq!(serde_json::to_string(&vv).unwrap(), r#"{"laptop":2}"#);
+    }
+}
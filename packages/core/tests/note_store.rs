@@ -0,0 +1,278 @@
+//! Conformance suite for `NoteStore` implementations.
+//!
+//! Every check runs against SQLite. With the `postgres` feature they also run against PostgreSQL
+//! when `NOTARO_TEST_POSTGRES_URL` names a database, e.g.
+//! `postgres://postgres@localhost/notaro_test`; each test writes as a fresh owner.
+
+use chrono::{Duration, Utc};
+use notaro_core::delta::NoteDelta;
+use notaro_core::hlc::Hlc;
+use notaro_core::{ChangeCursor, DatabaseConnection, NoteStore, SettingEntry, SyncFilter};
+use serde_json::json;
+
+/// Opens an empty store written as replica `device_id`
+type Open<'a> = &'a dyn Fn(&str) -> Box<dyn NoteStore>;
+
+/// Another device's replica, used to produce remote changes
+fn remote(device_id: &str) -> DatabaseConnection {
+    DatabaseConnection::with_device_id(":memory:", device_id).unwrap()
+}
+
+fn check_crud_lifecycle(open: Open) {
+    let store = open("server");
+    let note = store.create_note("Hello".into(), "World".into(), Some("Work".into())).unwrap();
+    assert_eq!(note.version, 1);
+    assert_eq!(note.hlc.node, "server");
+    assert_eq!(store.get_note_by_id(&note.id).unwrap(), note);
+    assert_eq!(store.find_note(&note.id).unwrap(), Some(note.clone()));
+    assert_eq!(store.find_note("missing").unwrap(), None);
+
+    let pinned = store.create_note("Pinned".into(), "".into(), None).unwrap();
+    let pinned = store.update_note(&pinned.id, "Pinned".into(), "!".into(), None, true).unwrap();
+    assert_eq!(pinned.version, 2);
+    assert_eq!(pinned.version_vector.get("server"), 2);
+
+    let updated = store.update_note(&note.id, "Hello 2".into(), "".into(), None, false).unwrap();
+    assert_eq!(store.get_note_by_id(&note.id).unwrap(), updated);
+    let ids: Vec<String> = store.get_all_notes().unwrap().into_iter().map(|n| n.id).collect();
+    assert_eq!(ids, [pinned.id.clone(), note.id.clone()]);
+
+    store.delete_note(&note.id).unwrap();
+    let tombstone = store.get_note_by_id(&note.id).unwrap();
+    assert!(tombstone.is_deleted);
+    assert_eq!(tombstone.version, 3);
+
+    store.delete_note(&note.id).unwrap();
+    assert!(store.get_note_by_id(&note.id).is_err());
+    assert!(store.delete_note("missing").is_err());
+    assert_eq!(store.get_all_notes().unwrap().len(), 1);
+}
+
+fn check_change_feed(open: Open) {
+    let store = open("server");
+    assert_eq!(store.current_version().unwrap(), 0);
+    let mut created = Vec::new();
+    for i in 0..5 {
+        created.push(store.create_note(format!("Note {i}"), "x".repeat(100), None).unwrap());
+    }
+    let edited = &created[2];
+    store.update_note(&edited.id, "Edited".into(), "".into(), None, false).unwrap();
+    assert_eq!(store.current_version().unwrap(), 2);
+
+    let feed = store.get_changes_since(0).unwrap();
+    let order: Vec<(i32, String)> = feed.iter().map(|n| (n.version, n.id.clone())).collect();
+    let mut sorted = order.clone();
+    sorted.sort();
+    assert_eq!(order, sorted);
+    assert_eq!(feed.len(), 5);
+    assert_eq!(store.get_changes_since(1).unwrap().len(), 1);
+
+    let mut walked = Vec::new();
+    let mut cursor: Option<ChangeCursor> = None;
+    loop {
+        let page = store.get_changes_page(0, cursor.as_ref(), 2, usize::MAX).unwrap();
+        assert!(page.changes.len() <= 2);
+        walked.extend(page.changes.iter().map(|n| n.id.clone()));
+        if !page.has_more {
+            assert_eq!(page.next_cursor, None);
+            break;
+        }
+        cursor = page.next_cursor;
+    }
+    assert_eq!(walked, feed.iter().map(|n| n.id.clone()).collect::<Vec<_>>());
+
+    // The byte cap ends a page early but never returns an empty one
+    let page = store.get_changes_page(0, None, 10, 1).unwrap();
+    assert_eq!(page.changes.len(), 1);
+    assert!(page.has_more);
+}
+
+fn check_merge(open: Open) {
+    let mut store = open("server");
+    let mut laptop = remote("laptop");
+    let note = laptop.create_note("Remote".into(), "From the laptop".into(), None).unwrap();
+
+    let report = store.merge_changes(vec![note.clone()]).unwrap();
+    assert_eq!(report.inserted, 1);
+    // Stored exactly as received, timestamps included
+    assert_eq!(store.get_note_by_id(&note.id).unwrap(), note);
+
+    let update = laptop.update_note(&note.id, "Remote 2".into(), "".into(), None, false).unwrap();
+    let report = store.merge_changes(vec![update.clone(), note.clone()]).unwrap();
+    assert_eq!((report.updated, report.ignored), (1, 1));
+    assert_eq!(store.get_note_by_id(&note.id).unwrap(), update);
+
+    // Both sides edit the same version; every replica has to settle on the same copy
+    let mine = store.update_note(&note.id, "Server edit".into(), "".into(), None, false).unwrap();
+    let theirs =
+        laptop.update_note(&note.id, "Laptop edit".into(), "".into(), None, false).unwrap();
+    let report = store.merge_changes(vec![theirs.clone()]).unwrap();
+    assert_eq!(report.concurrent, vec![note.id.clone()]);
+    laptop.merge_changes(vec![mine]).unwrap();
+    assert_eq!(store.get_note_by_id(&note.id).unwrap(), laptop.get_note_by_id(&note.id).unwrap());
+
+    // Merged stamps move the clock, so later local writes sort after them
+    let later = store.create_note("Later".into(), "".into(), None).unwrap();
+    assert!(later.hlc > theirs.hlc);
+}
+
+fn check_deltas(open: Open) {
+    let mut store = open("server");
+    let laptop = remote("laptop");
+    let body = "A long body that only changes a little at the end. ".repeat(10);
+    let base = laptop.create_note("Delta".into(), body.clone(), None).unwrap();
+    store.merge_changes(vec![base.clone()]).unwrap();
+
+    let edited =
+        laptop.update_note(&base.id, "Delta".into(), format!("{body}!"), None, false).unwrap();
+    let delta = NoteDelta::between(base.version, &base.content, &edited).unwrap();
+    let mut stale = delta.clone();
+    stale.base_hash = "not the base".into();
+
+    assert_eq!(store.merge_deltas(vec![stale]).unwrap(), vec![base.id.clone()]);
+    assert!(store.merge_deltas(vec![delta]).unwrap().is_empty());
+    assert_eq!(store.get_note_by_id(&base.id).unwrap(), edited);
+}
+
+fn check_shared_notes_and_eviction(open: Open) {
+    let mut store = open("server");
+    let laptop = remote("laptop");
+    let note = laptop.create_note("Runbook".into(), "Restart it".into(), None).unwrap();
+    let shared = laptop.move_to_collection(&note.id, "runbooks").unwrap();
+    assert_eq!(shared.version, 2);
+
+    store.merge_changes(vec![shared.clone()]).unwrap();
+    assert_eq!(store.get_note_by_id(&note.id).unwrap().collection.as_deref(), Some("runbooks"));
+
+    // Evicted notes leave no tombstone behind
+    assert_eq!(store.evict_notes(&[note.id.clone(), "missing".into()]).unwrap(), 1);
+    assert!(store.get_note_by_id(&note.id).is_err());
+    assert_eq!(store.note_counts().unwrap().tombstones, 0);
+}
+
+fn check_reconcile_ranges(open: Open) {
+    let mut store = open("server");
+    let laptop = remote("laptop");
+    for i in 0..20 {
+        let folder = if i % 2 == 0 { "Work" } else { "Home" };
+        laptop.create_note(format!("Note {i}"), "".into(), Some(folder.into())).unwrap();
+    }
+    store.merge_changes(laptop.get_changes_since(0).unwrap()).unwrap();
+
+    let all = SyncFilter::default();
+    assert_eq!(store.merkle_tree(&all).unwrap().root(), laptop.merkle_tree(&all).unwrap().root());
+    let work = SyncFilter { include_folders: vec!["Work".into()], ..Default::default() };
+    assert_eq!(store.merkle_tree(&work).unwrap().root(), laptop.merkle_tree(&work).unwrap().root());
+
+    let note = laptop.get_all_notes().unwrap().remove(0);
+    let leaf = notaro_core::merkle::range_of(&note.id);
+    let in_leaf = store.get_notes_in_ranges(&[leaf[..1].to_string()]).unwrap();
+    assert_eq!(in_leaf, laptop.get_notes_in_ranges(&[leaf[..1].to_string()]).unwrap());
+    assert!(in_leaf.contains(&note));
+    assert!(store.get_notes_in_ranges(&[]).unwrap().is_empty());
+}
+
+fn check_settings(open: Open) {
+    let store = open("server");
+    let stamp = |wall_ms| Hlc { wall_ms, counter: 0, node: "laptop".into() };
+    let entry =
+        |key: &str, value, wall_ms| SettingEntry { key: key.into(), value, hlc: stamp(wall_ms) };
+
+    let applied = store
+        .merge_settings(vec![
+            entry("theme_mode", json!("dark"), 10),
+            entry("accent_hue", json!(200), 10),
+        ])
+        .unwrap();
+    assert_eq!(applied.len(), 2);
+
+    let applied = store
+        .merge_settings(vec![
+            entry("theme_mode", json!("light"), 5),
+            entry("accent_hue", json!(90), 20),
+        ])
+        .unwrap();
+    assert_eq!(applied, [entry("accent_hue", json!(90), 20)]);
+    assert_eq!(
+        store.get_synced_settings().unwrap(),
+        [entry("accent_hue", json!(90), 20), entry("theme_mode", json!("dark"), 10)]
+    );
+}
+
+fn check_maintenance(open: Open) {
+    let store = open("server");
+    let kept = store.create_note("Kept".into(), "".into(), None).unwrap();
+    let deleted = store.create_note("Deleted".into(), "".into(), None).unwrap();
+    store.delete_note(&deleted.id).unwrap();
+
+    let counts = store.note_counts().unwrap();
+    assert_eq!((counts.live, counts.tombstones, counts.bytes), (1, 1, kept.size() as u64));
+    assert!(store.integrity_check().unwrap().is_empty());
+
+    assert_eq!(store.purge_tombstones(Utc::now() - Duration::days(1)).unwrap(), 0);
+    assert_eq!(store.purge_tombstones(Utc::now() + Duration::days(1)).unwrap(), 1);
+    assert!(store.get_note_by_id(&deleted.id).is_err());
+    assert!(store.get_note_by_id(&kept.id).is_ok());
+}
+
+fn check_stores_are_isolated(open: Open) {
+    let first = open("server");
+    first.create_note("Private".into(), "".into(), None).unwrap();
+    let second = open("server");
+    assert!(second.get_all_notes().unwrap().is_empty());
+    assert_eq!(second.current_version().unwrap(), 0);
+}
+
+/// Declares one test per check and backend
+macro_rules! conformance {
+    ($($check:ident),* $(,)?) => {
+        mod sqlite {
+            use notaro_core::{DatabaseConnection, NoteStore};
+
+            fn open(device_id: &str) -> Box<dyn NoteStore> {
+                Box::new(DatabaseConnection::with_device_id(":memory:", device_id).unwrap())
+            }
+
+            $(#[test]
+            fn $check() {
+                super::$check(&open);
+            })*
+        }
+
+        #[cfg(feature = "postgres")]
+        mod postgres {
+            use notaro_core::NoteStore;
+            use notaro_core::postgres::PostgresDatabase;
+            use notaro_core::tls::ServerTrust;
+
+            fn database() -> PostgresDatabase {
+                let url = std::env::var("NOTARO_TEST_POSTGRES_URL")
+                    .expect("NOTARO_TEST_POSTGRES_URL names the database to test against");
+                PostgresDatabase::connect(&url, 4, &ServerTrust::default()).unwrap()
+            }
+
+            $(#[test]
+            #[ignore = "needs NOTARO_TEST_POSTGRES_URL"]
+            fn $check() {
+                let database = database();
+                let open = |device_id: &str| -> Box<dyn NoteStore> {
+                    let owner = uuid::Uuid::new_v4().to_string();
+                    Box::new(database.store(&owner, device_id).unwrap())
+                };
+                super::$check(&open);
+            })*
+        }
+    };
+}
+
+conformance!(
+    check_crud_lifecycle,
+    check_change_feed,
+    check_merge,
+    check_deltas,
+    check_shared_notes_and_eviction,
+    check_reconcile_ranges,
+    check_settings,
+    check_maintenance,
+    check_stores_are_isolated,
+);
@@ -0,0 +1,56 @@
+use notaro_core::simulation::{SimConfig, Simulation};
+
+fn run_seed(seed: u64, config: SimConfig) {
+    let mut sim = Simulation::new(seed, config).expect("Failed to set up simulation");
+    let report = sim.run().expect("Simulation failed");
+    let problems = sim.verify().expect("Failed to inspect replicas");
+    assert!(problems.is_empty(), "seed {seed} ({report:?}):\n{}", problems.join("\n"));
+}
+
+#[test]
+fn test_replicas_converge_over_lossy_network() {
+    for seed in 0..20 {
+        run_seed(seed, SimConfig::default());
+    }
+}
+
+#[test]
+fn test_replicas_converge_with_heavy_loss_and_duplication() {
+    let config = SimConfig {
+        replicas: 4,
+        steps: 400,
+        loss_rate: 0.4,
+        duplicate_rate: 0.3,
+        ..SimConfig::default()
+    };
+    for seed in 100..110 {
+        run_seed(seed, config.clone());
+    }
+}
+
+#[test]
+fn test_replicas_converge_on_reliable_fifo_network() {
+    let config = SimConfig {
+        loss_rate: 0.0,
+        duplicate_rate: 0.0,
+        reorder: false,
+        max_clock_skew_ms: 0,
+        ..SimConfig::default()
+    };
+    for seed in 200..205 {
+        run_seed(seed, config.clone());
+    }
+}
+
+#[test]
+fn test_schedule_is_deterministic_per_seed() {
+    let run = |seed| {
+        let mut sim = Simulation::new(seed, SimConfig::default()).unwrap();
+        sim.run().unwrap()
+    };
+
+    let first = run(7);
+    assert_eq!(first, run(7));
+    assert_ne!(first, run(8));
+    assert!(first.local_writes > 0 && first.messages_dropped > 0);
+}
@@ -1,4 +1,5 @@
-use notaro_core::DatabaseConnection;
+use notaro_core::sync::{DEFAULT_PULL_LIMIT, answer_pull};
+use notaro_core::{DatabaseConnection, SyncFilter, SyncMessage};
 use std::thread;
 use std::time::Duration;
 
@@ -96,22 +97,24 @@ fn test_conflict_resolution_last_write_wins() {
         .expect("Device B failed to update");
 
     // 4. Sync A -> B (B has v2, A sends v2)
-    // Current logic: If remote.version (2) > local.version (2) -> False.
-    // B ignores A's changes. Local wins tie.
-    db_b.merge_changes(vec![note_a_v2.clone()]).unwrap();
+    // Versions tie, so the HLC stamps decide. Both devices must pick the same winner.
+    let winner = if note_a_v2.hlc > note_b_v2.hlc { &note_a_v2 } else { &note_b_v2 };
+    let report = db_b.merge_changes(vec![note_a_v2.clone()]).unwrap();
+    assert_eq!(report.concurrent, vec![note.id.clone()]); // Detected as concurrent, not stale
 
     let current_b = db_b.get_all_notes().unwrap().pop().unwrap();
-    assert_eq!(current_b.title, "Version B"); // Local B kept its own change
+    assert_eq!(current_b.title, winner.title);
 
     // 5. Sync B -> A (A has v2, B sends v2)
-    // A ignores B's changes. Local wins tie.
     db_a.merge_changes(vec![note_b_v2.clone()]).unwrap();
 
     let current_a = db_a.get_all_notes().unwrap().pop().unwrap();
-    assert_eq!(current_a.title, "Version A"); // Local A kept its own change
+    assert_eq!(current_a.title, winner.title);
+    assert_eq!(current_a, current_b); // Converged, including the joined version vector
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn test_delete_propagation() {
     let db_a = create_device_db();
     let mut db_b = create_device_db();
@@ -139,3 +142,35 @@ fn test_delete_propagation() {
     let active_notes_b: Vec<_> = notes_b.iter().filter(|n| !n.is_deleted).collect();
     assert_eq!(active_notes_b.len(), 0);
 }
+
+#[test]
+fn test_paginated_initial_sync() {
+    let server = create_device_db();
+    let mut fresh_device = create_device_db();
+
+    for i in 0..1_200 {
+        server.create_note(format!("Note {i}"), "Body".into(), Some("Archive".into())).unwrap();
+    }
+
+    // Fresh device walks the feed page by page until the server reports no more
+    let mut cursor = None;
+    let mut pages = 0;
+    loop {
+        let SyncMessage::PullResponse { changes, has_more, next_cursor, .. } =
+            answer_pull(&server, 0, cursor.as_ref(), None, &SyncFilter::default()).unwrap()
+        else {
+            panic!("Expected PullResponse");
+        };
+        assert!(changes.len() <= DEFAULT_PULL_LIMIT as usize);
+        fresh_device.merge_changes(changes).unwrap();
+        pages += 1;
+
+        if !has_more {
+            break;
+        }
+        cursor = next_cursor;
+    }
+
+    assert_eq!(pages, 3);
+    assert_eq!(fresh_device.get_all_notes().unwrap().len(), 1_200);
+}